@@ -0,0 +1,317 @@
+//! Requests for access to a collection, which its owner approves or denies.
+//! Open requests are kept under a global sequence number, indexed by
+//! collection and by requester; answered requests are removed, and a timer
+//! removes those left open for [`ACCESS_REQUEST_TTL_NS`]. Since ids grow
+//! with time, the oldest requests are the first ones in ACCESS_REQUESTS.
+
+use crate::collections::{owned_collection_names, share_collection};
+use crate::file_shares::is_file_share;
+use crate::notifications::{notify, NotificationKind};
+use crate::users::{ensure_active, record_activity, user_for_principal};
+use crate::{
+    collection_name_to_blob, ApiError, MapId, Username, ACCESS_REQUESTS,
+    ACCESS_REQUESTS_BY_COLLECTION, ACCESS_REQUESTS_BY_REQUESTER, ENCRYPTED_MAPS,
+    NEXT_ACCESS_REQUEST_ID,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{AccessRights, ByteBuf};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const ACCESS_REQUEST_TTL_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const ACCESS_REQUEST_EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_ACCESS_REQUEST_MESSAGE_LEN: usize = 500;
+const MAX_OPEN_REQUESTS_PER_COLLECTION: usize = 100;
+const MAX_OPEN_REQUESTS_PER_REQUESTER: usize = 20;
+/// Upper bound on requests removed per timer tick. Any rest is picked up by
+/// the next tick.
+const MAX_EXPIRED_REQUESTS_PER_TICK: usize = 100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AccessRequest {
+    pub request_id: u64,
+    pub requester: Principal,
+    pub collection_owner: Principal,
+    pub collection_name: ByteBuf,
+    pub message: Option<String>,
+    pub created_at: u64,
+}
+
+impl Storable for AccessRequest {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccessRequestView {
+    pub request: AccessRequest,
+    pub requester_username: Option<Username>,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AccessRequestDecision {
+    Approve(AccessRights),
+    Deny,
+}
+
+/// Asks the owner of a collection for access to it. The request stays open
+/// until the owner answers it, or for 30 days.
+#[update]
+fn request_access(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    message: Option<String>,
+) -> Result<u64, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    if caller == Principal::anonymous() {
+        return Err("anonymous principals cannot request access"
+            .to_string()
+            .into());
+    }
+    ensure_active(caller)?;
+    record_activity(caller);
+    if caller == collection_owner {
+        return Err("you own this collection".to_string().into());
+    }
+    let message = message
+        .map(|message| message.trim().to_string())
+        .filter(|message| !message.is_empty());
+    if message
+        .as_ref()
+        .is_some_and(|message| message.chars().count() > MAX_ACCESS_REQUEST_MESSAGE_LEN)
+    {
+        return Err(format!(
+            "the message must be at most {MAX_ACCESS_REQUEST_MESSAGE_LEN} characters"
+        )
+        .into());
+    }
+    let map_id = (
+        collection_owner,
+        collection_name_to_blob(collection_name.clone())?,
+    );
+    if is_file_share(map_id) || !owned_collection_names(collection_owner).contains(&map_id.1) {
+        return Err("collection not found".to_string().into());
+    }
+    let rights = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(collection_owner, map_id, caller)
+    })?;
+    if rights.is_some() {
+        return Err("you already have access to this collection"
+            .to_string()
+            .into());
+    }
+    let open_requests = collection_request_ids(map_id);
+    if open_requests
+        .iter()
+        .filter_map(|request_id| access_request(*request_id))
+        .any(|request| request.requester == caller)
+    {
+        return Err("you have already requested access to this collection"
+            .to_string()
+            .into());
+    }
+    if open_requests.len() >= MAX_OPEN_REQUESTS_PER_COLLECTION {
+        return Err("the collection has too many open access requests"
+            .to_string()
+            .into());
+    }
+    if requester_request_ids(caller).len() >= MAX_OPEN_REQUESTS_PER_REQUESTER {
+        return Err(format!(
+            "at most {MAX_OPEN_REQUESTS_PER_REQUESTER} access requests can be open at once"
+        )
+        .into());
+    }
+
+    let request_id = NEXT_ACCESS_REQUEST_ID.with_borrow_mut(|next_request_id| {
+        let request_id = *next_request_id.get();
+        next_request_id
+            .set(request_id + 1)
+            .expect("failed to store access request id");
+        request_id
+    });
+    let request = AccessRequest {
+        request_id,
+        requester: caller,
+        collection_owner,
+        collection_name,
+        message,
+        created_at: ic_cdk::api::time(),
+    };
+    ACCESS_REQUESTS.with_borrow_mut(|requests| requests.insert(request_id, request));
+    ACCESS_REQUESTS_BY_COLLECTION
+        .with_borrow_mut(|index| index.insert((map_id.0, map_id.1, request_id), ()));
+    ACCESS_REQUESTS_BY_REQUESTER.with_borrow_mut(|index| index.insert((caller, request_id), ()));
+    notify(
+        collection_owner,
+        caller,
+        map_id,
+        NotificationKind::AccessRequested { request_id },
+    );
+    Ok(request_id)
+}
+
+/// The open access requests for one of the caller's collections, oldest
+/// first.
+#[query]
+fn list_access_requests(collection_name: ByteBuf) -> Result<Vec<AccessRequestView>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    Ok(collection_request_ids(map_id)
+        .into_iter()
+        .filter_map(access_request)
+        .map(|request| AccessRequestView {
+            requester_username: user_for_principal(&request.requester).map(|user| user.username),
+            expires_at: request.created_at.saturating_add(ACCESS_REQUEST_TTL_NS),
+            request,
+        })
+        .collect())
+}
+
+/// Answers an access request for one of the caller's collections. Approving
+/// grants the requester the given rights right away.
+#[update]
+fn respond_to_access_request(
+    request_id: u64,
+    decision: AccessRequestDecision,
+) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let request = access_request(request_id)
+        .filter(|request| request.collection_owner == caller)
+        .ok_or_else(|| "access request not found".to_string())?;
+    let map_id = (
+        caller,
+        collection_name_to_blob(request.collection_name.clone())?,
+    );
+    let kind = match decision {
+        AccessRequestDecision::Approve(access_rights) => {
+            share_collection(
+                request.collection_name.clone(),
+                request.requester,
+                access_rights,
+            )?;
+            NotificationKind::AccessRequestApproved { access_rights }
+        }
+        AccessRequestDecision::Deny => NotificationKind::AccessRequestDenied,
+    };
+    notify(request.requester, caller, map_id, kind);
+    remove_access_request(&request);
+    Ok(())
+}
+
+/// Removes the open requests for a collection, e.g. when it is deleted or
+/// handed over.
+pub fn remove_collection_access_requests(map_id: MapId) {
+    for request_id in collection_request_ids(map_id) {
+        if let Some(request) = access_request(request_id) {
+            remove_access_request(&request);
+        }
+    }
+}
+
+/// Points the open requests for a renamed collection to its new name.
+pub fn move_collection_access_requests(from: MapId, to: MapId, new_name: &ByteBuf) {
+    for request_id in collection_request_ids(from) {
+        let Some(request) = access_request(request_id) else {
+            continue;
+        };
+        let moved = AccessRequest {
+            collection_name: new_name.clone(),
+            ..request
+        };
+        ACCESS_REQUESTS.with_borrow_mut(|requests| requests.insert(request_id, moved));
+        ACCESS_REQUESTS_BY_COLLECTION.with_borrow_mut(|index| {
+            index.remove(&(from.0, from.1, request_id));
+            index.insert((to.0, to.1, request_id), ());
+        });
+    }
+}
+
+/// Removes the requests `user` has made and those for their collections,
+/// e.g. with their account.
+pub fn remove_user_access_requests(user: Principal) {
+    let for_owned: Vec<u64> = ACCESS_REQUESTS_BY_COLLECTION.with_borrow(|index| {
+        index
+            .range((user, Blob::default(), 0)..)
+            .take_while(|((owner, _, _), _)| owner == &user)
+            .map(|((_, _, request_id), _)| request_id)
+            .collect()
+    });
+    for request_id in for_owned.into_iter().chain(requester_request_ids(user)) {
+        if let Some(request) = access_request(request_id) {
+            remove_access_request(&request);
+        }
+    }
+}
+
+/// Removes requests left open too long periodically. Timers do not survive
+/// upgrades, so this is called from both init and post_upgrade.
+pub fn start_access_request_expiry_timer() {
+    ic_cdk_timers::set_timer_interval(ACCESS_REQUEST_EXPIRY_INTERVAL, remove_expired_requests);
+}
+
+fn remove_expired_requests() {
+    let cutoff = ic_cdk::api::time().saturating_sub(ACCESS_REQUEST_TTL_NS);
+    let expired: Vec<AccessRequest> = ACCESS_REQUESTS.with_borrow(|requests| {
+        requests
+            .iter()
+            .map(|(_, request)| request)
+            .take_while(|request| request.created_at <= cutoff)
+            .take(MAX_EXPIRED_REQUESTS_PER_TICK)
+            .collect()
+    });
+    for request in expired {
+        remove_access_request(&request);
+    }
+}
+
+fn access_request(request_id: u64) -> Option<AccessRequest> {
+    ACCESS_REQUESTS.with_borrow(|requests| requests.get(&request_id))
+}
+
+fn collection_request_ids(map_id: MapId) -> Vec<u64> {
+    ACCESS_REQUESTS_BY_COLLECTION.with_borrow(|index| {
+        index
+            .range((map_id.0, map_id.1, 0)..)
+            .take_while(|((owner, name, _), _)| (*owner, *name) == map_id)
+            .map(|((_, _, request_id), _)| request_id)
+            .collect()
+    })
+}
+
+fn requester_request_ids(requester: Principal) -> Vec<u64> {
+    ACCESS_REQUESTS_BY_REQUESTER.with_borrow(|index| {
+        index
+            .range((requester, 0)..)
+            .take_while(|((request_requester, _), _)| request_requester == &requester)
+            .map(|((_, request_id), _)| request_id)
+            .collect()
+    })
+}
+
+fn remove_access_request(request: &AccessRequest) {
+    let request_id = request.request_id;
+    ACCESS_REQUESTS.with_borrow_mut(|requests| requests.remove(&request_id));
+    if let Ok(map_name) = collection_name_to_blob(request.collection_name.clone()) {
+        ACCESS_REQUESTS_BY_COLLECTION.with_borrow_mut(|index| {
+            index.remove(&(request.collection_owner, map_name, request_id))
+        });
+    }
+    ACCESS_REQUESTS_BY_REQUESTER
+        .with_borrow_mut(|index| index.remove(&(request.requester, request_id)));
+}
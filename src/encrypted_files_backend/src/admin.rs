@@ -0,0 +1,81 @@
+use crate::CONFIG;
+use candid::Principal;
+use ic_cdk::{query, update};
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Canister-wide settings that must survive upgrades.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Config {
+    pub admin: Option<Principal>,
+    /// `None` means the default of 30 days.
+    #[serde(default)]
+    pub trash_retention_days: Option<u32>,
+    /// Whether register_user creates a "home" collection for the new user.
+    #[serde(default)]
+    pub create_default_collection: bool,
+    /// `None` means the default of 30 days.
+    #[serde(default)]
+    pub deleted_collection_retention_days: Option<u32>,
+    /// `None` means the default of 100.
+    #[serde(default)]
+    pub max_grantees_per_collection: Option<u32>,
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[query]
+fn get_admin() -> Option<Principal> {
+    CONFIG.with_borrow(|config| config.get().admin)
+}
+
+/// Hands the admin role to another principal. Callable by the current admin
+/// and, so that a lost admin key can be recovered, by controllers.
+#[update]
+fn set_admin(admin: Principal) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        ensure_admin(caller)?;
+    }
+    set_admin_principal(admin);
+    Ok(())
+}
+
+pub fn set_admin_principal(admin: Principal) {
+    CONFIG.with_borrow_mut(|config| {
+        let mut updated = config.get().clone();
+        updated.admin = Some(admin);
+        config.set(updated).expect("failed to store config");
+    });
+}
+
+pub fn set_create_default_collection(enabled: bool) {
+    CONFIG.with_borrow_mut(|config| {
+        let mut updated = config.get().clone();
+        updated.create_default_collection = enabled;
+        config.set(updated).expect("failed to store config");
+    });
+}
+
+pub fn creates_default_collection() -> bool {
+    CONFIG.with_borrow(|config| config.get().create_default_collection)
+}
+
+pub fn ensure_admin(caller: Principal) -> Result<(), String> {
+    if CONFIG.with_borrow(|config| config.get().admin) == Some(caller) {
+        Ok(())
+    } else {
+        Err("unauthorized".to_string())
+    }
+}
@@ -0,0 +1,71 @@
+use crate::users::{caller_user, principal_for_username, record_activity};
+use crate::AVATARS;
+use ic_cdk::{query, update};
+use ic_stable_structures::{storable::Bound, Storable};
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const MAX_AVATAR_SIZE: usize = 64 * 1024;
+const ALLOWED_AVATAR_CONTENT_TYPES: [&str; 3] = ["image/png", "image/jpeg", "image/webp"];
+
+/// A user's profile picture. Kept out of `User` so that iterating over users
+/// does not decode image data.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Avatar {
+    pub content_type: String,
+    pub bytes: ByteBuf,
+}
+
+impl Storable for Avatar {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[update]
+fn set_my_avatar(bytes: Vec<u8>, content_type: String) -> Result<(), String> {
+    let user = caller_user()?;
+    record_activity(user.principal);
+    if bytes.len() > MAX_AVATAR_SIZE {
+        return Err(format!("avatar must be at most {MAX_AVATAR_SIZE} bytes"));
+    }
+    if !ALLOWED_AVATAR_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(format!(
+            "avatar content type must be one of {}",
+            ALLOWED_AVATAR_CONTENT_TYPES.join(", ")
+        ));
+    }
+    let avatar = Avatar {
+        content_type,
+        bytes: ByteBuf::from(bytes),
+    };
+    AVATARS.with_borrow_mut(|avatars| avatars.insert(user.principal, avatar));
+    Ok(())
+}
+
+/// Removes the caller's avatar. Returns whether there was one.
+#[update]
+fn remove_my_avatar() -> bool {
+    let Ok(user) = caller_user() else {
+        return false;
+    };
+    record_activity(user.principal);
+    AVATARS
+        .with_borrow_mut(|avatars| avatars.remove(&user.principal))
+        .is_some()
+}
+
+#[query]
+fn get_avatar(username: String) -> Option<(Vec<u8>, String)> {
+    let principal = principal_for_username(&username).ok()?;
+    AVATARS
+        .with_borrow(|avatars| avatars.get(&principal))
+        .map(|avatar| (avatar.bytes.as_ref().to_vec(), avatar.content_type))
+}
@@ -0,0 +1,84 @@
+//! Index from plaintext checksum to file per collection, for detecting
+//! uploads of content the collection already holds.
+
+use crate::versions::FileKey;
+use crate::{MapKey, MapName, MapOwner, CHECKSUM_INDEX, FILE_METADATA};
+use candid::Principal;
+use ic_stable_structures::storable::Blob;
+
+pub type Checksum = Blob<32>;
+
+/// Another file in the collection of `file_key` with the given checksum.
+pub fn find_duplicate(file_key: FileKey, checksum: &[u8; 32]) -> Option<MapKey> {
+    let (owner, name, file_id) = file_key;
+    let prefix = (owner, name, to_checksum(checksum));
+    CHECKSUM_INDEX.with_borrow(|index| {
+        index
+            .range((prefix, MapKey::default())..)
+            .take_while(|((key_prefix, _), _)| key_prefix == &prefix)
+            .map(|((_, other), _)| other)
+            .find(|other| other != &file_id)
+    })
+}
+
+/// Moves the index entry of a file from its `previous` to its `current`
+/// checksum.
+pub fn update_checksum_index(
+    file_key: FileKey,
+    previous: Option<&[u8; 32]>,
+    current: Option<&[u8; 32]>,
+) {
+    if previous == current {
+        return;
+    }
+    let (owner, name, file_id) = file_key;
+    CHECKSUM_INDEX.with_borrow_mut(|index| {
+        if let Some(checksum) = previous {
+            index.remove(&((owner, name, to_checksum(checksum)), file_id));
+        }
+        if let Some(checksum) = current {
+            index.insert(((owner, name, to_checksum(checksum)), file_id), ());
+        }
+    });
+}
+
+/// Removes the index entries of all collections of `owner`.
+pub fn remove_owned_checksums(owner: Principal) {
+    CHECKSUM_INDEX.with_borrow_mut(|index| {
+        let keys: Vec<_> = index
+            .range(
+                (
+                    (owner, MapName::default(), Checksum::default()),
+                    MapKey::default(),
+                )..,
+            )
+            .take_while(|(((key_owner, _, _), _), _)| key_owner == &owner)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+}
+
+/// Fills the index from FILE_METADATA if it is empty, i.e. on the first
+/// upgrade after it was introduced.
+pub fn backfill_checksum_index() {
+    if !CHECKSUM_INDEX.with_borrow(|index| index.is_empty()) {
+        return;
+    }
+    let entries: Vec<((MapOwner, MapName, MapKey), [u8; 32])> =
+        FILE_METADATA.with_borrow(|file_metadata| {
+            file_metadata
+                .iter()
+                .filter_map(|(file_key, metadata)| Some((file_key, metadata.checksum?)))
+                .collect()
+        });
+    for (file_key, checksum) in entries {
+        update_checksum_index(file_key, None, Some(&checksum));
+    }
+}
+
+fn to_checksum(checksum: &[u8; 32]) -> Checksum {
+    Blob::try_from(checksum.as_slice()).expect("checksum fits 32 bytes")
+}
@@ -0,0 +1,140 @@
+//! Append-only activity log of each collection: who uploaded, removed or
+//! renamed which file and who shared the collection with whom. Entries are
+//! numbered per collection, and only the newest [`MAX_ACTIVITY_ENTRIES`] are
+//! kept.
+
+use crate::{caller_rights, collection_name_to_blob, MapId, COLLECTION_ACTIVITY};
+use candid::{CandidType, Principal};
+use ic_cdk::query;
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const MAX_ACTIVITY_ENTRIES: usize = 1_000;
+const MAX_ACTIVITY_PAGE_LIMIT: u32 = 100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ActivityAction {
+    Uploaded { filename: String },
+    Removed { filename: String },
+    Renamed { from: String, to: String },
+    Shared { user: Principal },
+    Unshared { user: Principal },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ActivityEntry {
+    pub seq: u64,
+    pub actor: Principal,
+    pub action: ActivityAction,
+    pub file_id: Option<ByteBuf>,
+    pub timestamp: u64,
+}
+
+impl Storable for ActivityEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The activity of a collection, newest first, starting below `before_seq`.
+/// Requires read access to the collection.
+#[query]
+fn get_collection_activity(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    before_seq: Option<u64>,
+    limit: u32,
+) -> Result<Vec<ActivityEntry>, String> {
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    caller_rights(ic_cdk::api::msg_caller(), map_id)?;
+    let before_seq = before_seq.unwrap_or(u64::MAX);
+    let limit = limit.min(MAX_ACTIVITY_PAGE_LIMIT) as usize;
+    let mut entries: Vec<ActivityEntry> = COLLECTION_ACTIVITY.with_borrow(|activity| {
+        activity
+            .range((map_id, 0)..(map_id, before_seq))
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    entries.reverse();
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+/// Appends an entry to the activity of a collection, pruning the oldest
+/// entries beyond [`MAX_ACTIVITY_ENTRIES`].
+pub fn log_activity(
+    map_id: MapId,
+    actor: Principal,
+    action: ActivityAction,
+    file_id: Option<Blob<32>>,
+) {
+    let seqs = activity_seqs(map_id);
+    let seq = seqs.last().map_or(0, |last| last + 1);
+    let entry = ActivityEntry {
+        seq,
+        actor,
+        action,
+        file_id: file_id.map(|file_id| ByteBuf::from(file_id.as_slice().to_vec())),
+        timestamp: ic_cdk::api::time(),
+    };
+    COLLECTION_ACTIVITY.with_borrow_mut(|activity| {
+        activity.insert((map_id, seq), entry);
+        let surplus = (seqs.len() + 1).saturating_sub(MAX_ACTIVITY_ENTRIES);
+        for seq in &seqs[..surplus] {
+            activity.remove(&(map_id, *seq));
+        }
+    });
+}
+
+/// Re-keys the activity of a collection, e.g. when it is renamed.
+pub fn move_collection_activity(from: MapId, to: MapId) {
+    for seq in activity_seqs(from) {
+        COLLECTION_ACTIVITY.with_borrow_mut(|activity| {
+            if let Some(entry) = activity.remove(&(from, seq)) {
+                activity.insert((to, seq), entry);
+            }
+        });
+    }
+}
+
+pub fn remove_collection_activity(map_id: MapId) {
+    let seqs = activity_seqs(map_id);
+    COLLECTION_ACTIVITY.with_borrow_mut(|activity| {
+        for seq in seqs {
+            activity.remove(&(map_id, seq));
+        }
+    });
+}
+
+/// Removes the activity of all collections of `owner`.
+pub fn remove_owned_collection_activity(owner: Principal) {
+    COLLECTION_ACTIVITY.with_borrow_mut(|activity| {
+        let keys: Vec<(MapId, u64)> = activity
+            .range(((owner, Blob::default()), 0)..)
+            .take_while(|(((map_owner, _), _), _)| map_owner == &owner)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            activity.remove(&key);
+        }
+    });
+}
+
+fn activity_seqs(map_id: MapId) -> Vec<u64> {
+    COLLECTION_ACTIVITY.with_borrow(|activity| {
+        activity
+            .range((map_id, 0)..)
+            .take_while(|((key, _), _)| key == &map_id)
+            .map(|((_, seq), _)| seq)
+            .collect()
+    })
+}
@@ -0,0 +1,398 @@
+//! Grantees whom the owner of a collection lets manage who it is shared
+//! with. EncryptedMaps only lets a map's owner change its grants, so
+//! managers act through our endpoints, which check the flag and then change
+//! the grants on the owner's behalf. The flag is kept next to the grant and
+//! only counts while the grant exists; the owner can always manage sharing.
+//! Co-owners are managers who may also edit the collection's details and
+//! whom other managers cannot remove; deleting and handing over the
+//! collection stays with the owner. Resharers may only share the collection
+//! with users who have no access yet, at rights no higher than their own.
+
+use crate::collections::{ensure_not_archived, owned_collection_names, set_grant};
+use crate::users::{ensure_active, principal_for_username, record_activity};
+use crate::{
+    collection_name_to_blob, ApiError, MapId, Username, COLLECTION_CO_OWNERS, COLLECTION_MANAGERS,
+    COLLECTION_RESHARERS, ENCRYPTED_MAPS,
+};
+use candid::Principal;
+use ic_cdk::update;
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::{AccessControl, AccessRights, ByteBuf};
+
+const MAX_CO_OWNERS: usize = 10;
+
+/// Lets `username`, who must already have access to one of the caller's
+/// collections, share it with others and revoke their access, or takes
+/// that away again.
+#[update]
+fn set_collection_manager(
+    collection_name: ByteBuf,
+    username: Username,
+    can_manage: bool,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string());
+    }
+    let user = principal_for_username(&username)?;
+    if user == caller {
+        return Err("the owner can always manage sharing".to_string());
+    }
+    if !has_grant(map_id, user)? {
+        return Err(format!("the collection is not shared with {username}"));
+    }
+    let key = (map_id.0, map_id.1, user);
+    COLLECTION_MANAGERS.with_borrow_mut(|managers| {
+        if can_manage {
+            managers.insert(key, ());
+        } else {
+            managers.remove(&key);
+        }
+    });
+    Ok(())
+}
+
+/// Lets `username`, who must already have access to one of the caller's
+/// collections, share it further at rights no higher than their own, or
+/// takes that away again.
+#[update]
+fn set_may_reshare(
+    collection_name: ByteBuf,
+    username: Username,
+    may_reshare: bool,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string());
+    }
+    let user = principal_for_username(&username)?;
+    if user == caller {
+        return Err("the owner can always share the collection".to_string());
+    }
+    if !has_grant(map_id, user)? {
+        return Err(format!("the collection is not shared with {username}"));
+    }
+    let key = (map_id.0, map_id.1, user);
+    COLLECTION_RESHARERS.with_borrow_mut(|resharers| {
+        if may_reshare {
+            resharers.insert(key, ());
+        } else {
+            resharers.remove(&key);
+        }
+    });
+    Ok(())
+}
+
+/// Makes `username` a co-owner of one of the caller's collections, giving
+/// them read-write access first if they have less.
+#[update]
+fn add_co_owner(collection_name: ByteBuf, username: Username) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string().into());
+    }
+    let user = principal_for_username(&username)?;
+    if user == caller {
+        return Err("you already own this collection".to_string().into());
+    }
+    ensure_not_archived(map_id)?;
+    if is_co_owner(map_id, user) {
+        return Ok(());
+    }
+    if collection_co_owners(map_id).len() >= MAX_CO_OWNERS {
+        return Err(format!("a collection can have at most {MAX_CO_OWNERS} co-owners").into());
+    }
+    let rights = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(map_id.0, map_id, user)
+    })?;
+    if rights.map_or(true, |rights| !rights.can_write()) {
+        set_grant(map_id, caller, user, AccessRights::ReadWrite)?;
+    }
+    COLLECTION_CO_OWNERS
+        .with_borrow_mut(|co_owners| co_owners.insert((map_id.0, map_id.1, user), ()));
+    Ok(())
+}
+
+/// Makes a co-owner of one of the caller's collections a plain grantee
+/// again, keeping their access.
+#[update]
+fn remove_co_owner(collection_name: ByteBuf, username: Username) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    let user = principal_for_username(&username)?;
+    let removed = COLLECTION_CO_OWNERS
+        .with_borrow_mut(|co_owners| co_owners.remove(&(map_id.0, map_id.1, user)))
+        .is_some();
+    if !removed {
+        return Err(format!("{username} is not a co-owner of the collection"));
+    }
+    Ok(())
+}
+
+/// Whether `user` is flagged as a manager of a collection. Unlike
+/// [`can_manage`], this does not check that the grant still exists.
+pub fn is_manager(map_id: MapId, user: Principal) -> bool {
+    COLLECTION_MANAGERS.with_borrow(|managers| managers.contains_key(&(map_id.0, map_id.1, user)))
+}
+
+/// Whether `user` is flagged as a co-owner of a collection, without
+/// checking that the grant still exists.
+pub fn is_co_owner(map_id: MapId, user: Principal) -> bool {
+    COLLECTION_CO_OWNERS
+        .with_borrow(|co_owners| co_owners.contains_key(&(map_id.0, map_id.1, user)))
+}
+
+/// Whether `user` is flagged as allowed to reshare a collection, without
+/// checking that the grant still exists.
+pub fn may_reshare(map_id: MapId, user: Principal) -> bool {
+    COLLECTION_RESHARERS
+        .with_borrow(|resharers| resharers.contains_key(&(map_id.0, map_id.1, user)))
+}
+
+/// Whether `user` may change who a collection is shared with.
+pub fn can_manage(map_id: MapId, user: Principal) -> Result<bool, String> {
+    if user == map_id.0 {
+        return Ok(true);
+    }
+    Ok((is_manager(map_id, user) || is_co_owner(map_id, user)) && has_grant(map_id, user)?)
+}
+
+/// Whether `user` owns or co-owns a collection.
+pub fn is_owner_or_co_owner(map_id: MapId, user: Principal) -> Result<bool, String> {
+    Ok(user == map_id.0 || (is_co_owner(map_id, user) && has_grant(map_id, user)?))
+}
+
+pub fn ensure_can_manage(map_id: MapId, user: Principal) -> Result<(), String> {
+    if !can_manage(map_id, user)? {
+        return Err("you cannot manage sharing of this collection".to_string());
+    }
+    Ok(())
+}
+
+/// Fails unless `user` may manage or at least reshare a collection.
+pub fn ensure_can_share(map_id: MapId, user: Principal) -> Result<(), String> {
+    if can_manage(map_id, user)? || (may_reshare(map_id, user) && has_grant(map_id, user)?) {
+        return Ok(());
+    }
+    Err("you cannot share this collection".to_string())
+}
+
+/// Fails if `user`, who is not the owner, would grant higher rights on a
+/// collection than they have themselves.
+pub fn ensure_within_own_rights(
+    map_id: MapId,
+    user: Principal,
+    rights: AccessRights,
+) -> Result<(), String> {
+    let own = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(map_id.0, map_id, user)
+    })?;
+    if own.map_or(true, |own| rights_rank(rights) > rights_rank(own)) {
+        return Err("you cannot grant higher rights than your own".to_string());
+    }
+    Ok(())
+}
+
+/// Drops the manager, co-owner and resharer flags of `user` on a
+/// collection, e.g. when their grant is revoked.
+pub fn remove_manager(map_id: MapId, user: Principal) {
+    let key = (map_id.0, map_id.1, user);
+    COLLECTION_MANAGERS.with_borrow_mut(|managers| managers.remove(&key));
+    COLLECTION_CO_OWNERS.with_borrow_mut(|co_owners| co_owners.remove(&key));
+    COLLECTION_RESHARERS.with_borrow_mut(|resharers| resharers.remove(&key));
+}
+
+/// Carries the manager, co-owner and resharer flags of a collection over
+/// when it is renamed.
+pub fn move_collection_managers(from: MapId, to: MapId) {
+    for user in collection_managers(from) {
+        COLLECTION_MANAGERS.with_borrow_mut(|managers| {
+            managers.remove(&(from.0, from.1, user));
+            managers.insert((to.0, to.1, user), ());
+        });
+    }
+    for user in collection_co_owners(from) {
+        COLLECTION_CO_OWNERS.with_borrow_mut(|co_owners| {
+            co_owners.remove(&(from.0, from.1, user));
+            co_owners.insert((to.0, to.1, user), ());
+        });
+    }
+    for user in collection_resharers(from) {
+        COLLECTION_RESHARERS.with_borrow_mut(|resharers| {
+            resharers.remove(&(from.0, from.1, user));
+            resharers.insert((to.0, to.1, user), ());
+        });
+    }
+}
+
+/// Drops the manager, co-owner and resharer flags of a collection, e.g.
+/// when it is deleted or all access to it is revoked.
+pub fn remove_collection_managers(map_id: MapId) {
+    for user in collection_managers(map_id)
+        .into_iter()
+        .chain(collection_co_owners(map_id))
+        .chain(collection_resharers(map_id))
+    {
+        remove_manager(map_id, user);
+    }
+}
+
+/// Drops the manager, co-owner and resharer flags on all collections of
+/// `owner`.
+pub fn remove_owned_collection_managers(owner: Principal) {
+    for flags in [
+        &COLLECTION_MANAGERS,
+        &COLLECTION_CO_OWNERS,
+        &COLLECTION_RESHARERS,
+    ] {
+        let keys: Vec<(Principal, Blob<32>, Principal)> = flags.with_borrow(|flags| {
+            flags
+                .range((owner, Blob::default(), Principal::management_canister())..)
+                .take_while(|((map_owner, _, _), _)| map_owner == &owner)
+                .map(|(key, _)| key)
+                .collect()
+        });
+        flags.with_borrow_mut(|flags| {
+            for key in keys {
+                flags.remove(&key);
+            }
+        });
+    }
+}
+
+fn collection_managers(map_id: MapId) -> Vec<Principal> {
+    COLLECTION_MANAGERS.with_borrow(|managers| {
+        managers
+            .range((map_id.0, map_id.1, Principal::management_canister())..)
+            .take_while(|((owner, name, _), _)| (*owner, *name) == map_id)
+            .map(|((_, _, user), _)| user)
+            .collect()
+    })
+}
+
+fn collection_co_owners(map_id: MapId) -> Vec<Principal> {
+    COLLECTION_CO_OWNERS.with_borrow(|co_owners| {
+        co_owners
+            .range((map_id.0, map_id.1, Principal::management_canister())..)
+            .take_while(|((owner, name, _), _)| (*owner, *name) == map_id)
+            .map(|((_, _, user), _)| user)
+            .collect()
+    })
+}
+
+fn collection_resharers(map_id: MapId) -> Vec<Principal> {
+    COLLECTION_RESHARERS.with_borrow(|resharers| {
+        resharers
+            .range((map_id.0, map_id.1, Principal::management_canister())..)
+            .take_while(|((owner, name, _), _)| (*owner, *name) == map_id)
+            .map(|((_, _, user), _)| user)
+            .collect()
+    })
+}
+
+fn rights_rank(rights: AccessRights) -> u8 {
+    match rights {
+        AccessRights::Read => 0,
+        AccessRights::ReadWrite => 1,
+        AccessRights::ReadWriteManage => 2,
+    }
+}
+
+fn has_grant(map_id: MapId, user: Principal) -> Result<bool, String> {
+    let rights = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(map_id.0, map_id, user)
+    })?;
+    Ok(rights.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A collection of principal 1 shared with principals 2 and 3, both
+    /// with ReadWrite.
+    fn shared_collection() -> (MapId, Principal, Principal) {
+        crate::init_encrypted_maps("test_key".to_string());
+        let owner = Principal::from_slice(&[1]);
+        let map_id = collection(owner, "docs");
+        let editor = Principal::from_slice(&[2]);
+        let colleague = Principal::from_slice(&[3]);
+        ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            let encrypted_maps = encrypted_maps.as_mut().unwrap();
+            for user in [editor, colleague] {
+                encrypted_maps
+                    .set_user_rights(owner, map_id, user, AccessRights::ReadWrite)
+                    .unwrap();
+            }
+        });
+        (map_id, editor, colleague)
+    }
+
+    fn collection(owner: Principal, name: &str) -> MapId {
+        (owner, Blob::try_from(name.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn owner_can_always_manage() {
+        let (map_id, _, _) = shared_collection();
+        assert!(ensure_can_manage(map_id, map_id.0).is_ok());
+        assert!(ensure_can_share(map_id, map_id.0).is_ok());
+    }
+
+    #[test]
+    fn editor_without_the_flag_cannot_manage() {
+        let (map_id, editor, _) = shared_collection();
+        assert!(!can_manage(map_id, editor).unwrap());
+        assert!(ensure_can_manage(map_id, editor).is_err());
+        assert!(ensure_can_share(map_id, editor).is_err());
+    }
+
+    #[test]
+    fn resharing_does_not_allow_managing() {
+        let (map_id, editor, _) = shared_collection();
+        COLLECTION_RESHARERS
+            .with_borrow_mut(|resharers| resharers.insert((map_id.0, map_id.1, editor), ()));
+        assert!(ensure_can_share(map_id, editor).is_ok());
+        assert!(ensure_can_manage(map_id, editor).is_err());
+    }
+
+    #[test]
+    fn flags_only_count_for_their_collection_and_user() {
+        let (map_id, editor, colleague) = shared_collection();
+        let other = collection(map_id.0, "photos");
+        COLLECTION_MANAGERS.with_borrow_mut(|managers| {
+            managers.insert((other.0, other.1, editor), ());
+            managers.insert((map_id.0, map_id.1, colleague), ());
+        });
+        COLLECTION_CO_OWNERS
+            .with_borrow_mut(|co_owners| co_owners.insert((other.0, other.1, editor), ()));
+        assert!(ensure_can_manage(map_id, editor).is_err());
+        assert!(!is_owner_or_co_owner(map_id, editor).unwrap());
+        assert!(ensure_can_manage(map_id, colleague).is_ok());
+    }
+
+    #[test]
+    fn rights_above_own_cannot_be_granted() {
+        let (map_id, editor, _) = shared_collection();
+        assert!(ensure_within_own_rights(map_id, editor, AccessRights::Read).is_ok());
+        assert!(ensure_within_own_rights(map_id, editor, AccessRights::ReadWrite).is_ok());
+        assert!(ensure_within_own_rights(map_id, editor, AccessRights::ReadWriteManage).is_err());
+    }
+}
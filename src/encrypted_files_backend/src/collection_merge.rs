@@ -0,0 +1,237 @@
+//! Merging one of a user's collections into another. Files are moved with
+//! their metadata, versions, stars, comments and statistics, but keep the
+//! encrypted bytes of the source collection: the client re-encrypts the
+//! moved files with the key of the destination.
+
+use crate::collection_managers::remove_collection_managers;
+use crate::collection_stats::collection_stats;
+use crate::collections::{
+    collection_metadata, ensure_policy_allows, owned_collection_names, remove_collection_remains,
+};
+use crate::filenames::files_named;
+use crate::files::{relocate_file, remove_file, store_metadata, validate_filename, FileMetadata};
+use crate::grant_expiry::set_grant_expiry;
+use crate::groups::remove_collection_group_grants;
+use crate::sharing_audit::log_sharing_change;
+use crate::stats::{owns_files, record_collection_ownership};
+use crate::trash::move_collection_trash;
+use crate::users::record_activity;
+use crate::{collection_name_to_blob, MapId, MapKey, ENCRYPTED_MAPS, FILE_METADATA};
+use candid::{CandidType, Principal};
+use ic_cdk::update;
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::{AccessRights, ByteBuf};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+const MAX_MERGE_FILES: usize = 200;
+const MAX_FILENAME_SUFFIX: u32 = 1_000;
+
+/// What to do with a file whose id or filename is already used in the
+/// destination collection.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Leave the destination file and send the source file to the trash.
+    Skip,
+    /// Send the destination file to the trash and move the source file.
+    Overwrite,
+    /// Move the source file under a fresh id and/or a "name (2).ext"
+    /// filename.
+    RenameWithSuffix,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct MergeReport {
+    /// Ids in the destination of the files moved by this call, still
+    /// encrypted with the key of the source collection.
+    pub moved: Vec<ByteBuf>,
+    /// Ids of source files left out because of a conflict.
+    pub skipped: Vec<ByteBuf>,
+    /// Ids of source files left out because the destination's size or
+    /// content type policy does not accept them.
+    pub rejected: Vec<ByteBuf>,
+    /// Ids of destination files replaced by source files.
+    pub overwritten: Vec<ByteBuf>,
+    /// Source files that got a new id, as (old id, new id).
+    pub renamed: Vec<(ByteBuf, ByteBuf)>,
+    /// Grants of the source collection revoked by this call, to be granted
+    /// again on the destination if wanted.
+    pub dropped_grants: Vec<(Principal, AccessRights)>,
+    /// Files left to move; call again until it is 0.
+    pub remaining: u64,
+}
+
+/// Moves the files of the caller's collection `src_name` into their
+/// collection `dst_name` and deletes `src_name` once it is empty. Moves at
+/// most [`MAX_MERGE_FILES`] files per call; call again until `remaining` is
+/// 0. The first call revokes the grants of the source collection. Skipped
+/// and rejected files go to the trash, which ends up in the destination.
+#[update]
+fn merge_collections(
+    src_name: ByteBuf,
+    dst_name: ByteBuf,
+    on_conflict: ConflictPolicy,
+) -> Result<MergeReport, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let from = (caller, collection_name_to_blob(src_name)?);
+    let to = (caller, collection_name_to_blob(dst_name)?);
+    if from == to {
+        return Err("a collection cannot be merged into itself".to_string());
+    }
+    let owned = owned_collection_names(caller);
+    if !owned.contains(&from.1) || !owned.contains(&to.1) {
+        return Err("collection not found".to_string());
+    }
+    if [from, to]
+        .into_iter()
+        .any(|map_id| collection_metadata(map_id).is_some_and(|metadata| metadata.archived))
+    {
+        return Err("archived collections cannot be merged".to_string());
+    }
+
+    let had_files = owns_files(caller);
+    let mut report = MergeReport::default();
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        for (grantee, rights) in encrypted_maps.get_shared_user_access_for_map(caller, from)? {
+            if grantee != caller {
+                encrypted_maps.remove_user(caller, from, grantee)?;
+                log_sharing_change(from, caller, grantee, Some(rights), None);
+                set_grant_expiry(from, grantee, None);
+                report.dropped_grants.push((grantee, rights));
+            }
+        }
+        Ok::<(), String>(())
+    })?;
+    remove_collection_managers(from);
+    remove_collection_group_grants(from);
+
+    let files: Vec<(MapKey, FileMetadata)> = FILE_METADATA.with_borrow(|file_metadata| {
+        file_metadata
+            .range((from.0, from.1, Blob::default())..)
+            .take_while(|((owner, name, _), _)| (*owner, *name) == from)
+            .take(MAX_MERGE_FILES)
+            .map(|((_, _, file_id), metadata)| (file_id, metadata))
+            .collect()
+    });
+    for (file_id, metadata) in files {
+        merge_file(
+            caller,
+            from,
+            to,
+            file_id,
+            metadata,
+            on_conflict,
+            &mut report,
+        )?;
+    }
+    report.remaining = collection_stats(from).file_count;
+    if report.remaining == 0 {
+        // Skipped files stay recoverable from the destination's trash.
+        move_collection_trash(from, to);
+        remove_collection_remains(caller, from)?;
+    }
+    record_collection_ownership(had_files, owns_files(caller));
+    Ok(report)
+}
+
+fn merge_file(
+    caller: Principal,
+    from: MapId,
+    to: MapId,
+    file_id: MapKey,
+    metadata: FileMetadata,
+    on_conflict: ConflictPolicy,
+    report: &mut MergeReport,
+) -> Result<(), String> {
+    let source = (from.0, from.1, file_id);
+    if ensure_policy_allows(to, metadata.file_size, &metadata.content_type).is_err() {
+        remove_file(caller, source)?;
+        report.rejected.push(to_bytebuf(file_id));
+        return Ok(());
+    }
+    let id_taken = FILE_METADATA
+        .with_borrow(|file_metadata| file_metadata.contains_key(&(to.0, to.1, file_id)));
+    let same_name = files_named(to, &metadata.filename);
+    let mut target_id = file_id;
+    let mut filename = metadata.filename.clone();
+    if id_taken || !same_name.is_empty() {
+        match on_conflict {
+            ConflictPolicy::Skip => {
+                remove_file(caller, source)?;
+                report.skipped.push(to_bytebuf(file_id));
+                return Ok(());
+            }
+            ConflictPolicy::Overwrite => {
+                let mut replaced = same_name;
+                if id_taken && !replaced.contains(&file_id) {
+                    replaced.push(file_id);
+                }
+                for other in replaced {
+                    remove_file(caller, (to.0, to.1, other))?;
+                    report.overwritten.push(to_bytebuf(other));
+                }
+            }
+            ConflictPolicy::RenameWithSuffix => {
+                if id_taken {
+                    target_id = free_file_id(to, file_id);
+                    report
+                        .renamed
+                        .push((to_bytebuf(file_id), to_bytebuf(target_id)));
+                }
+                if !same_name.is_empty() {
+                    filename = free_filename(to, &metadata.filename)?;
+                }
+            }
+        }
+    }
+    if filename != metadata.filename {
+        let renamed = FileMetadata {
+            filename,
+            ..metadata.modified_by(caller)
+        };
+        store_metadata(source, renamed);
+    }
+    if relocate_file(caller, source, (to.0, to.1, target_id))? {
+        report.moved.push(to_bytebuf(target_id));
+    }
+    Ok(())
+}
+
+/// An id not used in `map_id`, derived from `file_id` so that retrying a
+/// merge picks the same one.
+fn free_file_id(map_id: MapId, file_id: MapKey) -> MapKey {
+    (1u32..)
+        .map(|attempt| {
+            let mut hasher = Sha256::new();
+            hasher.update(file_id.as_slice());
+            hasher.update(attempt.to_be_bytes());
+            Blob::try_from(hasher.finalize().as_slice()).unwrap()
+        })
+        .find(|candidate| {
+            !FILE_METADATA.with_borrow(|file_metadata| {
+                file_metadata.contains_key(&(map_id.0, map_id.1, *candidate))
+            })
+        })
+        .unwrap()
+}
+
+/// `filename` with the first " (n)" suffix, inserted before the extension,
+/// that no file of `map_id` uses.
+fn free_filename(map_id: MapId, filename: &str) -> Result<String, String> {
+    let (stem, extension) = match filename.rfind('.') {
+        Some(dot) if dot > 0 => filename.split_at(dot),
+        _ => (filename, ""),
+    };
+    (2..=MAX_FILENAME_SUFFIX)
+        .map(|n| format!("{stem} ({n}){extension}"))
+        .find(|candidate| {
+            validate_filename(candidate).is_ok() && files_named(map_id, candidate).is_empty()
+        })
+        .ok_or_else(|| format!("no free filename for {filename}"))
+}
+
+fn to_bytebuf(file_id: MapKey) -> ByteBuf {
+    ByteBuf::from(file_id.as_slice().to_vec())
+}
@@ -0,0 +1,130 @@
+//! Collections each user pinned to the top of their collection list, in the
+//! order the user chose. Only a user's own collections can be pinned.
+
+use crate::collections::owned_collection_names;
+use crate::users::record_activity;
+use crate::{collection_name_to_blob, MapId, MapName, COLLECTION_PINS};
+use candid::Principal;
+use ic_cdk::update;
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+const MAX_PINNED_COLLECTIONS: usize = 20;
+
+/// Keys of the pinned collections, in display order.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PinnedCollections {
+    pub names: Vec<ByteBuf>,
+}
+
+impl Storable for PinnedCollections {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Pins one of the caller's collections after the already pinned ones, or
+/// unpins it.
+#[update]
+fn set_collection_pin(collection_name: ByteBuf, pinned: bool) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_name = collection_name_to_blob(collection_name)?;
+    let mut pins = pinned_collections(caller);
+    if pinned {
+        if !owned_collection_names(caller).contains(&map_name) {
+            return Err("collection not found".to_string());
+        }
+        if pins.contains(&map_name) {
+            return Ok(());
+        }
+        if pins.len() >= MAX_PINNED_COLLECTIONS {
+            return Err(format!(
+                "at most {MAX_PINNED_COLLECTIONS} collections can be pinned"
+            ));
+        }
+        pins.push(map_name);
+    } else {
+        pins.retain(|pin| pin != &map_name);
+    }
+    store_pinned_collections(caller, pins);
+    Ok(())
+}
+
+/// Reorders the caller's pinned collections. `collection_names` must list
+/// exactly the pinned collections.
+#[update]
+fn set_pinned_collections_order(collection_names: Vec<ByteBuf>) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let ordered = collection_names
+        .into_iter()
+        .map(collection_name_to_blob)
+        .collect::<Result<Vec<MapName>, String>>()?;
+    let pins = pinned_collections(caller);
+    let expected: BTreeSet<&MapName> = pins.iter().collect();
+    let given: BTreeSet<&MapName> = ordered.iter().collect();
+    if ordered.len() != pins.len() || given != expected {
+        return Err("the new order must list exactly the pinned collections".to_string());
+    }
+    store_pinned_collections(caller, ordered);
+    Ok(())
+}
+
+/// The collections `owner` pinned, in display order.
+pub fn pinned_collections(owner: Principal) -> Vec<MapName> {
+    COLLECTION_PINS
+        .with_borrow(|collection_pins| collection_pins.get(&owner))
+        .unwrap_or_default()
+        .names
+        .into_iter()
+        .filter_map(|name| Blob::try_from(name.as_ref()).ok())
+        .collect()
+}
+
+/// Unpins a collection, e.g. when it is deleted.
+pub fn unpin_collection(map_id: MapId) {
+    let (owner, name) = map_id;
+    let mut pins = pinned_collections(owner);
+    if let Some(position) = pins.iter().position(|pin| pin == &name) {
+        pins.remove(position);
+        store_pinned_collections(owner, pins);
+    }
+}
+
+/// Keeps the pin of a renamed collection in place.
+pub fn move_collection_pin(from: MapId, to: MapId) {
+    let mut pins = pinned_collections(from.0);
+    if let Some(pin) = pins.iter_mut().find(|pin| **pin == from.1) {
+        *pin = to.1;
+        store_pinned_collections(from.0, pins);
+    }
+}
+
+pub fn remove_pinned_collections(owner: Principal) {
+    COLLECTION_PINS.with_borrow_mut(|collection_pins| collection_pins.remove(&owner));
+}
+
+fn store_pinned_collections(owner: Principal, pins: Vec<MapName>) {
+    if pins.is_empty() {
+        remove_pinned_collections(owner);
+        return;
+    }
+    let pins = PinnedCollections {
+        names: pins
+            .into_iter()
+            .map(|name| ByteBuf::from(name.as_slice().to_vec()))
+            .collect(),
+    };
+    COLLECTION_PINS.with_borrow_mut(|collection_pins| collection_pins.insert(owner, pins));
+}
@@ -0,0 +1,323 @@
+//! Running totals per collection, so that collection sizes need no scan of
+//! FILE_METADATA. They follow every metadata write; recount_collection_stats
+//! repairs them should they drift anyway.
+
+use crate::admin::ensure_admin;
+use crate::files::FileMetadata;
+use crate::users::{record_activity, user_for_principal};
+use crate::versions::FileKey;
+use crate::{
+    caller_rights, collection_name_to_blob, MapId, MapKey, Username, COLLECTION_METADATA,
+    COLLECTION_STATS, FILE_METADATA,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ops::Bound as RangeBound;
+
+const MAX_ADMIN_COLLECTIONS_PAGE: u32 = 200;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CollectionStats {
+    pub file_count: u64,
+    /// Sum of the `file_size` of all files.
+    pub total_bytes: u64,
+    /// Id and size of the largest file.
+    pub largest_file: Option<(ByteBuf, u64)>,
+    /// `null` if there was no upload since the stats were introduced.
+    pub last_upload_at: Option<u64>,
+}
+
+impl Storable for CollectionStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollectionOrder {
+    ByOwnerAndName,
+    /// Largest collections first.
+    ByTotalBytesDesc,
+}
+
+/// Where a page of admin_list_collections ends.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CollectionCursor {
+    pub owner: Principal,
+    /// The collection's key, which is its name unless that is long.
+    pub name: ByteBuf,
+    pub total_bytes: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AdminCollectionEntry {
+    pub owner: Principal,
+    /// `null` if the owner is not registered.
+    pub owner_username: Option<Username>,
+    pub name: ByteBuf,
+    pub stats: CollectionStats,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AdminCollectionPage {
+    pub collections: Vec<AdminCollectionEntry>,
+    /// Pass as `cursor`, with the same order, to get the next page; `null`
+    /// on the last page.
+    pub next_cursor: Option<CollectionCursor>,
+}
+
+/// All collections of all owners with their statistics, for capacity
+/// planning. Admin only. Sorting by size reads the record and statistics of
+/// every collection, but no file metadata.
+#[query]
+fn admin_list_collections(
+    cursor: Option<CollectionCursor>,
+    limit: u32,
+    order: CollectionOrder,
+) -> Result<AdminCollectionPage, String> {
+    ensure_admin(ic_cdk::api::msg_caller())?;
+    let limit = limit.min(MAX_ADMIN_COLLECTIONS_PAGE) as usize;
+    let after = cursor
+        .map(|cursor| {
+            let name = collection_name_to_blob(cursor.name)?;
+            Ok::<_, String>(((cursor.owner, name), cursor.total_bytes))
+        })
+        .transpose()?;
+    let mut entries: Vec<(MapId, ByteBuf, CollectionStats)> = match order {
+        CollectionOrder::ByOwnerAndName => {
+            let start = after.map_or(RangeBound::Unbounded, |(map_id, _)| {
+                RangeBound::Excluded(map_id)
+            });
+            COLLECTION_METADATA.with_borrow(|collection_metadata| {
+                collection_metadata
+                    .range((start, RangeBound::Unbounded))
+                    .take(limit + 1)
+                    .map(|(map_id, metadata)| (map_id, metadata.name, collection_stats(map_id)))
+                    .collect()
+            })
+        }
+        CollectionOrder::ByTotalBytesDesc => {
+            let all_stats: BTreeMap<MapId, CollectionStats> =
+                COLLECTION_STATS.with_borrow(|collection_stats| collection_stats.iter().collect());
+            let mut entries: Vec<(MapId, ByteBuf, CollectionStats)> = COLLECTION_METADATA
+                .with_borrow(|collection_metadata| {
+                    collection_metadata
+                        .iter()
+                        .map(|(map_id, metadata)| {
+                            let stats = all_stats.get(&map_id).cloned().unwrap_or_default();
+                            (map_id, metadata.name, stats)
+                        })
+                        .collect()
+                });
+            let sort_key =
+                |map_id: MapId, total_bytes: u64| (Reverse(total_bytes), map_id.0, map_id.1);
+            entries.sort_by_key(|(map_id, _, stats)| sort_key(*map_id, stats.total_bytes));
+            if let Some((after_id, after_bytes)) = after {
+                let after_key = sort_key(after_id, after_bytes);
+                entries
+                    .retain(|(map_id, _, stats)| sort_key(*map_id, stats.total_bytes) > after_key);
+            }
+            entries.truncate(limit + 1);
+            entries
+        }
+    };
+    let has_more = entries.len() > limit;
+    entries.truncate(limit);
+    let next_cursor = entries
+        .last()
+        .filter(|_| has_more)
+        .map(|((owner, name), _, stats)| CollectionCursor {
+            owner: *owner,
+            name: ByteBuf::from(name.as_slice().to_vec()),
+            total_bytes: stats.total_bytes,
+        });
+    let collections = entries
+        .into_iter()
+        .map(|((owner, _), name, stats)| AdminCollectionEntry {
+            owner,
+            owner_username: user_for_principal(&owner).map(|user| user.username),
+            name,
+            stats,
+        })
+        .collect();
+    Ok(AdminCollectionPage {
+        collections,
+        next_cursor,
+    })
+}
+
+/// Size statistics of a collection the caller has access to.
+#[query]
+fn get_collection_stats(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<CollectionStats, String> {
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    caller_rights(ic_cdk::api::msg_caller(), map_id)?;
+    Ok(collection_stats(map_id))
+}
+
+/// Recomputes the statistics of a collection from its files. Only available
+/// to the collection's owner and the admin.
+#[update]
+fn recount_collection_stats(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<CollectionStats, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    if caller != collection_owner {
+        ensure_admin(caller)?;
+    }
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    let (owner, name) = map_id;
+    let mut stats = CollectionStats {
+        last_upload_at: collection_stats(map_id).last_upload_at,
+        ..CollectionStats::default()
+    };
+    FILE_METADATA.with_borrow(|file_metadata| {
+        for ((_, _, file_id), metadata) in file_metadata
+            .range((owner, name, Blob::default())..)
+            .take_while(|((key_owner, key_name, _), _)| (*key_owner, *key_name) == map_id)
+        {
+            add_file(&mut stats, file_id, &metadata);
+        }
+    });
+    store_collection_stats(map_id, stats.clone());
+    Ok(stats)
+}
+
+pub fn collection_stats(map_id: MapId) -> CollectionStats {
+    COLLECTION_STATS
+        .with_borrow(|collection_stats| collection_stats.get(&map_id))
+        .unwrap_or_default()
+}
+
+/// Accounts for a file's metadata changing from `previous` to `current`,
+/// where `None` means the file does not exist.
+pub fn update_collection_stats(
+    file_key: FileKey,
+    previous: Option<&FileMetadata>,
+    current: Option<&FileMetadata>,
+) {
+    let (owner, name, file_id) = file_key;
+    let map_id = (owner, name);
+    let mut stats = collection_stats(map_id);
+    if let Some(previous) = previous {
+        stats.file_count = stats.file_count.saturating_sub(1);
+        stats.total_bytes = stats.total_bytes.saturating_sub(previous.file_size);
+    }
+    let largest_before = stats.largest_file.clone();
+    if let Some(current) = current {
+        add_file(&mut stats, file_id, current);
+    }
+    // Only a scan finds the runner-up when the largest file shrinks or goes.
+    let largest_shrank = largest_before.is_some_and(|(largest_id, size)| {
+        largest_id.as_ref() == file_id.as_slice()
+            && current.map_or(true, |current| current.file_size < size)
+    });
+    if largest_shrank {
+        stats.largest_file = largest_file(map_id);
+    }
+    store_collection_stats(map_id, stats);
+}
+
+pub fn record_upload_time(map_id: MapId, time: u64) {
+    let mut stats = collection_stats(map_id);
+    stats.last_upload_at = Some(time);
+    store_collection_stats(map_id, stats);
+}
+
+pub fn remove_collection_stats(map_id: MapId) {
+    COLLECTION_STATS.with_borrow_mut(|collection_stats| collection_stats.remove(&map_id));
+}
+
+/// Removes the statistics of all collections of `owner`.
+pub fn remove_owned_collection_stats(owner: Principal) {
+    COLLECTION_STATS.with_borrow_mut(|collection_stats| {
+        let map_ids: Vec<MapId> = collection_stats
+            .range((owner, Blob::default())..)
+            .take_while(|((map_owner, _), _)| map_owner == &owner)
+            .map(|(map_id, _)| map_id)
+            .collect();
+        for map_id in map_ids {
+            collection_stats.remove(&map_id);
+        }
+    });
+}
+
+/// Computes the statistics of all collections if there are none, i.e. on
+/// the first upgrade after they were introduced.
+pub fn backfill_collection_stats() {
+    if !COLLECTION_STATS.with_borrow(|collection_stats| collection_stats.is_empty()) {
+        return;
+    }
+    let mut all_stats: BTreeMap<MapId, CollectionStats> = BTreeMap::new();
+    FILE_METADATA.with_borrow(|file_metadata| {
+        for ((owner, name, file_id), metadata) in file_metadata.iter() {
+            add_file(
+                all_stats.entry((owner, name)).or_default(),
+                file_id,
+                &metadata,
+            );
+        }
+    });
+    for (map_id, stats) in all_stats {
+        store_collection_stats(map_id, stats);
+    }
+}
+
+fn add_file(stats: &mut CollectionStats, file_id: MapKey, metadata: &FileMetadata) {
+    stats.file_count += 1;
+    stats.total_bytes = stats.total_bytes.saturating_add(metadata.file_size);
+    if largest_size(stats).map_or(true, |size| metadata.file_size > size) {
+        stats.largest_file = Some((
+            ByteBuf::from(file_id.as_slice().to_vec()),
+            metadata.file_size,
+        ));
+    }
+}
+
+fn largest_size(stats: &CollectionStats) -> Option<u64> {
+    stats.largest_file.as_ref().map(|(_, size)| *size)
+}
+
+/// Scans a collection for its largest file.
+fn largest_file(map_id: MapId) -> Option<(ByteBuf, u64)> {
+    let (owner, name) = map_id;
+    FILE_METADATA.with_borrow(|file_metadata| {
+        file_metadata
+            .range((owner, name, Blob::default())..)
+            .take_while(|((key_owner, key_name, _), _)| (*key_owner, *key_name) == map_id)
+            .max_by_key(|(_, metadata)| metadata.file_size)
+            .map(|((_, _, file_id), metadata)| {
+                (
+                    ByteBuf::from(file_id.as_slice().to_vec()),
+                    metadata.file_size,
+                )
+            })
+    })
+}
+
+fn store_collection_stats(map_id: MapId, stats: CollectionStats) {
+    COLLECTION_STATS.with_borrow_mut(|collection_stats| {
+        if stats.file_count == 0 && stats.last_upload_at.is_none() {
+            collection_stats.remove(&map_id);
+        } else {
+            collection_stats.insert(map_id, stats);
+        }
+    });
+}
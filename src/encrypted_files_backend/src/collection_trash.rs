@@ -0,0 +1,396 @@
+//! Deleted collections. delete_collection keeps the files, record, settings
+//! and sharing grants of a collection here, from where its owner can
+//! restore it, until a timer purges it once it is older than the configured
+//! retention. Collection keys are derived from the owner and name, so a
+//! restored collection decrypts as before.
+
+use crate::admin::ensure_admin;
+use crate::collections::{
+    collection_metadata_or_default, owned_collection_names, CollectionMetadata, CollectionSettings,
+};
+use crate::files::{store_metadata, FileMetadata};
+use crate::grant_expiry::set_grant_expiry;
+use crate::sharing_audit::log_sharing_change;
+use crate::stats::{owns_files, record_collection_ownership};
+use crate::trash::TrashedFile;
+use crate::users::record_activity;
+use crate::{
+    collection_name_to_blob, MapId, MapKey, MapName, MapOwner, COLLECTION_METADATA,
+    COLLECTION_SETTINGS, CONFIG, DELETED_COLLECTIONS, DELETED_COLLECTION_FILES, ENCRYPTED_MAPS,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{AccessRights, ByteBuf, EncryptedMapValue};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const DEFAULT_COLLECTION_RETENTION_DAYS: u32 = 30;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_RESTORE_FILES: usize = 200;
+/// Files purged per timer run, so that a run fits in one message.
+const MAX_PURGE_FILES: usize = 1_000;
+
+/// (owner, collection name, time of deletion).
+pub type DeletedCollectionKey = (MapOwner, MapName, u64);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeletionState {
+    /// delete_collection is still moving the files here.
+    Deleting,
+    Deleted,
+    /// restore_collection is moving the files back.
+    Restoring,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeletedCollection {
+    pub state: DeletionState,
+    pub metadata: CollectionMetadata,
+    pub settings: Option<CollectionSettings>,
+    /// Grants revoked by the deletion, given back on restore.
+    pub grants: Vec<(Principal, AccessRights)>,
+    /// Expiries of those grants; expired grants are not given back.
+    #[serde(default)]
+    pub grant_expiries: Vec<(Principal, u64)>,
+    pub file_count: u64,
+}
+
+impl Storable for DeletedCollection {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeletedCollectionEntry {
+    pub metadata: CollectionMetadata,
+    pub deleted_at: u64,
+    pub file_count: u64,
+    /// When the collection is purged for good.
+    pub purge_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct RestoreReport {
+    pub restored_files: u64,
+    /// Files left to restore; call again until it is 0.
+    pub remaining: u64,
+}
+
+/// The caller's deleted collections that can still be restored, oldest
+/// first.
+#[query]
+fn list_deleted_collections() -> Vec<DeletedCollectionEntry> {
+    let caller = ic_cdk::api::msg_caller();
+    let retention = collection_retention_days() as u64 * NANOS_PER_DAY;
+    deleted_collections(caller)
+        .into_iter()
+        .filter(|(_, deleted)| deleted.state == DeletionState::Deleted)
+        .map(|((_, _, deleted_at), deleted)| DeletedCollectionEntry {
+            metadata: deleted.metadata,
+            deleted_at,
+            file_count: deleted.file_count,
+            purge_at: deleted_at.saturating_add(retention),
+        })
+        .collect()
+}
+
+/// Brings back the caller's most recently deleted collection of this name,
+/// with its files, settings and sharing grants. Fails if the caller has
+/// created a collection of the same name since. Restores at most
+/// [`MAX_RESTORE_FILES`] files per call; call again until `remaining` is 0.
+#[update]
+fn restore_collection(collection_name: ByteBuf) -> Result<RestoreReport, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    let candidates: Vec<_> = deleted_collections(caller)
+        .into_iter()
+        .filter(|((_, name, _), _)| *name == map_id.1)
+        .collect();
+    let restoring = candidates
+        .iter()
+        .find(|(_, deleted)| deleted.state == DeletionState::Restoring)
+        .or_else(|| {
+            candidates
+                .iter()
+                .rev()
+                .find(|(_, deleted)| deleted.state == DeletionState::Deleted)
+        })
+        .cloned();
+    let Some((key, deleted)) = restoring else {
+        return Err("no deleted collection with this name".to_string());
+    };
+    if deleted.state == DeletionState::Deleted {
+        if owned_collection_names(caller).contains(&map_id.1) {
+            return Err(
+                "a collection with this name was created since; rename or delete it first"
+                    .to_string(),
+            );
+        }
+        reinstate_collection(map_id, key, deleted)?;
+    }
+
+    let had_files = owns_files(caller);
+    let files: Vec<(MapKey, TrashedFile)> = DELETED_COLLECTION_FILES.with_borrow(|files| {
+        files
+            .range((key, Blob::default())..)
+            .take_while(|((file_key, _), _)| *file_key == key)
+            .take(MAX_RESTORE_FILES)
+            .map(|((_, file_id), trashed)| (file_id, trashed))
+            .collect()
+    });
+    let mut report = RestoreReport::default();
+    let time_now = ic_cdk::api::time();
+    for (file_id, trashed) in files {
+        ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            encrypted_maps.as_mut().unwrap().insert_encrypted_value(
+                caller,
+                map_id,
+                file_id,
+                trashed.value,
+            )
+        })?;
+        let metadata = FileMetadata {
+            // An expiry that has passed is dropped rather than deleting the
+            // restored file right away.
+            expires_at: trashed
+                .metadata
+                .expires_at
+                .filter(|expires_at| *expires_at > time_now),
+            ..trashed.metadata
+        };
+        store_metadata((map_id.0, map_id.1, file_id), metadata);
+        DELETED_COLLECTION_FILES.with_borrow_mut(|files| files.remove(&(key, file_id)));
+        report.restored_files += 1;
+    }
+    report.remaining = deleted_file_count(key);
+    if report.remaining == 0 {
+        DELETED_COLLECTIONS.with_borrow_mut(|deleted| deleted.remove(&key));
+    }
+    record_collection_ownership(had_files, owns_files(caller));
+    Ok(report)
+}
+
+/// Days after which deleted collections are purged.
+#[query]
+fn get_deleted_collection_retention_days() -> u32 {
+    collection_retention_days()
+}
+
+#[update]
+fn set_deleted_collection_retention_days(days: u32) -> Result<(), String> {
+    ensure_admin(ic_cdk::api::msg_caller())?;
+    if days == 0 {
+        return Err("deleted collection retention must be at least one day".to_string());
+    }
+    CONFIG.with_borrow_mut(|config| {
+        let mut updated = config.get().clone();
+        updated.deleted_collection_retention_days = Some(days);
+        config.set(updated).expect("failed to store config");
+    });
+    Ok(())
+}
+
+/// Starts keeping a collection that is being deleted, or continues the
+/// unfinished deletion of it, and adds `revoked_grants` to what a restore
+/// gives back. Returns the key of the deleted collection.
+pub fn begin_collection_deletion(
+    map_id: MapId,
+    revoked_grants: Vec<(Principal, AccessRights, Option<u64>)>,
+) -> DeletedCollectionKey {
+    let pending = deleted_collections(map_id.0)
+        .into_iter()
+        .find(|((_, name, _), deleted)| {
+            *name == map_id.1 && deleted.state == DeletionState::Deleting
+        });
+    let (key, mut deleted) = pending.unwrap_or_else(|| {
+        let key = (map_id.0, map_id.1, ic_cdk::api::time());
+        let deleted = DeletedCollection {
+            state: DeletionState::Deleting,
+            metadata: collection_metadata_or_default(map_id),
+            settings: COLLECTION_SETTINGS.with_borrow(|settings| settings.get(&map_id)),
+            grants: Vec::new(),
+            grant_expiries: Vec::new(),
+            file_count: 0,
+        };
+        (key, deleted)
+    });
+    for (grantee, rights, expires_at) in revoked_grants {
+        deleted.grants.push((grantee, rights));
+        if let Some(expires_at) = expires_at {
+            deleted.grant_expiries.push((grantee, expires_at));
+        }
+    }
+    DELETED_COLLECTIONS
+        .with_borrow_mut(|deleted_collections| deleted_collections.insert(key, deleted));
+    key
+}
+
+/// Keeps a file of a collection that is being deleted.
+pub fn keep_deleted_file(
+    key: DeletedCollectionKey,
+    file_id: MapKey,
+    value: EncryptedMapValue,
+    metadata: FileMetadata,
+    deleted_by: Principal,
+) {
+    DELETED_COLLECTION_FILES.with_borrow_mut(|files| {
+        files.insert(
+            (key, file_id),
+            TrashedFile {
+                value,
+                metadata,
+                deleted_by,
+                overrides: Vec::new(),
+            },
+        )
+    });
+}
+
+/// Marks a deleted collection as restorable once all its files are kept.
+pub fn finish_collection_deletion(key: DeletedCollectionKey) {
+    let file_count = deleted_file_count(key);
+    DELETED_COLLECTIONS.with_borrow_mut(|deleted_collections| {
+        if let Some(deleted) = deleted_collections.get(&key) {
+            let deleted = DeletedCollection {
+                state: DeletionState::Deleted,
+                file_count,
+                ..deleted
+            };
+            deleted_collections.insert(key, deleted);
+        }
+    });
+}
+
+/// Removes the deleted collections of `owner`, e.g. with their account.
+pub fn remove_owned_deleted_collections(owner: Principal) {
+    for (key, _) in deleted_collections(owner) {
+        remove_deleted_files(key, usize::MAX);
+        DELETED_COLLECTIONS.with_borrow_mut(|deleted| deleted.remove(&key));
+    }
+}
+
+/// Purges expired deleted collections periodically. Timers do not survive
+/// upgrades, so this is called from both init and post_upgrade.
+pub fn start_collection_purge_timer() {
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, purge_expired_collections);
+}
+
+fn purge_expired_collections() {
+    let retention = collection_retention_days() as u64 * NANOS_PER_DAY;
+    let cutoff = ic_cdk::api::time().saturating_sub(retention);
+    let expired: Vec<DeletedCollectionKey> = DELETED_COLLECTIONS.with_borrow(|deleted| {
+        deleted
+            .iter()
+            .filter(|((_, _, deleted_at), deleted)| {
+                *deleted_at < cutoff && deleted.state == DeletionState::Deleted
+            })
+            .map(|(key, _)| key)
+            .collect()
+    });
+    let mut budget = MAX_PURGE_FILES;
+    for key in expired {
+        budget -= remove_deleted_files(key, budget);
+        if deleted_file_count(key) > 0 {
+            // Continued on the next run.
+            break;
+        }
+        DELETED_COLLECTIONS.with_borrow_mut(|deleted| deleted.remove(&key));
+    }
+}
+
+/// Puts back the record, settings and unexpired grants of a deleted
+/// collection and marks it as being restored.
+fn reinstate_collection(
+    map_id: MapId,
+    key: DeletedCollectionKey,
+    deleted: DeletedCollection,
+) -> Result<(), String> {
+    let now = ic_cdk::api::time();
+    for (grantee, rights) in &deleted.grants {
+        let expires_at = deleted
+            .grant_expiries
+            .iter()
+            .find(|(expiring, _)| expiring == grantee)
+            .map(|(_, expires_at)| *expires_at);
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            continue;
+        }
+        ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            encrypted_maps
+                .as_mut()
+                .unwrap()
+                .set_user_rights(map_id.0, map_id, *grantee, *rights)
+        })?;
+        log_sharing_change(map_id, map_id.0, *grantee, None, Some(*rights));
+        set_grant_expiry(map_id, *grantee, expires_at);
+    }
+    COLLECTION_METADATA.with_borrow_mut(|collection_metadata| {
+        collection_metadata.insert(map_id, deleted.metadata.clone())
+    });
+    if let Some(settings) = deleted.settings.clone() {
+        COLLECTION_SETTINGS
+            .with_borrow_mut(|collection_settings| collection_settings.insert(map_id, settings));
+    }
+    let restoring = DeletedCollection {
+        state: DeletionState::Restoring,
+        ..deleted
+    };
+    DELETED_COLLECTIONS
+        .with_borrow_mut(|deleted_collections| deleted_collections.insert(key, restoring));
+    Ok(())
+}
+
+fn deleted_collections(owner: Principal) -> Vec<(DeletedCollectionKey, DeletedCollection)> {
+    DELETED_COLLECTIONS.with_borrow(|deleted| {
+        deleted
+            .range((owner, Blob::default(), 0)..)
+            .take_while(|((key_owner, _, _), _)| key_owner == &owner)
+            .collect()
+    })
+}
+
+fn deleted_file_count(key: DeletedCollectionKey) -> u64 {
+    DELETED_COLLECTION_FILES.with_borrow(|files| {
+        files
+            .range((key, Blob::default())..)
+            .take_while(|((file_key, _), _)| *file_key == key)
+            .count() as u64
+    })
+}
+
+/// Removes up to `limit` kept files of a deleted collection and returns how
+/// many were removed.
+fn remove_deleted_files(key: DeletedCollectionKey, limit: usize) -> usize {
+    let file_ids: Vec<MapKey> = DELETED_COLLECTION_FILES.with_borrow(|files| {
+        files
+            .range((key, Blob::default())..)
+            .take_while(|((file_key, _), _)| *file_key == key)
+            .take(limit)
+            .map(|((_, file_id), _)| file_id)
+            .collect()
+    });
+    DELETED_COLLECTION_FILES.with_borrow_mut(|files| {
+        for file_id in &file_ids {
+            files.remove(&(key, *file_id));
+        }
+    });
+    file_ids.len()
+}
+
+fn collection_retention_days() -> u32 {
+    CONFIG
+        .with_borrow(|config| config.get().deleted_collection_retention_days)
+        .unwrap_or(DEFAULT_COLLECTION_RETENTION_DAYS)
+}
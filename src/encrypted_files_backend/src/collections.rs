@@ -0,0 +1,2013 @@
+use crate::access_requests::{move_collection_access_requests, remove_collection_access_requests};
+use crate::admin::ensure_admin;
+use crate::collection_activity::{
+    log_activity, move_collection_activity, remove_collection_activity, ActivityAction,
+};
+use crate::collection_managers::{
+    ensure_can_manage, is_co_owner, is_manager, is_owner_or_co_owner, may_reshare,
+    move_collection_managers, remove_collection_managers, remove_manager,
+};
+use crate::collection_pins::{move_collection_pin, pinned_collections, unpin_collection};
+use crate::collection_stats::{collection_stats, record_upload_time, remove_collection_stats};
+use crate::collection_trash::{
+    begin_collection_deletion, finish_collection_deletion, keep_deleted_file,
+};
+use crate::devices::grant_linked_access;
+use crate::file_overrides::remove_user_file_overrides;
+use crate::file_shares::{is_file_share, remove_collection_file_shares};
+use crate::files::{
+    clean_tags, contains_tag, purge_file, relocate_file, store_metadata, FileMetadata,
+};
+use crate::grant_expiry::{grant_expiry, move_grant_expiry, set_grant_expiry};
+use crate::groups::{
+    forget_group_grant, move_collection_group_grants, remove_collection_group_grants,
+};
+use crate::invitations::{
+    cancel_collection_invitations, move_collection_invitations, prepare_share, share_or_invite,
+    share_or_invite_prepared, ShareOutcome,
+};
+use crate::notifications::{notify, remove_collection_notifications, NotificationKind};
+use crate::read_audit::move_read_audit_log;
+use crate::recent::{move_collection_recent_uploads, remove_collection_recent_uploads};
+use crate::share_links::remove_collection_share_links;
+use crate::sharing_audit::{log_all_access_revoked, log_sharing_change, move_sharing_audit_log};
+use crate::stars::remove_collection_stars;
+use crate::stats::{owns_files, record_collection_ownership};
+use crate::thumbnails::copy_thumbnail;
+use crate::trash::{collection_trash_len, move_collection_trash, remove_collection_trash};
+use crate::users::{ensure_active, principal_for_username, record_activity, user_for_principal};
+use crate::versions::FileKey;
+use crate::{
+    bytebuf_to_blob, caller_rights, collection_name_to_blob, ensure_can_write, or_trap, ApiError,
+    MapId, MapKey, MapName, Username, COLLECTION_METADATA, COLLECTION_SETTINGS, CONFIG,
+    ENCRYPTED_MAPS, FILE_METADATA,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{AccessRights, ByteBuf};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound as RangeBound;
+
+const DEFAULT_COLLECTION_NAME: &str = "home";
+const MAX_COLLECTION_DESCRIPTION_LEN: usize = 1_024;
+const MAX_COLLECTION_LABEL_LEN: usize = 100;
+const MAX_COLLECTION_ICON_LEN: usize = 64;
+const MAX_COLLECTION_DELETE_FILES: usize = 200;
+const MAX_COLLECTION_SUMMARIES: usize = 200;
+const MAX_SHARED_WITH_ME_PAGE: usize = 100;
+const MAX_CLONE_FILES: usize = 200;
+const MAX_POLICY_CONTENT_TYPES: usize = 100;
+const MIN_COLLECTION_SEARCH_QUERY_LEN: usize = 2;
+const MAX_COLLECTION_SEARCH_SCAN: usize = 1_000;
+const MAX_COLLECTION_SEARCH_RESULTS: usize = 50;
+/// Largest collection, counting files and trash, that rename_collection
+/// moves in a single call.
+const MAX_RENAME_FILES: usize = 200;
+const MAX_BULK_SHARE_USERS: usize = 50;
+const DEFAULT_MAX_GRANTEES_PER_COLLECTION: u32 = 100;
+
+/// Per-collection settings, chosen by the collection's owner.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CollectionSettings {
+    /// How many previous versions of each file to keep when an upload
+    /// replaces it. 0 disables versioning.
+    #[serde(default)]
+    pub keep_versions: u8,
+    /// Rejects uploads and renames to a filename another file in the
+    /// collection already has, ignoring case.
+    #[serde(default)]
+    pub unique_filenames: bool,
+    /// Logs which grantees fetch the encrypted files of the collection. See
+    /// [`crate::read_audit`].
+    #[serde(default)]
+    pub audit_reads: bool,
+}
+
+impl Storable for CollectionSettings {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A collection's own record, created by create_collection or by the first
+/// upload into it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CollectionMetadata {
+    pub owner: Principal,
+    /// The full name. Names over 32 bytes are keyed by their SHA-256 hash,
+    /// which is also accepted wherever the name is.
+    pub name: ByteBuf,
+    pub description: Option<String>,
+    pub created_at: u64,
+    /// Human-readable name shown instead of `name`.
+    #[serde(default)]
+    pub display_label: Option<String>,
+    /// E.g. "#1e90ff".
+    #[serde(default)]
+    pub color: Option<String>,
+    /// An emoji or short icon name.
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Set on the collection created at registration, which clients pin and
+    /// delete_collection only removes with `force`.
+    #[serde(default)]
+    pub is_default: bool,
+    /// Archived collections are read-only for everyone, including the owner,
+    /// until unarchived.
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    #[serde(default)]
+    pub allowed_content_types: Option<Vec<String>>,
+    /// Set on a collection transferred to its owner until they re-upload
+    /// its files, which are still encrypted with the previous owner's key.
+    #[serde(default)]
+    pub pending_reencryption_from: Option<Principal>,
+    /// Set on the emptied collection left behind by a transfer.
+    #[serde(default)]
+    pub transferred_to: Option<Principal>,
+    /// Set on a renamed collection until its owner re-uploads its files,
+    /// which are still encrypted with the key of this former name.
+    #[serde(default)]
+    pub renamed_from: Option<ByteBuf>,
+    /// Set on the emptied record left behind under the former name of a
+    /// renamed collection, whose grantees keep access to fetch its key.
+    #[serde(default)]
+    pub renamed_to: Option<ByteBuf>,
+    /// When revoke_all_access last ran. Users who lost access may still
+    /// hold the collection's vetkey, so its files should be moved to a new
+    /// collection; cleared with clear_key_rotation_flag.
+    #[serde(default)]
+    pub key_rotation_needed_since: Option<u64>,
+}
+
+impl Storable for CollectionMetadata {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Rules that uploads to a collection must follow. `unique_filenames` is
+/// the setting of the same name in [`CollectionSettings`].
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CollectionPolicy {
+    pub max_file_size: Option<u64>,
+    /// Content types such as "application/pdf", or "image/*" for a whole
+    /// type, compared ignoring case; `null` accepts any.
+    pub allowed_content_types: Option<Vec<String>>,
+    pub unique_filenames: bool,
+}
+
+/// The owner-editable part of [`CollectionMetadata`].
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CollectionMetadataInput {
+    pub display_label: Option<String>,
+    pub description: Option<String>,
+    pub color: Option<String>,
+    pub icon: Option<String>,
+}
+
+impl CollectionMetadataInput {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(display_label) = &self.display_label {
+            if display_label.trim().is_empty()
+                || display_label.len() > MAX_COLLECTION_LABEL_LEN
+                || display_label.chars().any(char::is_control)
+            {
+                return Err(format!(
+                    "display label must be 1 to {MAX_COLLECTION_LABEL_LEN} bytes without \
+                     control characters"
+                ));
+            }
+        }
+        validate_description(&self.description)?;
+        if let Some(color) = &self.color {
+            let is_hex_color = color.len() == 7
+                && color.starts_with('#')
+                && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+            if !is_hex_color {
+                return Err("color must have the form #rrggbb".to_string());
+            }
+        }
+        if let Some(icon) = &self.icon {
+            if icon.is_empty()
+                || icon.len() > MAX_COLLECTION_ICON_LEN
+                || icon.chars().any(char::is_control)
+            {
+                return Err(format!(
+                    "icon must be 1 to {MAX_COLLECTION_ICON_LEN} bytes without control characters"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OwnedCollection {
+    pub metadata: CollectionMetadata,
+    pub is_empty: bool,
+}
+
+/// A collection as shown in a listing of the caller's collections.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CollectionSummary {
+    pub metadata: CollectionMetadata,
+    pub file_count: u64,
+    pub total_bytes: u64,
+    /// Number of users the collection is shared with.
+    pub shared_with: u32,
+    pub pinned: bool,
+    /// Username of the owner, for collections shared with the caller.
+    pub owner_username: Option<Username>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CollectionSummaryPage {
+    pub collections: Vec<CollectionSummary>,
+    /// Pass as `start_after` to get the next page; `null` on the last page.
+    pub next_cursor: Option<ByteBuf>,
+}
+
+/// A grantee of a collection, with their username if registered.
+pub type OutgoingGrant = (Principal, Option<Username>, AccessRights);
+
+/// The grants on a collection as shown in the sharing dialog, with how many
+/// principals besides the owner have access and how many may.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SharedAccessDetails {
+    pub users: Vec<SharedUserView>,
+    pub grantee_count: u32,
+    pub max_grantees: u32,
+}
+
+/// A grant on a collection as shown in the sharing dialog.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SharedUserView {
+    pub principal: Principal,
+    /// `None` for principals that are not registered.
+    pub username: Option<Username>,
+    pub display_name: Option<String>,
+    pub access_rights: AccessRights,
+    pub expires_at: Option<u64>,
+    /// Whether they may change who the collection is shared with; always
+    /// true for the owner.
+    pub can_manage: bool,
+    pub is_co_owner: bool,
+    /// Whether they may share the collection with users who have no access
+    /// yet, at rights no higher than their own.
+    pub may_reshare: bool,
+}
+
+/// The outcome of sharing with one user of a bulk share.
+pub type BulkShareResult = (Username, Result<ShareOutcome, ApiError>);
+
+/// A collection shared with the caller, as shown in their listing.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SharedCollectionView {
+    pub owner: Principal,
+    pub owner_username: Option<Username>,
+    pub collection_name: ByteBuf,
+    pub my_access_rights: AccessRights,
+    pub file_count: u64,
+    /// Time of the last upload; `null` if there was none since the stats
+    /// were introduced.
+    pub last_modified: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SharedWithMePage {
+    pub collections: Vec<SharedCollectionView>,
+    /// Pass as `start_after` to get the next page; `null` on the last page.
+    pub next_cursor: Option<(Principal, ByteBuf)>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OutgoingSharePage {
+    pub shares: Vec<(ByteBuf, Vec<OutgoingGrant>)>,
+    /// Pass as `start_after` to get the next page; `null` on the last page.
+    pub next_cursor: Option<ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CloneReport {
+    /// Ids of the files copied by this call. Keys are derived per collection,
+    /// so the client must re-encrypt each of them for the destination.
+    pub copied: Vec<ByteBuf>,
+    /// Pass as `start_after` to continue; `null` once all files are copied.
+    pub next_cursor: Option<ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RenameReport {
+    /// Ids of the files moved to the new name. Keys are derived per
+    /// collection, so they are still encrypted with the key of the old name.
+    pub moved: Vec<ByteBuf>,
+    /// Whether the client must re-upload the files, including kept
+    /// versions and trashed files, with the key of the new name and then
+    /// call `complete_collection_reencryption`.
+    pub needs_reencryption: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct DeleteReport {
+    pub deleted_files: u64,
+    pub revoked_grants: u64,
+    /// Files left to delete; call again until it is 0.
+    pub remaining: u64,
+}
+
+/// The collections owned by the caller, including empty ones: every
+/// collection gets a record when it is created or first uploaded to.
+#[query]
+fn get_my_collections() -> Vec<OwnedCollection> {
+    let caller = ic_cdk::api::msg_caller();
+    let non_empty: BTreeSet<MapName> = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_owned_non_empty_map_names(caller)
+            .into_iter()
+            .collect()
+    });
+    COLLECTION_METADATA.with_borrow(|collection_metadata| {
+        collection_metadata
+            .range((caller, Blob::default())..)
+            .take_while(|((owner, _), _)| owner == &caller)
+            .map(|((_, map_name), metadata)| OwnedCollection {
+                metadata,
+                is_empty: !non_empty.contains(&map_name),
+            })
+            .collect()
+    })
+}
+
+/// Like `get_my_collections`, with the size and sharing of each collection.
+/// The first page starts with the pinned collections in the caller's order,
+/// followed by the others ordered by name, at most 200 per page. With `tag`,
+/// only collections with that tag (compared trimmed and lowercased) are
+/// listed.
+#[query]
+fn get_my_collections_detailed(
+    start_after: Option<ByteBuf>,
+    tag: Option<String>,
+) -> Result<CollectionSummaryPage, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let start_after = start_after.map(bytebuf_to_blob).transpose()?;
+    let owned_names = owned_collection_names(caller);
+    let pins: Vec<MapName> = pinned_collections(caller)
+        .into_iter()
+        .filter(|map_name| owned_names.contains(map_name))
+        .collect();
+    let mut summaries = Vec::new();
+    if start_after.is_none() {
+        for map_name in &pins {
+            let metadata = collection_metadata_or_default((caller, *map_name));
+            if has_tag(&metadata, &tag) {
+                summaries.push(collection_summary((caller, *map_name), metadata, true));
+            }
+        }
+    }
+    let mut collections = owned_names
+        .into_iter()
+        .filter(|map_name| !pins.contains(map_name))
+        .filter(|map_name| start_after.map_or(true, |start_after| *map_name > start_after))
+        .map(|map_name| (map_name, collection_metadata_or_default((caller, map_name))))
+        .filter(|(_, metadata)| has_tag(metadata, &tag))
+        .peekable();
+    let mut listed = 0;
+    let mut last_name = None;
+    while listed < MAX_COLLECTION_SUMMARIES {
+        let Some((map_name, metadata)) = collections.next() else {
+            break;
+        };
+        summaries.push(collection_summary((caller, map_name), metadata, false));
+        listed += 1;
+        last_name = Some(map_name);
+    }
+    let next_cursor = last_name
+        .filter(|_| collections.peek().is_some())
+        .map(|map_name| ByteBuf::from(map_name.as_slice().to_vec()));
+    Ok(CollectionSummaryPage {
+        collections: summaries,
+        next_cursor,
+    })
+}
+
+fn collection_summary(
+    map_id: MapId,
+    metadata: CollectionMetadata,
+    pinned: bool,
+) -> CollectionSummary {
+    let stats = collection_stats(map_id);
+    let shared_with = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_shared_user_access_for_map(map_id.0, map_id)
+            .map_or(0, |shared| shared.len() as u32)
+    });
+    CollectionSummary {
+        metadata,
+        file_count: stats.file_count,
+        total_bytes: stats.total_bytes,
+        shared_with,
+        pinned,
+        owner_username: None,
+    }
+}
+
+/// The caller's own and shared collections whose name, display label or
+/// description contains `query`, ignoring case. Inspects at most
+/// [`MAX_COLLECTION_SEARCH_SCAN`] collections and returns at most
+/// [`MAX_COLLECTION_SEARCH_RESULTS`].
+#[query]
+fn search_my_collections(query: String) -> Result<Vec<CollectionSummary>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let query = query.to_lowercase();
+    if query.chars().count() < MIN_COLLECTION_SEARCH_QUERY_LEN {
+        return Err(format!(
+            "search query must be at least {MIN_COLLECTION_SEARCH_QUERY_LEN} characters"
+        ));
+    }
+    let pins = pinned_collections(caller);
+    let shared = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_accessible_shared_map_names(caller)
+    });
+    let map_ids = owned_collection_names(caller)
+        .into_iter()
+        .map(|map_name| (caller, map_name))
+        .chain(shared)
+        .take(MAX_COLLECTION_SEARCH_SCAN);
+    let mut results = Vec::new();
+    for map_id in map_ids {
+        let metadata = collection_metadata_or_default(map_id);
+        let matches = [
+            Some(String::from_utf8_lossy(metadata.name.as_ref()).into_owned()),
+            metadata.display_label.clone(),
+            metadata.description.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|text| text.to_lowercase().contains(&query));
+        if !matches {
+            continue;
+        }
+        let pinned = map_id.0 == caller && pins.contains(&map_id.1);
+        results.push(CollectionSummary {
+            owner_username: (map_id.0 != caller)
+                .then(|| user_for_principal(&map_id.0).map(|user| user.username))
+                .flatten(),
+            ..collection_summary(map_id, metadata, pinned)
+        });
+        if results.len() == MAX_COLLECTION_SEARCH_RESULTS {
+            break;
+        }
+    }
+    Ok(results)
+}
+
+/// Creates an empty collection owned by the caller, which can be shared
+/// before any file is uploaded. Fails if the caller already has a
+/// collection with that name.
+#[update]
+fn create_collection(
+    collection_name: ByteBuf,
+    description: Option<String>,
+) -> Result<CollectionMetadata, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    validate_description(&description)?;
+    let map_id = (caller, collection_name_to_blob(collection_name.clone())?);
+    if let Some(existing) = collection_metadata(map_id) {
+        if existing.name != collection_name {
+            return Err(
+                "collection name collides with the key of an existing collection".to_string(),
+            );
+        }
+        return Err("collection already exists".to_string());
+    }
+    if owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection already exists".to_string());
+    }
+    let metadata = CollectionMetadata {
+        name: collection_name,
+        description,
+        ..new_collection_metadata(map_id, ic_cdk::api::time())
+    };
+    COLLECTION_METADATA.with_borrow_mut(|collection_metadata| {
+        collection_metadata.insert(map_id, metadata.clone())
+    });
+    grant_linked_access(map_id);
+    Ok(metadata)
+}
+
+/// Replaces the label, description, color and icon of a collection.
+/// Requires write access to the collection or being one of its co-owners.
+#[update]
+fn set_collection_metadata(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    metadata: CollectionMetadataInput,
+) -> Result<CollectionMetadata, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    metadata.validate()?;
+    let map_id = (
+        collection_owner,
+        collection_name_to_blob(collection_name.clone())?,
+    );
+    if !is_owner_or_co_owner(map_id, caller)? {
+        ensure_can_write(caller, map_id)?;
+    }
+    ensure_not_archived(map_id)?;
+    ensure_collection_name(map_id, &collection_name)?;
+    let updated = CollectionMetadata {
+        display_label: metadata.display_label,
+        description: metadata.description,
+        color: metadata.color,
+        icon: metadata.icon,
+        ..collection_metadata(map_id).unwrap_or_else(|| CollectionMetadata {
+            name: collection_name,
+            ..new_collection_metadata(map_id, ic_cdk::api::time())
+        })
+    };
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.insert(map_id, updated.clone()));
+    Ok(updated)
+}
+
+/// Deletes one of the caller's collections. Its files, record, settings and
+/// sharing grants are kept for restore_collection until the retention of
+/// deleted collections passes; its trash, statistics and activity are
+/// removed. Deletes at most [`MAX_COLLECTION_DELETE_FILES`] files per call;
+/// call again until `remaining` is 0. Grants are revoked on the first call,
+/// so others lose access while the deletion is still in progress. The
+/// default collection is only deleted with `force`.
+#[update]
+fn delete_collection(collection_name: ByteBuf, force: bool) -> Result<DeleteReport, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string());
+    }
+    if !force && collection_metadata(map_id).is_some_and(|metadata| metadata.is_default) {
+        return Err("the default collection can only be deleted with force".to_string());
+    }
+    let (owner, name) = map_id;
+    let had_files = owns_files(caller);
+    let mut report = DeleteReport::default();
+    let revoked_grants = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        let mut revoked_grants = Vec::new();
+        for (grantee, rights) in encrypted_maps.get_shared_user_access_for_map(caller, map_id)? {
+            if grantee != caller {
+                encrypted_maps.remove_user(caller, map_id, grantee)?;
+                log_sharing_change(map_id, caller, grantee, Some(rights), None);
+                revoked_grants.push((grantee, rights, grant_expiry(map_id, grantee)));
+                set_grant_expiry(map_id, grantee, None);
+            }
+        }
+        Ok::<_, String>(revoked_grants)
+    })?;
+    remove_collection_managers(map_id);
+    remove_collection_group_grants(map_id);
+    report.revoked_grants = revoked_grants.len() as u64;
+    for (grantee, _, _) in &revoked_grants {
+        notify(*grantee, caller, map_id, NotificationKind::Unshared);
+    }
+    cancel_collection_invitations(map_id);
+    remove_collection_access_requests(map_id);
+    let deleted_key = begin_collection_deletion(map_id, revoked_grants);
+
+    let file_keys: Vec<FileKey> = FILE_METADATA.with_borrow(|file_metadata| {
+        file_metadata
+            .range((owner, name, Blob::default())..)
+            .take_while(|((key_owner, key_name, _), _)| (*key_owner, *key_name) == map_id)
+            .take(MAX_COLLECTION_DELETE_FILES)
+            .map(|(file_key, _)| file_key)
+            .collect()
+    });
+    for file_key in file_keys {
+        if let Some((value, metadata)) = purge_file(caller, file_key)? {
+            keep_deleted_file(deleted_key, file_key.2, value, metadata, caller);
+            report.deleted_files += 1;
+        }
+    }
+    let has_more = FILE_METADATA.with_borrow(|file_metadata| {
+        file_metadata
+            .range((owner, name, Blob::default())..)
+            .next()
+            .is_some_and(|((key_owner, key_name, _), _)| (key_owner, key_name) == map_id)
+    });
+    if has_more {
+        report.remaining = collection_stats(map_id).file_count.max(1);
+    } else {
+        finish_collection_deletion(deleted_key);
+        remove_collection_remains(caller, map_id)?;
+    }
+    record_collection_ownership(had_files, owns_files(caller));
+    Ok(report)
+}
+
+/// Removes everything but the files of a collection whose files are gone:
+/// stray values, trash, record, settings, statistics, activity and pin.
+pub fn remove_collection_remains(caller: Principal, map_id: MapId) -> Result<(), String> {
+    // Values without metadata, if any, go with the rest of the map.
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .remove_map_values(caller, map_id)
+    })?;
+    cancel_collection_invitations(map_id);
+    remove_collection_access_requests(map_id);
+    remove_collection_trash(map_id);
+    remove_collection_recent_uploads(map_id);
+    remove_collection_stats(map_id);
+    remove_collection_activity(map_id);
+    unpin_collection(map_id);
+    COLLECTION_METADATA.with_borrow_mut(|collection_metadata| collection_metadata.remove(&map_id));
+    COLLECTION_SETTINGS.with_borrow_mut(|collection_settings| collection_settings.remove(&map_id));
+    Ok(())
+}
+
+/// Renames one of the caller's collections by moving its files, sharing
+/// grants, trash and everything attached to its files to the new name.
+/// Fails if the caller already has a collection named `new_name`. Runs in a
+/// single call, so collections with more than [`MAX_RENAME_FILES`] files and
+/// trashed files are rejected.
+///
+/// Keys are derived per collection, so the moved files, their versions and
+/// the trash stay encrypted with the key of the old name. Until the client
+/// has re-uploaded them and called `complete_collection_reencryption`, the
+/// new record has `renamed_from` set, and an archived record with
+/// `renamed_to` is left under the old name. The grantees keep their access
+/// to that old record, so that they can still fetch the old key.
+#[update]
+fn rename_collection(old_name: ByteBuf, new_name: ByteBuf) -> Result<RenameReport, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let from = (caller, collection_name_to_blob(old_name.clone())?);
+    let to = (caller, collection_name_to_blob(new_name.clone())?);
+    let owned_names = owned_collection_names(caller);
+    if !owned_names.contains(&from.1) {
+        return Err("collection not found".to_string());
+    }
+    if owned_names.contains(&to.1) {
+        return Err("a collection with the new name already exists".to_string());
+    }
+    let source = collection_metadata(from)
+        .unwrap_or_else(|| new_collection_metadata(from, ic_cdk::api::time()));
+    if source.archived {
+        return Err("archived collections cannot be renamed".to_string());
+    }
+    if source.renamed_from.is_some() || source.pending_reencryption_from.is_some() {
+        return Err(
+            "the collection must be re-encrypted before it can be renamed again".to_string(),
+        );
+    }
+    let file_ids: Vec<MapKey> = FILE_METADATA.with_borrow(|file_metadata| {
+        file_metadata
+            .range((from.0, from.1, Blob::default())..)
+            .take_while(|((owner, name, _), _)| (*owner, *name) == from)
+            .take(MAX_RENAME_FILES + 1)
+            .map(|((_, _, file_id), _)| file_id)
+            .collect()
+    });
+    let trash_len = collection_trash_len(from);
+    if file_ids.len() + trash_len > MAX_RENAME_FILES {
+        return Err(format!(
+            "collections with more than {MAX_RENAME_FILES} files, including the trash, \
+             cannot be renamed; create a new collection and move the files instead"
+        ));
+    }
+    let grants = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_shared_user_access_for_map(caller, from)
+    })?;
+    let needs_reencryption = !file_ids.is_empty() || trash_len > 0;
+
+    // The first write happens below, so later failures trap to undo it.
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        for (grantee, rights) in grants {
+            if grantee != caller {
+                or_trap(
+                    "renaming the collection",
+                    encrypted_maps.set_user_rights(caller, to, grantee, rights),
+                );
+                if !needs_reencryption {
+                    or_trap(
+                        "renaming the collection",
+                        encrypted_maps.remove_user(caller, from, grantee),
+                    );
+                }
+                move_grant_expiry(from, to, grantee);
+            }
+        }
+    });
+    let last_upload_at = collection_stats(from).last_upload_at;
+    let mut report = RenameReport {
+        moved: Vec::new(),
+        needs_reencryption,
+    };
+    for file_id in file_ids {
+        let from_key = (from.0, from.1, file_id);
+        if or_trap(
+            "renaming the collection",
+            relocate_file(caller, from_key, (to.0, to.1, file_id)),
+        ) {
+            report
+                .moved
+                .push(ByteBuf::from(file_id.as_slice().to_vec()));
+        }
+    }
+    or_trap(
+        "renaming the collection",
+        ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            encrypted_maps
+                .as_mut()
+                .unwrap()
+                .remove_map_values(caller, from)
+        }),
+    );
+    move_collection_trash(from, to);
+    move_collection_recent_uploads(from, to);
+    move_collection_activity(from, to);
+    move_collection_pin(from, to);
+    move_collection_invitations(from, &new_name);
+    move_sharing_audit_log(from, to);
+    move_read_audit_log(from, to);
+    move_collection_managers(from, to);
+    move_collection_group_grants(from, to);
+    move_collection_access_requests(from, to, &new_name);
+    remove_collection_stats(from);
+    if let Some(last_upload_at) = last_upload_at {
+        record_upload_time(to, last_upload_at);
+    }
+    let renamed = CollectionMetadata {
+        name: new_name.clone(),
+        renamed_from: needs_reencryption.then_some(old_name),
+        ..source.clone()
+    };
+    COLLECTION_METADATA.with_borrow_mut(|collection_metadata| {
+        collection_metadata.insert(to, renamed);
+        if needs_reencryption {
+            let left_behind = CollectionMetadata {
+                is_default: false,
+                archived: true,
+                renamed_to: Some(new_name),
+                ..source
+            };
+            collection_metadata.insert(from, left_behind);
+        } else {
+            collection_metadata.remove(&from);
+        }
+    });
+    if let Some(settings) =
+        COLLECTION_SETTINGS.with_borrow_mut(|collection_settings| collection_settings.remove(&from))
+    {
+        COLLECTION_SETTINGS
+            .with_borrow_mut(|collection_settings| collection_settings.insert(to, settings));
+    }
+    Ok(report)
+}
+
+/// Revokes the grants kept under the former name of a renamed collection
+/// of `owner` and removes the record left behind there, once the files
+/// were re-encrypted with the key of the new name.
+pub fn finish_rename(owner: Principal, old_name: &ByteBuf) -> Result<(), String> {
+    let previous = (owner, collection_name_to_blob(old_name.clone())?);
+    if !collection_metadata(previous).is_some_and(|metadata| metadata.renamed_to.is_some()) {
+        return Ok(());
+    }
+    let grants = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_shared_user_access_for_map(owner, previous)
+    })?;
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        for (grantee, _) in grants {
+            or_trap(
+                "completing the rename",
+                encrypted_maps.remove_user(owner, previous, grantee),
+            );
+        }
+    });
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.remove(&previous));
+    Ok(())
+}
+
+/// The record of a collection the caller has access to. Collections whose
+/// files predate explicit creation may have none.
+#[query]
+fn get_collection_metadata(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<Option<CollectionMetadata>, String> {
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    caller_rights(ic_cdk::api::msg_caller(), map_id)?;
+    Ok(collection_metadata(map_id))
+}
+
+/// Copies one of the caller's collections, with its files, thumbnails and
+/// record but not its sharing grants, into a new collection `dst_name`.
+/// Copies at most [`MAX_CLONE_FILES`] files per call; call again with
+/// `next_cursor` as `start_after` until it is `null`. The encrypted bytes are
+/// copied as is, so they have to be re-encrypted with the destination's key.
+#[update]
+fn clone_collection(
+    src_name: ByteBuf,
+    dst_name: ByteBuf,
+    start_after: Option<ByteBuf>,
+) -> Result<CloneReport, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let from = (caller, collection_name_to_blob(src_name)?);
+    let to = (caller, collection_name_to_blob(dst_name.clone())?);
+    let start_after = start_after.map(bytebuf_to_blob).transpose()?;
+    let owned_names = owned_collection_names(caller);
+    if !owned_names.contains(&from.1) {
+        return Err("collection not found".to_string());
+    }
+    if from == to {
+        return Err("source and destination are the same collection".to_string());
+    }
+    if start_after.is_none() {
+        if owned_names.contains(&to.1) {
+            return Err("a collection with the destination name already exists".to_string());
+        }
+        let metadata = CollectionMetadata {
+            name: dst_name,
+            created_at: ic_cdk::api::time(),
+            is_default: false,
+            archived: false,
+            pending_reencryption_from: None,
+            transferred_to: None,
+            renamed_from: None,
+            renamed_to: None,
+            key_rotation_needed_since: None,
+            ..collection_metadata_or_default(from)
+        };
+        COLLECTION_METADATA
+            .with_borrow_mut(|collection_metadata| collection_metadata.insert(to, metadata));
+        grant_linked_access(to);
+    } else if !owned_names.contains(&to.1) {
+        return Err("destination collection not found".to_string());
+    }
+
+    let start = start_after.map_or(RangeBound::Unbounded, |file_id| {
+        RangeBound::Excluded((from.0, from.1, file_id))
+    });
+    let files: Vec<(MapKey, FileMetadata)> = FILE_METADATA.with_borrow(|file_metadata| {
+        file_metadata
+            .range((start, RangeBound::Unbounded))
+            .take_while(|((owner, name, _), _)| (*owner, *name) == from)
+            .take(MAX_CLONE_FILES + 1)
+            .map(|((_, _, file_id), metadata)| (file_id, metadata))
+            .collect()
+    });
+    let next_cursor = if files.len() > MAX_CLONE_FILES {
+        files
+            .get(MAX_CLONE_FILES - 1)
+            .map(|(file_id, _)| ByteBuf::from(file_id.as_slice().to_vec()))
+    } else {
+        None
+    };
+    let mut copied = Vec::new();
+    for (file_id, metadata) in files.into_iter().take(MAX_CLONE_FILES) {
+        let value = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_encrypted_value(caller, from, file_id)
+        })?;
+        let Some(value) = value else {
+            continue;
+        };
+        ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            encrypted_maps
+                .as_mut()
+                .unwrap()
+                .insert_encrypted_value(caller, to, file_id, value)
+        })?;
+        store_metadata((to.0, to.1, file_id), metadata);
+        copy_thumbnail((from.0, from.1, file_id), (to.0, to.1, file_id));
+        copied.push(ByteBuf::from(file_id.as_slice().to_vec()));
+    }
+    Ok(CloneReport {
+        copied,
+        next_cursor,
+    })
+}
+
+/// Collections owned by others that the caller has been granted access to,
+/// optionally only those with `tag`.
+#[query]
+fn get_accessible_shared_collections(tag: Option<String>) -> Vec<CollectionMetadata> {
+    let map_ids = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_accessible_shared_map_names(ic_cdk::api::msg_caller())
+    });
+    map_ids
+        .into_iter()
+        .filter(|map_id| !is_file_share(*map_id))
+        .map(collection_metadata_or_default)
+        .filter(|metadata| has_tag(metadata, &tag))
+        .collect()
+}
+
+/// Collections owned by others that the caller has been granted access to,
+/// with their owner's username and the caller's rights, ordered by owner and
+/// name, at most [`MAX_SHARED_WITH_ME_PAGE`] per page.
+#[query]
+fn get_shared_with_me_detailed(
+    start_after: Option<(Principal, ByteBuf)>,
+) -> Result<SharedWithMePage, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let start_after = start_after
+        .map(|(owner, name)| collection_name_to_blob(name).map(|name| (owner, name)))
+        .transpose()?;
+    let mut map_ids = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_accessible_shared_map_names(caller)
+    });
+    map_ids.sort();
+    let mut map_ids = map_ids
+        .into_iter()
+        .filter(|map_id| !is_file_share(*map_id))
+        .filter(|map_id| start_after.map_or(true, |start_after| *map_id > start_after))
+        .peekable();
+    let mut collections = Vec::new();
+    while collections.len() < MAX_SHARED_WITH_ME_PAGE {
+        let Some(map_id) = map_ids.next() else {
+            break;
+        };
+        let rights = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_user_rights(caller, map_id, caller)
+        })?;
+        let Some(my_access_rights) = rights else {
+            continue;
+        };
+        let stats = collection_stats(map_id);
+        collections.push(SharedCollectionView {
+            owner: map_id.0,
+            owner_username: user_for_principal(&map_id.0).map(|user| user.username),
+            collection_name: collection_metadata_or_default(map_id).name,
+            my_access_rights,
+            file_count: stats.file_count,
+            last_modified: stats.last_upload_at,
+        });
+    }
+    let next_cursor = collections
+        .last()
+        .filter(|_| map_ids.peek().is_some())
+        .map(|view| (view.owner, view.collection_name.clone()));
+    Ok(SharedWithMePage {
+        collections,
+        next_cursor,
+    })
+}
+
+/// The caller's collections that are shared with others, ordered by name,
+/// with who they are shared with. Inspects at most 200 collections per page,
+/// so a page may hold fewer entries while `next_cursor` is set.
+#[query]
+fn get_my_outgoing_shares(start_after: Option<ByteBuf>) -> Result<OutgoingSharePage, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let start_after = start_after.map(bytebuf_to_blob).transpose()?;
+    let mut collections = owned_collection_names(caller)
+        .into_iter()
+        .filter(|map_name| start_after.map_or(true, |start_after| *map_name > start_after))
+        .peekable();
+    let mut shares = Vec::new();
+    let mut last_name = None;
+    for _ in 0..MAX_COLLECTION_SUMMARIES {
+        let Some(map_name) = collections.next() else {
+            break;
+        };
+        last_name = Some(map_name);
+        let map_id = (caller, map_name);
+        let grants = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_shared_user_access_for_map(caller, map_id)
+        })?;
+        let grantees: Vec<OutgoingGrant> = grants
+            .into_iter()
+            .filter(|(grantee, _)| grantee != &caller)
+            .map(|(grantee, rights)| {
+                let username = user_for_principal(&grantee).map(|user| user.username);
+                (grantee, username, rights)
+            })
+            .collect();
+        if !grantees.is_empty() {
+            shares.push((collection_metadata_or_default(map_id).name, grantees));
+        }
+    }
+    let next_cursor = last_name
+        .filter(|_| collections.peek().is_some())
+        .map(|map_name| ByteBuf::from(map_name.as_slice().to_vec()));
+    Ok(OutgoingSharePage {
+        shares,
+        next_cursor,
+    })
+}
+
+/// Replaces the tags of one of the caller's collections. Tags are trimmed
+/// and deduplicated like file tags.
+#[update]
+fn set_collection_tags(
+    collection_name: ByteBuf,
+    tags: Vec<String>,
+) -> Result<Vec<String>, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let tags = clean_tags(tags)?;
+    let map_id = (caller, collection_name_to_blob(collection_name.clone())?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string().into());
+    }
+    ensure_not_archived(map_id)?;
+    let metadata = CollectionMetadata {
+        tags: tags.clone(),
+        ..collection_metadata(map_id).unwrap_or_else(|| CollectionMetadata {
+            name: collection_name,
+            ..new_collection_metadata(map_id, ic_cdk::api::time())
+        })
+    };
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.insert(map_id, metadata));
+    Ok(tags)
+}
+
+/// The grants on a collection, with the time each one expires, if any.
+#[query]
+fn get_shared_user_access_for_collection(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<Vec<(Principal, AccessRights, Option<u64>)>, String> {
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    let grants = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_shared_user_access_for_map(ic_cdk::api::msg_caller(), map_id)
+    })?;
+    Ok(grants
+        .into_iter()
+        .map(|(grantee, rights)| (grantee, rights, grant_expiry(map_id, grantee)))
+        .collect())
+}
+
+/// Like get_shared_user_access_for_collection, with the username and
+/// display name of each registered grantee, whether they may manage
+/// sharing, and the number of grantees against the limit.
+#[query]
+fn get_shared_user_access_for_collection_detailed(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<SharedAccessDetails, String> {
+    let map_id = (
+        collection_owner,
+        collection_name_to_blob(collection_name.clone())?,
+    );
+    let users: Vec<SharedUserView> =
+        get_shared_user_access_for_collection(collection_owner, collection_name)?
+            .into_iter()
+            .map(|(principal, access_rights, expires_at)| {
+                let user = user_for_principal(&principal);
+                SharedUserView {
+                    principal,
+                    username: user.as_ref().map(|user| user.username.clone()),
+                    display_name: user.and_then(|user| user.display_name),
+                    can_manage: principal == map_id.0
+                        || is_manager(map_id, principal)
+                        || is_co_owner(map_id, principal),
+                    is_co_owner: is_co_owner(map_id, principal),
+                    may_reshare: may_reshare(map_id, principal),
+                    access_rights,
+                    expires_at,
+                }
+            })
+            .collect();
+    let grantee_count = users
+        .iter()
+        .filter(|user| user.principal != collection_owner)
+        .count() as u32;
+    Ok(SharedAccessDetails {
+        users,
+        grantee_count,
+        max_grantees: max_grantees_per_collection(),
+    })
+}
+
+/// How many principals besides the owner a collection can be shared with.
+#[query]
+fn get_max_grantees_per_collection() -> u32 {
+    max_grantees_per_collection()
+}
+
+/// Sets the limit of get_max_grantees_per_collection. Collections already
+/// above it keep their grants but cannot gain new ones.
+#[update]
+fn set_max_grantees_per_collection(limit: u32) -> Result<(), String> {
+    ensure_admin(ic_cdk::api::msg_caller())?;
+    if limit == 0 {
+        return Err("the limit must be at least one".to_string());
+    }
+    CONFIG.with_borrow_mut(|config| {
+        let mut updated = config.get().clone();
+        updated.max_grantees_per_collection = Some(limit);
+        config.set(updated).expect("failed to store config");
+    });
+    Ok(())
+}
+
+pub fn max_grantees_per_collection() -> u32 {
+    CONFIG
+        .with_borrow(|config| config.get().max_grantees_per_collection)
+        .unwrap_or(DEFAULT_MAX_GRANTEES_PER_COLLECTION)
+}
+
+/// Rejects sharing a collection with `additional` more principals if that
+/// would take it past [`max_grantees_per_collection`].
+pub fn ensure_room_for_grantees(map_id: MapId, additional: u32) -> Result<(), ApiError> {
+    let current = ENCRYPTED_MAPS
+        .with_borrow(|encrypted_maps| {
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_shared_user_access_for_map(map_id.0, map_id)
+        })?
+        .into_iter()
+        .filter(|(grantee, _)| grantee != &map_id.0)
+        .count() as u32;
+    let limit = max_grantees_per_collection();
+    if current.saturating_add(additional) > limit {
+        return Err(ApiError::LimitExceeded { current, limit });
+    }
+    Ok(())
+}
+
+/// Gives `username` the given rights on a collection of the caller or, for
+/// managers, of `collection_owner`. Users without access yet are invited
+/// and only get access once they accept; the rights of users who already
+/// have access change right away.
+#[update]
+fn share_collection_with_user(
+    collection_name: ByteBuf,
+    username: Username,
+    access_rights: AccessRights,
+    message: Option<String>,
+    collection_owner: Option<Principal>,
+) -> Result<ShareOutcome, ApiError> {
+    let user = principal_for_username(&username)?;
+    share_or_invite(
+        collection_owner,
+        collection_name,
+        user,
+        access_rights,
+        message,
+        None,
+    )
+}
+
+/// Like share_collection_with_user for up to [`MAX_BULK_SHARE_USERS`] users
+/// at once, with one message for all. A failure for one user, such as an
+/// unknown username, is reported next to it and does not stop the others.
+#[update]
+fn share_collection_with_users(
+    collection_name: ByteBuf,
+    grants: Vec<(Username, AccessRights)>,
+    message: Option<String>,
+) -> Result<Vec<BulkShareResult>, ApiError> {
+    if grants.len() > MAX_BULK_SHARE_USERS {
+        return Err(format!("at most {MAX_BULK_SHARE_USERS} users can be added at once").into());
+    }
+    let caller = ic_cdk::api::msg_caller();
+    let (map_id, message) = prepare_share(caller, None, &collection_name, message)?;
+    Ok(grants
+        .into_iter()
+        .map(|(username, access_rights)| {
+            let outcome = principal_for_username(&username)
+                .map_err(ApiError::from)
+                .and_then(|user| {
+                    share_or_invite_prepared(
+                        caller,
+                        map_id,
+                        collection_name.clone(),
+                        user,
+                        access_rights,
+                        message.clone(),
+                        None,
+                    )
+                });
+            (username, outcome)
+        })
+        .collect())
+}
+
+/// Grants a principal the given rights on one of the caller's collections
+/// without requiring it to be registered, e.g. a service or a canister.
+#[update]
+fn share_collection_with_principal(
+    collection_name: ByteBuf,
+    principal: Principal,
+    access_rights: AccessRights,
+) -> Result<Option<AccessRights>, ApiError> {
+    share_collection(collection_name, principal, access_rights)
+}
+
+/// Grants `user` the given rights on one of the caller's collections for
+/// good, replacing any expiry of an earlier grant.
+pub fn share_collection(
+    collection_name: ByteBuf,
+    user: Principal,
+    access_rights: AccessRights,
+) -> Result<Option<AccessRights>, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    ensure_not_archived(map_id)?;
+    set_grant(map_id, caller, user, access_rights)
+}
+
+/// Sets `user`'s rights on a collection on behalf of its owner, for good.
+/// `actor` is whoever asked for it: the owner or one of the collection's
+/// managers, who must have been checked by the caller.
+pub fn set_grant(
+    map_id: MapId,
+    actor: Principal,
+    user: Principal,
+    access_rights: AccessRights,
+) -> Result<Option<AccessRights>, ApiError> {
+    ensure_grantee_allowed(map_id, actor, user)?;
+    if actor != map_id.0 {
+        ensure_manager_may_change(map_id, user, Some(access_rights))?;
+    }
+    let current = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(map_id.0, map_id, user)
+    })?;
+    if current.is_none() {
+        ensure_room_for_grantees(map_id, 1)?;
+    }
+    let previous = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .set_user_rights(map_id.0, map_id, user, access_rights)
+    })?;
+    forget_group_grant(map_id, user);
+    log_sharing_change(map_id, actor, user, previous, Some(access_rights));
+    if previous != Some(access_rights) {
+        notify(
+            user,
+            actor,
+            map_id,
+            NotificationKind::Shared { access_rights },
+        );
+    }
+    set_grant_expiry(map_id, user, None);
+    log_activity(map_id, actor, ActivityAction::Shared { user }, None);
+    Ok(previous)
+}
+
+/// Rejects grants to the anonymous principal, to the collection's owner and
+/// by `actor` to themselves.
+pub fn ensure_grantee_allowed(
+    map_id: MapId,
+    actor: Principal,
+    user: Principal,
+) -> Result<(), ApiError> {
+    if user == Principal::anonymous() {
+        return Err("cannot share with the anonymous principal"
+            .to_string()
+            .into());
+    }
+    if user == actor {
+        return Err("cannot share a collection with yourself".to_string().into());
+    }
+    if user == map_id.0 {
+        return Err("the owner always has access to the collection"
+            .to_string()
+            .into());
+    }
+    Ok(())
+}
+
+/// Managers and co-owners can neither hand out the right to manage the
+/// collection through EncryptedMaps nor change the grants of other managers
+/// and co-owners; only the owner can.
+pub fn ensure_manager_may_change(
+    map_id: MapId,
+    user: Principal,
+    new_rights: Option<AccessRights>,
+) -> Result<(), ApiError> {
+    if new_rights == Some(AccessRights::ReadWriteManage) {
+        return Err("only the owner can grant manage rights".to_string().into());
+    }
+    let current = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(map_id.0, map_id, user)
+    })?;
+    if current == Some(AccessRights::ReadWriteManage)
+        || is_manager(map_id, user)
+        || is_co_owner(map_id, user)
+    {
+        return Err(
+            "only the owner can change the access of managers and co-owners"
+                .to_string()
+                .into(),
+        );
+    }
+    Ok(())
+}
+
+/// Revokes `username`'s access to a collection of the caller or, for
+/// managers, of `collection_owner`. Managers may also remove themselves.
+#[update]
+fn remove_user_from_collection(
+    collection_name: ByteBuf,
+    username: Username,
+    collection_owner: Option<Principal>,
+) -> Result<Option<AccessRights>, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let owner = collection_owner.unwrap_or(caller);
+    let map_id = (owner, collection_name_to_blob(collection_name)?);
+    let user = principal_for_username(&username)?;
+    remove_grantee(caller, map_id, user)
+}
+
+/// remove_user_from_collection once the names are resolved.
+fn remove_grantee(
+    caller: Principal,
+    map_id: MapId,
+    user: Principal,
+) -> Result<Option<AccessRights>, ApiError> {
+    ensure_can_manage(map_id, caller)?;
+    ensure_not_archived(map_id)?;
+    if caller != map_id.0 && user != caller {
+        ensure_manager_may_change(map_id, user, None)?;
+    }
+    Ok(revoke_grant(map_id, caller, user)?)
+}
+
+/// Gives up the caller's access to a collection someone else shared with
+/// them, dropping their stars on its files, their notifications about it and
+/// the file overrides set for them. Works on archived collections too.
+#[update]
+fn leave_shared_collection(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    if caller == collection_owner {
+        return Err("you cannot leave your own collection".to_string());
+    }
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    // EncryptedMaps only lets the owner remove grants, so this is done on
+    // their behalf, like in delete_my_account.
+    if revoke_grant(map_id, caller, caller)?.is_none() {
+        return Err("the collection is not shared with you".to_string());
+    }
+    remove_collection_stars(caller, map_id);
+    remove_collection_notifications(caller, map_id);
+    remove_user_file_overrides(map_id, caller);
+    Ok(())
+}
+
+/// Revokes `user`'s access to a collection on behalf of its owner. `actor`
+/// is whoever asked for it, who must have been checked by the caller.
+pub fn revoke_grant(
+    map_id: MapId,
+    actor: Principal,
+    user: Principal,
+) -> Result<Option<AccessRights>, String> {
+    let removed = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .remove_user(map_id.0, map_id, user)
+    })?;
+    set_grant_expiry(map_id, user, None);
+    remove_manager(map_id, user);
+    forget_group_grant(map_id, user);
+    log_sharing_change(map_id, actor, user, removed, None);
+    if removed.is_some() {
+        notify(user, actor, map_id, NotificationKind::Unshared);
+        log_activity(map_id, actor, ActivityAction::Unshared { user }, None);
+    }
+    Ok(removed)
+}
+
+/// Revokes every grant on a collection of the caller or, for co-owners, of
+/// `collection_owner`, cancels its pending invitations and removes its
+/// share links and file shares, e.g. after it was compromised. Plain
+/// managers cannot, since it also removes co-owners. A co-owner revokes
+/// their own grant with the others. Works on archived collections
+/// too. Flags the collection for key rotation, since the revoked users may
+/// have kept its vetkey. Returns the number of grants revoked on the
+/// collection itself.
+#[update]
+fn revoke_all_access(
+    collection_name: ByteBuf,
+    collection_owner: Option<Principal>,
+) -> Result<u32, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let owner = collection_owner.unwrap_or(caller);
+    let map_id = (owner, collection_name_to_blob(collection_name.clone())?);
+    revoke_all_grants(caller, map_id, collection_name)
+}
+
+/// revoke_all_access once the collection is resolved.
+fn revoke_all_grants(
+    caller: Principal,
+    map_id: MapId,
+    collection_name: ByteBuf,
+) -> Result<u32, String> {
+    let owner = map_id.0;
+    if !owned_collection_names(owner).contains(&map_id.1) {
+        return Err("collection not found".to_string());
+    }
+    if !is_owner_or_co_owner(map_id, caller)? {
+        return Err("only the owner and co-owners can revoke all access".to_string());
+    }
+    let revoked = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        let mut revoked = Vec::new();
+        for (grantee, rights) in encrypted_maps.get_shared_user_access_for_map(owner, map_id)? {
+            if grantee != owner {
+                encrypted_maps.remove_user(owner, map_id, grantee)?;
+                revoked.push((grantee, rights));
+            }
+        }
+        Ok::<_, String>(revoked)
+    })?;
+    remove_collection_managers(map_id);
+    remove_collection_group_grants(map_id);
+    for (grantee, _) in &revoked {
+        set_grant_expiry(map_id, *grantee, None);
+        notify(*grantee, caller, map_id, NotificationKind::Unshared);
+        log_activity(
+            map_id,
+            caller,
+            ActivityAction::Unshared { user: *grantee },
+            None,
+        );
+    }
+    let revoked_count = revoked.len() as u32;
+    log_all_access_revoked(map_id, caller, revoked);
+    cancel_collection_invitations(map_id);
+    remove_collection_share_links(map_id);
+    remove_collection_file_shares(map_id)?;
+    let metadata = CollectionMetadata {
+        key_rotation_needed_since: Some(ic_cdk::api::time()),
+        ..collection_metadata(map_id).unwrap_or_else(|| CollectionMetadata {
+            name: collection_name,
+            ..new_collection_metadata(map_id, ic_cdk::api::time())
+        })
+    };
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.insert(map_id, metadata));
+    Ok(revoked_count)
+}
+
+/// Clears the key rotation flag set by revoke_all_access, once the owner
+/// has moved the files to a collection with a fresh key or decided not to.
+#[update]
+fn clear_key_rotation_flag(collection_name: ByteBuf) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    let metadata = collection_metadata(map_id)
+        .filter(|metadata| metadata.owner == caller)
+        .ok_or_else(|| "collection not found".to_string())?;
+    let metadata = CollectionMetadata {
+        key_rotation_needed_since: None,
+        ..metadata
+    };
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.insert(map_id, metadata));
+    Ok(())
+}
+
+/// Makes one of the caller's collections read-only, or writable again.
+/// Reading files and fetching the collection's vetkey keep working.
+#[update]
+fn set_collection_archived(collection_name: ByteBuf, archived: bool) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name.clone())?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string());
+    }
+    let metadata = CollectionMetadata {
+        archived,
+        ..collection_metadata(map_id).unwrap_or_else(|| CollectionMetadata {
+            name: collection_name,
+            ..new_collection_metadata(map_id, ic_cdk::api::time())
+        })
+    };
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.insert(map_id, metadata));
+    Ok(())
+}
+
+/// Settings of a collection the caller has access to.
+#[query]
+fn get_collection_settings(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<CollectionSettings, String> {
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    caller_rights(ic_cdk::api::msg_caller(), map_id)?;
+    Ok(collection_settings(map_id))
+}
+
+/// Sets how many previous versions of each file one of the caller's
+/// collections keeps. Lowering it prunes a file's surplus versions on its
+/// next upload.
+#[update]
+fn set_collection_keep_versions(collection_name: ByteBuf, keep_versions: u8) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    let mut settings = collection_settings(map_id);
+    settings.keep_versions = keep_versions;
+    COLLECTION_SETTINGS
+        .with_borrow_mut(|collection_settings| collection_settings.insert(map_id, settings));
+    Ok(())
+}
+
+/// Turns filename uniqueness on or off for one of the caller's collections.
+/// Files that already share a name are left as they are.
+#[update]
+fn set_collection_unique_filenames(
+    collection_name: ByteBuf,
+    unique_filenames: bool,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    let mut settings = collection_settings(map_id);
+    settings.unique_filenames = unique_filenames;
+    COLLECTION_SETTINGS
+        .with_borrow_mut(|collection_settings| collection_settings.insert(map_id, settings));
+    Ok(())
+}
+
+/// Turns the read audit log on or off for one of the caller's collections.
+/// While it is on, grantees have to fetch its files with update calls.
+#[update]
+fn set_collection_audit_reads(collection_name: ByteBuf, audit_reads: bool) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string());
+    }
+    let mut settings = collection_settings(map_id);
+    settings.audit_reads = audit_reads;
+    COLLECTION_SETTINGS
+        .with_borrow_mut(|collection_settings| collection_settings.insert(map_id, settings));
+    Ok(())
+}
+
+/// The upload policy of a collection the caller has access to.
+#[query]
+fn get_collection_policy(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<CollectionPolicy, String> {
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    caller_rights(ic_cdk::api::msg_caller(), map_id)?;
+    Ok(collection_policy(map_id))
+}
+
+/// Replaces the upload policy of one of the caller's collections. Files
+/// already in the collection are not checked against it.
+#[update]
+fn set_collection_policy(collection_name: ByteBuf, policy: CollectionPolicy) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    if policy
+        .allowed_content_types
+        .as_ref()
+        .is_some_and(|content_types| content_types.len() > MAX_POLICY_CONTENT_TYPES)
+    {
+        return Err(format!(
+            "at most {MAX_POLICY_CONTENT_TYPES} content types are allowed"
+        ));
+    }
+    let map_id = (caller, collection_name_to_blob(collection_name.clone())?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string());
+    }
+    let metadata = CollectionMetadata {
+        max_file_size: policy.max_file_size,
+        allowed_content_types: policy.allowed_content_types,
+        ..collection_metadata(map_id).unwrap_or_else(|| CollectionMetadata {
+            name: collection_name,
+            ..new_collection_metadata(map_id, ic_cdk::api::time())
+        })
+    };
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.insert(map_id, metadata));
+    let mut settings = collection_settings(map_id);
+    settings.unique_filenames = policy.unique_filenames;
+    COLLECTION_SETTINGS
+        .with_borrow_mut(|collection_settings| collection_settings.insert(map_id, settings));
+    Ok(())
+}
+
+pub fn collection_policy(map_id: MapId) -> CollectionPolicy {
+    let metadata = collection_metadata(map_id);
+    CollectionPolicy {
+        max_file_size: metadata
+            .as_ref()
+            .and_then(|metadata| metadata.max_file_size),
+        allowed_content_types: metadata.and_then(|metadata| metadata.allowed_content_types),
+        unique_filenames: collection_settings(map_id).unique_filenames,
+    }
+}
+
+/// Checks the size and content type of a file entering a collection, by
+/// upload, move or copy, against the collection's policy. Filename
+/// uniqueness is checked with the filename index.
+pub fn ensure_policy_allows(
+    map_id: MapId,
+    file_size: u64,
+    content_type: &str,
+) -> Result<(), ApiError> {
+    let Some(collection) = collection_metadata(map_id) else {
+        return Ok(());
+    };
+    if let Some(max_file_size) = collection.max_file_size {
+        if file_size > max_file_size {
+            return Err(ApiError::FileTooLarge { max_file_size });
+        }
+    }
+    if let Some(allowed_content_types) = collection.allowed_content_types {
+        let content_type = content_type.trim().to_lowercase();
+        let allowed = allowed_content_types.iter().any(|allowed| {
+            let allowed = allowed.trim().to_lowercase();
+            match allowed.strip_suffix("/*") {
+                Some(prefix) => content_type
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/')),
+                None => content_type == allowed,
+            }
+        });
+        if !allowed {
+            return Err(ApiError::ContentTypeNotAllowed {
+                allowed_content_types,
+            });
+        }
+    }
+    Ok(())
+}
+
+pub fn collection_metadata(map_id: MapId) -> Option<CollectionMetadata> {
+    COLLECTION_METADATA.with_borrow(|collection_metadata| collection_metadata.get(&map_id))
+}
+
+pub fn ensure_not_archived(map_id: MapId) -> Result<(), ApiError> {
+    if collection_metadata(map_id).is_some_and(|metadata| metadata.archived) {
+        return Err(ApiError::CollectionArchived);
+    }
+    Ok(())
+}
+
+/// Creates the "home" collection of a newly registered user, unless they
+/// already have a collection of that name.
+pub fn create_default_collection(owner: Principal) {
+    let map_id = (
+        owner,
+        Blob::try_from(DEFAULT_COLLECTION_NAME.as_bytes()).unwrap(),
+    );
+    if collection_metadata(map_id).is_some() || owned_collection_names(owner).contains(&map_id.1) {
+        return;
+    }
+    let metadata = CollectionMetadata {
+        is_default: true,
+        ..new_collection_metadata(map_id, ic_cdk::api::time())
+    };
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.insert(map_id, metadata));
+}
+
+/// The record of a collection, or an empty one dated 0 if it has none.
+pub fn collection_metadata_or_default(map_id: MapId) -> CollectionMetadata {
+    collection_metadata(map_id).unwrap_or_else(|| new_collection_metadata(map_id, 0))
+}
+
+/// Fails if a name longer than 32 bytes hashes to the key of a collection
+/// with a different name. Shorter names are keys themselves and always match.
+pub fn ensure_collection_name(map_id: MapId, name: &ByteBuf) -> Result<(), String> {
+    if name.as_ref().len() <= 32 {
+        return Ok(());
+    }
+    match collection_metadata(map_id) {
+        Some(existing) if existing.name != *name => {
+            Err("collection name collides with the key of an existing collection".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Creates the record of a collection on its first upload, unless it was
+/// created explicitly, and grants it to the owner's linked principals.
+pub fn ensure_collection_metadata(map_id: MapId, name: ByteBuf) {
+    if collection_metadata(map_id).is_some() || is_file_share(map_id) {
+        return;
+    }
+    let metadata = CollectionMetadata {
+        name,
+        ..new_collection_metadata(map_id, ic_cdk::api::time())
+    };
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.insert(map_id, metadata));
+    grant_linked_access(map_id);
+}
+
+/// Names of the collections of `owner` that have files or a record, not
+/// counting the hidden collections of shared files.
+pub fn owned_collection_names(owner: Principal) -> BTreeSet<MapName> {
+    let mut names: BTreeSet<MapName> = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_owned_non_empty_map_names(owner)
+            .into_iter()
+            .collect()
+    });
+    COLLECTION_METADATA.with_borrow(|collection_metadata| {
+        names.extend(
+            collection_metadata
+                .range((owner, Blob::default())..)
+                .take_while(|((map_owner, _), _)| map_owner == &owner)
+                .map(|((_, map_name), _)| map_name),
+        );
+    });
+    names.retain(|map_name| !is_file_share((owner, *map_name)));
+    names
+}
+
+/// Removes the records of all collections of `owner`.
+pub fn remove_owned_collection_metadata(owner: Principal) {
+    COLLECTION_METADATA.with_borrow_mut(|collection_metadata| {
+        let map_ids: Vec<MapId> = collection_metadata
+            .range((owner, Blob::default())..)
+            .take_while(|((map_owner, _), _)| map_owner == &owner)
+            .map(|(map_id, _)| map_id)
+            .collect();
+        for map_id in map_ids {
+            collection_metadata.remove(&map_id);
+        }
+    });
+}
+
+/// Creates the missing records of collections that have files, dated by
+/// their oldest file, so that listings driven by the records include them.
+pub fn backfill_collection_metadata() {
+    let mut created_at: BTreeMap<MapId, u64> = BTreeMap::new();
+    FILE_METADATA.with_borrow(|file_metadata| {
+        for ((owner, name, _), metadata) in file_metadata.iter() {
+            let oldest = created_at.entry((owner, name)).or_insert(u64::MAX);
+            *oldest = (*oldest).min(metadata.creation_date);
+        }
+    });
+    COLLECTION_METADATA.with_borrow_mut(|collection_metadata| {
+        for (map_id, created_at) in created_at {
+            if !collection_metadata.contains_key(&map_id) && !is_file_share(map_id) {
+                collection_metadata.insert(map_id, new_collection_metadata(map_id, created_at));
+            }
+        }
+    });
+}
+
+fn new_collection_metadata(map_id: MapId, created_at: u64) -> CollectionMetadata {
+    CollectionMetadata {
+        owner: map_id.0,
+        name: ByteBuf::from(map_id.1.as_slice().to_vec()),
+        description: None,
+        created_at,
+        display_label: None,
+        color: None,
+        icon: None,
+        is_default: false,
+        archived: false,
+        tags: Vec::new(),
+        max_file_size: None,
+        allowed_content_types: None,
+        pending_reencryption_from: None,
+        transferred_to: None,
+        renamed_from: None,
+        renamed_to: None,
+        key_rotation_needed_since: None,
+    }
+}
+
+/// Whether the collection has `tag`, compared trimmed and lowercased;
+/// `None` matches every collection.
+fn has_tag(metadata: &CollectionMetadata, tag: &Option<String>) -> bool {
+    tag.as_ref()
+        .map_or(true, |tag| contains_tag(&metadata.tags, tag))
+}
+
+fn validate_description(description: &Option<String>) -> Result<(), String> {
+    if description
+        .as_ref()
+        .is_some_and(|description| description.len() > MAX_COLLECTION_DESCRIPTION_LEN)
+    {
+        return Err(format!(
+            "description must be at most {MAX_COLLECTION_DESCRIPTION_LEN} bytes"
+        ));
+    }
+    Ok(())
+}
+
+pub fn collection_settings(map_id: MapId) -> CollectionSettings {
+    COLLECTION_SETTINGS
+        .with_borrow(|collection_settings| collection_settings.get(&map_id))
+        .unwrap_or_default()
+}
+
+/// Removes the settings of all collections of `owner`.
+pub fn remove_collection_settings(owner: Principal) {
+    COLLECTION_SETTINGS.with_borrow_mut(|collection_settings| {
+        let map_ids: Vec<MapId> = collection_settings
+            .range((owner, Blob::default())..)
+            .take_while(|((map_owner, _), _)| map_owner == &owner)
+            .map(|(map_id, _)| map_id)
+            .collect();
+        for map_id in map_ids {
+            collection_settings.remove(&map_id);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{COLLECTION_CO_OWNERS, COLLECTION_MANAGERS};
+
+    const NAME: &[u8] = b"docs";
+
+    /// A collection of principal 1 with one file, shared with an editor
+    /// (principal 2, ReadWrite) and a member (principal 3, Read).
+    fn shared_collection() -> (MapId, Principal, Principal) {
+        crate::init_encrypted_maps("test_key".to_string());
+        let owner = Principal::from_slice(&[1]);
+        let editor = Principal::from_slice(&[2]);
+        let member = Principal::from_slice(&[3]);
+        let map_id = (owner, Blob::try_from(NAME).unwrap());
+        ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            let encrypted_maps = encrypted_maps.as_mut().unwrap();
+            let file_id = Blob::try_from([1].as_slice()).unwrap();
+            encrypted_maps
+                .insert_encrypted_value(owner, map_id, file_id, ByteBuf::from(vec![1]))
+                .unwrap();
+            encrypted_maps
+                .set_user_rights(owner, map_id, editor, AccessRights::ReadWrite)
+                .unwrap();
+            encrypted_maps
+                .set_user_rights(owner, map_id, member, AccessRights::Read)
+                .unwrap();
+        });
+        (map_id, editor, member)
+    }
+
+    fn rights(map_id: MapId, user: Principal) -> Option<AccessRights> {
+        ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_user_rights(map_id.0, map_id, user)
+                .unwrap()
+        })
+    }
+
+    fn error_text(error: ApiError) -> String {
+        match error {
+            ApiError::Other(message) => message,
+            other => panic!("unexpected error {other:?}"),
+        }
+    }
+
+    #[test]
+    fn editor_without_the_flag_cannot_share() {
+        let (map_id, editor, member) = shared_collection();
+        let error =
+            prepare_share(editor, Some(map_id.0), &ByteBuf::from(NAME.to_vec()), None).unwrap_err();
+        assert_eq!(error_text(error), "you cannot share this collection");
+        let error = share_or_invite_prepared(
+            editor,
+            map_id,
+            ByteBuf::from(NAME.to_vec()),
+            member,
+            AccessRights::ReadWrite,
+            None,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error_text(error),
+            "only managers can change the rights of users with access"
+        );
+        assert_eq!(rights(map_id, member), Some(AccessRights::Read));
+    }
+
+    #[test]
+    fn editor_without_the_flag_cannot_remove_users() {
+        let (map_id, editor, member) = shared_collection();
+        let error = remove_grantee(editor, map_id, member).unwrap_err();
+        assert_eq!(
+            error_text(error),
+            "you cannot manage sharing of this collection"
+        );
+        assert_eq!(rights(map_id, member), Some(AccessRights::Read));
+    }
+
+    #[test]
+    fn editor_without_the_flag_cannot_revoke_all_access() {
+        let (map_id, editor, member) = shared_collection();
+        let error = revoke_all_grants(editor, map_id, ByteBuf::from(NAME.to_vec())).unwrap_err();
+        assert_eq!(error, "only the owner and co-owners can revoke all access");
+        assert_eq!(rights(map_id, editor), Some(AccessRights::ReadWrite));
+        assert_eq!(rights(map_id, member), Some(AccessRights::Read));
+    }
+
+    #[test]
+    fn managers_cannot_revoke_all_access() {
+        let (map_id, editor, member) = shared_collection();
+        COLLECTION_MANAGERS
+            .with_borrow_mut(|managers| managers.insert((map_id.0, map_id.1, editor), ()));
+        COLLECTION_CO_OWNERS
+            .with_borrow_mut(|co_owners| co_owners.insert((map_id.0, map_id.1, member), ()));
+        let error = revoke_all_grants(editor, map_id, ByteBuf::from(NAME.to_vec())).unwrap_err();
+        assert_eq!(error, "only the owner and co-owners can revoke all access");
+        assert_eq!(rights(map_id, member), Some(AccessRights::Read));
+    }
+
+    #[test]
+    fn the_flag_only_counts_with_a_grant() {
+        let (map_id, editor, _) = shared_collection();
+        let former = Principal::from_slice(&[4]);
+        COLLECTION_MANAGERS.with_borrow_mut(|managers| {
+            managers.insert((map_id.0, map_id.1, editor), ());
+            managers.insert((map_id.0, map_id.1, former), ());
+        });
+        assert!(ensure_can_manage(map_id, editor).is_ok());
+        assert!(ensure_can_manage(map_id, former).is_err());
+        assert!(remove_grantee(former, map_id, editor).is_err());
+        assert_eq!(rights(map_id, editor), Some(AccessRights::ReadWrite));
+    }
+}
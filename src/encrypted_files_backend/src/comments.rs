@@ -0,0 +1,178 @@
+//! Comment threads on files. Comments are keyed by file and a global
+//! sequence number, with a reverse index from the number to the file so
+//! that they can be deleted by id alone.
+
+use crate::file_overrides::is_hidden_from;
+use crate::users::record_activity;
+use crate::versions::FileKey;
+use crate::{
+    bytebuf_to_blob, caller_rights, collection_name_to_blob, ensure_can_write, COMMENTS,
+    COMMENT_FILES, FILE_METADATA, NEXT_COMMENT_ID,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const MAX_COMMENT_LEN: usize = 2_048;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Comment {
+    pub comment_id: u64,
+    pub author: Principal,
+    pub text: String,
+    pub created_at: u64,
+}
+
+impl Storable for Comment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Adds a comment to a file. Requires read access to the collection.
+#[update]
+fn add_file_comment(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    text: String,
+) -> Result<Comment, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    if text.trim().is_empty() || text.len() > MAX_COMMENT_LEN {
+        return Err(format!("comments must be 1 to {MAX_COMMENT_LEN} bytes"));
+    }
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_key = (collection_owner, collection_name, bytebuf_to_blob(file_id)?);
+    caller_rights(caller, (collection_owner, collection_name))?;
+    if !FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key))
+        || is_hidden_from(file_key, caller)
+    {
+        return Err("file not found".to_string());
+    }
+    let comment_id = NEXT_COMMENT_ID.with_borrow_mut(|next_comment_id| {
+        let comment_id = *next_comment_id.get();
+        next_comment_id
+            .set(comment_id + 1)
+            .expect("failed to store comment id");
+        comment_id
+    });
+    let comment = Comment {
+        comment_id,
+        author: caller,
+        text,
+        created_at: ic_cdk::api::time(),
+    };
+    COMMENTS.with_borrow_mut(|comments| comments.insert((file_key, comment_id), comment.clone()));
+    COMMENT_FILES.with_borrow_mut(|comment_files| comment_files.insert(comment_id, file_key));
+    Ok(comment)
+}
+
+/// Comments on a file, oldest first. Requires read access to the collection,
+/// and files hidden from the caller are reported as missing.
+#[query]
+fn list_file_comments(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<Vec<Comment>, String> {
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_key = (collection_owner, collection_name, bytebuf_to_blob(file_id)?);
+    let caller = ic_cdk::api::msg_caller();
+    caller_rights(caller, (collection_owner, collection_name))?;
+    if is_hidden_from(file_key, caller) {
+        return Err("file not found".to_string());
+    }
+    Ok(COMMENTS.with_borrow(|comments| {
+        comments
+            .range((file_key, 0)..)
+            .take_while(|((key, _), _)| key == &file_key)
+            .map(|(_, comment)| comment)
+            .collect()
+    }))
+}
+
+/// Deletes a comment. Allowed for its author while they can still read the
+/// collection, and for anyone with write access to it.
+#[update]
+fn delete_file_comment(comment_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let file_key = COMMENT_FILES
+        .with_borrow(|comment_files| comment_files.get(&comment_id))
+        .ok_or_else(|| "comment not found".to_string())?;
+    let (owner, name, _) = file_key;
+    let comment = COMMENTS
+        .with_borrow(|comments| comments.get(&(file_key, comment_id)))
+        .ok_or_else(|| "comment not found".to_string())?;
+    if comment.author == caller {
+        caller_rights(caller, (owner, name))?;
+    } else {
+        ensure_can_write(caller, (owner, name))?;
+    }
+    remove_comment(file_key, comment_id);
+    Ok(())
+}
+
+/// Re-keys the comments on a file, e.g. when its collection is renamed.
+/// Comment ids stay the same.
+pub fn move_file_comments(from: FileKey, to: FileKey) {
+    let comments: Vec<Comment> = COMMENTS.with_borrow(|comments| {
+        comments
+            .range((from, 0)..)
+            .take_while(|((key, _), _)| key == &from)
+            .map(|(_, comment)| comment)
+            .collect()
+    });
+    for comment in comments {
+        let comment_id = comment.comment_id;
+        COMMENTS.with_borrow_mut(|comments| {
+            comments.remove(&(from, comment_id));
+            comments.insert((to, comment_id), comment);
+        });
+        COMMENT_FILES.with_borrow_mut(|comment_files| comment_files.insert(comment_id, to));
+    }
+}
+
+/// Removes all comments on a file.
+pub fn remove_file_comments(file_key: FileKey) {
+    let comment_ids: Vec<u64> = COMMENTS.with_borrow(|comments| {
+        comments
+            .range((file_key, 0)..)
+            .take_while(|((key, _), _)| key == &file_key)
+            .map(|((_, comment_id), _)| comment_id)
+            .collect()
+    });
+    for comment_id in comment_ids {
+        remove_comment(file_key, comment_id);
+    }
+}
+
+/// Removes all comments on files in collections of `owner`.
+pub fn remove_owned_file_comments(owner: Principal) {
+    let keys: Vec<(FileKey, u64)> = COMMENTS.with_borrow(|comments| {
+        comments
+            .range(((owner, Blob::default(), Blob::default()), 0)..)
+            .take_while(|(((file_owner, _, _), _), _)| file_owner == &owner)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for (file_key, comment_id) in keys {
+        remove_comment(file_key, comment_id);
+    }
+}
+
+fn remove_comment(file_key: FileKey, comment_id: u64) {
+    COMMENTS.with_borrow_mut(|comments| comments.remove(&(file_key, comment_id)));
+    COMMENT_FILES.with_borrow_mut(|comment_files| comment_files.remove(&comment_id));
+}
@@ -0,0 +1,194 @@
+//! Export of a collection's file listing as CSV (RFC 4180), for opening in
+//! a spreadsheet. Only metadata is exported, never the file contents.
+
+use crate::file_overrides::is_hidden_from;
+use crate::files::FileMetadata;
+use crate::users::user_for_principal;
+use crate::{bytebuf_to_blob, caller_rights, collection_name_to_blob, MapKey, FILE_METADATA};
+use candid::{CandidType, Principal};
+use ic_cdk::query;
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::ByteBuf;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ops::Bound as RangeBound;
+
+/// Size a CSV page stops growing at, well below the response limit.
+const MAX_CSV_PAGE_BYTES: usize = 1_000_000;
+/// Upper bound on metadata entries inspected per call.
+const MAX_CSV_SCAN: usize = 5_000;
+const CSV_HEADER: &str = "filename,size,content_type,uploaded_by,tags,created_at,modified_at\r\n";
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CsvPage {
+    /// CRLF-terminated rows; the header row only on the first page.
+    pub csv: String,
+    /// Pass as `cursor` to get the next page; `null` on the last page.
+    pub next_cursor: Option<ByteBuf>,
+}
+
+/// A page of the file listing of a collection as CSV, starting after the
+/// file `cursor`. Uploaders are shown by username, or by principal if they
+/// are not registered, and timestamps in UTC. Requires read access to the
+/// collection.
+#[query]
+fn export_collection_csv(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    cursor: Option<ByteBuf>,
+) -> Result<CsvPage, String> {
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    let caller = ic_cdk::api::msg_caller();
+    caller_rights(caller, map_id)?;
+    let mut csv = String::new();
+    let start = match cursor.map(bytebuf_to_blob).transpose()? {
+        Some(file_id) => RangeBound::Excluded((collection_owner, collection_name, file_id)),
+        None => {
+            csv.push_str(CSV_HEADER);
+            RangeBound::Included((collection_owner, collection_name, Blob::default()))
+        }
+    };
+
+    let now = ic_cdk::api::time();
+    let mut uploaders: BTreeMap<Principal, String> = BTreeMap::new();
+    let mut last_scanned: Option<MapKey> = None;
+    let mut next_cursor = None;
+    FILE_METADATA.with_borrow(|file_metadata| {
+        for (scanned, ((owner, name, file_id), metadata)) in file_metadata
+            .range((start, RangeBound::Unbounded))
+            .enumerate()
+        {
+            if (owner, name) != map_id {
+                break;
+            }
+            if scanned == MAX_CSV_SCAN {
+                next_cursor = last_scanned;
+                break;
+            }
+            if !metadata.is_expired(now) && !is_hidden_from((owner, name, file_id), caller) {
+                let uploader = uploaders
+                    .entry(metadata.uploaded_by)
+                    .or_insert_with(|| {
+                        user_for_principal(&metadata.uploaded_by)
+                            .map_or_else(|| metadata.uploaded_by.to_text(), |user| user.username)
+                    })
+                    .clone();
+                let row = csv_row(&metadata, &uploader);
+                if last_scanned.is_some() && csv.len() + row.len() > MAX_CSV_PAGE_BYTES {
+                    next_cursor = last_scanned;
+                    break;
+                }
+                csv.push_str(&row);
+            }
+            last_scanned = Some(file_id);
+        }
+    });
+    Ok(CsvPage {
+        csv,
+        next_cursor: next_cursor.map(|file_id| ByteBuf::from(file_id.as_slice().to_vec())),
+    })
+}
+
+fn csv_row(metadata: &FileMetadata, uploader: &str) -> String {
+    let fields = [
+        csv_field(&metadata.filename),
+        metadata.file_size.to_string(),
+        csv_field(&metadata.content_type),
+        csv_field(uploader),
+        csv_field(&metadata.tags.join(";")),
+        utc_timestamp(metadata.creation_date),
+        utc_timestamp(metadata.last_modification_date),
+    ];
+    let mut row = fields.join(",");
+    row.push_str("\r\n");
+    row
+}
+
+/// Quotes a field if it contains a comma, quote or line break, doubling
+/// the quotes in it. Text a spreadsheet would evaluate as a formula gets a
+/// leading apostrophe, so that opening an export cannot run one.
+fn csv_field(text: &str) -> String {
+    let text = if text.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{text}")
+    } else {
+        text.to_string()
+    };
+    if text.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+/// Formats nanoseconds since the epoch as an ISO 8601 UTC timestamp.
+fn utc_timestamp(nanos: u64) -> String {
+    let seconds = nanos / NANOS_PER_SECOND;
+    let (days, time_of_day) = (seconds / SECONDS_PER_DAY, seconds % SECONDS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time_of_day / 3_600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+/// The Gregorian date `days` after 1970-01-01, after Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_plain_fields_as_is() {
+        assert_eq!(csv_field("report.pdf"), "report.pdf");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a-b+c=d@e"), "a-b+c=d@e");
+    }
+
+    #[test]
+    fn quotes_fields_with_commas_and_line_breaks() {
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(csv_field("line\r\nbreak"), "\"line\r\nbreak\"");
+    }
+
+    #[test]
+    fn doubles_quotes() {
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("\""), "\"\"\"\"");
+    }
+
+    #[test]
+    fn neutralizes_formulas() {
+        for formula in ["=1+1", "+1", "-1", "@SUM(A1)", "\tx"] {
+            assert_eq!(csv_field(formula), format!("'{formula}"));
+        }
+        assert_eq!(
+            csv_field("=HYPERLINK(\"x\",\"y\")"),
+            "\"'=HYPERLINK(\"\"x\"\",\"\"y\"\")\""
+        );
+        assert_eq!(csv_field("\rx"), "\"'\rx\"");
+    }
+}
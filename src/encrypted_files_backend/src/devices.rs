@@ -0,0 +1,204 @@
+//! Linking additional device principals to a user account.
+//!
+//! EncryptedMaps keys collections by principal, so a linked principal does
+//! not own the account's collections. Instead, linking grants it
+//! `ReadWriteManage` on every collection of the account's primary principal
+//! that has files or was created explicitly, and unlinking revokes those
+//! grants again. Collections the account creates, clones or receives later
+//! are granted to its linked principals as they appear.
+
+use crate::collections::owned_collection_names;
+use crate::users::caller_user;
+use crate::{or_trap, MapId, ENCRYPTED_MAPS, LINKED_PRINCIPALS, PRINCIPAL_TO_USERNAME, USERS};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_vetkeys::types::AccessRights;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const LINK_CODE_TTL_NS: u64 = 10 * 60 * 1_000_000_000;
+const LINK_CODE_LEN: usize = 8;
+// Without 0/O and 1/I so codes can be typed from another screen.
+const LINK_CODE_ALPHABET: &[u8; 32] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const MAX_LINKED_PRINCIPALS: usize = 8;
+
+struct PendingLink {
+    primary: Principal,
+    expires_at: u64,
+}
+
+thread_local! {
+    /// Outstanding link codes. Kept on the heap on purpose: they are only
+    /// valid for a few minutes, so losing them on upgrade is harmless.
+    static LINK_CODES: RefCell<BTreeMap<String, PendingLink>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LinkCode {
+    pub code: String,
+    pub expires_at: u64,
+}
+
+/// Creates a single-use code that links another principal to the caller's
+/// account when redeemed with link_principal within ten minutes. Must be
+/// called from the account's primary principal.
+#[update]
+async fn create_link_code() -> Result<LinkCode, String> {
+    let primary = ic_cdk::api::msg_caller();
+    if caller_user()?.principal != primary {
+        return Err("link codes can only be created from the primary principal".to_string());
+    }
+    let random_bytes = ic_cdk::management_canister::raw_rand()
+        .await
+        .map_err(|e| format!("failed to obtain randomness: {e}"))?;
+    let code: String = random_bytes
+        .iter()
+        .take(LINK_CODE_LEN)
+        .map(|byte| LINK_CODE_ALPHABET[*byte as usize % LINK_CODE_ALPHABET.len()] as char)
+        .collect();
+    let expires_at = ic_cdk::api::time() + LINK_CODE_TTL_NS;
+    LINK_CODES.with_borrow_mut(|link_codes| {
+        let time_now = ic_cdk::api::time();
+        link_codes.retain(|_, pending| pending.expires_at > time_now);
+        link_codes.insert(
+            code.clone(),
+            PendingLink {
+                primary,
+                expires_at,
+            },
+        );
+    });
+    Ok(LinkCode { code, expires_at })
+}
+
+/// Redeems a link code, making the caller a secondary principal of the
+/// account that created it. The caller must not be registered yet.
+#[update]
+fn link_principal(link_code: String) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    if caller == Principal::anonymous() {
+        return Err("anonymous principal cannot be linked".to_string());
+    }
+    if PRINCIPAL_TO_USERNAME.with_borrow(|p2u| p2u.contains_key(&caller)) {
+        return Err("caller is already registered".to_string());
+    }
+    let pending = LINK_CODES
+        .with_borrow_mut(|link_codes| link_codes.remove(&link_code.to_uppercase()))
+        .filter(|pending| pending.expires_at > ic_cdk::api::time())
+        .ok_or_else(|| "invalid or expired link code".to_string())?;
+    let primary = pending.primary;
+    let key = PRINCIPAL_TO_USERNAME
+        .with_borrow(|p2u| p2u.get(&primary))
+        .filter(|key| USERS.with_borrow(|users| users.contains_key(key)))
+        .ok_or_else(|| "the account to link to no longer exists".to_string())?;
+    if linked_principals(primary).len() >= MAX_LINKED_PRINCIPALS {
+        return Err(format!(
+            "at most {MAX_LINKED_PRINCIPALS} principals can be linked to an account"
+        ));
+    }
+
+    let owned_names = owned_collection_names(primary);
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        for map_name in owned_names {
+            encrypted_maps.set_user_rights(
+                primary,
+                (primary, map_name),
+                caller,
+                AccessRights::ReadWriteManage,
+            )?;
+        }
+        Ok::<(), String>(())
+    })?;
+    PRINCIPAL_TO_USERNAME.with_borrow_mut(|p2u| p2u.insert(caller, key));
+    LINKED_PRINCIPALS
+        .with_borrow_mut(|linked| linked.insert((primary, caller), ic_cdk::api::time()));
+    Ok(())
+}
+
+/// Unlinks a secondary principal and revokes the grants it received when it
+/// was linked. Allowed from the primary principal and from the linked
+/// principal itself.
+#[update]
+fn unlink_principal(principal: Principal) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let primary = caller_user()?.principal;
+    if caller != primary && caller != principal {
+        return Err("unauthorized".to_string());
+    }
+    if !LINKED_PRINCIPALS.with_borrow(|linked| linked.contains_key(&(primary, principal))) {
+        return Err(format!(
+            "principal {principal} is not linked to this account"
+        ));
+    }
+    revoke_linked_access(primary, principal)?;
+    LINKED_PRINCIPALS.with_borrow_mut(|linked| linked.remove(&(primary, principal)));
+    PRINCIPAL_TO_USERNAME.with_borrow_mut(|p2u| p2u.remove(&principal));
+    Ok(())
+}
+
+/// The principals linked to the caller's account and when they were linked.
+#[query]
+fn get_my_linked_principals() -> Result<Vec<(Principal, u64)>, String> {
+    let primary = caller_user()?.principal;
+    Ok(LINKED_PRINCIPALS.with_borrow(|linked| {
+        linked
+            .range((primary, Principal::management_canister())..)
+            .take_while(|((owner, _), _)| owner == &primary)
+            .map(|((_, principal), linked_at)| (principal, linked_at))
+            .collect()
+    }))
+}
+
+/// The secondary principals linked to `primary`.
+pub fn linked_principals(primary: Principal) -> Vec<Principal> {
+    LINKED_PRINCIPALS.with_borrow(|linked| {
+        linked
+            .range((primary, Principal::management_canister())..)
+            .take_while(|((owner, _), _)| owner == &primary)
+            .map(|((_, principal), _)| principal)
+            .collect()
+    })
+}
+
+/// Grants the principals linked to the owner of a new collection the access
+/// that linking gives them to the owner's other collections.
+pub fn grant_linked_access(map_id: MapId) {
+    let principals = linked_principals(map_id.0);
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        for principal in principals {
+            or_trap(
+                "granting linked principals access",
+                encrypted_maps.set_user_rights(
+                    map_id.0,
+                    map_id,
+                    principal,
+                    AccessRights::ReadWriteManage,
+                ),
+            );
+        }
+    });
+}
+
+/// Removes all links of `primary`, including their PRINCIPAL_TO_USERNAME
+/// entries. Grants are left to the caller, which is deleting the account.
+pub fn remove_linked_principals(primary: Principal) {
+    for principal in linked_principals(primary) {
+        LINKED_PRINCIPALS.with_borrow_mut(|linked| linked.remove(&(primary, principal)));
+        PRINCIPAL_TO_USERNAME.with_borrow_mut(|p2u| p2u.remove(&principal));
+    }
+}
+
+fn revoke_linked_access(primary: Principal, principal: Principal) -> Result<(), String> {
+    let owned_names = owned_collection_names(primary);
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        for map_name in owned_names {
+            encrypted_maps.remove_user(primary, (primary, map_name), principal)?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,75 @@
+//! Automatic deletion of files whose `expires_at` has passed. An index
+//! ordered by expiry time lets a periodic timer find due files without
+//! scanning FILE_METADATA; due files are moved to the trash like manual
+//! removals.
+
+use crate::files::remove_file;
+use crate::stats::{owns_files, record_collection_ownership};
+use crate::versions::FileKey;
+use crate::{FILE_EXPIRY, FILE_METADATA};
+use candid::Principal;
+use ic_stable_structures::storable::Blob;
+use std::time::Duration;
+
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Upper bound on files removed per timer tick, to stay well within the
+/// instruction limit. Any rest is picked up by the next tick.
+const MAX_EXPIRED_PER_TICK: usize = 100;
+
+/// Moves the index entry of a file from its `previous` to its `current`
+/// expiry time.
+pub fn update_expiry_index(file_key: FileKey, previous: Option<u64>, current: Option<u64>) {
+    if previous == current {
+        return;
+    }
+    FILE_EXPIRY.with_borrow_mut(|index| {
+        if let Some(expires_at) = previous {
+            index.remove(&(expires_at, file_key));
+        }
+        if let Some(expires_at) = current {
+            index.insert((expires_at, file_key), ());
+        }
+    });
+}
+
+/// Removes due files periodically. Timers do not survive upgrades, so this
+/// is called from both init and post_upgrade.
+pub fn start_expiry_timer() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_INTERVAL, remove_expired_files);
+}
+
+fn remove_expired_files() {
+    let now = ic_cdk::api::time();
+    let due: Vec<(u64, FileKey)> = FILE_EXPIRY.with_borrow(|index| {
+        index
+            .range(
+                ..(
+                    now,
+                    (
+                        Principal::management_canister(),
+                        Blob::default(),
+                        Blob::default(),
+                    ),
+                ),
+            )
+            .take(MAX_EXPIRED_PER_TICK)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for (expires_at, file_key) in due {
+        FILE_EXPIRY.with_borrow_mut(|index| index.remove(&(expires_at, file_key)));
+        let still_due = FILE_METADATA
+            .with_borrow(|file_metadata| file_metadata.get(&file_key))
+            .is_some_and(|metadata| metadata.expires_at == Some(expires_at));
+        if !still_due {
+            continue;
+        }
+        // The owner always has the rights to remove its files.
+        let collection_owner = file_key.0;
+        let had_files = owns_files(collection_owner);
+        if let Err(e) = remove_file(collection_owner, file_key) {
+            ic_cdk::println!("failed to remove expired file: {e}");
+        }
+        record_collection_ownership(had_files, owns_files(collection_owner));
+    }
+}
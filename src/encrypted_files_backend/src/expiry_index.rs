@@ -0,0 +1,172 @@
+//! One index of everything that ends at a set time: timed grants, share
+//! links, unanswered invitations and answered invitations due for removal.
+//! Each subsystem registers an entry, keyed by (expires_at, kind, id), when
+//! it creates or reschedules the object, and a single timer processes due
+//! entries in bounded batches. The handlers check the object's current
+//! state before acting, so entries outdated by later changes, or processed
+//! twice, do nothing. Uploads are single calls without a session, so there
+//! is no upload kind; one would be added here if resumable uploads are.
+
+use crate::grant_expiry::{register_grant_expiries, revoke_expired_grant};
+use crate::invitations::{
+    expire_invitation, register_invitation_expiries, remove_stale_invitation,
+};
+use crate::share_links::{register_share_link_expiries, remove_expired_link};
+use crate::{bytebuf_to_blob, MapId, EXPIRY_INDEX};
+use candid::Principal;
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const EXPIRY_INDEX_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Upper bound on entries processed per timer tick. Any rest is picked up
+/// by the next tick.
+const MAX_EXPIRIES_PER_TICK: usize = 100;
+
+/// (expires_at, kind, id).
+type ExpiryKey = (u64, (u8, Blob<32>));
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ExpiryTarget {
+    Grant {
+        owner: Principal,
+        /// Key of the collection.
+        collection_name: ByteBuf,
+        grantee: Principal,
+    },
+    ShareLink {
+        link_id: ByteBuf,
+    },
+    /// A pending invitation reaching its TTL.
+    Invitation {
+        invitation_id: u64,
+    },
+    /// An answered invitation leaving the inviter's history.
+    InvitationHistory {
+        invitation_id: u64,
+    },
+}
+
+impl Storable for ExpiryTarget {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl ExpiryTarget {
+    pub fn grant(map_id: MapId, grantee: Principal) -> Self {
+        ExpiryTarget::Grant {
+            owner: map_id.0,
+            collection_name: ByteBuf::from(map_id.1.as_slice().to_vec()),
+            grantee,
+        }
+    }
+
+    pub fn share_link(link_id: Blob<32>) -> Self {
+        ExpiryTarget::ShareLink {
+            link_id: ByteBuf::from(link_id.as_slice().to_vec()),
+        }
+    }
+
+    /// The (kind, id) part of the index key. Grants have no fixed-size id
+    /// of their own and use a hash of (owner, collection, grantee).
+    fn key(&self) -> (u8, Blob<32>) {
+        match self {
+            ExpiryTarget::Grant {
+                owner,
+                collection_name,
+                grantee,
+            } => {
+                let mut hasher = Sha256::new();
+                for part in [
+                    owner.as_slice(),
+                    collection_name.as_ref(),
+                    grantee.as_slice(),
+                ] {
+                    hasher.update((part.len() as u32).to_be_bytes());
+                    hasher.update(part);
+                }
+                (0, to_id(&hasher.finalize()))
+            }
+            ExpiryTarget::ShareLink { link_id } => (1, to_id(link_id.as_ref())),
+            ExpiryTarget::Invitation { invitation_id } => (2, to_id(&invitation_id.to_be_bytes())),
+            ExpiryTarget::InvitationHistory { invitation_id } => {
+                (3, to_id(&invitation_id.to_be_bytes()))
+            }
+        }
+    }
+}
+
+pub fn register_expiry(expires_at: u64, target: ExpiryTarget) {
+    EXPIRY_INDEX.with_borrow_mut(|index| index.insert((expires_at, target.key()), target));
+}
+
+pub fn unregister_expiry(expires_at: u64, target: &ExpiryTarget) {
+    EXPIRY_INDEX.with_borrow_mut(|index| index.remove(&(expires_at, target.key())));
+}
+
+/// Fills the index from the subsystems if it is empty, i.e. on the first
+/// upgrade after it was introduced.
+pub fn backfill_expiry_index() {
+    if !EXPIRY_INDEX.with_borrow(|index| index.is_empty()) {
+        return;
+    }
+    register_grant_expiries();
+    register_share_link_expiries();
+    register_invitation_expiries();
+}
+
+/// Processes due entries periodically. Timers do not survive upgrades, so
+/// this is called from both init and post_upgrade.
+pub fn start_expiry_index_timer() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_INDEX_INTERVAL, process_due_expiries);
+}
+
+fn process_due_expiries() {
+    let now = ic_cdk::api::time();
+    let due: Vec<(ExpiryKey, ExpiryTarget)> = EXPIRY_INDEX.with_borrow(|index| {
+        index
+            .range(..(now.saturating_add(1), (0, Blob::default())))
+            .take(MAX_EXPIRIES_PER_TICK)
+            .collect()
+    });
+    for (key, target) in due {
+        EXPIRY_INDEX.with_borrow_mut(|index| index.remove(&key));
+        match target {
+            ExpiryTarget::Grant {
+                owner,
+                collection_name,
+                grantee,
+            } => match bytebuf_to_blob(collection_name) {
+                Ok(name) => revoke_expired_grant((owner, name), grantee),
+                Err(e) => {
+                    ic_cdk::println!("invalid expiry entry: {e}");
+                }
+            },
+            ExpiryTarget::ShareLink { link_id } => match bytebuf_to_blob(link_id) {
+                Ok(link_id) => remove_expired_link(link_id),
+                Err(e) => {
+                    ic_cdk::println!("invalid expiry entry: {e}");
+                }
+            },
+            ExpiryTarget::Invitation { invitation_id } => expire_invitation(invitation_id),
+            ExpiryTarget::InvitationHistory { invitation_id } => {
+                remove_stale_invitation(invitation_id)
+            }
+        }
+    }
+}
+
+fn to_id(bytes: &[u8]) -> Blob<32> {
+    Blob::try_from(bytes).expect("expiry ids fit 32 bytes")
+}
@@ -0,0 +1,198 @@
+//! Per-file restrictions for single grantees of a shared collection, e.g. to
+//! keep a few files read-only for a team that may otherwise write. They are
+//! enforced by this canister's file endpoints only: EncryptedMaps still
+//! lets the grantee read and write the raw values as their collection
+//! grant allows, so overrides are a workflow aid, not a security boundary.
+
+use crate::collections::owned_collection_names;
+use crate::users::record_activity;
+use crate::versions::FileKey;
+use crate::{bytebuf_to_blob, collection_name_to_blob, MapId, FILE_METADATA, FILE_OVERRIDES};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const MAX_OVERRIDES_PER_FILE: usize = 100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileOverride {
+    /// The grantee may read the file but not replace, edit or remove it.
+    ReadOnly,
+    /// The file is left out of the grantee's listings and metadata queries,
+    /// and they cannot change it either.
+    Hidden,
+}
+
+impl Storable for FileOverride {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Restricts what `grantee` may do with a file of one of the caller's
+/// collections through this canister's endpoints, or lifts the restriction
+/// with `null`. EncryptedMaps itself still allows whatever the grantee's
+/// collection grant allows.
+#[update]
+fn set_file_override(
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    grantee: Principal,
+    file_override: Option<FileOverride>,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_name = collection_name_to_blob(collection_name)?;
+    if !owned_collection_names(caller).contains(&map_name) {
+        return Err("collection not found".to_string());
+    }
+    let file_key = (caller, map_name, bytebuf_to_blob(file_id)?);
+    if !FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+        return Err("file not found".to_string());
+    }
+    if grantee == caller {
+        return Err("the owner cannot be restricted".to_string());
+    }
+    match file_override {
+        Some(file_override) => {
+            let overrides = file_overrides(file_key);
+            if overrides.len() >= MAX_OVERRIDES_PER_FILE
+                && !overrides.iter().any(|(user, _)| user == &grantee)
+            {
+                return Err(format!(
+                    "at most {MAX_OVERRIDES_PER_FILE} overrides per file are allowed"
+                ));
+            }
+            FILE_OVERRIDES
+                .with_borrow_mut(|overrides| overrides.insert((file_key, grantee), file_override));
+        }
+        None => {
+            FILE_OVERRIDES.with_borrow_mut(|overrides| overrides.remove(&(file_key, grantee)));
+        }
+    }
+    Ok(())
+}
+
+/// The overrides on a file of one of the caller's collections.
+#[query]
+fn get_file_overrides(
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<Vec<(Principal, FileOverride)>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let file_key = (
+        caller,
+        collection_name_to_blob(collection_name)?,
+        bytebuf_to_blob(file_id)?,
+    );
+    Ok(file_overrides(file_key))
+}
+
+pub fn file_override(file_key: FileKey, user: Principal) -> Option<FileOverride> {
+    if user == file_key.0 {
+        return None;
+    }
+    FILE_OVERRIDES.with_borrow(|overrides| overrides.get(&(file_key, user)))
+}
+
+pub fn is_hidden_from(file_key: FileKey, user: Principal) -> bool {
+    file_override(file_key, user) == Some(FileOverride::Hidden)
+}
+
+/// Rejects changes to a file by a grantee it is restricted for. Hidden
+/// files are reported as missing.
+pub fn ensure_may_modify(file_key: FileKey, user: Principal) -> Result<(), String> {
+    match file_override(file_key, user) {
+        None => Ok(()),
+        Some(FileOverride::ReadOnly) => Err("the file is read-only for you".to_string()),
+        Some(FileOverride::Hidden) => Err("file not found".to_string()),
+    }
+}
+
+pub fn move_file_overrides(from: FileKey, to: FileKey) {
+    for (user, file_override) in file_overrides(from) {
+        FILE_OVERRIDES.with_borrow_mut(|overrides| {
+            overrides.remove(&(from, user));
+            overrides.insert((to, user), file_override);
+        });
+    }
+}
+
+/// Sets the overrides of a file again, e.g. when it is restored from the
+/// trash.
+pub fn restore_file_overrides(file_key: FileKey, restored: Vec<(Principal, FileOverride)>) {
+    for (user, file_override) in restored {
+        FILE_OVERRIDES
+            .with_borrow_mut(|overrides| overrides.insert((file_key, user), file_override));
+    }
+}
+
+pub fn remove_file_overrides(file_key: FileKey) {
+    for (user, _) in file_overrides(file_key) {
+        FILE_OVERRIDES.with_borrow_mut(|overrides| overrides.remove(&(file_key, user)));
+    }
+}
+
+/// Removes the overrides set for `user` on files of a collection, e.g. when
+/// they leave it.
+pub fn remove_user_file_overrides(map_id: MapId, user: Principal) {
+    let keys: Vec<(FileKey, Principal)> = FILE_OVERRIDES.with_borrow(|overrides| {
+        overrides
+            .range(
+                (
+                    (map_id.0, map_id.1, Blob::default()),
+                    Principal::management_canister(),
+                )..,
+            )
+            .take_while(|(((owner, name, _), _), _)| (*owner, *name) == map_id)
+            .filter(|((_, grantee), _)| grantee == &user)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    FILE_OVERRIDES.with_borrow_mut(|overrides| {
+        for key in keys {
+            overrides.remove(&key);
+        }
+    });
+}
+
+/// Removes the overrides on all files in collections of `owner`.
+pub fn remove_owned_file_overrides(owner: Principal) {
+    let keys: Vec<(FileKey, Principal)> = FILE_OVERRIDES.with_borrow(|overrides| {
+        overrides
+            .range(
+                (
+                    (owner, Blob::default(), Blob::default()),
+                    Principal::management_canister(),
+                )..,
+            )
+            .take_while(|(((file_owner, _, _), _), _)| file_owner == &owner)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    FILE_OVERRIDES.with_borrow_mut(|overrides| {
+        for key in keys {
+            overrides.remove(&key);
+        }
+    });
+}
+
+pub fn file_overrides(file_key: FileKey) -> Vec<(Principal, FileOverride)> {
+    FILE_OVERRIDES.with_borrow(|overrides| {
+        overrides
+            .range((file_key, Principal::management_canister())..)
+            .take_while(|((key, _), _)| key == &file_key)
+            .map(|((_, user), file_override)| (user, file_override))
+            .collect()
+    })
+}
@@ -0,0 +1,378 @@
+//! Sharing single files. A shared file is copied into a hidden collection
+//! of its owner that holds only that file, named by hashing the file's
+//! collection and id, and the recipients are granted rights on that hidden
+//! collection. Its vetkey differs from the original collection's, so the
+//! owner's client re-encrypts the copy and stores it with
+//! set_file_share_value. The copy is a snapshot: later uploads to the
+//! original file are not propagated, and removing the original removes the
+//! copy and marks the share stale.
+
+use crate::collections::{ensure_not_archived, owned_collection_names};
+use crate::files::{purge_file, store_metadata};
+use crate::users::{ensure_active, principal_for_username, record_activity};
+use crate::versions::FileKey;
+use crate::{
+    bytebuf_to_blob, collection_name_to_blob, ApiError, MapId, MapName, Username,
+    COLLECTION_METADATA, ENCRYPTED_MAPS, FILE_METADATA, FILE_SHARES,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{AccessRights, ByteBuf, EncryptedMapValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FileShare {
+    /// Key of the collection holding the original file.
+    pub collection_name: ByteBuf,
+    pub file_id: ByteBuf,
+    pub created_at: u64,
+    /// Whether the copy is still encrypted with the original collection's
+    /// key and waits for set_file_share_value.
+    pub needs_reencryption: bool,
+    /// The original file was removed, and with it the copy.
+    pub stale: bool,
+}
+
+impl Storable for FileShare {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileShareView {
+    /// The hidden collection holding the copy: fetch its vetkey and the
+    /// file with these as collection owner and name.
+    pub map_owner: Principal,
+    pub map_name: ByteBuf,
+    pub share: FileShare,
+    pub grants: Vec<(Principal, AccessRights)>,
+}
+
+/// Shares one file of one of the caller's collections with `username`,
+/// creating the hidden collection for the file on the first share.
+#[update]
+fn share_file_with_user(
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    username: Username,
+    access_rights: AccessRights,
+) -> Result<FileShareView, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    let user = principal_for_username(&username)?;
+    if user == caller {
+        return Err("cannot share a file with yourself".to_string().into());
+    }
+    let file_key = owned_file_key(caller, collection_name, file_id)?;
+    ensure_not_archived((file_key.0, file_key.1))?;
+    let share_id = (caller, file_share_name(file_key));
+    let share = match file_share(share_id).filter(|share| !share.stale) {
+        Some(share) => share,
+        None => create_file_share(file_key, share_id)?,
+    };
+    let grants = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        encrypted_maps.set_user_rights(caller, share_id, user, access_rights)?;
+        encrypted_maps.get_shared_user_access_for_map(caller, share_id)
+    })?;
+    Ok(file_share_view(share_id, share, grants))
+}
+
+/// Replaces the copy of a shared file with the owner's re-encryption of it
+/// under the key of the hidden collection.
+#[update]
+fn set_file_share_value(
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    value: EncryptedMapValue,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let file_key = owned_file_key(caller, collection_name, file_id)?;
+    let share_id = (caller, file_share_name(file_key));
+    let share = file_share(share_id)
+        .filter(|share| !share.stale)
+        .ok_or_else(|| "the file is not shared".to_string())?;
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .insert_encrypted_value(caller, share_id, file_key.2, value)
+    })?;
+    let share = FileShare {
+        needs_reencryption: false,
+        ..share
+    };
+    FILE_SHARES.with_borrow_mut(|file_shares| file_shares.insert(share_id, share));
+    Ok(())
+}
+
+/// The files the caller shared, with their recipients.
+#[query]
+fn list_my_file_shares() -> Result<Vec<FileShareView>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let shares: Vec<(MapId, FileShare)> = FILE_SHARES.with_borrow(|file_shares| {
+        file_shares
+            .range((caller, Blob::default())..)
+            .take_while(|((owner, _), _)| owner == &caller)
+            .collect()
+    });
+    ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_ref().unwrap();
+        shares
+            .into_iter()
+            .map(|(share_id, share)| {
+                let grants = encrypted_maps.get_shared_user_access_for_map(caller, share_id)?;
+                Ok(file_share_view(share_id, share, grants))
+            })
+            .collect()
+    })
+}
+
+/// The files others shared with the caller. Grants are only listed to the
+/// files' owners, so they are left empty here.
+#[query]
+fn list_files_shared_with_me() -> Vec<FileShareView> {
+    let map_ids = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_accessible_shared_map_names(ic_cdk::api::msg_caller())
+    });
+    map_ids
+        .into_iter()
+        .filter_map(|share_id| {
+            file_share(share_id).map(|share| file_share_view(share_id, share, Vec::new()))
+        })
+        .collect()
+}
+
+/// Revokes `username`'s access to one of the caller's shared files, or
+/// everybody's if `username` is `null`. The hidden collection is removed
+/// once nobody has access to it anymore.
+#[update]
+fn revoke_file_share(
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    username: Option<Username>,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    let file_key = (map_id.0, map_id.1, bytebuf_to_blob(file_id)?);
+    let share_id = (caller, file_share_name(file_key));
+    if file_share(share_id).is_none() {
+        return Err("the file is not shared".to_string());
+    }
+    let user = username
+        .map(|username| principal_for_username(&username))
+        .transpose()?;
+    let remaining = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        let mut remaining = 0;
+        for (grantee, _) in encrypted_maps.get_shared_user_access_for_map(caller, share_id)? {
+            if grantee == caller {
+                continue;
+            }
+            if user.map_or(true, |user| user == grantee) {
+                encrypted_maps.remove_user(caller, share_id, grantee)?;
+            } else {
+                remaining += 1;
+            }
+        }
+        Ok::<_, String>(remaining)
+    })?;
+    if remaining == 0 {
+        remove_file_share(share_id)?;
+    }
+    Ok(())
+}
+
+pub fn is_file_share(map_id: MapId) -> bool {
+    FILE_SHARES.with_borrow(|file_shares| file_shares.contains_key(&map_id))
+}
+
+/// Removes the copy of a file that was shared and marks its share stale,
+/// keeping the grants so that the owner sees whom it was shared with.
+/// Called whenever a file is removed.
+pub fn mark_file_share_stale(file_key: FileKey) -> Result<(), String> {
+    let share_id = (file_key.0, file_share_name(file_key));
+    let Some(share) = file_share(share_id).filter(|share| !share.stale) else {
+        return Ok(());
+    };
+    purge_file(share_id.0, (share_id.0, share_id.1, file_key.2))?;
+    let share = FileShare {
+        stale: true,
+        ..share
+    };
+    FILE_SHARES.with_borrow_mut(|file_shares| file_shares.insert(share_id, share));
+    Ok(())
+}
+
+/// Removes the shares of the files of a collection, with their grants.
+pub fn remove_collection_file_shares(map_id: MapId) -> Result<(), String> {
+    let share_ids: Vec<MapId> = FILE_SHARES.with_borrow(|file_shares| {
+        file_shares
+            .range((map_id.0, Blob::default())..)
+            .take_while(|((share_owner, _), _)| share_owner == &map_id.0)
+            .filter(|(_, share)| share.collection_name.as_ref() == map_id.1.as_slice())
+            .map(|(share_id, _)| share_id)
+            .collect()
+    });
+    for share_id in share_ids {
+        revoke_all(share_id)?;
+        remove_file_share(share_id)?;
+    }
+    Ok(())
+}
+
+/// Removes the shares of all files of `owner`, with their grants.
+pub fn remove_owned_file_shares(owner: Principal) -> Result<(), String> {
+    let share_ids: Vec<MapId> = FILE_SHARES.with_borrow(|file_shares| {
+        file_shares
+            .range((owner, Blob::default())..)
+            .take_while(|((share_owner, _), _)| share_owner == &owner)
+            .map(|(share_id, _)| share_id)
+            .collect()
+    });
+    for share_id in share_ids {
+        revoke_all(share_id)?;
+        remove_file_share(share_id)?;
+    }
+    Ok(())
+}
+
+fn revoke_all(share_id: MapId) -> Result<(), String> {
+    let owner = share_id.0;
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        for (grantee, _) in encrypted_maps.get_shared_user_access_for_map(owner, share_id)? {
+            if grantee != owner {
+                encrypted_maps.remove_user(owner, share_id, grantee)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Copies a file of its owner's collection into the hidden collection
+/// `share_id`, encrypted as it is. Recipients of a stale share of an
+/// earlier file with the same id lose their access.
+fn create_file_share(file_key: FileKey, share_id: MapId) -> Result<FileShare, String> {
+    let (owner, _, file_id) = file_key;
+    if COLLECTION_METADATA
+        .with_borrow(|collection_metadata| collection_metadata.contains_key(&share_id))
+    {
+        return Err("a collection uses the name reserved for sharing this file".to_string());
+    }
+    revoke_all(share_id)?;
+    let value = ENCRYPTED_MAPS
+        .with_borrow(|encrypted_maps| {
+            encrypted_maps.as_ref().unwrap().get_encrypted_value(
+                owner,
+                (file_key.0, file_key.1),
+                file_id,
+            )
+        })?
+        .ok_or_else(|| "file not found".to_string())?;
+    let metadata = FILE_METADATA
+        .with_borrow(|file_metadata| file_metadata.get(&file_key))
+        .ok_or_else(|| "file not found".to_string())?;
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .insert_encrypted_value(owner, share_id, file_id, value)
+    })?;
+    store_metadata((share_id.0, share_id.1, file_id), metadata);
+    let share = FileShare {
+        collection_name: ByteBuf::from(file_key.1.as_slice().to_vec()),
+        file_id: ByteBuf::from(file_id.as_slice().to_vec()),
+        created_at: ic_cdk::api::time(),
+        needs_reencryption: true,
+        stale: false,
+    };
+    FILE_SHARES.with_borrow_mut(|file_shares| file_shares.insert(share_id, share.clone()));
+    Ok(share)
+}
+
+/// Removes a hidden collection with its copy and record.
+fn remove_file_share(share_id: MapId) -> Result<(), String> {
+    let file_ids: Vec<_> = FILE_METADATA.with_borrow(|file_metadata| {
+        file_metadata
+            .range((share_id.0, share_id.1, Blob::default())..)
+            .take_while(|((owner, name, _), _)| (*owner, *name) == share_id)
+            .map(|((_, _, file_id), _)| file_id)
+            .collect()
+    });
+    for file_id in file_ids {
+        purge_file(share_id.0, (share_id.0, share_id.1, file_id))?;
+    }
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .remove_map_values(share_id.0, share_id)
+    })?;
+    FILE_SHARES.with_borrow_mut(|file_shares| file_shares.remove(&share_id));
+    Ok(())
+}
+
+/// The key of a file in one of the caller's own collections.
+fn owned_file_key(
+    caller: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<FileKey, String> {
+    let map_name = collection_name_to_blob(collection_name)?;
+    if !owned_collection_names(caller).contains(&map_name) {
+        return Err("collection not found".to_string());
+    }
+    let file_key = (caller, map_name, bytebuf_to_blob(file_id)?);
+    if !FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+        return Err("file not found".to_string());
+    }
+    Ok(file_key)
+}
+
+fn file_share(share_id: MapId) -> Option<FileShare> {
+    FILE_SHARES.with_borrow(|file_shares| file_shares.get(&share_id))
+}
+
+fn file_share_view(
+    share_id: MapId,
+    share: FileShare,
+    grants: Vec<(Principal, AccessRights)>,
+) -> FileShareView {
+    FileShareView {
+        map_owner: share_id.0,
+        map_name: ByteBuf::from(share_id.1.as_slice().to_vec()),
+        share,
+        grants: grants
+            .into_iter()
+            .filter(|(grantee, _)| *grantee != share_id.0)
+            .collect(),
+    }
+}
+
+/// Name of the hidden collection sharing a file.
+fn file_share_name(file_key: FileKey) -> MapName {
+    let (_, name, file_id) = file_key;
+    let mut hasher = Sha256::new();
+    hasher.update(b"file-share");
+    hasher.update(name.as_slice());
+    hasher.update(file_id.as_slice());
+    Blob::try_from(hasher.finalize().as_slice()).unwrap()
+}
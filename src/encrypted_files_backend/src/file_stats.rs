@@ -0,0 +1,148 @@
+//! Access counters per file, kept apart from FileMetadata so that counting
+//! does not rewrite the metadata record on every access. Queries cannot
+//! persist state, so accesses are only counted by update calls: clients
+//! report them with record_file_access after a successful decryption.
+
+use crate::users::record_activity;
+use crate::versions::FileKey;
+use crate::{
+    bytebuf_to_blob, caller_rights, collection_name_to_blob, FILE_ACCESSORS, FILE_METADATA,
+    FILE_STATS,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FileStats {
+    pub access_count: u64,
+    pub last_accessed_at: Option<u64>,
+    pub unique_accessors: u64,
+}
+
+impl Storable for FileStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Counts an access to a file by the caller, e.g. after the client
+/// decrypted it. Requires read access to the collection.
+#[update]
+fn record_file_access(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_key = (collection_owner, collection_name, bytebuf_to_blob(file_id)?);
+    caller_rights(caller, (collection_owner, collection_name))?;
+    if !FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+        return Err("file not found".to_string());
+    }
+    record_access(file_key, caller);
+    Ok(())
+}
+
+/// Access counters of a file. Only available to the collection's owner.
+#[query]
+fn get_file_stats(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<FileStats, String> {
+    if ic_cdk::api::msg_caller() != collection_owner {
+        return Err("unauthorized".to_string());
+    }
+    let file_key = (
+        collection_owner,
+        collection_name_to_blob(collection_name)?,
+        bytebuf_to_blob(file_id)?,
+    );
+    if !FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+        return Err("file not found".to_string());
+    }
+    Ok(file_stats(file_key))
+}
+
+/// Counts an access to a file by `accessor`. To be called by update
+/// endpoints that serve encrypted values.
+pub fn record_access(file_key: FileKey, accessor: Principal) {
+    let is_new_accessor = FILE_ACCESSORS
+        .with_borrow_mut(|accessors| accessors.insert((file_key, accessor), ()))
+        .is_none();
+    let mut stats = file_stats(file_key);
+    stats.access_count += 1;
+    stats.last_accessed_at = Some(ic_cdk::api::time());
+    if is_new_accessor {
+        stats.unique_accessors += 1;
+    }
+    FILE_STATS.with_borrow_mut(|file_stats| file_stats.insert(file_key, stats));
+}
+
+pub fn file_stats(file_key: FileKey) -> FileStats {
+    FILE_STATS
+        .with_borrow(|file_stats| file_stats.get(&file_key))
+        .unwrap_or_default()
+}
+
+/// Removes the counters of a file.
+/// Re-keys the counters of a file, e.g. when its collection is renamed.
+pub fn move_file_stats(from: FileKey, to: FileKey) {
+    if let Some(stats) = FILE_STATS.with_borrow_mut(|file_stats| file_stats.remove(&from)) {
+        FILE_STATS.with_borrow_mut(|file_stats| file_stats.insert(to, stats));
+    }
+    FILE_ACCESSORS.with_borrow_mut(|file_accessors| {
+        let accessors: Vec<Principal> = file_accessors
+            .range((from, Principal::management_canister())..)
+            .take_while(|((key, _), _)| key == &from)
+            .map(|((_, accessor), _)| accessor)
+            .collect();
+        for accessor in accessors {
+            file_accessors.remove(&(from, accessor));
+            file_accessors.insert((to, accessor), ());
+        }
+    });
+}
+
+pub fn remove_file_stats(file_key: FileKey) {
+    FILE_STATS.with_borrow_mut(|file_stats| file_stats.remove(&file_key));
+    let accessors: Vec<Principal> = FILE_ACCESSORS.with_borrow(|accessors| {
+        accessors
+            .range((file_key, Principal::management_canister())..)
+            .take_while(|((key, _), _)| key == &file_key)
+            .map(|((_, accessor), _)| accessor)
+            .collect()
+    });
+    FILE_ACCESSORS.with_borrow_mut(|file_accessors| {
+        for accessor in accessors {
+            file_accessors.remove(&(file_key, accessor));
+        }
+    });
+}
+
+/// Removes the counters of all files in collections of `owner`.
+pub fn remove_owned_file_stats(owner: Principal) {
+    let file_keys: Vec<FileKey> = FILE_STATS.with_borrow(|file_stats| {
+        file_stats
+            .range((owner, Blob::default(), Blob::default())..)
+            .take_while(|((file_owner, _, _), _)| file_owner == &owner)
+            .map(|(file_key, _)| file_key)
+            .collect()
+    });
+    for file_key in file_keys {
+        remove_file_stats(file_key);
+    }
+}
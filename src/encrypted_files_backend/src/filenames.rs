@@ -0,0 +1,105 @@
+//! Index from lowercased filename to file per collection, backing the
+//! `unique_filenames` collection setting. Filenames are hashed so that keys
+//! stay bounded whatever their length after lowercasing.
+
+use crate::collections::collection_settings;
+use crate::versions::FileKey;
+use crate::{MapId, MapKey, MapName, MapOwner, FILENAME_INDEX, FILE_METADATA};
+use candid::Principal;
+use ic_stable_structures::storable::Blob;
+use sha2::{Digest, Sha256};
+
+pub type FilenameHash = Blob<32>;
+
+/// Fails if the collection of `file_key` has `unique_filenames` set and
+/// another file in it is named `filename`, ignoring case.
+pub fn ensure_unique_filename(file_key: FileKey, filename: &str) -> Result<(), String> {
+    if filename_taken(file_key, filename) {
+        Err(format!(
+            "a file named \"{filename}\" already exists in this collection"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether the collection requires unique filenames and another file in it
+/// is already named `filename`.
+pub fn filename_taken(file_key: FileKey, filename: &str) -> bool {
+    let (owner, name, file_id) = file_key;
+    collection_settings((owner, name)).unique_filenames
+        && files_named((owner, name), filename)
+            .into_iter()
+            .any(|other| other != file_id)
+}
+
+/// The files of a collection named `filename`, ignoring case.
+pub fn files_named(map_id: MapId, filename: &str) -> Vec<MapKey> {
+    let prefix = (map_id.0, map_id.1, filename_hash(filename));
+    FILENAME_INDEX.with_borrow(|index| {
+        index
+            .range((prefix, MapKey::default())..)
+            .take_while(|((key_prefix, _), _)| key_prefix == &prefix)
+            .map(|((_, file_id), _)| file_id)
+            .collect()
+    })
+}
+
+/// Moves the index entry of a file from its `previous` to its `current`
+/// filename.
+pub fn update_filename_index(file_key: FileKey, previous: Option<&str>, current: Option<&str>) {
+    if previous == current {
+        return;
+    }
+    let (owner, name, file_id) = file_key;
+    FILENAME_INDEX.with_borrow_mut(|index| {
+        if let Some(filename) = previous {
+            index.remove(&((owner, name, filename_hash(filename)), file_id));
+        }
+        if let Some(filename) = current {
+            index.insert(((owner, name, filename_hash(filename)), file_id), ());
+        }
+    });
+}
+
+/// Removes the index entries of all collections of `owner`.
+pub fn remove_owned_filenames(owner: Principal) {
+    FILENAME_INDEX.with_borrow_mut(|index| {
+        let keys: Vec<_> = index
+            .range(
+                (
+                    (owner, MapName::default(), FilenameHash::default()),
+                    MapKey::default(),
+                )..,
+            )
+            .take_while(|(((key_owner, _, _), _), _)| key_owner == &owner)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+}
+
+/// Fills the index from FILE_METADATA if it is empty, i.e. on the first
+/// upgrade after it was introduced.
+pub fn backfill_filename_index() {
+    if !FILENAME_INDEX.with_borrow(|index| index.is_empty()) {
+        return;
+    }
+    let entries: Vec<((MapOwner, MapName, MapKey), String)> =
+        FILE_METADATA.with_borrow(|file_metadata| {
+            file_metadata
+                .iter()
+                .map(|(file_key, metadata)| (file_key, metadata.filename))
+                .collect()
+        });
+    for (file_key, filename) in entries {
+        update_filename_index(file_key, None, Some(&filename));
+    }
+}
+
+fn filename_hash(filename: &str) -> FilenameHash {
+    Blob::try_from(Sha256::digest(filename.to_lowercase().as_bytes()).as_slice())
+        .expect("SHA-256 digest fits 32 bytes")
+}
@@ -0,0 +1,1698 @@
+use crate::checksums::{find_duplicate, update_checksum_index};
+use crate::collection_activity::{log_activity, ActivityAction};
+use crate::collection_stats::{record_upload_time, update_collection_stats};
+use crate::collections::{
+    ensure_collection_metadata, ensure_collection_name, ensure_not_archived, ensure_policy_allows,
+};
+use crate::comments::{move_file_comments, remove_file_comments};
+use crate::expiry::update_expiry_index;
+use crate::file_overrides::{
+    ensure_may_modify, file_override, file_overrides, is_hidden_from, move_file_overrides,
+    remove_file_overrides,
+};
+use crate::file_shares::mark_file_share_stale;
+use crate::file_stats::{file_stats, move_file_stats, remove_file_stats};
+use crate::filenames::{ensure_unique_filename, filename_taken, update_filename_index};
+use crate::ordering::{collection_order, move_sort_index, remove_sort_index, sort_index};
+use crate::read_audit::{ensure_unaudited_query, log_read, ReadMode};
+use crate::recent::record_upload;
+use crate::stars::{move_file_stars, remove_file_stars};
+use crate::stats::{owns_files, record_collection_ownership};
+use crate::thumbnails::{copy_thumbnail, move_thumbnail, remove_thumbnail, thumbnail};
+use crate::trash::move_to_trash;
+use crate::users::{ensure_active, record_activity, user_for_principal};
+use crate::versions::{archive_version, move_versions, remove_versions, FileKey};
+use crate::{
+    bytebuf_to_blob, caller_rights, collection_name_to_blob, ensure_can_write, ApiError, MapId,
+    MapKey, MapName, MapOwner, Username, ENCRYPTED_MAPS, FILE_METADATA,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{storable::Bound, Storable};
+use ic_vetkeys::types::{ByteBuf, EncryptedMapValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound as RangeBound;
+
+const MAX_FILENAME_LEN: usize = 255;
+const DEFAULT_FILES_LIMIT: usize = 1_000;
+const MAX_FILES_PAGE_LIMIT: u32 = 100;
+/// Upper bound on metadata entries inspected per unsorted get_files_page
+/// call, so that selective filters cannot exhaust the instruction limit.
+const MAX_FILES_PAGE_SCAN: usize = 5_000;
+const MAX_FILE_SEARCH_LIMIT: u64 = 100;
+/// Upper bound on metadata entries inspected per file search call, to stay
+/// well within the instruction limit.
+const MAX_FILE_SEARCH_SCAN: usize = 5_000;
+const MIN_NAME_SEARCH_QUERY_LEN: usize = 2;
+const MAX_COLLECTION_TAGS: usize = 500;
+const MAX_BULK_FILES: usize = 100;
+const MAX_METADATA_BATCH: usize = 200;
+const MAX_TAGS_PER_FILE: usize = 50;
+/// Upper bound on files inspected per rename_tag call.
+const MAX_TAG_RENAME_SCAN: usize = 1_000;
+const MAX_CUSTOM_ENTRIES: usize = 20;
+const MAX_CUSTOM_KEY_LEN: usize = 64;
+const MAX_CUSTOM_VALUE_LEN: usize = 512;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FileMetadata {
+    pub filename: String,
+    pub content_type: String,
+    pub file_size: u64,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub uploaded_by: Principal,
+    pub creation_date: u64,
+    pub last_modification_date: u64,
+    pub number_of_modifications: u64,
+    pub last_modified_principal: Principal,
+    /// SHA-256 of the plaintext, computed by the uploading client.
+    #[serde(default)]
+    pub checksum: Option<[u8; 32]>,
+    /// Structured client data such as EXIF extracts.
+    #[serde(default)]
+    pub custom: Vec<(String, String)>,
+    /// When the file is deleted automatically; `None` keeps it.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Incremented on every change to the file, for optimistic concurrency
+    /// control: edits pass the revision they are based on.
+    #[serde(default)]
+    pub revision: u64,
+}
+
+/// The client-supplied part of [`FileMetadata`].
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileMetadataInput {
+    pub filename: String,
+    pub content_type: String,
+    pub file_size: u64,
+    pub description: String,
+    pub tags: Vec<String>,
+    /// Replaces the file's custom entries; `null` keeps them.
+    pub custom: Option<Vec<(String, String)>>,
+    /// Replaces the file's expiry time; `null` means no expiry.
+    pub expires_at: Option<u64>,
+}
+
+impl FileMetadataInput {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tags.len() > MAX_TAGS_PER_FILE {
+            return Err(format!("at most {MAX_TAGS_PER_FILE} tags per file"));
+        }
+        if self
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= ic_cdk::api::time())
+        {
+            return Err("expires_at must be in the future".to_string());
+        }
+        let Some(custom) = &self.custom else {
+            return Ok(());
+        };
+        if custom.len() > MAX_CUSTOM_ENTRIES {
+            return Err(format!(
+                "at most {MAX_CUSTOM_ENTRIES} custom entries are allowed"
+            ));
+        }
+        for (key, value) in custom {
+            if key.is_empty() || key.len() > MAX_CUSTOM_KEY_LEN {
+                return Err(format!(
+                    "custom keys must be 1 to {MAX_CUSTOM_KEY_LEN} bytes"
+                ));
+            }
+            if value.len() > MAX_CUSTOM_VALUE_LEN {
+                return Err(format!(
+                    "custom values must be at most {MAX_CUSTOM_VALUE_LEN} bytes"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UploadResult {
+    /// The content and metadata the upload replaced, if any.
+    pub previous: Option<(EncryptedMapValue, FileMetadata)>,
+    /// Another file in the collection with the same checksum, so the UI can
+    /// warn about the duplicate.
+    pub duplicate_of: Option<ByteBuf>,
+}
+
+/// FileMetadata as returned by listings, with the uploader's names
+/// resolved so clients need no extra lookups.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileMetadataView {
+    pub metadata: FileMetadata,
+    /// Username of `metadata.uploaded_by`, if registered.
+    pub uploaded_by_username: Option<Username>,
+    pub uploaded_by_display_name: Option<String>,
+}
+
+/// Resolves uploaders to their names for a single call, looking each
+/// principal up only once.
+#[derive(Default)]
+struct UploaderNames(BTreeMap<Principal, (Option<Username>, Option<String>)>);
+
+impl UploaderNames {
+    fn view(&mut self, metadata: FileMetadata) -> FileMetadataView {
+        let (uploaded_by_username, uploaded_by_display_name) = self
+            .0
+            .entry(metadata.uploaded_by)
+            .or_insert_with(|| match user_for_principal(&metadata.uploaded_by) {
+                Some(user) => (Some(user.username), user.display_name),
+                None => (None, None),
+            })
+            .clone();
+        FileMetadataView {
+            metadata,
+            uploaded_by_username,
+            uploaded_by_display_name,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileEntry {
+    pub file_id: ByteBuf,
+    pub value: EncryptedMapValue,
+    pub metadata: FileMetadataView,
+    /// When the file was last reported read; `null` if never.
+    pub last_accessed_at: Option<u64>,
+    /// Position set with set_file_order, if any.
+    pub sort_index: Option<u32>,
+    /// Encrypted thumbnail set with set_file_thumbnail, if requested.
+    pub thumbnail: Option<ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CollectionTags {
+    /// Most used first, ties by name.
+    pub tags: Vec<(String, u32)>,
+    /// Set if tags were left out, either beyond [`MAX_COLLECTION_TAGS`] or
+    /// because the collection has more files than one call inspects.
+    pub truncated: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TagRename {
+    pub files_updated: u32,
+    /// Pass as `start_after` to continue; `null` once the collection is done.
+    pub next_cursor: Option<ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StaleFile {
+    pub file_id: ByteBuf,
+    pub metadata: FileMetadata,
+    pub last_accessed_at: Option<u64>,
+}
+
+/// Restricts get_files_page to matching files. Unset fields match all.
+/// Timestamps are in nanoseconds and the bounds are exclusive.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct FileListFilter {
+    pub uploaded_by: Option<Principal>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+    pub modified_after: Option<u64>,
+    pub modified_before: Option<u64>,
+    /// E.g. "image/", compared ignoring case.
+    pub content_type_prefix: Option<String>,
+}
+
+impl FileListFilter {
+    fn matches(&self, metadata: &FileMetadata) -> bool {
+        self.uploaded_by
+            .map_or(true, |uploaded_by| metadata.uploaded_by == uploaded_by)
+            && self
+                .created_after
+                .map_or(true, |time| metadata.creation_date > time)
+            && self
+                .created_before
+                .map_or(true, |time| metadata.creation_date < time)
+            && self
+                .modified_after
+                .map_or(true, |time| metadata.last_modification_date > time)
+            && self
+                .modified_before
+                .map_or(true, |time| metadata.last_modification_date < time)
+            && self.content_type_prefix.as_deref().map_or(true, |prefix| {
+                has_content_type_prefix(&metadata.content_type, prefix)
+            })
+    }
+}
+
+/// Order of get_files_page results. Ties are broken by file key.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortField {
+    /// Filename, ignoring case.
+    Name,
+    SizeAsc,
+    SizeDesc,
+    /// Newest first.
+    CreatedAt,
+    /// Most recently modified first.
+    LastModified,
+    /// By the sort index set with set_file_order; files without one last.
+    Manual,
+}
+
+impl SortField {
+    /// `order` holds the sort indices of the collection for `Manual`.
+    fn compare(
+        &self,
+        a: &(MapKey, FileMetadata),
+        b: &(MapKey, FileMetadata),
+        order: &BTreeMap<MapKey, u32>,
+    ) -> Ordering {
+        let (a_key, a) = a;
+        let (b_key, b) = b;
+        match self {
+            SortField::Name => a.filename.to_lowercase().cmp(&b.filename.to_lowercase()),
+            SortField::SizeAsc => a.file_size.cmp(&b.file_size),
+            SortField::SizeDesc => b.file_size.cmp(&a.file_size),
+            SortField::CreatedAt => b.creation_date.cmp(&a.creation_date),
+            SortField::LastModified => b.last_modification_date.cmp(&a.last_modification_date),
+            SortField::Manual => {
+                let a_index = order.get(a_key);
+                let b_index = order.get(b_key);
+                (a_index.is_none(), a_index).cmp(&(b_index.is_none(), b_index))
+            }
+        }
+        .then_with(|| a_key.cmp(b_key))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FilesPage {
+    pub files: Vec<FileEntry>,
+    /// Pass as `start_after` to get the next page; `null` on the last page.
+    /// May be set even if `files` is short or empty when a filtered scan
+    /// stopped early.
+    pub next_cursor: Option<ByteBuf>,
+    pub served_by: ReadMode,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ServedFileValue {
+    pub value: EncryptedMapValue,
+    pub served_by: ReadMode,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileSearchHit {
+    pub collection_owner: Principal,
+    pub collection_name: ByteBuf,
+    pub file_id: ByteBuf,
+    pub metadata: FileMetadata,
+}
+
+/// Position of the last file inspected by a search.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileCursor {
+    pub collection_owner: Principal,
+    pub collection_name: ByteBuf,
+    pub file_id: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileSearchPage {
+    pub hits: Vec<FileSearchHit>,
+    /// Pass as `cursor` to continue; `null` once all collections are done.
+    pub next_cursor: Option<FileCursor>,
+}
+
+impl FileMetadata {
+    pub fn new(caller: Principal, input: FileMetadataInput) -> Self {
+        let time_now = ic_cdk::api::time();
+        Self {
+            filename: input.filename,
+            content_type: input.content_type,
+            file_size: input.file_size,
+            description: input.description,
+            tags: input.tags,
+            custom: input.custom.unwrap_or_default(),
+            expires_at: input.expires_at,
+            uploaded_by: caller,
+            creation_date: time_now,
+            last_modification_date: time_now,
+            number_of_modifications: 0,
+            last_modified_principal: caller,
+            checksum: None,
+            revision: 0,
+        }
+    }
+
+    /// Whether the file has expired but not been removed yet.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn update(self, caller: Principal, input: FileMetadataInput) -> Self {
+        let modified = self.modified_by(caller);
+        Self {
+            filename: input.filename,
+            content_type: input.content_type,
+            file_size: input.file_size,
+            description: input.description,
+            tags: input.tags,
+            custom: input.custom.unwrap_or(modified.custom),
+            expires_at: input.expires_at,
+            ..modified
+        }
+    }
+
+    /// Records a change by `caller`: bumps the revision and the modification
+    /// count and date. Every edit of stored metadata goes through this.
+    pub fn modified_by(self, caller: Principal) -> Self {
+        Self {
+            last_modification_date: ic_cdk::api::time(),
+            number_of_modifications: self.number_of_modifications + 1,
+            last_modified_principal: caller,
+            revision: self.revision + 1,
+            ..self
+        }
+    }
+
+    /// Fails with `Conflict` unless the file is at `expected_revision`.
+    pub fn ensure_revision(&self, expected_revision: u64) -> Result<(), ApiError> {
+        if self.revision == expected_revision {
+            Ok(())
+        } else {
+            Err(ApiError::Conflict {
+                current: Box::new(self.clone()),
+            })
+        }
+    }
+}
+
+impl Storable for FileMetadata {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// All files of a collection with their encrypted values, up to
+/// [`DEFAULT_FILES_LIMIT`]. Responses get large quickly; prefer
+/// get_files_page, which this delegates to. Fails for grantees of
+/// collections whose reads are audited, who have to call
+/// get_files_in_collection_with_metadata_update instead.
+#[query]
+fn get_files_in_collection_with_metadata(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<Vec<(ByteBuf, EncryptedMapValue, FileMetadataView)>, String> {
+    files_with_metadata(collection_owner, collection_name, ReadMode::Query)
+}
+
+/// get_files_in_collection_with_metadata as an update call, which logs the
+/// read if the collection's reads are audited.
+#[update]
+fn get_files_in_collection_with_metadata_update(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<Vec<(ByteBuf, EncryptedMapValue, FileMetadataView)>, String> {
+    record_activity(ic_cdk::api::msg_caller());
+    files_with_metadata(collection_owner, collection_name, ReadMode::Update)
+}
+
+fn files_with_metadata(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    served_by: ReadMode,
+) -> Result<Vec<(ByteBuf, EncryptedMapValue, FileMetadataView)>, String> {
+    let page = files_page(
+        collection_owner,
+        collection_name,
+        None,
+        DEFAULT_FILES_LIMIT,
+        None,
+        FileListFilter::default(),
+        false,
+        served_by,
+    )?;
+    Ok(page
+        .files
+        .into_iter()
+        .map(|file| (file.file_id, file.value, file.metadata))
+        .collect())
+}
+
+/// Metadata of all files in a collection, without the encrypted values.
+/// The caller needs at least read access to the collection.
+#[query]
+fn list_collection_metadata(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<Vec<(ByteBuf, FileMetadataView)>, String> {
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    let caller = ic_cdk::api::msg_caller();
+    caller_rights(caller, map_id)?;
+    let start = RangeBound::Included((collection_owner, collection_name, Blob::default()));
+    let (entries, _) = collection_metadata(
+        map_id,
+        caller,
+        start,
+        usize::MAX,
+        usize::MAX,
+        &FileListFilter::default(),
+    );
+    let mut uploader_names = UploaderNames::default();
+    Ok(entries
+        .into_iter()
+        .map(|(file_id, metadata)| {
+            (
+                ByteBuf::from(file_id.as_slice().to_vec()),
+                uploader_names.view(metadata),
+            )
+        })
+        .collect())
+}
+
+/// Files neither read nor written within the last `older_than_ns`
+/// nanoseconds, for cleaning up inactive files. Reads are those reported
+/// through record_file_access. Only available to the collection's owner.
+#[query]
+fn list_stale_files(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    older_than_ns: u64,
+) -> Result<Vec<StaleFile>, String> {
+    if ic_cdk::api::msg_caller() != collection_owner {
+        return Err("unauthorized".to_string());
+    }
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    let cutoff = ic_cdk::api::time().saturating_sub(older_than_ns);
+    let start = RangeBound::Included((collection_owner, collection_name, Blob::default()));
+    let (entries, _) = collection_metadata(
+        map_id,
+        collection_owner,
+        start,
+        usize::MAX,
+        usize::MAX,
+        &FileListFilter::default(),
+    );
+    Ok(entries
+        .into_iter()
+        .filter_map(|(file_id, metadata)| {
+            let last_accessed_at =
+                file_stats((collection_owner, collection_name, file_id)).last_accessed_at;
+            let last_active = last_accessed_at
+                .unwrap_or(0)
+                .max(metadata.last_modification_date);
+            (last_active < cutoff).then(|| StaleFile {
+                file_id: ByteBuf::from(file_id.as_slice().to_vec()),
+                metadata,
+                last_accessed_at,
+            })
+        })
+        .collect())
+}
+
+/// A page of files matching `filter`, starting after the file
+/// `start_after`. Each entry carries the uploader's names. Without `sort`,
+/// files come in file key order and at most [`MAX_FILES_PAGE_SCAN`] entries
+/// are inspected per call, so a filtered page can come back short or empty
+/// with a `next_cursor` to continue from. With `sort`, the metadata of the
+/// whole collection is read and sorted on every call, i.e. O(n log n) in the
+/// collection size, which is fine for collections of a few thousand files.
+/// Fails for grantees of collections whose reads are audited, who have to
+/// call get_files_page_update instead.
+#[query]
+fn get_files_page(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    start_after: Option<ByteBuf>,
+    limit: u32,
+    sort: Option<SortField>,
+    filter: Option<FileListFilter>,
+    include_thumbnails: bool,
+) -> Result<FilesPage, String> {
+    files_page(
+        collection_owner,
+        collection_name,
+        start_after,
+        limit.clamp(1, MAX_FILES_PAGE_LIMIT) as usize,
+        sort,
+        filter.unwrap_or_default(),
+        include_thumbnails,
+        ReadMode::Query,
+    )
+}
+
+/// get_files_page as an update call, which logs the read if the
+/// collection's reads are audited.
+#[update]
+fn get_files_page_update(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    start_after: Option<ByteBuf>,
+    limit: u32,
+    sort: Option<SortField>,
+    filter: Option<FileListFilter>,
+    include_thumbnails: bool,
+) -> Result<FilesPage, String> {
+    record_activity(ic_cdk::api::msg_caller());
+    files_page(
+        collection_owner,
+        collection_name,
+        start_after,
+        limit.clamp(1, MAX_FILES_PAGE_LIMIT) as usize,
+        sort,
+        filter.unwrap_or_default(),
+        include_thumbnails,
+        ReadMode::Update,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn files_page(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    start_after: Option<ByteBuf>,
+    limit: usize,
+    sort: Option<SortField>,
+    filter: FileListFilter,
+    include_thumbnails: bool,
+    served_by: ReadMode,
+) -> Result<FilesPage, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    caller_rights(caller, map_id)?;
+    if served_by == ReadMode::Query {
+        ensure_unaudited_query(map_id, caller)?;
+    }
+    let start_after = start_after.map(bytebuf_to_blob).transpose()?;
+    // One extra entry tells whether there is a next page.
+    let (mut entries, scan_stopped_at) = match sort {
+        None => {
+            let start = match start_after {
+                Some(file_id) => RangeBound::Excluded((collection_owner, collection_name, file_id)),
+                None => RangeBound::Included((collection_owner, collection_name, Blob::default())),
+            };
+            collection_metadata(
+                map_id,
+                caller,
+                start,
+                limit + 1,
+                MAX_FILES_PAGE_SCAN,
+                &filter,
+            )
+        }
+        Some(sort) => {
+            let start = RangeBound::Included((collection_owner, collection_name, Blob::default()));
+            let (mut all, _) =
+                collection_metadata(map_id, caller, start, usize::MAX, usize::MAX, &filter);
+            let order = match sort {
+                SortField::Manual => collection_order(map_id),
+                _ => BTreeMap::new(),
+            };
+            all.sort_by(|a, b| sort.compare(a, b, &order));
+            let skip = match start_after {
+                Some(file_id) => {
+                    all.iter()
+                        .position(|(key, _)| key == &file_id)
+                        .ok_or_else(|| "start_after file not found".to_string())?
+                        + 1
+                }
+                None => 0,
+            };
+            (all.into_iter().skip(skip).take(limit + 1).collect(), None)
+        }
+    };
+    let has_more = entries.len() > limit;
+    entries.truncate(limit);
+
+    let mut uploader_names = UploaderNames::default();
+    let files = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_ref().unwrap();
+        entries
+            .into_iter()
+            .filter_map(|(file_id, metadata)| {
+                encrypted_maps
+                    .get_encrypted_value(caller, map_id, file_id)
+                    .transpose()
+                    .map(|value| {
+                        if served_by == ReadMode::Update && value.is_ok() {
+                            log_read(map_id, caller, file_id);
+                        }
+                        value.map(|value| FileEntry {
+                            file_id: ByteBuf::from(file_id.as_slice().to_vec()),
+                            value,
+                            last_accessed_at: file_stats((
+                                collection_owner,
+                                collection_name,
+                                file_id,
+                            ))
+                            .last_accessed_at,
+                            sort_index: sort_index((collection_owner, collection_name, file_id)),
+                            thumbnail: include_thumbnails
+                                .then(|| thumbnail((collection_owner, collection_name, file_id)))
+                                .flatten(),
+                            metadata: uploader_names.view(metadata),
+                        })
+                    })
+            })
+            .collect::<Result<Vec<_>, String>>()
+    })?;
+    let next_cursor = if has_more {
+        files.last().map(|file| file.file_id.clone())
+    } else {
+        scan_stopped_at.map(|file_id| ByteBuf::from(file_id.as_slice().to_vec()))
+    };
+    Ok(FilesPage {
+        files,
+        next_cursor,
+        served_by,
+    })
+}
+
+/// The encrypted value of a single file. Fails for grantees of collections
+/// whose reads are audited, who have to call get_file_value_update instead.
+#[query]
+fn get_file_value(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<ServedFileValue, String> {
+    file_value(collection_owner, collection_name, file_id, ReadMode::Query)
+}
+
+/// get_file_value as an update call, which logs the read if the
+/// collection's reads are audited.
+#[update]
+fn get_file_value_update(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<ServedFileValue, String> {
+    record_activity(ic_cdk::api::msg_caller());
+    file_value(collection_owner, collection_name, file_id, ReadMode::Update)
+}
+
+fn file_value(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    served_by: ReadMode,
+) -> Result<ServedFileValue, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    let file_key = (map_id.0, map_id.1, bytebuf_to_blob(file_id)?);
+    caller_rights(caller, map_id)?;
+    if served_by == ReadMode::Query {
+        ensure_unaudited_query(map_id, caller)?;
+    }
+    let available = FILE_METADATA
+        .with_borrow(|file_metadata| file_metadata.get(&file_key))
+        .is_some_and(|metadata| !metadata.is_expired(ic_cdk::api::time()));
+    if !available || is_hidden_from(file_key, caller) {
+        return Err("file not found".to_string());
+    }
+    let value = ENCRYPTED_MAPS
+        .with_borrow(|encrypted_maps| {
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_encrypted_value(caller, map_id, file_key.2)
+        })?
+        .ok_or_else(|| "file not found".to_string())?;
+    if served_by == ReadMode::Update {
+        log_read(map_id, caller, file_key.2);
+    }
+    Ok(ServedFileValue { value, served_by })
+}
+
+/// Up to `limit` metadata entries of the collection `map_id` that match
+/// `filter`, have not expired and are not hidden from `viewer`, in file key
+/// order, starting at `start`.
+/// Gives up after inspecting `scan_limit` entries; if that happens before
+/// the end of the collection, the last inspected key is returned alongside.
+fn collection_metadata(
+    map_id: MapId,
+    viewer: Principal,
+    start: RangeBound<(MapOwner, MapName, MapKey)>,
+    limit: usize,
+    scan_limit: usize,
+    filter: &FileListFilter,
+) -> (Vec<(MapKey, FileMetadata)>, Option<MapKey>) {
+    let now = ic_cdk::api::time();
+    FILE_METADATA.with_borrow(|file_metadata| {
+        let mut matches = Vec::new();
+        let mut last_scanned = None;
+        for (scanned, ((owner, name, file_id), metadata)) in file_metadata
+            .range((start, RangeBound::Unbounded))
+            .enumerate()
+        {
+            if (owner, name) != map_id || matches.len() == limit {
+                break;
+            }
+            if scanned == scan_limit {
+                return (matches, last_scanned);
+            }
+            last_scanned = Some(file_id);
+            if filter.matches(&metadata)
+                && !metadata.is_expired(now)
+                && !is_hidden_from((owner, name, file_id), viewer)
+            {
+                matches.push((file_id, metadata));
+            }
+        }
+        (matches, None)
+    })
+}
+
+/// Metadata of a single file. Fails with "unauthorized" if the caller has no
+/// access to the collection and with "file not found" if it has access but
+/// the file does not exist or has expired.
+#[query]
+fn get_file_metadata(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<FileMetadata, String> {
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_id = bytebuf_to_blob(file_id)?;
+    let caller = ic_cdk::api::msg_caller();
+    caller_rights(caller, (collection_owner, collection_name))?;
+    let file_key = (collection_owner, collection_name, file_id);
+    FILE_METADATA
+        .with_borrow(|file_metadata| file_metadata.get(&file_key))
+        .filter(|metadata| {
+            !metadata.is_expired(ic_cdk::api::time()) && !is_hidden_from(file_key, caller)
+        })
+        .ok_or_else(|| "file not found".to_string())
+}
+
+/// Metadata of the given files in request order, `None` for ids that do not
+/// exist or have expired. At most [`MAX_METADATA_BATCH`] ids per call. Requires read access
+/// to the collection.
+#[query]
+fn get_files_metadata_batch(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_ids: Vec<ByteBuf>,
+) -> Result<Vec<Option<FileMetadata>>, String> {
+    if file_ids.len() > MAX_METADATA_BATCH {
+        return Err(format!("at most {MAX_METADATA_BATCH} files per call"));
+    }
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_ids = file_ids
+        .into_iter()
+        .map(bytebuf_to_blob)
+        .collect::<Result<Vec<_>, _>>()?;
+    let caller = ic_cdk::api::msg_caller();
+    caller_rights(caller, (collection_owner, collection_name))?;
+    let now = ic_cdk::api::time();
+    Ok(FILE_METADATA.with_borrow(|file_metadata| {
+        file_ids
+            .into_iter()
+            .map(|file_id| {
+                let file_key = (collection_owner, collection_name, file_id);
+                file_metadata.get(&file_key).filter(|metadata| {
+                    !metadata.is_expired(now) && !is_hidden_from(file_key, caller)
+                })
+            })
+            .collect()
+    }))
+}
+
+/// The distinct tags (trimmed and lowercased) of a collection and how many
+/// files carry each. Inspects at most [`MAX_FILE_SEARCH_SCAN`] files.
+/// Requires read access to the collection.
+#[query]
+fn get_collection_tags(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<CollectionTags, String> {
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    let caller = ic_cdk::api::msg_caller();
+    caller_rights(caller, map_id)?;
+    let start = RangeBound::Included((collection_owner, collection_name, Blob::default()));
+    let (entries, scan_stopped_at) = collection_metadata(
+        map_id,
+        caller,
+        start,
+        usize::MAX,
+        MAX_FILE_SEARCH_SCAN,
+        &FileListFilter::default(),
+    );
+    let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+    for (_, metadata) in entries {
+        let file_tags: BTreeSet<String> = metadata.tags.iter().map(|t| normalize_tag(t)).collect();
+        for tag in file_tags {
+            *counts.entry(tag).or_default() += 1;
+        }
+    }
+    let mut tags: Vec<(String, u32)> = counts.into_iter().collect();
+    tags.sort_by(|(a_tag, a_count), (b_tag, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_tag.cmp(b_tag))
+    });
+    let truncated = scan_stopped_at.is_some() || tags.len() > MAX_COLLECTION_TAGS;
+    tags.truncate(MAX_COLLECTION_TAGS);
+    Ok(CollectionTags { tags, truncated })
+}
+
+/// Replaces the tag `from` with `to` on every file of a collection, merging
+/// the two where a file carries both. Tags are compared trimmed and
+/// lowercased. Inspects at most [`MAX_TAG_RENAME_SCAN`] files per call;
+/// call again with `next_cursor` until it is `null`. Requires write access
+/// to the collection.
+#[update]
+fn rename_tag(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    from: String,
+    to: String,
+    start_after: Option<ByteBuf>,
+) -> Result<TagRename, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    ensure_can_write(caller, map_id)?;
+    ensure_not_archived(map_id)?;
+    let from = normalize_tag(&from);
+    let to = to.trim().to_string();
+    if to.is_empty() {
+        return Err("tag must not be empty".to_string().into());
+    }
+    let start = match start_after.map(bytebuf_to_blob).transpose()? {
+        Some(file_id) => RangeBound::Excluded((collection_owner, collection_name, file_id)),
+        None => RangeBound::Included((collection_owner, collection_name, Blob::default())),
+    };
+    let (entries, scan_stopped_at) = collection_metadata(
+        map_id,
+        caller,
+        start,
+        usize::MAX,
+        MAX_TAG_RENAME_SCAN,
+        &FileListFilter::default(),
+    );
+
+    let mut files_updated = 0;
+    FILE_METADATA.with_borrow_mut(|file_metadata| {
+        for (file_id, metadata) in entries {
+            if !metadata.tags.iter().any(|tag| normalize_tag(tag) == from)
+                || file_override((collection_owner, collection_name, file_id), caller).is_some()
+            {
+                continue;
+            }
+            let mut tags: Vec<String> = Vec::new();
+            for tag in metadata.tags.iter() {
+                let tag = if normalize_tag(tag) == from { &to } else { tag };
+                if !contains_tag(&tags, tag) {
+                    tags.push(tag.clone());
+                }
+            }
+            let renamed = FileMetadata {
+                tags,
+                ..metadata.modified_by(caller)
+            };
+            file_metadata.insert((collection_owner, collection_name, file_id), renamed);
+            files_updated += 1;
+        }
+    });
+    Ok(TagRename {
+        files_updated,
+        next_cursor: scan_stopped_at.map(|file_id| ByteBuf::from(file_id.as_slice().to_vec())),
+    })
+}
+
+/// Adds and removes tags on several files, given with the revision each
+/// edit is based on, with a result per file in the order given. Tags are
+/// compared trimmed and lowercased, removals apply before additions, and a
+/// file that would end up with more than [`MAX_TAGS_PER_FILE`] tags or has
+/// changed since is left unchanged with an error. At most
+/// [`MAX_BULK_FILES`] files per call. Requires write access to the
+/// collection.
+#[update]
+fn bulk_update_tags(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    files: Vec<(ByteBuf, u64)>,
+    add: Vec<String>,
+    remove: Vec<String>,
+) -> Result<Vec<Result<FileMetadata, ApiError>>, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    if files.len() > MAX_BULK_FILES {
+        return Err(format!("at most {MAX_BULK_FILES} files per call").into());
+    }
+    let collection_name = collection_name_to_blob(collection_name)?;
+    ensure_can_write(caller, (collection_owner, collection_name))?;
+    ensure_not_archived((collection_owner, collection_name))?;
+    let remove: BTreeSet<String> = remove.iter().map(|tag| normalize_tag(tag)).collect();
+    let add: Vec<String> = add
+        .iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+
+    Ok(files
+        .into_iter()
+        .map(|(file_id, expected_revision)| {
+            let metadata_key = (collection_owner, collection_name, bytebuf_to_blob(file_id)?);
+            ensure_may_modify(metadata_key, caller)?;
+            FILE_METADATA.with_borrow_mut(|file_metadata| {
+                let existing = file_metadata
+                    .get(&metadata_key)
+                    .ok_or_else(|| "file not found".to_string())?;
+                existing.ensure_revision(expected_revision)?;
+                let mut tags: Vec<String> = existing
+                    .tags
+                    .iter()
+                    .filter(|tag| !remove.contains(&normalize_tag(tag)))
+                    .cloned()
+                    .collect();
+                for tag in &add {
+                    if !contains_tag(&tags, tag) {
+                        tags.push(tag.clone());
+                    }
+                }
+                if tags.len() > MAX_TAGS_PER_FILE {
+                    return Err(format!("at most {MAX_TAGS_PER_FILE} tags per file").into());
+                }
+                let updated = FileMetadata {
+                    tags,
+                    ..existing.modified_by(caller)
+                };
+                file_metadata.insert(metadata_key, updated.clone());
+                Ok(updated)
+            })
+        })
+        .collect())
+}
+
+/// Files tagged with `tag` (compared trimmed and lowercased) in collections
+/// the caller owns or has been granted, optionally only those whose content
+/// type starts with `content_type_prefix` and that carry the custom entry
+/// `custom_eq`. See [`search_accessible_files`] for how paging works.
+#[query]
+fn search_files_by_tag(
+    tag: String,
+    cursor: Option<FileCursor>,
+    limit: u64,
+    content_type_prefix: Option<String>,
+    custom_eq: Option<(String, String)>,
+) -> Result<FileSearchPage, String> {
+    let tag = normalize_tag(&tag);
+    search_accessible_files(cursor, limit, |metadata| {
+        metadata.tags.iter().any(|t| normalize_tag(t) == tag)
+            && matches_search_options(metadata, &content_type_prefix, &custom_eq)
+    })
+}
+
+/// Files whose name contains `query`, ignoring case, in collections the
+/// caller owns or has been granted, with the same options as
+/// search_files_by_tag. Never returns encrypted values.
+#[query]
+fn search_files_by_name(
+    query: String,
+    cursor: Option<FileCursor>,
+    limit: u64,
+    content_type_prefix: Option<String>,
+    custom_eq: Option<(String, String)>,
+) -> Result<FileSearchPage, String> {
+    let query = query.to_lowercase();
+    if query.chars().count() < MIN_NAME_SEARCH_QUERY_LEN {
+        return Err(format!(
+            "search query must be at least {MIN_NAME_SEARCH_QUERY_LEN} characters"
+        ));
+    }
+    search_accessible_files(cursor, limit, |metadata| {
+        metadata.filename.to_lowercase().contains(&query)
+            && matches_search_options(metadata, &content_type_prefix, &custom_eq)
+    })
+}
+
+/// Walks the metadata of all collections accessible to the caller, in key
+/// order, starting after `cursor`, and collects files matching `predicate`.
+/// A page may hold fewer than `limit` hits, or none, while `next_cursor` is
+/// set: each call inspects at most [`MAX_FILE_SEARCH_SCAN`] files.
+fn search_accessible_files(
+    cursor: Option<FileCursor>,
+    limit: u64,
+    predicate: impl Fn(&FileMetadata) -> bool,
+) -> Result<FileSearchPage, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let limit = limit.clamp(1, MAX_FILE_SEARCH_LIMIT) as usize;
+    let cursor = cursor
+        .map(|cursor| {
+            Ok::<_, String>((
+                cursor.collection_owner,
+                collection_name_to_blob(cursor.collection_name)?,
+                bytebuf_to_blob(cursor.file_id)?,
+            ))
+        })
+        .transpose()?;
+    let accessible: BTreeSet<MapId> = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_ref().unwrap();
+        encrypted_maps
+            .get_owned_non_empty_map_names(caller)
+            .into_iter()
+            .map(|map_name| (caller, map_name))
+            .chain(encrypted_maps.get_accessible_shared_map_names(caller))
+            .collect()
+    });
+    let first_map = cursor.map_or(RangeBound::Unbounded, |(owner, name, _)| {
+        RangeBound::Included((owner, name))
+    });
+
+    let now = ic_cdk::api::time();
+    let mut hits = Vec::new();
+    let mut scanned = 0;
+    let mut next_cursor = None;
+    FILE_METADATA.with_borrow(|file_metadata| {
+        for &(owner, name) in accessible.range((first_map, RangeBound::Unbounded)) {
+            let start = match cursor {
+                Some(key) if (key.0, key.1) == (owner, name) => RangeBound::Excluded(key),
+                _ => RangeBound::Included((owner, name, Blob::default())),
+            };
+            let entries = file_metadata
+                .range((start, RangeBound::Unbounded))
+                .take_while(|((file_owner, file_map, _), _)| {
+                    (*file_owner, *file_map) == (owner, name)
+                });
+            for (key, metadata) in entries {
+                scanned += 1;
+                if !metadata.is_expired(now) && predicate(&metadata) && !is_hidden_from(key, caller)
+                {
+                    hits.push(FileSearchHit {
+                        collection_owner: owner,
+                        collection_name: ByteBuf::from(name.as_slice().to_vec()),
+                        file_id: ByteBuf::from(key.2.as_slice().to_vec()),
+                        metadata,
+                    });
+                }
+                if hits.len() == limit || scanned == MAX_FILE_SEARCH_SCAN {
+                    next_cursor = Some(FileCursor {
+                        collection_owner: owner,
+                        collection_name: ByteBuf::from(name.as_slice().to_vec()),
+                        file_id: ByteBuf::from(key.2.as_slice().to_vec()),
+                    });
+                    return;
+                }
+            }
+        }
+    });
+    Ok(FileSearchPage { hits, next_cursor })
+}
+
+/// Stores a file, replacing any previous content under `file_id`.
+/// `checksum` is the SHA-256 of the plaintext, kept for downloaders to
+/// verify against after decryption. If another file in the collection has
+/// the same checksum, the upload fails with `DuplicateContent` when
+/// `reject_duplicates` is set and is reported in `duplicate_of` otherwise.
+#[update]
+fn upload_file_to_collection(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    value: EncryptedMapValue,
+    metadata: FileMetadataInput,
+    checksum: Option<[u8; 32]>,
+    reject_duplicates: bool,
+) -> Result<UploadResult, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    metadata.validate()?;
+    let full_name = collection_name.clone();
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    ensure_collection_name(map_id, &full_name)?;
+    ensure_not_archived(map_id)?;
+    let file_id = bytebuf_to_blob(file_id)?;
+    let file_key = (collection_owner, collection_name, file_id);
+    ensure_may_modify(file_key, caller)?;
+    ensure_policy_allows(map_id, metadata.file_size, &metadata.content_type)?;
+    if filename_taken(file_key, &metadata.filename) {
+        return Err(ApiError::FilenameTaken {
+            filename: metadata.filename,
+        });
+    }
+    let duplicate_of = checksum
+        .as_ref()
+        .and_then(|checksum| find_duplicate(file_key, checksum));
+    if let Some(duplicate_id) = duplicate_of.filter(|_| reject_duplicates) {
+        let duplicate_key = (collection_owner, collection_name, duplicate_id);
+        if let Some(metadata) =
+            FILE_METADATA.with_borrow(|file_metadata| file_metadata.get(&duplicate_key))
+        {
+            return Err(ApiError::DuplicateContent {
+                file_id: ByteBuf::from(duplicate_id.as_slice().to_vec()),
+                metadata: Box::new(metadata),
+            });
+        }
+    }
+    let had_files = owns_files(collection_owner);
+    let opt_prev_value = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .insert_encrypted_value(caller, map_id, file_id, value)
+    })?;
+    let metadata_value = FileMetadata {
+        checksum,
+        ..match FILE_METADATA.with_borrow(|file_metadata| file_metadata.get(&file_key)) {
+            Some(existing) => existing.update(caller, metadata),
+            None => FileMetadata::new(caller, metadata),
+        }
+    };
+    record_upload(file_key, metadata_value.creation_date);
+    let action = ActivityAction::Uploaded {
+        filename: metadata_value.filename.clone(),
+    };
+    let previous = opt_prev_value.zip(store_metadata(file_key, metadata_value));
+    log_activity(map_id, caller, action, Some(file_id));
+    record_upload_time(map_id, ic_cdk::api::time());
+    ensure_collection_metadata(map_id, full_name);
+    if let Some((value, metadata)) = &previous {
+        archive_version(file_key, value.clone(), metadata.clone());
+    }
+    record_collection_ownership(had_files, owns_files(collection_owner));
+    Ok(UploadResult {
+        previous,
+        duplicate_of: duplicate_of.map(|file_id| ByteBuf::from(file_id.as_slice().to_vec())),
+    })
+}
+
+/// Whether the SHA-256 of the stored encrypted value of a file equals
+/// `encrypted_sha256`, so clients can detect truncated uploads without
+/// downloading the file again. Requires read access to the collection.
+#[query]
+fn verify_upload(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    encrypted_sha256: [u8; 32],
+) -> Result<bool, String> {
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    let file_id = bytebuf_to_blob(file_id)?;
+    let value = ENCRYPTED_MAPS
+        .with_borrow(|encrypted_maps| {
+            encrypted_maps.as_ref().unwrap().get_encrypted_value(
+                ic_cdk::api::msg_caller(),
+                map_id,
+                file_id,
+            )
+        })?
+        .ok_or_else(|| "file not found".to_string())?;
+    Ok(Sha256::digest(value.as_ref()).as_slice() == encrypted_sha256)
+}
+
+/// Replaces the metadata of an existing file without touching its encrypted
+/// content. Fails with `Conflict` if the file is no longer at
+/// `expected_revision`. Requires write access to the collection.
+#[update]
+fn update_file_metadata(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    metadata: FileMetadataInput,
+    expected_revision: u64,
+) -> Result<FileMetadata, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    metadata.validate()?;
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_id = bytebuf_to_blob(file_id)?;
+    ensure_can_write(caller, (collection_owner, collection_name))?;
+    ensure_not_archived((collection_owner, collection_name))?;
+    let file_key = (collection_owner, collection_name, file_id);
+    ensure_may_modify(file_key, caller)?;
+    let existing = FILE_METADATA
+        .with_borrow(|file_metadata| file_metadata.get(&file_key))
+        .ok_or_else(|| "file not found".to_string())?;
+    existing.ensure_revision(expected_revision)?;
+    let updated = existing.update(caller, metadata);
+    ensure_unique_filename(file_key, &updated.filename)?;
+    store_metadata(file_key, updated.clone());
+    Ok(updated)
+}
+
+/// Changes only the filename of an existing file. Fails with `Conflict` if
+/// the file is no longer at `expected_revision`. Requires write access to
+/// the collection.
+#[update]
+fn rename_file(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    new_filename: String,
+    expected_revision: u64,
+) -> Result<FileMetadata, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    validate_filename(&new_filename)?;
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_id = bytebuf_to_blob(file_id)?;
+    ensure_can_write(caller, (collection_owner, collection_name))?;
+    ensure_not_archived((collection_owner, collection_name))?;
+    let file_key = (collection_owner, collection_name, file_id);
+    ensure_may_modify(file_key, caller)?;
+    let existing = FILE_METADATA
+        .with_borrow(|file_metadata| file_metadata.get(&file_key))
+        .ok_or_else(|| "file not found".to_string())?;
+    existing.ensure_revision(expected_revision)?;
+    ensure_unique_filename(file_key, &new_filename)?;
+    let action = ActivityAction::Renamed {
+        from: existing.filename.clone(),
+        to: new_filename.clone(),
+    };
+    let renamed = FileMetadata {
+        filename: new_filename,
+        ..existing.modified_by(caller)
+    };
+    store_metadata(file_key, renamed.clone());
+    log_activity(
+        (collection_owner, collection_name),
+        caller,
+        action,
+        Some(file_id),
+    );
+    Ok(renamed)
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MoveResult {
+    pub metadata: FileMetadata,
+    /// Whether source and destination share an encryption key, which is
+    /// derived per collection. Always false, since a file is only moved
+    /// between collections: the client must re-upload the moved bytes
+    /// encrypted with the destination key.
+    pub same_key: bool,
+}
+
+/// Moves a file, with its versions, stars, comments and statistics, to
+/// another collection of the caller. The encrypted value is moved as is,
+/// still encrypted with the key of the source collection. Fails with
+/// `FileIdTaken` if the destination already holds a file with the same id.
+#[update]
+fn move_file(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    destination_name: ByteBuf,
+) -> Result<MoveResult, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    if collection_owner != caller {
+        return Err(
+            "files can only be moved out of the caller's own collections"
+                .to_string()
+                .into(),
+        );
+    }
+    let file_id = bytebuf_to_blob(file_id)?;
+    let from = (caller, collection_name_to_blob(collection_name)?, file_id);
+    let to = (
+        caller,
+        collection_name_to_blob(destination_name.clone())?,
+        file_id,
+    );
+    ensure_collection_name((to.0, to.1), &destination_name)?;
+    if from.1 == to.1 {
+        return Err("source and destination are the same collection"
+            .to_string()
+            .into());
+    }
+    ensure_not_archived((from.0, from.1))?;
+    ensure_not_archived((to.0, to.1))?;
+    let metadata = FILE_METADATA
+        .with_borrow(|file_metadata| file_metadata.get(&from))
+        .ok_or_else(|| "file not found".to_string())?;
+    ensure_free_file_id(to)?;
+    ensure_unique_filename(to, &metadata.filename)?;
+    ensure_policy_allows((to.0, to.1), metadata.file_size, &metadata.content_type)?;
+    if !relocate_file(caller, from, to)? {
+        return Err("file not found".to_string().into());
+    }
+    ensure_collection_metadata((to.0, to.1), destination_name);
+    let removed = ActivityAction::Removed {
+        filename: metadata.filename.clone(),
+    };
+    log_activity((from.0, from.1), caller, removed, Some(from.2));
+    let uploaded = ActivityAction::Uploaded {
+        filename: metadata.filename.clone(),
+    };
+    log_activity((to.0, to.1), caller, uploaded, Some(to.2));
+    Ok(MoveResult {
+        metadata,
+        same_key: false,
+    })
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CopyResult {
+    pub metadata: FileMetadata,
+    /// Whether source and destination share an encryption key, which is
+    /// derived per collection. If not, the copied bytes cannot be decrypted
+    /// with the destination key until the client re-uploads them.
+    pub same_key: bool,
+}
+
+/// Copies a file and its thumbnail to `new_file_id` in the destination
+/// collection, as a new file uploaded by the caller. The encrypted bytes are
+/// copied as is. Requires read access to the source and write access to the
+/// destination; fails with `FileIdTaken` if `new_file_id` is in use.
+#[update]
+fn copy_file(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    destination_owner: Principal,
+    destination_name: ByteBuf,
+    new_file_id: ByteBuf,
+) -> Result<CopyResult, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    let from = (
+        collection_owner,
+        collection_name_to_blob(collection_name)?,
+        bytebuf_to_blob(file_id)?,
+    );
+    let to = (
+        destination_owner,
+        collection_name_to_blob(destination_name.clone())?,
+        bytebuf_to_blob(new_file_id)?,
+    );
+    let destination = (to.0, to.1);
+    ensure_can_write(caller, destination)?;
+    ensure_collection_name(destination, &destination_name)?;
+    ensure_not_archived(destination)?;
+    if is_hidden_from(from, caller) {
+        return Err("file not found".to_string().into());
+    }
+    let value = ENCRYPTED_MAPS
+        .with_borrow(|encrypted_maps| {
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_encrypted_value(caller, (from.0, from.1), from.2)
+        })?
+        .ok_or_else(|| "file not found".to_string())?;
+    log_read((from.0, from.1), caller, from.2);
+    let source = FILE_METADATA
+        .with_borrow(|file_metadata| file_metadata.get(&from))
+        .ok_or_else(|| "file not found".to_string())?;
+    ensure_free_file_id(to)?;
+    ensure_unique_filename(to, &source.filename)?;
+    ensure_policy_allows(destination, source.file_size, &source.content_type)?;
+    let now = ic_cdk::api::time();
+    let metadata = FileMetadata {
+        uploaded_by: caller,
+        creation_date: now,
+        last_modification_date: now,
+        number_of_modifications: 0,
+        last_modified_principal: caller,
+        revision: 0,
+        ..source
+    };
+    let had_files = owns_files(destination_owner);
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .insert_encrypted_value(caller, destination, to.2, value)
+    })?;
+    record_upload(to, now);
+    store_metadata(to, metadata.clone());
+    let action = ActivityAction::Uploaded {
+        filename: metadata.filename.clone(),
+    };
+    log_activity(destination, caller, action, Some(to.2));
+    copy_thumbnail(from, to);
+    record_upload_time(destination, now);
+    ensure_collection_metadata(destination, destination_name);
+    record_collection_ownership(had_files, owns_files(destination_owner));
+    Ok(CopyResult {
+        metadata,
+        same_key: (from.0, from.1) == destination,
+    })
+}
+
+/// Moves a file to the collection's trash, from which restore_from_trash
+/// can bring it back until it is purged. Kept versions, stars and access
+/// counters are deleted.
+#[update]
+fn remove_file_from_collection(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<Option<(EncryptedMapValue, FileMetadata)>, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_id = bytebuf_to_blob(file_id)?;
+    ensure_not_archived((collection_owner, collection_name))?;
+    ensure_may_modify((collection_owner, collection_name, file_id), caller)?;
+    let had_files = owns_files(collection_owner);
+    let result = remove_file(caller, (collection_owner, collection_name, file_id))?;
+    record_collection_ownership(had_files, owns_files(collection_owner));
+    if let Some((_, metadata)) = &result {
+        let action = ActivityAction::Removed {
+            filename: metadata.filename.clone(),
+        };
+        log_activity(
+            (collection_owner, collection_name),
+            caller,
+            action,
+            Some(file_id),
+        );
+    }
+    Ok(result)
+}
+
+/// Removes several files like remove_file_from_collection, with a result
+/// per file id in the order given; an item failing does not stop the
+/// others. At most [`MAX_BULK_FILES`] ids per call.
+#[update]
+fn remove_files_from_collection(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_ids: Vec<ByteBuf>,
+) -> Result<Vec<Result<Option<FileMetadata>, String>>, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    if file_ids.len() > MAX_BULK_FILES {
+        return Err(format!("at most {MAX_BULK_FILES} files per call").into());
+    }
+    let collection_name = collection_name_to_blob(collection_name)?;
+    ensure_can_write(caller, (collection_owner, collection_name))?;
+    ensure_not_archived((collection_owner, collection_name))?;
+    let had_files = owns_files(collection_owner);
+    let results = file_ids
+        .into_iter()
+        .map(|file_id| {
+            let file_id = bytebuf_to_blob(file_id)?;
+            let file_key = (collection_owner, collection_name, file_id);
+            ensure_may_modify(file_key, caller)?;
+            let removed = remove_file(caller, file_key)?.map(|(_, metadata)| metadata);
+            if let Some(metadata) = &removed {
+                let action = ActivityAction::Removed {
+                    filename: metadata.filename.clone(),
+                };
+                log_activity(
+                    (collection_owner, collection_name),
+                    caller,
+                    action,
+                    Some(file_id),
+                );
+            }
+            Ok(removed)
+        })
+        .collect();
+    record_collection_ownership(had_files, owns_files(collection_owner));
+    Ok(results)
+}
+
+/// Moves a file to the trash on behalf of `caller`, dropping its versions,
+/// stars and access counters. Leaves the collection owner count to the
+/// caller.
+pub fn remove_file(
+    caller: Principal,
+    file_key: FileKey,
+) -> Result<Option<(EncryptedMapValue, FileMetadata)>, String> {
+    let overrides = file_overrides(file_key);
+    let removed = purge_file(caller, file_key)?;
+    if let Some((value, metadata)) = &removed {
+        move_to_trash(file_key, value.clone(), metadata.clone(), caller, overrides);
+    }
+    Ok(removed)
+}
+
+/// Deletes a file with everything attached to it, bypassing the trash.
+/// Leaves the collection owner count to the caller.
+pub fn purge_file(
+    caller: Principal,
+    file_key: FileKey,
+) -> Result<Option<(EncryptedMapValue, FileMetadata)>, String> {
+    let (collection_owner, collection_name, file_id) = file_key;
+    let removed = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps.as_mut().unwrap().remove_encrypted_value(
+            caller,
+            (collection_owner, collection_name),
+            file_id,
+        )
+    })?;
+    let removed = removed.zip(remove_metadata(file_key));
+    if removed.is_some() {
+        mark_file_share_stale(file_key)?;
+        remove_versions(file_key);
+        remove_file_stars(file_key);
+        remove_file_comments(file_key);
+        remove_sort_index(file_key);
+        remove_thumbnail(file_key);
+        remove_file_stats(file_key);
+        remove_file_overrides(file_key);
+    }
+    Ok(removed)
+}
+
+/// Moves the value, metadata and per-file records of `from` to `to`,
+/// overwriting whatever is stored there. Returns false if `from` does not
+/// exist.
+pub fn relocate_file(caller: Principal, from: FileKey, to: FileKey) -> Result<bool, String> {
+    let value = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_encrypted_value(caller, (from.0, from.1), from.2)
+    })?;
+    let metadata = FILE_METADATA.with_borrow(|file_metadata| file_metadata.get(&from));
+    let (Some(value), Some(metadata)) = (value, metadata) else {
+        return Ok(false);
+    };
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .insert_encrypted_value(caller, (to.0, to.1), to.2, value)
+    })?;
+    store_metadata(to, metadata);
+    move_versions(from, to);
+    move_file_stars(from, to);
+    move_file_stats(from, to);
+    move_file_comments(from, to);
+    move_sort_index(from, to);
+    move_thumbnail(from, to);
+    move_file_overrides(from, to);
+    purge_file(caller, from)?;
+    Ok(true)
+}
+
+fn ensure_free_file_id(file_key: FileKey) -> Result<(), ApiError> {
+    if FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+        return Err(ApiError::FileIdTaken);
+    }
+    Ok(())
+}
+
+/// Writes the metadata of a file, keeping the expiry, filename and checksum
+/// indices in sync. Returns the previous metadata.
+pub fn store_metadata(file_key: FileKey, metadata: FileMetadata) -> Option<FileMetadata> {
+    let previous = FILE_METADATA
+        .with_borrow_mut(|file_metadata| file_metadata.insert(file_key, metadata.clone()));
+    update_expiry_index(
+        file_key,
+        previous.as_ref().and_then(|previous| previous.expires_at),
+        metadata.expires_at,
+    );
+    update_filename_index(
+        file_key,
+        previous.as_ref().map(|previous| previous.filename.as_str()),
+        Some(&metadata.filename),
+    );
+    update_checksum_index(
+        file_key,
+        previous
+            .as_ref()
+            .and_then(|previous| previous.checksum.as_ref()),
+        metadata.checksum.as_ref(),
+    );
+    update_collection_stats(file_key, previous.as_ref(), Some(&metadata));
+    previous
+}
+
+/// Removes the metadata of a file together with its index entries.
+fn remove_metadata(file_key: FileKey) -> Option<FileMetadata> {
+    let removed = FILE_METADATA.with_borrow_mut(|file_metadata| file_metadata.remove(&file_key))?;
+    update_expiry_index(file_key, removed.expires_at, None);
+    update_filename_index(file_key, Some(&removed.filename), None);
+    update_checksum_index(file_key, removed.checksum.as_ref(), None);
+    update_collection_stats(file_key, Some(&removed), None);
+    Some(removed)
+}
+
+pub fn validate_filename(filename: &str) -> Result<(), String> {
+    if filename.is_empty() || filename.len() > MAX_FILENAME_LEN {
+        return Err(format!("filename must be 1 to {MAX_FILENAME_LEN} bytes"));
+    }
+    if filename.chars().any(char::is_control) {
+        return Err("filename must not contain control characters".to_string());
+    }
+    Ok(())
+}
+
+fn matches_search_options(
+    metadata: &FileMetadata,
+    content_type_prefix: &Option<String>,
+    custom_eq: &Option<(String, String)>,
+) -> bool {
+    content_type_prefix.as_deref().map_or(true, |prefix| {
+        has_content_type_prefix(&metadata.content_type, prefix)
+    }) && custom_eq
+        .as_ref()
+        .map_or(true, |entry| metadata.custom.contains(entry))
+}
+
+fn has_content_type_prefix(content_type: &str, prefix: &str) -> bool {
+    content_type
+        .to_lowercase()
+        .starts_with(&prefix.to_lowercase())
+}
+
+/// Trims `tags` and drops empty and duplicate ones, keeping the first
+/// spelling, under the same limit as the tags of a file.
+pub fn clean_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    let mut cleaned: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !contains_tag(&cleaned, tag) {
+            cleaned.push(tag.to_string());
+        }
+    }
+    if cleaned.len() > MAX_TAGS_PER_FILE {
+        return Err(format!("at most {MAX_TAGS_PER_FILE} tags are allowed"));
+    }
+    Ok(cleaned)
+}
+
+pub fn contains_tag(tags: &[String], tag: &str) -> bool {
+    let tag = normalize_tag(tag);
+    tags.iter().any(|existing| normalize_tag(existing) == tag)
+}
+
+fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
@@ -0,0 +1,175 @@
+//! Sharing grants that end at a set time. The expiry of a grant is stored
+//! next to EncryptedMaps' own grants and registered in the expiry index,
+//! whose timer revokes due grants.
+
+use crate::collection_activity::{log_activity, ActivityAction};
+use crate::collection_managers::remove_manager;
+use crate::collections::ensure_not_archived;
+use crate::expiry_index::{register_expiry, unregister_expiry, ExpiryTarget};
+use crate::invitations::{share_or_invite, ShareOutcome};
+use crate::notifications::{notify, NotificationKind};
+use crate::sharing_audit::log_sharing_change;
+use crate::users::{principal_for_username, record_activity};
+use crate::{
+    collection_name_to_blob, ApiError, MapId, Username, ENCRYPTED_MAPS, GRANT_EXPIRY,
+    GRANT_EXPIRY_INDEX,
+};
+use candid::Principal;
+use ic_cdk::update;
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::{AccessRights, ByteBuf};
+
+/// Like share_collection_with_user, with a grant that ends at `expires_at`,
+/// in nanoseconds since the epoch.
+#[update]
+fn share_collection_with_user_until(
+    collection_name: ByteBuf,
+    username: Username,
+    access_rights: AccessRights,
+    expires_at: u64,
+    message: Option<String>,
+) -> Result<ShareOutcome, ApiError> {
+    if expires_at <= ic_cdk::api::time() {
+        return Err("the expiry must be in the future".to_string().into());
+    }
+    let user = principal_for_username(&username)?;
+    share_or_invite(
+        None,
+        collection_name,
+        user,
+        access_rights,
+        message,
+        Some(expires_at),
+    )
+}
+
+/// Moves the expiry of `username`'s grant on one of the caller's
+/// collections, or makes the grant permanent with `null`, keeping its
+/// rights.
+#[update]
+fn update_grant_expiry(
+    collection_name: ByteBuf,
+    username: Username,
+    expires_at: Option<u64>,
+) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    if expires_at.is_some_and(|expires_at| expires_at <= ic_cdk::api::time()) {
+        return Err("the expiry must be in the future".to_string().into());
+    }
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    ensure_not_archived(map_id)?;
+    let user = principal_for_username(&username)?;
+    let rights = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(caller, map_id, user)
+    })?;
+    if user == caller || rights.is_none() {
+        return Err(format!("the collection is not shared with {username}").into());
+    }
+    set_grant_expiry(map_id, user, expires_at);
+    Ok(())
+}
+
+/// When the grant of `grantee` on a collection ends; `None` if it does not.
+pub fn grant_expiry(map_id: MapId, grantee: Principal) -> Option<u64> {
+    GRANT_EXPIRY.with_borrow(|expiry| expiry.get(&(map_id.0, map_id.1, grantee)))
+}
+
+/// Sets or, with `None`, removes the expiry of a grant.
+pub fn set_grant_expiry(map_id: MapId, grantee: Principal, expires_at: Option<u64>) {
+    let key = (map_id.0, map_id.1, grantee);
+    let previous = GRANT_EXPIRY.with_borrow_mut(|expiry| match expires_at {
+        Some(expires_at) => expiry.insert(key, expires_at),
+        None => expiry.remove(&key),
+    });
+    let target = ExpiryTarget::grant(map_id, grantee);
+    if let Some(previous) = previous {
+        unregister_expiry(previous, &target);
+    }
+    if let Some(expires_at) = expires_at {
+        register_expiry(expires_at, target);
+    }
+}
+
+/// Carries the expiry of a grant over when the grant moves to another
+/// collection, e.g. on rename.
+pub fn move_grant_expiry(from: MapId, to: MapId, grantee: Principal) {
+    if let Some(expires_at) = grant_expiry(from, grantee) {
+        set_grant_expiry(from, grantee, None);
+        set_grant_expiry(to, grantee, Some(expires_at));
+    }
+}
+
+/// Removes the expiries of all grants on collections of `owner`.
+pub fn remove_owned_grant_expiries(owner: Principal) {
+    let keys: Vec<(Principal, Blob<32>, Principal)> = GRANT_EXPIRY.with_borrow(|expiry| {
+        expiry
+            .range((owner, Blob::default(), Principal::management_canister())..)
+            .take_while(|((map_owner, _, _), _)| map_owner == &owner)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for (owner, name, grantee) in keys {
+        set_grant_expiry((owner, name), grantee, None);
+    }
+}
+
+/// Registers the expiries of all timed grants in the expiry index and
+/// drains the grant-only index it replaces.
+pub fn register_grant_expiries() {
+    let expiries: Vec<((Principal, Blob<32>, Principal), u64)> =
+        GRANT_EXPIRY.with_borrow(|expiry| expiry.iter().collect());
+    for ((owner, name, grantee), expires_at) in expiries {
+        register_expiry(expires_at, ExpiryTarget::grant((owner, name), grantee));
+    }
+    GRANT_EXPIRY_INDEX.with_borrow_mut(|index| {
+        let keys: Vec<(u64, (Principal, Blob<32>, Principal))> =
+            index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+}
+
+/// Revokes the grant of `grantee` on a collection if it has ended; does
+/// nothing if it was removed or extended in the meantime.
+pub fn revoke_expired_grant(map_id: MapId, grantee: Principal) {
+    let owner = map_id.0;
+    if grant_expiry(map_id, grantee).map_or(true, |expires_at| expires_at > ic_cdk::api::time()) {
+        return;
+    }
+    set_grant_expiry(map_id, grantee, None);
+    remove_manager(map_id, grantee);
+    // The owner always has the rights to revoke grants.
+    let removed = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps
+            .as_mut()
+            .unwrap()
+            .remove_user(owner, map_id, grantee)
+    });
+    match removed {
+        Ok(Some(rights)) => {
+            notify(grantee, owner, map_id, NotificationKind::AccessExpired);
+            log_sharing_change(
+                map_id,
+                ic_cdk::api::canister_self(),
+                grantee,
+                Some(rights),
+                None,
+            );
+            log_activity(
+                map_id,
+                owner,
+                ActivityAction::Unshared { user: grantee },
+                None,
+            )
+        }
+        Ok(None) => {}
+        Err(e) => {
+            ic_cdk::println!("failed to revoke expired grant: {e}");
+        }
+    }
+}
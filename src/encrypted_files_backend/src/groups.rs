@@ -0,0 +1,427 @@
+//! Named groups of users that a collection can be shared with at once.
+//! Sharing with a group grants each current member access and records the
+//! group grant, so that members added later get access too and removed
+//! members lose it. Grants made through a group are remembered per grantee:
+//! members who already had access keep their own grant, and only grants a
+//! group made are revoked when the member leaves all groups sharing the
+//! collection.
+
+use crate::collections::{
+    collection_metadata_or_default, ensure_not_archived, ensure_room_for_grantees,
+    owned_collection_names, revoke_grant, set_grant,
+};
+use crate::users::{ensure_active, principal_for_username, record_activity, user_for_principal};
+use crate::{
+    collection_name_to_blob, ApiError, MapId, MapName, Username, ENCRYPTED_MAPS, GROUPS,
+    GROUPS_BY_OWNER, GROUP_GRANTS, GROUP_MADE_GRANTS, GROUP_MEMBERS, NEXT_GROUP_ID,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{AccessRights, ByteBuf};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const MAX_GROUP_NAME_LEN: usize = 64;
+const MAX_GROUPS_PER_OWNER: usize = 50;
+const MAX_GROUP_MEMBERS: usize = 100;
+/// Upper bound on the collections shared with a group, which bounds the
+/// grants made when a member is added.
+const MAX_GROUP_COLLECTIONS: usize = 50;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Group {
+    pub group_id: u64,
+    pub owner: Principal,
+    pub name: String,
+    pub created_at: u64,
+}
+
+impl Storable for Group {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GroupMember {
+    pub principal: Principal,
+    pub username: Option<Username>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GroupView {
+    pub group: Group,
+    pub members: Vec<GroupMember>,
+    /// The collections shared with the group and the members' rights.
+    pub collections: Vec<(ByteBuf, AccessRights)>,
+}
+
+#[update]
+fn create_group(name: String) -> Result<u64, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_GROUP_NAME_LEN {
+        return Err(format!("group names must be 1 to {MAX_GROUP_NAME_LEN} characters").into());
+    }
+    let groups = owned_group_ids(caller);
+    if groups.len() >= MAX_GROUPS_PER_OWNER {
+        return Err(format!("at most {MAX_GROUPS_PER_OWNER} groups are allowed").into());
+    }
+    if groups
+        .into_iter()
+        .filter_map(group)
+        .any(|group| group.name == name)
+    {
+        return Err("you already have a group with this name".to_string().into());
+    }
+    let group_id = NEXT_GROUP_ID.with_borrow_mut(|next_group_id| {
+        let group_id = *next_group_id.get();
+        next_group_id
+            .set(group_id + 1)
+            .expect("failed to store group id");
+        group_id
+    });
+    let group = Group {
+        group_id,
+        owner: caller,
+        name,
+        created_at: ic_cdk::api::time(),
+    };
+    GROUPS.with_borrow_mut(|groups| groups.insert(group_id, group));
+    GROUPS_BY_OWNER.with_borrow_mut(|index| index.insert((caller, group_id), ()));
+    Ok(group_id)
+}
+
+/// Adds `username` to one of the caller's groups and gives them access to
+/// the collections shared with it. Archived collections are skipped.
+#[update]
+fn add_group_member(group_id: u64, username: Username) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    let group = owned_group(caller, group_id)?;
+    let user = principal_for_username(&username)?;
+    if user == caller {
+        return Err("the owner of a group cannot be a member of it"
+            .to_string()
+            .into());
+    }
+    let members = group_members(group_id);
+    if members.contains(&user) {
+        return Ok(());
+    }
+    if members.len() >= MAX_GROUP_MEMBERS {
+        return Err(format!("groups can have at most {MAX_GROUP_MEMBERS} members").into());
+    }
+    GROUP_MEMBERS.with_borrow_mut(|members| members.insert((group_id, user), ()));
+    for (map_name, access_rights) in group_collections(&group) {
+        let map_id = (caller, map_name);
+        if ensure_not_archived(map_id).is_ok() {
+            grant_through_group(map_id, user, access_rights)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes `username` from one of the caller's groups, revoking the access
+/// the group gave them unless another group of the caller still gives it.
+#[update]
+fn remove_group_member(group_id: u64, username: Username) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let group = owned_group(caller, group_id)?;
+    let user = principal_for_username(&username)?;
+    let removed =
+        GROUP_MEMBERS.with_borrow_mut(|members| members.remove(&(group_id, user)).is_some());
+    if !removed {
+        return Err(format!("{username} is not a member of the group").into());
+    }
+    for (map_name, _) in group_collections(&group) {
+        revoke_unless_still_covered((caller, map_name), user)?;
+    }
+    Ok(())
+}
+
+#[query]
+fn list_my_groups() -> Vec<GroupView> {
+    let caller = ic_cdk::api::msg_caller();
+    owned_group_ids(caller)
+        .into_iter()
+        .filter_map(group)
+        .map(|group| GroupView {
+            members: group_members(group.group_id)
+                .into_iter()
+                .map(|principal| GroupMember {
+                    principal,
+                    username: user_for_principal(&principal).map(|user| user.username),
+                })
+                .collect(),
+            collections: group_grants(group.group_id)
+                .into_iter()
+                .map(|(map_name, access_rights)| {
+                    let name = collection_metadata_or_default((group.owner, map_name)).name;
+                    (name, access_rights)
+                })
+                .collect(),
+            group,
+        })
+        .collect()
+}
+
+/// Shares one of the caller's collections with every current member of one
+/// of their groups, and with members added later. Members who already have
+/// access of their own keep it unchanged. Returns the number of members
+/// whose access was granted or changed.
+#[update]
+fn share_collection_with_group(
+    collection_name: ByteBuf,
+    group_id: u64,
+    access_rights: AccessRights,
+) -> Result<u32, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    let group = owned_group(caller, group_id)?;
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string().into());
+    }
+    ensure_not_archived(map_id)?;
+    let grants = group_collections(&group);
+    if grants.len() >= MAX_GROUP_COLLECTIONS && !grants.iter().any(|(name, _)| name == &map_id.1) {
+        return Err(format!(
+            "at most {MAX_GROUP_COLLECTIONS} collections can be shared with a group"
+        )
+        .into());
+    }
+    let members = group_members(group_id);
+    let new_grantees = ENCRYPTED_MAPS
+        .with_borrow(|encrypted_maps| {
+            let encrypted_maps = encrypted_maps.as_ref().unwrap();
+            members
+                .iter()
+                .map(|user| encrypted_maps.get_user_rights(map_id.0, map_id, *user))
+                .collect::<Result<Vec<_>, String>>()
+        })?
+        .into_iter()
+        .filter(Option::is_none)
+        .count() as u32;
+    ensure_room_for_grantees(map_id, new_grantees)?;
+    GROUP_GRANTS.with_borrow_mut(|grants| grants.insert((group_id, map_id.1), access_rights));
+    let mut granted = 0;
+    for user in members {
+        if grant_through_group(map_id, user, access_rights)? {
+            granted += 1;
+        }
+    }
+    Ok(granted)
+}
+
+/// Stops sharing one of the caller's collections with one of their groups,
+/// revoking the access the group gave its members unless another group of
+/// the caller still gives it. Returns the number of grants revoked.
+#[update]
+fn unshare_collection_with_group(collection_name: ByteBuf, group_id: u64) -> Result<u32, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    owned_group(caller, group_id)?;
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    if GROUP_GRANTS
+        .with_borrow_mut(|grants| grants.remove(&(group_id, map_id.1)))
+        .is_none()
+    {
+        return Err("the collection is not shared with the group"
+            .to_string()
+            .into());
+    }
+    let mut revoked = 0;
+    for user in group_members(group_id) {
+        if revoke_unless_still_covered(map_id, user)? {
+            revoked += 1;
+        }
+    }
+    Ok(revoked)
+}
+
+/// Forgets that `user`'s grant on a collection was made through a group,
+/// e.g. because it was revoked or replaced by a grant of its own.
+pub fn forget_group_grant(map_id: MapId, user: Principal) {
+    GROUP_MADE_GRANTS.with_borrow_mut(|made| made.remove(&(map_id.0, map_id.1, user)));
+}
+
+/// Points the group grants of a renamed collection to its new name.
+pub fn move_collection_group_grants(from: MapId, to: MapId) {
+    for group_id in owned_group_ids(from.0) {
+        if let Some(access_rights) =
+            GROUP_GRANTS.with_borrow_mut(|grants| grants.remove(&(group_id, from.1)))
+        {
+            GROUP_GRANTS.with_borrow_mut(|grants| grants.insert((group_id, to.1), access_rights));
+        }
+    }
+    for user in group_made_grantees(from) {
+        forget_group_grant(from, user);
+        GROUP_MADE_GRANTS.with_borrow_mut(|made| made.insert((to.0, to.1, user), ()));
+    }
+}
+
+/// Drops the group grants of a collection, e.g. when it is deleted or all
+/// access to it is revoked.
+pub fn remove_collection_group_grants(map_id: MapId) {
+    for group_id in owned_group_ids(map_id.0) {
+        GROUP_GRANTS.with_borrow_mut(|grants| grants.remove(&(group_id, map_id.1)));
+    }
+    for user in group_made_grantees(map_id) {
+        forget_group_grant(map_id, user);
+    }
+}
+
+/// Removes the groups of `user` and their memberships in the groups of
+/// others, e.g. with their account. Grants are left to the caller.
+pub fn remove_user_groups(user: Principal) {
+    for group_id in owned_group_ids(user) {
+        for member in group_members(group_id) {
+            GROUP_MEMBERS.with_borrow_mut(|members| members.remove(&(group_id, member)));
+        }
+        for (map_name, _) in group_grants(group_id) {
+            GROUP_GRANTS.with_borrow_mut(|grants| grants.remove(&(group_id, map_name)));
+        }
+        GROUPS.with_borrow_mut(|groups| groups.remove(&group_id));
+        GROUPS_BY_OWNER.with_borrow_mut(|index| index.remove(&(user, group_id)));
+    }
+    // Memberships are not indexed by member, so this walks all of them.
+    let memberships: Vec<(u64, Principal)> = GROUP_MEMBERS.with_borrow(|members| {
+        members
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(_, member)| member == &user)
+            .collect()
+    });
+    GROUP_MEMBERS.with_borrow_mut(|members| {
+        for key in memberships {
+            members.remove(&key);
+        }
+    });
+    let made: Vec<(Principal, MapName, Principal)> = GROUP_MADE_GRANTS.with_borrow(|made| {
+        made.iter()
+            .map(|(key, _)| key)
+            .filter(|(owner, _, grantee)| owner == &user || grantee == &user)
+            .collect()
+    });
+    GROUP_MADE_GRANTS.with_borrow_mut(|grants| {
+        for key in made {
+            grants.remove(&key);
+        }
+    });
+}
+
+/// Gives a group member the group's rights on a collection, unless they
+/// have access of their own. Returns whether their access changed.
+fn grant_through_group(
+    map_id: MapId,
+    user: Principal,
+    access_rights: AccessRights,
+) -> Result<bool, ApiError> {
+    let current = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(map_id.0, map_id, user)
+    })?;
+    let made_by_group =
+        GROUP_MADE_GRANTS.with_borrow(|made| made.contains_key(&(map_id.0, map_id.1, user)));
+    if (current.is_some() && !made_by_group) || current == Some(access_rights) {
+        return Ok(false);
+    }
+    set_grant(map_id, map_id.0, user, access_rights)?;
+    GROUP_MADE_GRANTS.with_borrow_mut(|made| made.insert((map_id.0, map_id.1, user), ()));
+    Ok(true)
+}
+
+/// Revokes `user`'s grant on a collection if a group made it and no group
+/// of the owner that `user` is in still shares the collection. Returns
+/// whether it was revoked.
+fn revoke_unless_still_covered(map_id: MapId, user: Principal) -> Result<bool, String> {
+    if !GROUP_MADE_GRANTS.with_borrow(|made| made.contains_key(&(map_id.0, map_id.1, user))) {
+        return Ok(false);
+    }
+    let still_covered = owned_group_ids(map_id.0).into_iter().any(|group_id| {
+        GROUP_GRANTS.with_borrow(|grants| grants.contains_key(&(group_id, map_id.1)))
+            && GROUP_MEMBERS.with_borrow(|members| members.contains_key(&(group_id, user)))
+    });
+    if still_covered {
+        return Ok(false);
+    }
+    Ok(revoke_grant(map_id, map_id.0, user)?.is_some())
+}
+
+fn group(group_id: u64) -> Option<Group> {
+    GROUPS.with_borrow(|groups| groups.get(&group_id))
+}
+
+fn owned_group(owner: Principal, group_id: u64) -> Result<Group, String> {
+    group(group_id)
+        .filter(|group| group.owner == owner)
+        .ok_or_else(|| "group not found".to_string())
+}
+
+fn owned_group_ids(owner: Principal) -> Vec<u64> {
+    GROUPS_BY_OWNER.with_borrow(|index| {
+        index
+            .range((owner, 0)..)
+            .take_while(|((group_owner, _), _)| group_owner == &owner)
+            .map(|((_, group_id), _)| group_id)
+            .collect()
+    })
+}
+
+fn group_members(group_id: u64) -> Vec<Principal> {
+    GROUP_MEMBERS.with_borrow(|members| {
+        members
+            .range((group_id, Principal::management_canister())..)
+            .take_while(|((member_group, _), _)| member_group == &group_id)
+            .map(|((_, member), _)| member)
+            .collect()
+    })
+}
+
+fn group_grants(group_id: u64) -> Vec<(MapName, AccessRights)> {
+    GROUP_GRANTS.with_borrow(|grants| {
+        grants
+            .range((group_id, Blob::default())..)
+            .take_while(|((grant_group, _), _)| grant_group == &group_id)
+            .map(|((_, map_name), access_rights)| (map_name, access_rights))
+            .collect()
+    })
+}
+
+/// The group grants of `group`, dropping those whose collection no longer
+/// exists.
+fn group_collections(group: &Group) -> Vec<(MapName, AccessRights)> {
+    let owned = owned_collection_names(group.owner);
+    let (live, gone): (Vec<_>, Vec<_>) = group_grants(group.group_id)
+        .into_iter()
+        .partition(|(map_name, _)| owned.contains(map_name));
+    for (map_name, _) in gone {
+        GROUP_GRANTS.with_borrow_mut(|grants| grants.remove(&(group.group_id, map_name)));
+    }
+    live
+}
+
+fn group_made_grantees(map_id: MapId) -> Vec<Principal> {
+    GROUP_MADE_GRANTS.with_borrow(|made| {
+        made.range((map_id.0, map_id.1, Principal::management_canister())..)
+            .take_while(|((owner, name, _), _)| (*owner, *name) == map_id)
+            .map(|((_, _, user), _)| user)
+            .collect()
+    })
+}
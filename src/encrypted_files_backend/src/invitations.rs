@@ -0,0 +1,668 @@
+//! Invitations to shared collections. Sharing a collection with a user who
+//! has no access yet creates a pending invitation; the grant is only made
+//! once the recipient accepts. Invitations are kept under a global sequence
+//! number, indexed by inviter and, while pending, by recipient. Through the
+//! expiry index, invitations left unanswered for [`INVITATION_TTL_NS`] are
+//! marked expired, and those answered, cancelled or expired more than
+//! [`INVITATION_HISTORY_NS`] ago are removed.
+
+use crate::collection_activity::{log_activity, ActivityAction};
+use crate::collection_managers::{can_manage, ensure_can_share, ensure_within_own_rights};
+use crate::collections::{
+    ensure_grantee_allowed, ensure_manager_may_change, ensure_not_archived,
+    ensure_room_for_grantees, owned_collection_names, set_grant,
+};
+use crate::expiry_index::{register_expiry, unregister_expiry, ExpiryTarget};
+use crate::grant_expiry::set_grant_expiry;
+use crate::groups::forget_group_grant;
+use crate::notifications::{notify, NotificationKind};
+use crate::sharing_audit::log_sharing_change;
+use crate::users::{ensure_active, record_activity, user_for_principal};
+use crate::{
+    collection_name_to_blob, ApiError, MapId, Username, ENCRYPTED_MAPS, INVITATIONS,
+    INVITATIONS_BY_INVITER, NEXT_INVITATION_ID, PENDING_INVITATIONS_BY_RECIPIENT,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{AccessRights, ByteBuf};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const MAX_INVITATION_MESSAGE_LEN: usize = 500;
+const MAX_PENDING_INVITATIONS_PER_RECIPIENT: usize = 20;
+/// How long an invitation waits for an answer.
+const INVITATION_TTL_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+/// How long answered, cancelled and expired invitations stay in the
+/// inviter's list.
+const INVITATION_HISTORY_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvitationStatus {
+    Pending,
+    Accepted,
+    Declined,
+    Cancelled,
+    /// Left unanswered for 30 days.
+    Expired,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Invitation {
+    pub invitation_id: u64,
+    /// The owner of the collection, on whose behalf the grant is made.
+    pub inviter: Principal,
+    pub recipient: Principal,
+    pub collection_name: ByteBuf,
+    pub access_rights: AccessRights,
+    pub message: Option<String>,
+    pub created_at: u64,
+    /// When the grant ends once accepted; `None` for a permanent grant.
+    pub grant_expires_at: Option<u64>,
+    pub status: InvitationStatus,
+    pub responded_at: Option<u64>,
+    /// The manager or resharer who sent the invitation; `None` if the owner
+    /// did.
+    #[serde(default)]
+    pub invited_by: Option<Principal>,
+}
+
+impl Storable for Invitation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InvitationView {
+    pub invitation: Invitation,
+    pub inviter_username: Option<Username>,
+    pub recipient_username: Option<Username>,
+    /// When a pending invitation expires.
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ShareOutcome {
+    /// The user already had access; the new rights apply right away.
+    Updated { previous: AccessRights },
+    /// The user gets access once they accept the invitation.
+    Invited { invitation_id: u64 },
+}
+
+/// The invitations waiting for the caller's answer, oldest first.
+#[query]
+fn list_my_invitations() -> Vec<InvitationView> {
+    let caller = ic_cdk::api::msg_caller();
+    let invitation_ids: Vec<u64> = PENDING_INVITATIONS_BY_RECIPIENT.with_borrow(|index| {
+        index
+            .range((caller, 0)..)
+            .take_while(|((recipient, _), _)| recipient == &caller)
+            .map(|((_, invitation_id), _)| invitation_id)
+            .collect()
+    });
+    let now = ic_cdk::api::time();
+    invitation_ids
+        .into_iter()
+        .filter_map(invitation)
+        .filter(|invitation| !is_expired(invitation, now))
+        .map(invitation_view)
+        .collect()
+}
+
+/// How many invitations are waiting for the caller's answer.
+#[query]
+fn pending_invitation_count() -> u32 {
+    let caller = ic_cdk::api::msg_caller();
+    let now = ic_cdk::api::time();
+    let invitation_ids: Vec<u64> = PENDING_INVITATIONS_BY_RECIPIENT.with_borrow(|index| {
+        index
+            .range((caller, 0)..)
+            .take_while(|((recipient, _), _)| recipient == &caller)
+            .map(|((_, invitation_id), _)| invitation_id)
+            .collect()
+    });
+    invitation_ids
+        .into_iter()
+        .filter_map(invitation)
+        .filter(|invitation| !is_expired(invitation, now))
+        .count() as u32
+}
+
+/// The invitations sent for the caller's collections, or only for
+/// `collection_name`, newest first. Answered, cancelled and expired ones
+/// are listed for 30 days.
+#[query]
+fn list_sent_invitations(collection_name: Option<ByteBuf>) -> Vec<InvitationView> {
+    let caller = ic_cdk::api::msg_caller();
+    let now = ic_cdk::api::time();
+    let map_name = match collection_name.map(collection_name_to_blob).transpose() {
+        Ok(map_name) => map_name,
+        Err(_) => return Vec::new(),
+    };
+    sent_invitation_ids(caller)
+        .into_iter()
+        .rev()
+        .filter_map(invitation)
+        .filter(|invitation| !is_stale(invitation, now))
+        .filter(|invitation| {
+            map_name.map_or(true, |map_name| {
+                collection_name_to_blob(invitation.collection_name.clone()) == Ok(map_name)
+            })
+        })
+        .map(invitation_view)
+        .collect()
+}
+
+/// Accepts an invitation of the caller, granting the offered rights. An
+/// invitation sent by a manager or resharer is cancelled instead if they
+/// could no longer send it, e.g. because their flag, grant or rights were
+/// taken away since.
+#[update]
+fn accept_invitation(invitation_id: u64) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    let invitation = pending_invitation_for(caller, invitation_id)?;
+    let map_id = (
+        invitation.inviter,
+        collection_name_to_blob(invitation.collection_name.clone())?,
+    );
+    if !owned_collection_names(map_id.0).contains(&map_id.1) {
+        return Err("the collection no longer exists".to_string().into());
+    }
+    ensure_not_archived(map_id)?;
+    let now = ic_cdk::api::time();
+    if invitation
+        .grant_expires_at
+        .is_some_and(|expires_at| expires_at <= now)
+    {
+        return Err("the offered access has already expired".to_string().into());
+    }
+    let current = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(map_id.0, map_id, caller)
+    })?;
+    if let Some(invited_by) = invitation.invited_by {
+        let still_allowed = ensure_can_share(map_id, invited_by).is_ok()
+            && ensure_within_own_rights(map_id, invited_by, invitation.access_rights).is_ok()
+            && (current.is_none() || can_manage(map_id, invited_by)?);
+        if !still_allowed {
+            resolve(invitation, InvitationStatus::Cancelled);
+            return Err("the user who invited you can no longer grant this access"
+                .to_string()
+                .into());
+        }
+    }
+    if current.is_none() {
+        ensure_room_for_grantees(map_id, 1)?;
+    }
+    // The inviter owns the collection and may therefore grant access to it.
+    let previous = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps.as_mut().unwrap().set_user_rights(
+            map_id.0,
+            map_id,
+            caller,
+            invitation.access_rights,
+        )
+    })?;
+    let granted_by = invitation.invited_by.unwrap_or(invitation.inviter);
+    log_sharing_change(
+        map_id,
+        granted_by,
+        caller,
+        previous,
+        Some(invitation.access_rights),
+    );
+    set_grant_expiry(map_id, caller, invitation.grant_expires_at);
+    forget_group_grant(map_id, caller);
+    log_activity(
+        map_id,
+        granted_by,
+        ActivityAction::Shared { user: caller },
+        None,
+    );
+    notify(
+        invitation.inviter,
+        caller,
+        map_id,
+        NotificationKind::InvitationAccepted { invitation_id },
+    );
+    resolve(invitation, InvitationStatus::Accepted);
+    Ok(())
+}
+
+#[update]
+fn decline_invitation(invitation_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let invitation = pending_invitation_for(caller, invitation_id)?;
+    if let Ok(map_name) = collection_name_to_blob(invitation.collection_name.clone()) {
+        notify(
+            invitation.inviter,
+            caller,
+            (invitation.inviter, map_name),
+            NotificationKind::InvitationDeclined { invitation_id },
+        );
+    }
+    resolve(invitation, InvitationStatus::Declined);
+    Ok(())
+}
+
+/// Withdraws a pending invitation to one of the caller's collections, or to
+/// a collection the caller manages.
+#[update]
+fn cancel_invitation(invitation_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let invitation = invitation(invitation_id).ok_or_else(|| "invitation not found".to_string())?;
+    let map_id = (
+        invitation.inviter,
+        collection_name_to_blob(invitation.collection_name.clone())?,
+    );
+    if !can_manage(map_id, caller)? {
+        return Err("invitation not found".to_string());
+    }
+    if invitation.status != InvitationStatus::Pending {
+        return Err("the invitation has already been answered".to_string());
+    }
+    notify(
+        invitation.recipient,
+        caller,
+        map_id,
+        NotificationKind::InvitationCancelled { invitation_id },
+    );
+    resolve(invitation, InvitationStatus::Cancelled);
+    Ok(())
+}
+
+/// Gives `user` the given rights on a collection of the caller or, for
+/// managers, of `collection_owner`: right away if they already have access,
+/// and otherwise by inviting them, replacing any pending invitation of
+/// theirs to the collection.
+pub fn share_or_invite(
+    collection_owner: Option<Principal>,
+    collection_name: ByteBuf,
+    user: Principal,
+    access_rights: AccessRights,
+    message: Option<String>,
+    grant_expires_at: Option<u64>,
+) -> Result<ShareOutcome, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    let (map_id, message) = prepare_share(caller, collection_owner, &collection_name, message)?;
+    share_or_invite_prepared(
+        caller,
+        map_id,
+        collection_name,
+        user,
+        access_rights,
+        message,
+        grant_expires_at,
+    )
+}
+
+/// The checks of share_or_invite that do not depend on the user, so that
+/// sharing with many users runs them once: the caller must be active and
+/// own, manage or be allowed to reshare the collection, which must not be
+/// archived. Returns the
+/// collection and the cleaned message.
+pub fn prepare_share(
+    caller: Principal,
+    collection_owner: Option<Principal>,
+    collection_name: &ByteBuf,
+    message: Option<String>,
+) -> Result<(MapId, Option<String>), ApiError> {
+    ensure_active(caller)?;
+    record_activity(caller);
+    let owner = collection_owner.unwrap_or(caller);
+    let map_id = (owner, collection_name_to_blob(collection_name.clone())?);
+    if !owned_collection_names(owner).contains(&map_id.1) {
+        return Err("collection not found".to_string().into());
+    }
+    ensure_can_share(map_id, caller)?;
+    ensure_not_archived(map_id)?;
+    let message = message
+        .map(|message| message.trim().to_string())
+        .filter(|message| !message.is_empty());
+    if message
+        .as_ref()
+        .is_some_and(|message| message.chars().count() > MAX_INVITATION_MESSAGE_LEN)
+    {
+        return Err(
+            format!("the message must be at most {MAX_INVITATION_MESSAGE_LEN} characters").into(),
+        );
+    }
+    remove_stale_invitations(owner, ic_cdk::api::time());
+    Ok((map_id, message))
+}
+
+/// share_or_invite after prepare_share. Grants and invitations are made on
+/// behalf of the owner; the caller is recorded as the one who made them.
+pub fn share_or_invite_prepared(
+    caller: Principal,
+    map_id: MapId,
+    collection_name: ByteBuf,
+    user: Principal,
+    access_rights: AccessRights,
+    message: Option<String>,
+    grant_expires_at: Option<u64>,
+) -> Result<ShareOutcome, ApiError> {
+    let owner = map_id.0;
+    ensure_grantee_allowed(map_id, caller, user)?;
+    let current = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_user_rights(owner, map_id, user)
+    })?;
+    if caller != owner {
+        ensure_within_own_rights(map_id, caller, access_rights)?;
+        if current.is_some() && !can_manage(map_id, caller)? {
+            return Err("only managers can change the rights of users with access"
+                .to_string()
+                .into());
+        }
+    }
+    if let Some(previous) = current {
+        set_grant(map_id, caller, user, access_rights)?;
+        if grant_expires_at.is_some() {
+            set_grant_expiry(map_id, user, grant_expires_at);
+        }
+        return Ok(ShareOutcome::Updated { previous });
+    }
+
+    if caller != owner {
+        ensure_manager_may_change(map_id, user, Some(access_rights))?;
+    }
+    let invited_by = (caller != owner).then_some(caller);
+    let now = ic_cdk::api::time();
+    let existing = sent_invitation_ids(owner)
+        .into_iter()
+        .filter_map(invitation)
+        .find(|invitation| {
+            invitation.status == InvitationStatus::Pending
+                && invitation.recipient == user
+                && collection_name_to_blob(invitation.collection_name.clone()).ok()
+                    == Some(map_id.1)
+        });
+    if let Some(existing) = existing {
+        let invitation_id = existing.invitation_id;
+        unregister_invitation_expiry(&existing);
+        let updated = Invitation {
+            collection_name,
+            access_rights,
+            message,
+            created_at: now,
+            grant_expires_at,
+            invited_by,
+            ..existing
+        };
+        register_invitation_expiry(&updated);
+        INVITATIONS.with_borrow_mut(|invitations| invitations.insert(invitation_id, updated));
+        return Ok(ShareOutcome::Invited { invitation_id });
+    }
+    let pending = PENDING_INVITATIONS_BY_RECIPIENT.with_borrow(|index| {
+        index
+            .range((user, 0)..)
+            .take_while(|((recipient, _), _)| recipient == &user)
+            .count()
+    });
+    if pending >= MAX_PENDING_INVITATIONS_PER_RECIPIENT {
+        return Err("the user has too many pending invitations"
+            .to_string()
+            .into());
+    }
+    ensure_room_for_grantees(map_id, 1)?;
+
+    let invitation_id = NEXT_INVITATION_ID.with_borrow_mut(|next_invitation_id| {
+        let invitation_id = *next_invitation_id.get();
+        next_invitation_id
+            .set(invitation_id + 1)
+            .expect("failed to store invitation id");
+        invitation_id
+    });
+    let invitation = Invitation {
+        invitation_id,
+        inviter: owner,
+        recipient: user,
+        collection_name,
+        access_rights,
+        message,
+        created_at: now,
+        grant_expires_at,
+        status: InvitationStatus::Pending,
+        responded_at: None,
+        invited_by,
+    };
+    register_invitation_expiry(&invitation);
+    INVITATIONS.with_borrow_mut(|invitations| invitations.insert(invitation_id, invitation));
+    INVITATIONS_BY_INVITER.with_borrow_mut(|index| index.insert((owner, invitation_id), ()));
+    PENDING_INVITATIONS_BY_RECIPIENT
+        .with_borrow_mut(|index| index.insert((user, invitation_id), ()));
+    notify(
+        user,
+        caller,
+        map_id,
+        NotificationKind::Invited { invitation_id },
+    );
+    Ok(ShareOutcome::Invited { invitation_id })
+}
+
+/// Cancels the pending invitations to a collection, e.g. when it is
+/// deleted or handed over.
+pub fn cancel_collection_invitations(map_id: MapId) {
+    for invitation in pending_collection_invitations(map_id) {
+        notify(
+            invitation.recipient,
+            map_id.0,
+            map_id,
+            NotificationKind::InvitationCancelled {
+                invitation_id: invitation.invitation_id,
+            },
+        );
+        resolve(invitation, InvitationStatus::Cancelled);
+    }
+}
+
+/// Points the pending invitations to a renamed collection to its new name.
+pub fn move_collection_invitations(from: MapId, new_name: &ByteBuf) {
+    for invitation in pending_collection_invitations(from) {
+        let invitation_id = invitation.invitation_id;
+        let moved = Invitation {
+            collection_name: new_name.clone(),
+            ..invitation
+        };
+        INVITATIONS.with_borrow_mut(|invitations| invitations.insert(invitation_id, moved));
+    }
+}
+
+/// Marks an invitation expired if it was left unanswered too long; does
+/// nothing if it was answered or removed in the meantime.
+pub fn expire_invitation(invitation_id: u64) {
+    if let Some(invitation) =
+        invitation(invitation_id).filter(|i| is_expired(i, ic_cdk::api::time()))
+    {
+        resolve(invitation, InvitationStatus::Expired);
+    }
+}
+
+/// Removes an answered invitation once it has left the history period.
+pub fn remove_stale_invitation(invitation_id: u64) {
+    if let Some(invitation) = invitation(invitation_id).filter(|i| is_stale(i, ic_cdk::api::time()))
+    {
+        remove_invitation(&invitation);
+    }
+}
+
+/// Registers the expiries of all invitations in the expiry index. Reads
+/// the whole invitation map, which the history limit keeps small.
+pub fn register_invitation_expiries() {
+    let invitations: Vec<Invitation> = INVITATIONS.with_borrow(|invitations| {
+        invitations
+            .iter()
+            .map(|(_, invitation)| invitation)
+            .collect()
+    });
+    for invitation in invitations {
+        register_invitation_expiry(&invitation);
+    }
+}
+
+/// Removes the invitations `user` has sent and those waiting for their
+/// answer, e.g. with their account.
+pub fn remove_user_invitations(user: Principal) {
+    for invitation_id in sent_invitation_ids(user) {
+        if let Some(invitation) = invitation(invitation_id) {
+            remove_invitation(&invitation);
+        }
+    }
+    let received: Vec<u64> = PENDING_INVITATIONS_BY_RECIPIENT.with_borrow(|index| {
+        index
+            .range((user, 0)..)
+            .take_while(|((recipient, _), _)| recipient == &user)
+            .map(|((_, invitation_id), _)| invitation_id)
+            .collect()
+    });
+    for invitation_id in received {
+        if let Some(invitation) = invitation(invitation_id) {
+            remove_invitation(&invitation);
+        }
+    }
+}
+
+fn invitation(invitation_id: u64) -> Option<Invitation> {
+    INVITATIONS.with_borrow(|invitations| invitations.get(&invitation_id))
+}
+
+/// The view of an invitation, showing one the timer has not processed yet
+/// as expired already.
+fn invitation_view(invitation: Invitation) -> InvitationView {
+    let status = if is_expired(&invitation, ic_cdk::api::time()) {
+        InvitationStatus::Expired
+    } else {
+        invitation.status
+    };
+    InvitationView {
+        inviter_username: user_for_principal(&invitation.inviter).map(|user| user.username),
+        recipient_username: user_for_principal(&invitation.recipient).map(|user| user.username),
+        expires_at: invitation.created_at.saturating_add(INVITATION_TTL_NS),
+        invitation: Invitation {
+            status,
+            ..invitation
+        },
+    }
+}
+
+fn pending_invitation_for(recipient: Principal, invitation_id: u64) -> Result<Invitation, String> {
+    let now = ic_cdk::api::time();
+    invitation(invitation_id)
+        .filter(|invitation| {
+            invitation.recipient == recipient
+                && invitation.status == InvitationStatus::Pending
+                && !is_expired(invitation, now)
+        })
+        .ok_or_else(|| "invitation not found".to_string())
+}
+
+fn sent_invitation_ids(inviter: Principal) -> Vec<u64> {
+    INVITATIONS_BY_INVITER.with_borrow(|index| {
+        index
+            .range((inviter, 0)..)
+            .take_while(|((invitation_inviter, _), _)| invitation_inviter == &inviter)
+            .map(|((_, invitation_id), _)| invitation_id)
+            .collect()
+    })
+}
+
+fn pending_collection_invitations(map_id: MapId) -> Vec<Invitation> {
+    sent_invitation_ids(map_id.0)
+        .into_iter()
+        .filter_map(invitation)
+        .filter(|invitation| {
+            invitation.status == InvitationStatus::Pending
+                && collection_name_to_blob(invitation.collection_name.clone()).ok()
+                    == Some(map_id.1)
+        })
+        .collect()
+}
+
+/// Records the answer to a pending invitation, which leaves the
+/// recipient's list.
+fn resolve(invitation: Invitation, status: InvitationStatus) {
+    let invitation_id = invitation.invitation_id;
+    unregister_invitation_expiry(&invitation);
+    PENDING_INVITATIONS_BY_RECIPIENT
+        .with_borrow_mut(|index| index.remove(&(invitation.recipient, invitation_id)));
+    let resolved = Invitation {
+        status,
+        responded_at: Some(ic_cdk::api::time()),
+        ..invitation
+    };
+    register_invitation_expiry(&resolved);
+    INVITATIONS.with_borrow_mut(|invitations| invitations.insert(invitation_id, resolved));
+}
+
+/// Whether a pending invitation has been left unanswered too long.
+fn is_expired(invitation: &Invitation, now: u64) -> bool {
+    invitation.status == InvitationStatus::Pending
+        && invitation.created_at.saturating_add(INVITATION_TTL_NS) <= now
+}
+
+fn is_stale(invitation: &Invitation, now: u64) -> bool {
+    invitation
+        .responded_at
+        .is_some_and(|responded_at| responded_at.saturating_add(INVITATION_HISTORY_NS) <= now)
+}
+
+fn remove_stale_invitations(inviter: Principal, now: u64) {
+    for invitation_id in sent_invitation_ids(inviter) {
+        if let Some(invitation) = invitation(invitation_id).filter(|i| is_stale(i, now)) {
+            remove_invitation(&invitation);
+        }
+    }
+}
+
+fn remove_invitation(invitation: &Invitation) {
+    let invitation_id = invitation.invitation_id;
+    unregister_invitation_expiry(invitation);
+    INVITATIONS.with_borrow_mut(|invitations| invitations.remove(&invitation_id));
+    INVITATIONS_BY_INVITER
+        .with_borrow_mut(|index| index.remove(&(invitation.inviter, invitation_id)));
+    PENDING_INVITATIONS_BY_RECIPIENT
+        .with_borrow_mut(|index| index.remove(&(invitation.recipient, invitation_id)));
+}
+
+/// When a pending invitation expires or an answered one leaves the
+/// history, with its entry in the expiry index.
+fn invitation_expiry(invitation: &Invitation) -> (u64, ExpiryTarget) {
+    let invitation_id = invitation.invitation_id;
+    match invitation.responded_at {
+        None => (
+            invitation.created_at.saturating_add(INVITATION_TTL_NS),
+            ExpiryTarget::Invitation { invitation_id },
+        ),
+        Some(responded_at) => (
+            responded_at.saturating_add(INVITATION_HISTORY_NS),
+            ExpiryTarget::InvitationHistory { invitation_id },
+        ),
+    }
+}
+
+fn register_invitation_expiry(invitation: &Invitation) {
+    let (expires_at, target) = invitation_expiry(invitation);
+    register_expiry(expires_at, target);
+}
+
+fn unregister_invitation_expiry(invitation: &Invitation) {
+    let (expires_at, target) = invitation_expiry(invitation);
+    unregister_expiry(expires_at, &target);
+}
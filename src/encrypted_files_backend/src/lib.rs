@@ -1,84 +1,495 @@
+use access_requests::AccessRequest;
+use admin::Config;
+use avatars::Avatar;
 use candid::{CandidType, Principal};
+use checksums::Checksum;
+use collection_activity::ActivityEntry;
+use collection_pins::PinnedCollections;
+use collection_stats::CollectionStats;
+use collection_trash::{DeletedCollection, DeletedCollectionKey};
+use collections::{CollectionMetadata, CollectionSettings};
+use comments::Comment;
+use expiry_index::ExpiryTarget;
+use file_overrides::FileOverride;
+use file_shares::FileShare;
+use file_stats::FileStats;
+use filenames::FilenameHash;
+use files::FileMetadata;
+use groups::Group;
 use ic_cdk::management_canister::{VetKDCurve, VetKDKeyId};
-use ic_cdk::{init, query, update};
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::Blob;
-use ic_stable_structures::{storable::Bound, Storable};
-use ic_stable_structures::{BTreeMap as StableBTreeMap, DefaultMemoryImpl};
+use ic_stable_structures::{BTreeMap as StableBTreeMap, Cell as StableCell, DefaultMemoryImpl};
 use ic_vetkeys::encrypted_maps::{EncryptedMaps, VetKey, VetKeyVerificationKey};
-use ic_vetkeys::types::{AccessRights, ByteBuf, EncryptedMapValue, TransportKey};
-use serde::{Deserialize, Serialize};
-use std::borrow::Cow;
+use ic_vetkeys::types::{AccessControl, AccessRights, ByteBuf, TransportKey};
+use invitations::Invitation;
+use notifications::Notification;
+use preferences::PreferenceKey;
+use read_audit::ReadAuditEntry;
+use search::SearchToken;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use share_links::ShareLinkRecord;
+use sharing_audit::SharingAuditEntry;
 use std::cell::RefCell;
+use trash::TrashedFile;
+use users::{ReservedUsernames, User};
+use versions::{FileKey, FileVersion};
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct PasswordMetadata {
-    creation_date: u64,
-    last_modification_date: u64,
-    number_of_modifications: u64,
-    last_modified_principal: Principal,
-    tags: Vec<String>,
-    url: String,
-}
-
-impl PasswordMetadata {
-    pub fn new(caller: Principal, tags: Vec<String>, url: String) -> Self {
-        let time_now = ic_cdk::api::time();
-        Self {
-            creation_date: time_now,
-            last_modification_date: time_now,
-            number_of_modifications: 0,
-            last_modified_principal: caller,
-            tags,
-            url,
-        }
-    }
-
-    pub fn update(self, caller: Principal, tags: Vec<String>, url: String) -> Self {
-        let time_now = ic_cdk::api::time();
-        Self {
-            creation_date: self.creation_date,
-            last_modification_date: time_now,
-            number_of_modifications: self.number_of_modifications + 1,
-            last_modified_principal: caller,
-            tags,
-            url,
-        }
-    }
-}
+mod access_requests;
+mod admin;
+mod avatars;
+mod checksums;
+mod collection_activity;
+mod collection_managers;
+mod collection_merge;
+mod collection_pins;
+mod collection_stats;
+mod collection_trash;
+mod collections;
+mod comments;
+mod csv_export;
+mod devices;
+mod expiry;
+mod expiry_index;
+mod file_overrides;
+mod file_shares;
+mod file_stats;
+mod filenames;
+mod files;
+mod grant_expiry;
+mod groups;
+mod invitations;
+mod manifest;
+mod notifications;
+mod ordering;
+mod preferences;
+mod read_audit;
+mod recent;
+mod search;
+mod share_links;
+mod sharing_audit;
+mod stars;
+mod stats;
+mod thumbnails;
+mod transfers;
+mod trash;
+mod users;
+mod versions;
 
-impl Storable for PasswordMetadata {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
-    }
-
-    const BOUND: Bound = Bound::Unbounded;
-}
+const MAX_COLLECTION_NAME_LEN: usize = 256;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type MapOwner = Principal;
 type MapName = Blob<32>;
 type MapKey = Blob<32>;
+type MapId = (MapOwner, MapName);
 // To understand the intuition how a stable map over a tuple type works, see
 // https://mmapped.blog/posts/14-stable-structures#stable-btree.
-type StableMetadataMap = StableBTreeMap<(MapOwner, MapName, MapKey), PasswordMetadata, Memory>;
+type StableFileMetadataMap = StableBTreeMap<(MapOwner, MapName, MapKey), FileMetadata, Memory>;
+type Username = String;
+type StableUserMap = StableBTreeMap<Username, User, Memory>;
+type StablePrincipalToUsernameMap = StableBTreeMap<Principal, Username, Memory>;
+type StableLinkedPrincipalMap = StableBTreeMap<(Principal, Principal), u64, Memory>;
+type StableAvatarMap = StableBTreeMap<Principal, Avatar, Memory>;
+type StableReservedUsernamesCell = StableCell<ReservedUsernames, Memory>;
+type StablePreferenceMap = StableBTreeMap<(Principal, PreferenceKey), String, Memory>;
+type StableDisplayNameIndex = StableBTreeMap<(SearchToken, Principal), (), Memory>;
+type StableConfigCell = StableCell<Config, Memory>;
+type StableCollectionSettingsMap = StableBTreeMap<MapId, CollectionSettings, Memory>;
+type StableFileVersionMap = StableBTreeMap<(FileKey, u32), FileVersion, Memory>;
+type StableTrashMap = StableBTreeMap<(FileKey, u64), TrashedFile, Memory>;
+type StableTrashIndex = StableBTreeMap<(u64, FileKey), (), Memory>;
+type StableStarMap = StableBTreeMap<(Principal, FileKey), u64, Memory>;
+type StableStarIndex = StableBTreeMap<(FileKey, Principal), (), Memory>;
+type StableFileStatsMap = StableBTreeMap<FileKey, FileStats, Memory>;
+type StableFileAccessorSet = StableBTreeMap<(FileKey, Principal), (), Memory>;
+type StableFileExpiryIndex = StableBTreeMap<(u64, FileKey), (), Memory>;
+type StableFilenameIndex = StableBTreeMap<((MapOwner, MapName, FilenameHash), MapKey), (), Memory>;
+type StableChecksumIndex = StableBTreeMap<((MapOwner, MapName, Checksum), MapKey), (), Memory>;
+type StableRecentUploads = StableBTreeMap<(MapId, (u64, MapKey)), (), Memory>;
+type StableCommentMap = StableBTreeMap<(FileKey, u64), Comment, Memory>;
+type StableActivityLog = StableBTreeMap<(MapId, u64), ActivityEntry, Memory>;
+type StableSharingAuditLog = StableBTreeMap<(MapId, u64), SharingAuditEntry, Memory>;
+type StableReadAuditLog = StableBTreeMap<(MapId, u64), ReadAuditEntry, Memory>;
+type StableGrantExpiryIndex = StableBTreeMap<(u64, (MapOwner, MapName, Principal)), (), Memory>;
+type StableExpiryIndex = StableBTreeMap<(u64, (u8, Blob<32>)), ExpiryTarget, Memory>;
+type StableDeletedCollectionMap = StableBTreeMap<DeletedCollectionKey, DeletedCollection, Memory>;
+type StableDeletedCollectionFileMap =
+    StableBTreeMap<(DeletedCollectionKey, MapKey), TrashedFile, Memory>;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
     static ENCRYPTED_MAPS: RefCell<Option<EncryptedMaps<AccessRights>>> =
         const { RefCell::new(None) };
-    static METADATA: RefCell<StableMetadataMap> = RefCell::new(StableBTreeMap::new(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+    static USERS: RefCell<StableUserMap> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+    ));
+    static PRINCIPAL_TO_USERNAME: RefCell<StablePrincipalToUsernameMap> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        ));
+    // MemoryId 4 held the password manager's metadata and is deliberately not reused.
+    static FILE_METADATA: RefCell<StableFileMetadataMap> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+    ));
+    static RESERVED_USERNAMES: RefCell<StableReservedUsernamesCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+            ReservedUsernames::default(),
+        )
+        .expect("failed to initialize reserved usernames"),
+    );
+    static AVATARS: RefCell<StableAvatarMap> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+    ));
+    // (primary principal, linked principal) -> time of linking.
+    static LINKED_PRINCIPALS: RefCell<StableLinkedPrincipalMap> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        ));
+    static CONFIG: RefCell<StableConfigCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+            Config::default(),
+        )
+        .expect("failed to initialize config"),
+    );
+    static PREFERENCES: RefCell<StablePreferenceMap> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+    ));
+    static DISPLAY_NAME_INDEX: RefCell<StableDisplayNameIndex> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        ));
+    // Hour since the epoch -> number of registrations in it, for the last week.
+    static REGISTRATIONS_PER_HOUR: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        ));
+    static COLLECTION_OWNER_COUNT: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))), 0)
+            .expect("failed to initialize collection owner count"),
+    );
+    static COLLECTION_SETTINGS: RefCell<StableCollectionSettingsMap> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        ));
+    // (owner, collection, file) and version number -> replaced content.
+    static FILE_VERSIONS: RefCell<StableFileVersionMap> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+    ));
+    // (owner, collection, file) and time of deletion -> removed file.
+    static TRASH: RefCell<StableTrashMap> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+    ));
+    // The TRASH keys ordered by time of deletion, for purging.
+    static TRASH_BY_DELETION_TIME: RefCell<StableTrashIndex> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        ));
+    // (principal, starred file) -> time of starring.
+    static STARS: RefCell<StableStarMap> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+    ));
+    // The STARS keys ordered by file, for dropping the stars of removed files.
+    static STARS_BY_FILE: RefCell<StableStarIndex> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+    ));
+    static FILE_STATS: RefCell<StableFileStatsMap> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+    ));
+    // Principals that accessed a file, for FileStats::unique_accessors.
+    static FILE_ACCESSORS: RefCell<StableFileAccessorSet> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+    ));
+    // Expiry time and file of every file that has one.
+    static FILE_EXPIRY: RefCell<StableFileExpiryIndex> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+    ));
+    // (owner, collection, hash of the lowercased filename) and file -> ().
+    static FILENAME_INDEX: RefCell<StableFilenameIndex> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+    ));
+    // (owner, collection, plaintext checksum) and file -> ().
+    static CHECKSUM_INDEX: RefCell<StableChecksumIndex> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+    ));
+    // Collection and (creation date, file) of its newest uploads -> ().
+    static RECENT_UPLOADS: RefCell<StableRecentUploads> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+    ));
+    // File and comment id -> comment.
+    static COMMENTS: RefCell<StableCommentMap> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+    ));
+    // Comment id -> file, to find comments by id.
+    static COMMENT_FILES: RefCell<StableBTreeMap<u64, FileKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+        ));
+    static NEXT_COMMENT_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))), 0)
+            .expect("failed to initialize comment ids"),
+    );
+    // File -> manual sort index.
+    static FILE_ORDER: RefCell<StableBTreeMap<FileKey, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+        ));
+    // File -> encrypted thumbnail.
+    static THUMBNAILS: RefCell<StableBTreeMap<FileKey, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))),
+        ));
+    static COLLECTION_STATS: RefCell<StableBTreeMap<MapId, CollectionStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+        ));
+    static COLLECTION_METADATA: RefCell<StableBTreeMap<MapId, CollectionMetadata, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+        ));
+    // Collection and sequence number -> activity entry.
+    static COLLECTION_ACTIVITY: RefCell<StableActivityLog> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))),
+    ));
+    static COLLECTION_PINS: RefCell<StableBTreeMap<Principal, PinnedCollections, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+        ));
+    // (owner, collection, time of deletion) -> deleted collection.
+    static DELETED_COLLECTIONS: RefCell<StableDeletedCollectionMap> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))),
+        ));
+    // Deleted collection and file -> file kept for restoring.
+    static DELETED_COLLECTION_FILES: RefCell<StableDeletedCollectionFileMap> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))),
+        ));
+    // (owner, hidden collection of a shared file) -> share.
+    static FILE_SHARES: RefCell<StableBTreeMap<MapId, FileShare, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))),
+        ));
+    // (owner, collection, grantee) -> when the grant ends.
+    static GRANT_EXPIRY: RefCell<StableBTreeMap<(MapOwner, MapName, Principal), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))),
+        ));
+    // The GRANT_EXPIRY entries ordered by expiry time. Superseded by
+    // EXPIRY_INDEX, into which it is drained on upgrade.
+    static GRANT_EXPIRY_INDEX: RefCell<StableGrantExpiryIndex> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))),
+        ));
+    // Hash of a share link token -> link.
+    static SHARE_LINKS: RefCell<StableBTreeMap<Blob<32>, ShareLinkRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))),
+        ));
+    // (owner, hash of a share link token), for listing an owner's links.
+    static SHARE_LINKS_BY_OWNER: RefCell<StableBTreeMap<(Principal, Blob<32>), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))),
+        ));
+    static INVITATIONS: RefCell<StableBTreeMap<u64, Invitation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))),
+        ));
+    static NEXT_INVITATION_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))), 0)
+            .expect("failed to initialize invitation ids"),
+    );
+    // (inviter, invitation id), for the invitations a user has sent.
+    static INVITATIONS_BY_INVITER: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))),
+        ));
+    // (recipient, invitation id) of invitations waiting for an answer.
+    static PENDING_INVITATIONS_BY_RECIPIENT: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))),
+        ));
+    // Collection and sequence number -> change of a sharing grant.
+    static SHARING_AUDIT: RefCell<StableSharingAuditLog> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48))),
+    ));
+    static ACCESS_REQUESTS: RefCell<StableBTreeMap<u64, AccessRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))),
+        ));
+    static NEXT_ACCESS_REQUEST_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))), 0)
+            .expect("failed to initialize access request ids"),
+    );
+    // (owner, collection, request id) of open access requests.
+    static ACCESS_REQUESTS_BY_COLLECTION: RefCell<StableBTreeMap<(MapOwner, MapName, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))),
+        ));
+    // (requester, request id) of open access requests.
+    static ACCESS_REQUESTS_BY_REQUESTER: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52))),
+        ));
+    // (recipient, sequence number) -> notification.
+    static NOTIFICATIONS: RefCell<StableBTreeMap<(Principal, u64), Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53))),
+        ));
+    // Recipient -> sequence number below which all notifications are read.
+    static NOTIFICATIONS_READ_BEFORE: RefCell<StableBTreeMap<Principal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54))),
+        ));
+    // (owner, collection, grantee) of grantees who may manage sharing.
+    static COLLECTION_MANAGERS: RefCell<StableBTreeMap<(MapOwner, MapName, Principal), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55))),
+        ));
+    // (file, grantee) -> restriction of the grantee on the file.
+    static FILE_OVERRIDES: RefCell<StableBTreeMap<(FileKey, Principal), FileOverride, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56))),
+        ));
+    static GROUPS: RefCell<StableBTreeMap<u64, Group, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57))),
+        ));
+    static NEXT_GROUP_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58))), 0)
+            .expect("failed to initialize group ids"),
+    );
+    // (owner, group id), for listing a user's groups.
+    static GROUPS_BY_OWNER: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59))),
+        ));
+    // (group id, member).
+    static GROUP_MEMBERS: RefCell<StableBTreeMap<(u64, Principal), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60))),
+        ));
+    // (group id, collection of the group's owner) -> rights of the members.
+    static GROUP_GRANTS: RefCell<StableBTreeMap<(u64, MapName), AccessRights, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))),
+        ));
+    // (owner, collection, grantee) of grants made through a group, which
+    // leaving the group revokes again.
+    static GROUP_MADE_GRANTS: RefCell<StableBTreeMap<(MapOwner, MapName, Principal), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62))),
+        ));
+    // (owner, collection, grantee) of the collection's co-owners.
+    static COLLECTION_CO_OWNERS: RefCell<StableBTreeMap<(MapOwner, MapName, Principal), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63))),
+        ));
+    // Collection and sequence number -> read of a file by a grantee.
+    static READ_AUDIT: RefCell<StableReadAuditLog> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64))),
+    ));
+    // (owner, collection, grantee) of grantees who may reshare the collection.
+    static COLLECTION_RESHARERS: RefCell<StableBTreeMap<(MapOwner, MapName, Principal), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65))),
+        ));
+    // (expires_at, kind, id) -> what ends then; see expiry_index.rs.
+    static EXPIRY_INDEX: RefCell<StableExpiryIndex> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66))),
     ));
 }
 
+/// Error of endpoints whose failures the frontend needs to tell apart.
+/// Everything without a dedicated variant is reported as `Other`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApiError {
+    AccountDeactivated,
+    /// The collection already holds a file with the uploaded content.
+    DuplicateContent {
+        file_id: ByteBuf,
+        metadata: Box<FileMetadata>,
+    },
+    /// The file was changed since the caller read the expected revision.
+    /// Carries the current metadata to merge with.
+    Conflict {
+        current: Box<FileMetadata>,
+    },
+    /// The destination collection already holds a file with the given id.
+    FileIdTaken,
+    /// The collection is archived and cannot be changed.
+    CollectionArchived,
+    /// The file is larger than the collection's policy allows.
+    FileTooLarge {
+        max_file_size: u64,
+    },
+    /// The collection's policy does not accept the file's content type.
+    ContentTypeNotAllowed {
+        allowed_content_types: Vec<String>,
+    },
+    /// The collection requires unique filenames and has a file of this name.
+    FilenameTaken {
+        filename: String,
+    },
+    /// The collection is shared with as many principals as allowed.
+    LimitExceeded {
+        current: u32,
+        limit: u32,
+    },
+    Other(String),
+}
+
+impl From<String> for ApiError {
+    fn from(message: String) -> Self {
+        Self::Other(message)
+    }
+}
+
+/// `admin` defaults to the installing principal. `default_collection`
+/// enables creating a "home" collection for every new user.
 #[init]
-fn init(key_name: String) {
+fn init(key_name: String, admin: Option<Principal>, default_collection: Option<bool>) {
+    init_encrypted_maps(key_name);
+    admin::set_admin_principal(admin.unwrap_or_else(ic_cdk::api::msg_caller));
+    admin::set_create_default_collection(default_collection.unwrap_or(false));
+    trash::start_purge_timer();
+    collection_trash::start_collection_purge_timer();
+    expiry::start_expiry_timer();
+    expiry_index::start_expiry_index_timer();
+    access_requests::start_access_request_expiry_timer();
+}
+
+/// Passing `admin` or `default_collection` on upgrade replaces the stored
+/// setting; `null` keeps it.
+#[post_upgrade]
+fn post_upgrade(key_name: String, admin: Option<Principal>, default_collection: Option<bool>) {
+    init_encrypted_maps(key_name);
+    if let Some(admin) = admin {
+        admin::set_admin_principal(admin);
+    }
+    if let Some(default_collection) = default_collection {
+        admin::set_create_default_collection(default_collection);
+    }
+    users::migrate_username_keys();
+    search::backfill_display_name_index();
+    filenames::backfill_filename_index();
+    checksums::backfill_checksum_index();
+    collection_stats::backfill_collection_stats();
+    collections::backfill_collection_metadata();
+    expiry_index::backfill_expiry_index();
+    trash::start_purge_timer();
+    collection_trash::start_collection_purge_timer();
+    expiry::start_expiry_timer();
+    expiry_index::start_expiry_index_timer();
+    access_requests::start_access_request_expiry_timer();
+}
+
+fn init_encrypted_maps(key_name: String) {
     let key_id = VetKDKeyId {
         curve: VetKDCurve::Bls12_381_G2,
         name: key_name,
@@ -126,41 +537,6 @@ fn get_shared_user_access_for_map(
     })
 }
 
-#[query]
-fn get_encrypted_values_for_map_with_metadata(
-    map_owner: Principal,
-    map_name: ByteBuf,
-) -> Result<Vec<(ByteBuf, EncryptedMapValue, PasswordMetadata)>, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
-    let map_id = (map_owner, map_name);
-    let encrypted_values_result = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
-        encrypted_maps
-            .as_ref()
-            .unwrap()
-            .get_encrypted_values_for_map(ic_cdk::api::msg_caller(), map_id)
-    });
-    encrypted_values_result.map(|map_values| {
-        METADATA.with_borrow(|metadata| {
-            let iter_metadata = metadata
-                .range((map_owner, map_name, Blob::default())..)
-                .take_while(|((owner, name, _), _)| owner == &map_owner && name == &map_name)
-                .map(|((_, _, key), metadata)| (key, metadata));
-
-            iter_metadata
-                .zip(map_values)
-                .map(|((key_left, metadata), (key_right, encrypted_value))| {
-                    debug_assert_eq!(key_left, key_right);
-                    (
-                        EncryptedMapValue::from(key_left.as_slice().to_vec()),
-                        encrypted_value,
-                        metadata,
-                    )
-                })
-                .collect()
-        })
-    })
-}
-
 #[query]
 fn get_owned_non_empty_map_names() -> Vec<ByteBuf> {
     ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
@@ -174,60 +550,6 @@ fn get_owned_non_empty_map_names() -> Vec<ByteBuf> {
     })
 }
 
-#[update]
-fn insert_encrypted_value_with_metadata(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    map_key: ByteBuf,
-    value: EncryptedMapValue,
-    tags: Vec<String>,
-    url: String,
-) -> Result<Option<(EncryptedMapValue, PasswordMetadata)>, String> {
-    let caller = ic_cdk::api::msg_caller();
-    let map_name = bytebuf_to_blob(map_name)?;
-    let map_id = (map_owner, map_name);
-    let map_key = bytebuf_to_blob(map_key)?;
-    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-        encrypted_maps
-            .as_mut()
-            .unwrap()
-            .insert_encrypted_value(caller, map_id, map_key, value)
-            .map(|opt_prev_value| {
-                METADATA.with_borrow_mut(|metadata| {
-                    let metadata_key = (map_owner, map_name, map_key);
-                    let metadata_value = metadata
-                        .get(&metadata_key)
-                        .map(|m| m.update(caller, tags.clone(), url.clone()))
-                        .unwrap_or(PasswordMetadata::new(caller, tags, url));
-                    opt_prev_value.zip(metadata.insert(metadata_key, metadata_value))
-                })
-            })
-    })
-}
-
-#[update]
-fn remove_encrypted_value_with_metadata(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    map_key: ByteBuf,
-) -> Result<Option<(EncryptedMapValue, PasswordMetadata)>, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
-    let map_id = (map_owner, map_name);
-    let map_key = bytebuf_to_blob(map_key)?;
-    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-        encrypted_maps
-            .as_mut()
-            .unwrap()
-            .remove_encrypted_value(ic_cdk::api::msg_caller(), map_id, map_key)
-            .map(|opt_prev_value| {
-                METADATA.with_borrow_mut(|metadata| {
-                    let metadata_key = (map_owner, map_name, map_key);
-                    opt_prev_value.zip(metadata.remove(&metadata_key))
-                })
-            })
-    })
-}
-
 #[update]
 async fn get_vetkey_verification_key() -> VetKeyVerificationKey {
     ENCRYPTED_MAPS
@@ -245,16 +567,17 @@ async fn get_encrypted_vetkey(
     map_owner: Principal,
     map_name: ByteBuf,
     transport_key: TransportKey,
-) -> Result<VetKey, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
+) -> Result<VetKey, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    users::ensure_active(caller)?;
+    let map_name = collection_name_to_blob(map_name)?;
     let map_id = (map_owner, map_name);
     Ok(ENCRYPTED_MAPS
         .with_borrow(|encrypted_maps| {
-            encrypted_maps.as_ref().unwrap().get_encrypted_vetkey(
-                ic_cdk::api::msg_caller(),
-                map_id,
-                transport_key,
-            )
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_encrypted_vetkey(caller, map_id, transport_key)
         })?
         .await)
 }
@@ -265,7 +588,7 @@ fn get_user_rights(
     map_name: ByteBuf,
     user: Principal,
 ) -> Result<Option<AccessRights>, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
+    let map_name = collection_name_to_blob(map_name)?;
     let map_id = (map_owner, map_name);
     ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
         encrypted_maps
@@ -275,43 +598,55 @@ fn get_user_rights(
     })
 }
 
-#[update]
-fn set_user_rights(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    user: Principal,
-    access_rights: AccessRights,
-) -> Result<Option<AccessRights>, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
-    let map_id = (map_owner, map_name);
-    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-        encrypted_maps.as_mut().unwrap().set_user_rights(
-            ic_cdk::api::msg_caller(),
-            map_id,
-            user,
-            access_rights,
-        )
-    })
+/// Returns the caller's rights on the collection, failing if the caller has
+/// no access at all. The owner always holds `ReadWriteManage`.
+fn caller_rights(caller: Principal, map_id: MapId) -> Result<AccessRights, String> {
+    ENCRYPTED_MAPS
+        .with_borrow(|encrypted_maps| {
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_user_rights(caller, map_id, caller)
+        })?
+        .ok_or_else(|| "unauthorized".to_string())
 }
 
-#[update]
-fn remove_user(
-    map_owner: Principal,
-    map_name: ByteBuf,
-    user: Principal,
-) -> Result<Option<AccessRights>, String> {
-    let map_name = bytebuf_to_blob(map_name)?;
-    let map_id = (map_owner, map_name);
-    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-        encrypted_maps
-            .as_mut()
-            .unwrap()
-            .remove_user(ic_cdk::api::msg_caller(), map_id, user)
-    })
+fn ensure_can_write(caller: Principal, map_id: MapId) -> Result<(), String> {
+    if caller_rights(caller, map_id)?.can_write() {
+        Ok(())
+    } else {
+        Err("unauthorized".to_string())
+    }
+}
+
+/// Unwraps the result of a write that follows other writes of the same
+/// call. Returning the error would keep the earlier writes, while a trap
+/// rolls the whole call back.
+fn or_trap<T>(operation: &str, result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| ic_cdk::trap(format!("{operation} failed: {e}")))
 }
 
 fn bytebuf_to_blob(buf: ByteBuf) -> Result<Blob<32>, String> {
     Blob::try_from(buf.as_ref()).map_err(|_| "too large input".to_string())
 }
 
+/// Maps a collection name to its key. Names of up to 32 bytes are their own
+/// key, as before; longer UTF-8 names are keyed by their SHA-256 hash, and
+/// the full name is kept in the collection's metadata.
+fn collection_name_to_blob(name: ByteBuf) -> Result<MapName, String> {
+    if name.as_ref().len() <= 32 {
+        return bytebuf_to_blob(name);
+    }
+    if name.as_ref().len() > MAX_COLLECTION_NAME_LEN {
+        return Err(format!(
+            "collection names must be at most {MAX_COLLECTION_NAME_LEN} bytes"
+        ));
+    }
+    if std::str::from_utf8(name.as_ref()).is_err() {
+        return Err("collection names longer than 32 bytes must be UTF-8".to_string());
+    }
+    let hash: [u8; 32] = Sha256::digest(name.as_ref()).into();
+    Ok(Blob::try_from(hash.as_slice()).unwrap())
+}
+
 ic_cdk::export_candid!();
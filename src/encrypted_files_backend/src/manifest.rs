@@ -0,0 +1,239 @@
+//! Export and import of a collection's file metadata as JSON lines, for
+//! backups and migrations. The encrypted contents are not part of the
+//! manifest.
+
+use crate::collections::ensure_not_archived;
+use crate::file_overrides::{ensure_may_modify, is_hidden_from};
+use crate::filenames::ensure_unique_filename;
+use crate::files::{store_metadata, validate_filename, FileMetadata, FileMetadataInput};
+use crate::users::record_activity;
+use crate::{
+    bytebuf_to_blob, caller_rights, collection_name_to_blob, ensure_can_write, ApiError, MapId,
+    MapKey, ENCRYPTED_MAPS, FILE_METADATA,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::ops::Bound as RangeBound;
+
+/// Size a manifest page stops growing at, well below the response limit.
+const MAX_MANIFEST_PAGE_BYTES: usize = 1_000_000;
+/// Upper bound on metadata entries inspected per export call, and on lines
+/// accepted per import call.
+const MAX_MANIFEST_SCAN: usize = 5_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ManifestPage {
+    /// One JSON object per file and line, in file id order.
+    pub lines: ByteBuf,
+    /// Pass as `cursor` to get the next page; `null` on the last page.
+    pub next_cursor: Option<ByteBuf>,
+}
+
+/// Outcome of importing a manifest page. Lines are numbered from 1; blank
+/// lines are ignored.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ImportReport {
+    pub updated: Vec<u32>,
+    /// Lines naming files the collection does not hold.
+    pub unknown_files: Vec<u32>,
+    /// Lines that could not be parsed or whose metadata was rejected.
+    pub failed: Vec<(u32, String)>,
+}
+
+/// A manifest line. Fields are serialized in declaration order, so the same
+/// metadata always yields the same bytes.
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    file_id: String,
+    filename: String,
+    content_type: String,
+    file_size: u64,
+    tags: Vec<String>,
+    description: String,
+    checksum: Option<String>,
+    creation_date: u64,
+    last_modification_date: u64,
+    expires_at: Option<u64>,
+    /// Absent from manifests exported before revisions existed.
+    #[serde(default)]
+    revision: u64,
+}
+
+/// A page of the manifest of a collection, starting after the file
+/// `cursor`. Requires read access to the collection.
+#[query]
+fn export_collection_manifest(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    cursor: Option<ByteBuf>,
+) -> Result<ManifestPage, String> {
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    let caller = ic_cdk::api::msg_caller();
+    caller_rights(caller, map_id)?;
+    let start = match cursor.map(bytebuf_to_blob).transpose()? {
+        Some(file_id) => RangeBound::Excluded((collection_owner, collection_name, file_id)),
+        None => RangeBound::Included((collection_owner, collection_name, Blob::default())),
+    };
+
+    let now = ic_cdk::api::time();
+    let mut lines = Vec::new();
+    let mut last_scanned: Option<MapKey> = None;
+    let mut next_cursor = None;
+    FILE_METADATA.with_borrow(|file_metadata| {
+        for (scanned, ((owner, name, file_id), metadata)) in file_metadata
+            .range((start, RangeBound::Unbounded))
+            .enumerate()
+        {
+            if (owner, name) != map_id {
+                break;
+            }
+            if scanned == MAX_MANIFEST_SCAN {
+                next_cursor = last_scanned;
+                break;
+            }
+            if !metadata.is_expired(now) && !is_hidden_from((owner, name, file_id), caller) {
+                let line = manifest_line(file_id, &metadata);
+                if !lines.is_empty() && lines.len() + line.len() > MAX_MANIFEST_PAGE_BYTES {
+                    next_cursor = last_scanned;
+                    break;
+                }
+                lines.extend(line);
+            }
+            last_scanned = Some(file_id);
+        }
+    });
+    Ok(ManifestPage {
+        lines: ByteBuf::from(lines),
+        next_cursor: next_cursor.map(|file_id| ByteBuf::from(file_id.as_slice().to_vec())),
+    })
+}
+
+/// Applies the filename, content type, description, tags and expiry of each
+/// line of an exported manifest page to the file with the same id, e.g.
+/// after re-uploading a collection's contents to a new deployment. Lines for
+/// files without stored content, or hidden from the caller, are skipped,
+/// never created. With `check_revisions`, a line fails if its file has
+/// changed since the export, so edits made in the meantime are not
+/// overwritten; leave it unset when importing into another deployment,
+/// where revisions start over. Requires write access to the collection.
+#[update]
+fn import_collection_manifest(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    manifest_page: ByteBuf,
+    check_revisions: bool,
+) -> Result<ImportReport, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    ensure_can_write(caller, map_id)?;
+    ensure_not_archived(map_id)?;
+    let manifest_page = std::str::from_utf8(manifest_page.as_ref())
+        .map_err(|_| "manifest page is not valid UTF-8".to_string())?;
+    if manifest_page.lines().count() > MAX_MANIFEST_SCAN {
+        return Err(format!("at most {MAX_MANIFEST_SCAN} lines per call").into());
+    }
+
+    let mut report = ImportReport::default();
+    for (line_number, line) in (1..).zip(manifest_page.lines()) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match import_line(caller, map_id, line, check_revisions) {
+            Ok(true) => report.updated.push(line_number),
+            Ok(false) => report.unknown_files.push(line_number),
+            Err(err) => report.failed.push((line_number, err)),
+        }
+    }
+    Ok(report)
+}
+
+/// Applies one manifest line. Returns whether the file exists.
+fn import_line(
+    caller: Principal,
+    map_id: MapId,
+    line: &str,
+    check_revisions: bool,
+) -> Result<bool, String> {
+    let entry: ManifestEntry =
+        serde_json::from_str(line).map_err(|err| format!("invalid line: {err}"))?;
+    let file_id = bytebuf_to_blob(ByteBuf::from(from_hex(&entry.file_id)?))?;
+    let has_content = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_encrypted_value(caller, map_id, file_id)
+    })?;
+    let file_key = (map_id.0, map_id.1, file_id);
+    let existing = FILE_METADATA.with_borrow(|file_metadata| file_metadata.get(&file_key));
+    let Some(existing) = existing.filter(|_| has_content.is_some()) else {
+        return Ok(false);
+    };
+    if is_hidden_from(file_key, caller) {
+        return Ok(false);
+    }
+    ensure_may_modify(file_key, caller)?;
+    if check_revisions && existing.revision != entry.revision {
+        return Err(format!(
+            "file has changed since the export (revision {} instead of {})",
+            existing.revision, entry.revision
+        ));
+    }
+    let input = FileMetadataInput {
+        filename: entry.filename,
+        content_type: entry.content_type,
+        file_size: existing.file_size,
+        description: entry.description,
+        tags: entry.tags,
+        custom: None,
+        expires_at: entry.expires_at,
+    };
+    input.validate()?;
+    validate_filename(&input.filename)?;
+    let updated = existing.update(caller, input);
+    ensure_unique_filename(file_key, &updated.filename)?;
+    store_metadata(file_key, updated);
+    Ok(true)
+}
+
+fn manifest_line(file_id: MapKey, metadata: &FileMetadata) -> Vec<u8> {
+    let entry = ManifestEntry {
+        file_id: to_hex(file_id.as_slice()),
+        filename: metadata.filename.clone(),
+        content_type: metadata.content_type.clone(),
+        file_size: metadata.file_size,
+        tags: metadata.tags.clone(),
+        description: metadata.description.clone(),
+        checksum: metadata.checksum.map(|checksum| to_hex(&checksum)),
+        creation_date: metadata.creation_date,
+        last_modification_date: metadata.last_modification_date,
+        expires_at: metadata.expires_at,
+        revision: metadata.revision,
+    };
+    let mut line = serde_json::to_vec(&entry).expect("failed to serialize");
+    line.push(b'\n');
+    line
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 != 0 {
+        return Err("file_id must be hex-encoded".to_string());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| "file_id must be hex-encoded".to_string())
+        })
+        .collect()
+}
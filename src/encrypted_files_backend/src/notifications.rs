@@ -0,0 +1,180 @@
+//! Per-user inbox of sharing events, so that users notice new shares,
+//! invitations and access requests without polling. Notifications are
+//! numbered per recipient, and only the newest [`MAX_NOTIFICATIONS`] are
+//! kept.
+
+use crate::collections::collection_metadata_or_default;
+use crate::{collection_name_to_blob, MapId, NOTIFICATIONS, NOTIFICATIONS_READ_BEFORE};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{AccessRights, ByteBuf};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const MAX_NOTIFICATIONS: usize = 200;
+const MAX_NOTIFICATION_PAGE_LIMIT: u32 = 100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum NotificationKind {
+    /// A collection was shared with the recipient, or their rights changed.
+    Shared {
+        access_rights: AccessRights,
+    },
+    Unshared,
+    /// The recipient's time-limited access ended.
+    AccessExpired,
+    Invited {
+        invitation_id: u64,
+    },
+    InvitationCancelled {
+        invitation_id: u64,
+    },
+    InvitationAccepted {
+        invitation_id: u64,
+    },
+    InvitationDeclined {
+        invitation_id: u64,
+    },
+    AccessRequested {
+        request_id: u64,
+    },
+    AccessRequestApproved {
+        access_rights: AccessRights,
+    },
+    AccessRequestDenied,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Notification {
+    pub seq: u64,
+    pub kind: NotificationKind,
+    pub collection_owner: Principal,
+    pub collection_name: ByteBuf,
+    pub actor: Principal,
+    pub timestamp: u64,
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The caller's notifications after `after_seq`, oldest first.
+#[query]
+fn list_notifications(after_seq: Option<u64>, limit: u32) -> Vec<Notification> {
+    let caller = ic_cdk::api::msg_caller();
+    let start = after_seq.map_or(0, |after_seq| after_seq.saturating_add(1));
+    let limit = limit.min(MAX_NOTIFICATION_PAGE_LIMIT) as usize;
+    NOTIFICATIONS.with_borrow(|notifications| {
+        notifications
+            .range((caller, start)..)
+            .take_while(|((recipient, _), _)| recipient == &caller)
+            .take(limit)
+            .map(|(_, notification)| notification)
+            .collect()
+    })
+}
+
+/// Marks the caller's notifications up to and including `up_to_seq` read.
+#[update]
+fn mark_notifications_read(up_to_seq: u64) {
+    let caller = ic_cdk::api::msg_caller();
+    let read_before = up_to_seq.saturating_add(1);
+    NOTIFICATIONS_READ_BEFORE.with_borrow_mut(|read| {
+        if read
+            .get(&caller)
+            .map_or(true, |previous| previous < read_before)
+        {
+            read.insert(caller, read_before);
+        }
+    });
+}
+
+#[query]
+fn get_unread_notification_count() -> u32 {
+    let caller = ic_cdk::api::msg_caller();
+    let read_before =
+        NOTIFICATIONS_READ_BEFORE.with_borrow(|read| read.get(&caller).unwrap_or_default());
+    NOTIFICATIONS.with_borrow(|notifications| {
+        notifications
+            .range((caller, read_before)..)
+            .take_while(|((recipient, _), _)| recipient == &caller)
+            .count() as u32
+    })
+}
+
+/// Adds a notification about a collection to `recipient`'s inbox, pruning
+/// the oldest beyond [`MAX_NOTIFICATIONS`]. Nothing is sent to the
+/// anonymous principal or to the actor themselves.
+pub fn notify(recipient: Principal, actor: Principal, map_id: MapId, kind: NotificationKind) {
+    if recipient == Principal::anonymous() || recipient == actor {
+        return;
+    }
+    let seqs: Vec<u64> = NOTIFICATIONS.with_borrow(|notifications| {
+        notifications
+            .range((recipient, 0)..)
+            .take_while(|((key, _), _)| key == &recipient)
+            .map(|((_, seq), _)| seq)
+            .collect()
+    });
+    let seq = seqs.last().map_or(0, |last| last + 1);
+    let notification = Notification {
+        seq,
+        kind,
+        collection_owner: map_id.0,
+        collection_name: collection_metadata_or_default(map_id).name,
+        actor,
+        timestamp: ic_cdk::api::time(),
+    };
+    NOTIFICATIONS.with_borrow_mut(|notifications| {
+        notifications.insert((recipient, seq), notification);
+        let surplus = (seqs.len() + 1).saturating_sub(MAX_NOTIFICATIONS);
+        for seq in &seqs[..surplus] {
+            notifications.remove(&(recipient, *seq));
+        }
+    });
+}
+
+/// Removes `recipient`'s notifications about a collection, e.g. when they
+/// leave it.
+pub fn remove_collection_notifications(recipient: Principal, map_id: MapId) {
+    NOTIFICATIONS.with_borrow_mut(|notifications| {
+        let keys: Vec<(Principal, u64)> = notifications
+            .range((recipient, 0)..)
+            .take_while(|((key, _), _)| key == &recipient)
+            .filter(|(_, notification)| {
+                notification.collection_owner == map_id.0
+                    && collection_name_to_blob(notification.collection_name.clone())
+                        .is_ok_and(|name| name == map_id.1)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            notifications.remove(&key);
+        }
+    });
+}
+
+/// Removes `user`'s inbox, e.g. with their account.
+pub fn remove_notifications(user: Principal) {
+    NOTIFICATIONS.with_borrow_mut(|notifications| {
+        let keys: Vec<(Principal, u64)> = notifications
+            .range((user, 0)..)
+            .take_while(|((recipient, _), _)| recipient == &user)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            notifications.remove(&key);
+        }
+    });
+    NOTIFICATIONS_READ_BEFORE.with_borrow_mut(|read| read.remove(&user));
+}
@@ -0,0 +1,104 @@
+//! Manual file order within a collection, e.g. for playlists. Each file can
+//! have a sort index in a side map; gaps left by deleted files are fine, so
+//! nothing is renumbered when a file goes away.
+
+use crate::collections::ensure_not_archived;
+use crate::users::record_activity;
+use crate::versions::FileKey;
+use crate::{
+    bytebuf_to_blob, collection_name_to_blob, ensure_can_write, ApiError, MapId, MapKey,
+    FILE_METADATA, FILE_ORDER,
+};
+use candid::Principal;
+use ic_cdk::update;
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::ByteBuf;
+use std::collections::BTreeMap;
+
+const MAX_ORDERED_FILES: usize = 1_000;
+
+/// Replaces the manual order of a collection: the files in `ordered_ids`
+/// get sort indices in list order and all other files lose theirs. Requires
+/// write access to the collection.
+#[update]
+fn set_file_order(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    ordered_ids: Vec<ByteBuf>,
+) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    if ordered_ids.len() > MAX_ORDERED_FILES {
+        return Err(format!("at most {MAX_ORDERED_FILES} files can be ordered").into());
+    }
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    ensure_can_write(caller, map_id)?;
+    ensure_not_archived(map_id)?;
+    let mut order = BTreeMap::new();
+    for (sort_index, file_id) in (0..).zip(ordered_ids) {
+        let file_id = bytebuf_to_blob(file_id)?;
+        let file_key = (collection_owner, collection_name, file_id);
+        if !FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+            return Err("file not found".to_string().into());
+        }
+        if order.insert(file_id, sort_index).is_some() {
+            return Err("file ids must be unique".to_string().into());
+        }
+    }
+
+    remove_collection_order(map_id);
+    FILE_ORDER.with_borrow_mut(|file_order| {
+        for (file_id, sort_index) in order {
+            file_order.insert((collection_owner, collection_name, file_id), sort_index);
+        }
+    });
+    Ok(())
+}
+
+pub fn sort_index(file_key: FileKey) -> Option<u32> {
+    FILE_ORDER.with_borrow(|file_order| file_order.get(&file_key))
+}
+
+/// Sort indices of the files of a collection.
+pub fn collection_order(map_id: MapId) -> BTreeMap<MapKey, u32> {
+    let (owner, name) = map_id;
+    FILE_ORDER.with_borrow(|file_order| {
+        file_order
+            .range((owner, name, Blob::default())..)
+            .take_while(|((key_owner, key_name, _), _)| (*key_owner, *key_name) == map_id)
+            .map(|((_, _, file_id), sort_index)| (file_id, sort_index))
+            .collect()
+    })
+}
+
+pub fn move_sort_index(from: FileKey, to: FileKey) {
+    if let Some(sort_index) = FILE_ORDER.with_borrow_mut(|file_order| file_order.remove(&from)) {
+        FILE_ORDER.with_borrow_mut(|file_order| file_order.insert(to, sort_index));
+    }
+}
+
+pub fn remove_sort_index(file_key: FileKey) {
+    FILE_ORDER.with_borrow_mut(|file_order| file_order.remove(&file_key));
+}
+
+/// Removes the sort indices of all collections of `owner`.
+pub fn remove_owned_file_order(owner: Principal) {
+    FILE_ORDER.with_borrow_mut(|file_order| {
+        let keys: Vec<FileKey> = file_order
+            .range((owner, Blob::default(), Blob::default())..)
+            .take_while(|((key_owner, _, _), _)| key_owner == &owner)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            file_order.remove(&key);
+        }
+    });
+}
+
+fn remove_collection_order(map_id: MapId) {
+    let (owner, name) = map_id;
+    for file_id in collection_order(map_id).into_keys() {
+        remove_sort_index((owner, name, file_id));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::users::{caller_user, record_activity};
+use crate::PREFERENCES;
+use candid::Principal;
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Blob;
+
+const MAX_PREFERENCES_PER_USER: usize = 50;
+const MAX_PREFERENCE_KEY_LEN: usize = 64;
+const MAX_PREFERENCE_VALUE_LEN: usize = 1024;
+
+pub type PreferenceKey = Blob<MAX_PREFERENCE_KEY_LEN>;
+
+/// Stores a small client setting for the caller's account. Preferences are
+/// kept per account rather than per principal, so linked devices share them.
+#[update]
+fn set_preference(key: String, value: String) -> Result<(), String> {
+    let owner = caller_user()?.principal;
+    record_activity(ic_cdk::api::msg_caller());
+    store_preference(owner, key, value)
+}
+
+fn store_preference(owner: Principal, key: String, value: String) -> Result<(), String> {
+    if key.is_empty() || key.len() > MAX_PREFERENCE_KEY_LEN {
+        return Err(format!(
+            "preference key must be 1 to {MAX_PREFERENCE_KEY_LEN} bytes"
+        ));
+    }
+    if value.len() > MAX_PREFERENCE_VALUE_LEN {
+        return Err(format!(
+            "preference value must be at most {MAX_PREFERENCE_VALUE_LEN} bytes"
+        ));
+    }
+    let preference_key = to_preference_key(&key);
+    let exists =
+        PREFERENCES.with_borrow(|preferences| preferences.contains_key(&(owner, preference_key)));
+    if !exists && preference_keys(owner).len() >= MAX_PREFERENCES_PER_USER {
+        return Err(format!(
+            "at most {MAX_PREFERENCES_PER_USER} preferences can be stored"
+        ));
+    }
+    PREFERENCES.with_borrow_mut(|preferences| preferences.insert((owner, preference_key), value));
+    Ok(())
+}
+
+/// Removes a preference. Returns whether it existed.
+#[update]
+fn remove_preference(key: String) -> Result<bool, String> {
+    let owner = caller_user()?.principal;
+    if key.len() > MAX_PREFERENCE_KEY_LEN {
+        return Ok(false);
+    }
+    Ok(PREFERENCES
+        .with_borrow_mut(|preferences| preferences.remove(&(owner, to_preference_key(&key))))
+        .is_some())
+}
+
+#[query]
+fn get_preferences() -> Result<Vec<(String, String)>, String> {
+    let owner = caller_user()?.principal;
+    Ok(PREFERENCES.with_borrow(|preferences| {
+        preferences
+            .range((owner, PreferenceKey::default())..)
+            .take_while(|((principal, _), _)| principal == &owner)
+            .map(|((_, key), value)| (String::from_utf8_lossy(key.as_slice()).into_owned(), value))
+            .collect()
+    }))
+}
+
+pub fn remove_preferences(owner: Principal) {
+    let keys = preference_keys(owner);
+    PREFERENCES.with_borrow_mut(|preferences| {
+        for key in keys {
+            preferences.remove(&(owner, key));
+        }
+    });
+}
+
+fn preference_keys(owner: Principal) -> Vec<PreferenceKey> {
+    PREFERENCES.with_borrow(|preferences| {
+        preferences
+            .range((owner, PreferenceKey::default())..)
+            .take_while(|((principal, _), _)| principal == &owner)
+            .map(|((_, key), _)| key)
+            .collect()
+    })
+}
+
+fn to_preference_key(key: &str) -> PreferenceKey {
+    PreferenceKey::try_from(key.as_bytes()).expect("preference key length is checked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(owner: Principal) -> Vec<(PreferenceKey, String)> {
+        let mut stored: Vec<(PreferenceKey, String)> = preference_keys(owner)
+            .into_iter()
+            .filter_map(|key| {
+                PREFERENCES
+                    .with_borrow(|preferences| preferences.get(&(owner, key)))
+                    .map(|value| (key, value))
+            })
+            .collect();
+        stored.sort_by(|a, b| a.0.as_slice().cmp(b.0.as_slice()));
+        stored
+    }
+
+    #[test]
+    fn stores_distinct_keys() {
+        let owner = Principal::from_slice(&[1]);
+        store_preference(owner, "locale".to_string(), "de".to_string()).unwrap();
+        store_preference(owner, "sort".to_string(), "name".to_string()).unwrap();
+        assert_eq!(
+            stored(owner),
+            vec![
+                (to_preference_key("locale"), "de".to_string()),
+                (to_preference_key("sort"), "name".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn overwrites_existing_keys_at_the_limit() {
+        let owner = Principal::from_slice(&[1]);
+        for i in 0..MAX_PREFERENCES_PER_USER {
+            store_preference(owner, format!("key{i}"), String::new()).unwrap();
+        }
+        assert!(store_preference(owner, "one_more".to_string(), String::new()).is_err());
+        store_preference(owner, "key0".to_string(), "updated".to_string()).unwrap();
+        assert_eq!(stored(owner).len(), MAX_PREFERENCES_PER_USER);
+    }
+}
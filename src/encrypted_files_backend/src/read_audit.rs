@@ -0,0 +1,160 @@
+//! Log of which grantees fetched the encrypted files of a collection, for
+//! collections whose owner turned on `audit_reads`. Query calls cannot
+//! persist anything, so while auditing is on the query endpoints that serve
+//! encrypted values refuse grantees, who have to call their `_update` twins
+//! instead; those log each file they serve. The owner's own reads are not
+//! logged. Like the sharing audit log, the log survives the deletion of the
+//! collection. Entries are numbered per collection, and only the newest
+//! [`MAX_READ_AUDIT_ENTRIES`] are kept.
+
+use crate::collections::collection_settings;
+use crate::{collection_name_to_blob, MapId, MapKey, READ_AUDIT};
+use candid::{CandidType, Principal};
+use ic_cdk::query;
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const MAX_READ_AUDIT_ENTRIES: usize = 1_000;
+const MAX_READ_AUDIT_PAGE_LIMIT: u32 = 100;
+
+/// How a response carrying encrypted values was served.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadMode {
+    Query,
+    /// Through an update call, which could log the read.
+    Update,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReadAuditEntry {
+    pub seq: u64,
+    pub principal: Principal,
+    pub file_id: ByteBuf,
+    pub timestamp: u64,
+}
+
+impl Storable for ReadAuditEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The logged reads of one of the caller's collections, newest first,
+/// starting below `before_seq`.
+#[query]
+fn get_read_audit_log(
+    collection_name: ByteBuf,
+    before_seq: Option<u64>,
+    limit: u32,
+) -> Result<Vec<ReadAuditEntry>, String> {
+    let map_id = (
+        ic_cdk::api::msg_caller(),
+        collection_name_to_blob(collection_name)?,
+    );
+    let before_seq = before_seq.unwrap_or(u64::MAX);
+    let limit = limit.min(MAX_READ_AUDIT_PAGE_LIMIT) as usize;
+    let mut entries: Vec<ReadAuditEntry> = READ_AUDIT.with_borrow(|audit| {
+        audit
+            .range((map_id, 0)..(map_id, before_seq))
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    entries.reverse();
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+/// Whether reads of the collection `map_id` by `reader` have to be logged.
+pub fn audits_reads(map_id: MapId, reader: Principal) -> bool {
+    reader != map_id.0 && collection_settings(map_id).audit_reads
+}
+
+/// Fails for query calls that would serve encrypted values of a collection
+/// whose reads by `reader` are audited.
+pub fn ensure_unaudited_query(map_id: MapId, reader: Principal) -> Result<(), String> {
+    if audits_reads(map_id, reader) {
+        return Err(
+            "reads of this collection are audited; call the _update variant of this endpoint"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Logs that `reader` was served a file of the collection `map_id`, if its
+/// reads are audited, pruning the oldest entries beyond
+/// [`MAX_READ_AUDIT_ENTRIES`].
+pub fn log_read(map_id: MapId, reader: Principal, file_id: MapKey) {
+    if !audits_reads(map_id, reader) {
+        return;
+    }
+    let seqs = audit_seqs(map_id);
+    let seq = seqs.last().map_or(0, |last| last + 1);
+    let entry = ReadAuditEntry {
+        seq,
+        principal: reader,
+        file_id: ByteBuf::from(file_id.as_slice().to_vec()),
+        timestamp: ic_cdk::api::time(),
+    };
+    READ_AUDIT.with_borrow_mut(|audit| {
+        audit.insert((map_id, seq), entry);
+        let surplus = (seqs.len() + 1).saturating_sub(MAX_READ_AUDIT_ENTRIES);
+        for seq in &seqs[..surplus] {
+            audit.remove(&(map_id, *seq));
+        }
+    });
+}
+
+/// Moves the read log of a collection when it is renamed, appending to and
+/// renumbering any log left at the new name by a deleted collection.
+pub fn move_read_audit_log(from: MapId, to: MapId) {
+    let from_seqs = audit_seqs(from);
+    let mut next_seq = audit_seqs(to).last().map_or(0, |last| last + 1);
+    for seq in from_seqs {
+        if let Some(entry) = READ_AUDIT.with_borrow_mut(|audit| audit.remove(&(from, seq))) {
+            READ_AUDIT.with_borrow_mut(|audit| {
+                audit.insert(
+                    (to, next_seq),
+                    ReadAuditEntry {
+                        seq: next_seq,
+                        ..entry
+                    },
+                )
+            });
+            next_seq += 1;
+        }
+    }
+}
+
+/// Removes the read logs of all collections of `owner`, with their account.
+pub fn remove_owned_read_audit_logs(owner: Principal) {
+    READ_AUDIT.with_borrow_mut(|audit| {
+        let keys: Vec<(MapId, u64)> = audit
+            .range(((owner, Blob::default()), 0)..)
+            .take_while(|(((map_owner, _), _), _)| map_owner == &owner)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            audit.remove(&key);
+        }
+    });
+}
+
+fn audit_seqs(map_id: MapId) -> Vec<u64> {
+    READ_AUDIT.with_borrow(|audit| {
+        audit
+            .range((map_id, 0)..)
+            .take_while(|((key, _), _)| key == &map_id)
+            .map(|((_, seq), _)| seq)
+            .collect()
+    })
+}
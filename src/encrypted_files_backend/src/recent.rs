@@ -0,0 +1,127 @@
+//! The newest uploads of each collection, kept in a small per-collection
+//! buffer so the recent-files feed does not scan every collection.
+
+use crate::file_overrides::is_hidden_from;
+use crate::files::FileMetadata;
+use crate::versions::FileKey;
+use crate::{MapId, MapKey, ENCRYPTED_MAPS, FILE_METADATA, RECENT_UPLOADS};
+use candid::Principal;
+use ic_cdk::query;
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::ByteBuf;
+use std::cmp::Reverse;
+
+/// Uploads kept per collection, and the most files the feed returns.
+const RECENT_UPLOADS_PER_COLLECTION: usize = 20;
+
+/// The newest files by creation date across the collections the caller
+/// owns or has been granted access to. Files hidden from the caller are
+/// left out.
+#[query]
+fn get_recent_files(limit: u32) -> Vec<(Principal, ByteBuf, ByteBuf, FileMetadata)> {
+    let caller = ic_cdk::api::msg_caller();
+    let limit = (limit as usize).min(RECENT_UPLOADS_PER_COLLECTION);
+    let accessible: Vec<MapId> = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_ref().unwrap();
+        encrypted_maps
+            .get_owned_non_empty_map_names(caller)
+            .into_iter()
+            .map(|map_name| (caller, map_name))
+            .chain(encrypted_maps.get_accessible_shared_map_names(caller))
+            .collect()
+    });
+
+    let now = ic_cdk::api::time();
+    let mut recent: Vec<(u64, FileKey, FileMetadata)> = accessible
+        .into_iter()
+        .flat_map(recent_uploads)
+        .filter_map(|(created_at, file_key)| {
+            // Entries of files that were deleted, or deleted and uploaded
+            // again under the same id, no longer match the metadata.
+            let metadata = FILE_METADATA
+                .with_borrow(|file_metadata| file_metadata.get(&file_key))
+                .filter(|metadata| metadata.creation_date == created_at)
+                .filter(|metadata| !metadata.is_expired(now))?;
+            if is_hidden_from(file_key, caller) {
+                return None;
+            }
+            Some((created_at, file_key, metadata))
+        })
+        .collect();
+    recent.sort_by_key(|(created_at, file_key, _)| Reverse((*created_at, *file_key)));
+    recent
+        .into_iter()
+        .take(limit)
+        .map(|(_, (owner, name, file_id), metadata)| {
+            (
+                owner,
+                ByteBuf::from(name.as_slice().to_vec()),
+                ByteBuf::from(file_id.as_slice().to_vec()),
+                metadata,
+            )
+        })
+        .collect()
+}
+
+/// Adds an upload to its collection's buffer, dropping the oldest entry
+/// once the buffer is full.
+pub fn record_upload(file_key: FileKey, created_at: u64) {
+    let (owner, name, file_id) = file_key;
+    let map_id = (owner, name);
+    RECENT_UPLOADS.with_borrow_mut(|recent_uploads| {
+        recent_uploads.insert((map_id, (created_at, file_id)), ());
+        let entries: Vec<(u64, MapKey)> = recent_uploads
+            .range((map_id, (0, Blob::default()))..)
+            .take_while(|((key_map_id, _), _)| key_map_id == &map_id)
+            .map(|((_, entry), _)| entry)
+            .collect();
+        let surplus = entries.len().saturating_sub(RECENT_UPLOADS_PER_COLLECTION);
+        for entry in entries.into_iter().take(surplus) {
+            recent_uploads.remove(&(map_id, entry));
+        }
+    });
+}
+
+/// Moves the buffer of collection `from` to collection `to`, e.g. when the
+/// collection is renamed.
+pub fn move_collection_recent_uploads(from: MapId, to: MapId) {
+    for (created_at, (_, _, file_id)) in recent_uploads(from) {
+        RECENT_UPLOADS.with_borrow_mut(|recent_uploads| {
+            recent_uploads.remove(&(from, (created_at, file_id)));
+            recent_uploads.insert((to, (created_at, file_id)), ());
+        });
+    }
+}
+
+pub fn remove_collection_recent_uploads(map_id: MapId) {
+    let (owner, name) = map_id;
+    for (created_at, (_, _, file_id)) in recent_uploads(map_id) {
+        RECENT_UPLOADS.with_borrow_mut(|recent_uploads| {
+            recent_uploads.remove(&((owner, name), (created_at, file_id)))
+        });
+    }
+}
+
+/// Removes the buffers of all collections of `owner`.
+pub fn remove_owned_recent_uploads(owner: Principal) {
+    RECENT_UPLOADS.with_borrow_mut(|recent_uploads| {
+        let keys: Vec<_> = recent_uploads
+            .range(((owner, Blob::default()), (0, Blob::default()))..)
+            .take_while(|(((key_owner, _), _), _)| key_owner == &owner)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            recent_uploads.remove(&key);
+        }
+    });
+}
+
+fn recent_uploads(map_id: MapId) -> Vec<(u64, FileKey)> {
+    RECENT_UPLOADS.with_borrow(|recent_uploads| {
+        recent_uploads
+            .range((map_id, (0, Blob::default()))..)
+            .take_while(|((key_map_id, _), _)| key_map_id == &map_id)
+            .map(|((_, (created_at, file_id)), _)| (created_at, (map_id.0, map_id.1, file_id)))
+            .collect()
+    })
+}
@@ -0,0 +1,252 @@
+//! User search backed by prefix range scans.
+//!
+//! USERS is keyed by the normalized username, so username prefixes are
+//! looked up there directly. Display names are indexed word by word in
+//! DISPLAY_NAME_INDEX, keyed by (normalized word, principal).
+
+use crate::users::{normalize_username, user_for_principal, User};
+use crate::{Username, DISPLAY_NAME_INDEX, USERS};
+use candid::{CandidType, Principal};
+use ic_cdk::query;
+use ic_stable_structures::storable::Blob;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound as RangeBound;
+
+const MIN_SEARCH_QUERY_LEN: usize = 2;
+const MAX_SEARCH_LIMIT: u32 = 50;
+/// Upper bound on index entries inspected per search, so that a very common
+/// prefix cannot make a query scan the whole directory.
+const MAX_SEARCH_SCAN: usize = 200;
+const MAX_SEARCH_TOKEN_LEN: usize = 32;
+
+pub type SearchToken = Blob<MAX_SEARCH_TOKEN_LEN>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SearchResult {
+    pub users: Vec<User>,
+    /// Pass as `cursor` to get the next page; `null` on the last page.
+    pub next_cursor: Option<Username>,
+}
+
+/// Case-insensitive search for discoverable users whose username, or any
+/// word of whose display name, starts with the first word of `query`. If
+/// `query` has several words, matches must also contain the whole query.
+/// Results are ordered by username and paged with `cursor`. Display-name
+/// matches are taken from the first 200 index entries only.
+#[query]
+fn search_users(
+    query: String,
+    cursor: Option<Username>,
+    limit: u32,
+) -> Result<SearchResult, String> {
+    let query = normalize_username(query.trim());
+    if query.chars().count() < MIN_SEARCH_QUERY_LEN {
+        return Err(format!(
+            "search query must be at least {MIN_SEARCH_QUERY_LEN} characters"
+        ));
+    }
+    let first_word = query
+        .split_whitespace()
+        .next()
+        .expect("trimmed query is not empty");
+    let cursor = cursor.map(|cursor| normalize_username(&cursor));
+    let is_after_cursor = |key: &Username| match &cursor {
+        Some(cursor) => key > cursor,
+        None => true,
+    };
+    let matches_query = |user: &User| {
+        normalize_username(&user.username).contains(&query)
+            || user
+                .display_name
+                .as_ref()
+                .is_some_and(|display_name| normalize_username(display_name).contains(&query))
+    };
+
+    // Candidates keyed by normalized username, so results come out in order.
+    let mut candidates = BTreeMap::new();
+    let start = match &cursor {
+        Some(cursor) if cursor.as_str() >= first_word => RangeBound::Excluded(cursor.clone()),
+        _ => RangeBound::Included(first_word.to_string()),
+    };
+    let by_username: Vec<(Username, User)> = USERS.with_borrow(|users| {
+        users
+            .range((start, RangeBound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(first_word))
+            .take(MAX_SEARCH_SCAN)
+            .collect()
+    });
+    // If the username scan stopped early, only candidates up to where it
+    // stopped are complete; the next page resumes from there.
+    let scan_end = (by_username.len() == MAX_SEARCH_SCAN)
+        .then(|| by_username.last().map(|(key, _)| key.clone()))
+        .flatten();
+    candidates.extend(by_username);
+    let by_display_name = DISPLAY_NAME_INDEX.with_borrow(|index| {
+        let prefix = to_search_token(first_word);
+        index
+            .range((prefix, Principal::management_canister())..)
+            .take_while(|((token, _), _)| token.as_slice().starts_with(prefix.as_slice()))
+            .take(MAX_SEARCH_SCAN)
+            .map(|((_, principal), _)| principal)
+            .collect::<Vec<_>>()
+    });
+    for user in by_display_name.iter().filter_map(user_for_principal) {
+        let key = normalize_username(&user.username);
+        if is_after_cursor(&key) {
+            candidates.insert(key, user);
+        }
+    }
+    if let Some(scan_end) = &scan_end {
+        candidates.retain(|key, _| key <= scan_end);
+    }
+
+    let mut matches = candidates
+        .into_iter()
+        .filter(|(_, user)| user.discoverable && user.active && matches_query(user));
+    let page: Vec<(Username, User)> = matches
+        .by_ref()
+        .take(limit.clamp(1, MAX_SEARCH_LIMIT) as usize)
+        .collect();
+    let next_cursor = if matches.next().is_some() {
+        page.last().map(|(key, _)| key.clone())
+    } else {
+        scan_end
+    };
+    Ok(SearchResult {
+        users: page.into_iter().map(|(_, user)| user).collect(),
+        next_cursor,
+    })
+}
+
+/// Adds the words of `display_name` to the index for `principal`.
+pub fn index_display_name(principal: Principal, display_name: &Option<String>) {
+    DISPLAY_NAME_INDEX.with_borrow_mut(|index| {
+        for token in display_name_tokens(display_name) {
+            index.insert((token, principal), ());
+        }
+    });
+}
+
+/// Removes the words of `display_name` from the index for `principal`.
+pub fn unindex_display_name(principal: Principal, display_name: &Option<String>) {
+    DISPLAY_NAME_INDEX.with_borrow_mut(|index| {
+        for token in display_name_tokens(display_name) {
+            index.remove(&(token, principal));
+        }
+    });
+}
+
+/// Fills the index from USERS if it is empty, i.e. on the first upgrade
+/// after it was introduced.
+pub fn backfill_display_name_index() {
+    if !DISPLAY_NAME_INDEX.with_borrow(|index| index.is_empty()) {
+        return;
+    }
+    let users: Vec<User> = USERS.with_borrow(|users| users.iter().map(|(_, user)| user).collect());
+    for user in users {
+        index_display_name(user.principal, &user.display_name);
+    }
+}
+
+fn display_name_tokens(display_name: &Option<String>) -> BTreeSet<SearchToken> {
+    display_name
+        .as_deref()
+        .map(normalize_username)
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(to_search_token)
+        .collect()
+}
+
+/// Truncates a word to the index key size on a character boundary.
+fn to_search_token(word: &str) -> SearchToken {
+    let mut end = word.len().min(MAX_SEARCH_TOKEN_LEN);
+    while !word.is_char_boundary(end) {
+        end -= 1;
+    }
+    SearchToken::try_from(&word.as_bytes()[..end]).expect("token length is checked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(username: &str, display_name: Option<&str>, id: u32, discoverable: bool) {
+        let principal = Principal::from_slice(&id.to_be_bytes());
+        let user = User {
+            username: username.to_string(),
+            display_name: display_name.map(str::to_string),
+            bio: None,
+            contact: None,
+            principal,
+            created_at: 0,
+            last_modified: 0,
+            discoverable,
+            last_active: 0,
+            active: true,
+        };
+        index_display_name(principal, &user.display_name);
+        USERS.with_borrow_mut(|users| users.insert(normalize_username(username), user.clone()));
+        crate::PRINCIPAL_TO_USERNAME
+            .with_borrow_mut(|p2u| p2u.insert(principal, normalize_username(username)));
+    }
+
+    fn usernames(result: &SearchResult) -> Vec<&str> {
+        result
+            .users
+            .iter()
+            .map(|user| user.username.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn matches_username_prefix_case_insensitively() {
+        register("Bob", None, 1, true);
+        register("bobby", None, 2, true);
+        register("alice", None, 3, true);
+        let result = search_users("BO".to_string(), None, 10).unwrap();
+        assert_eq!(usernames(&result), ["Bob", "bobby"]);
+        assert_eq!(result.next_cursor, None);
+    }
+
+    #[test]
+    fn matches_display_name_words() {
+        register("rsmith", Some("Robert Smith"), 1, true);
+        register("alice", None, 2, true);
+        let result = search_users("smi".to_string(), None, 10).unwrap();
+        assert_eq!(usernames(&result), ["rsmith"]);
+    }
+
+    #[test]
+    fn prefix_search_in_large_registry_is_a_range_lookup() {
+        for id in 0..10_000 {
+            register(&format!("user{id:05}"), None, id, true);
+        }
+        register("zed", None, 10_000, true);
+        let result = search_users("ze".to_string(), None, 10).unwrap();
+        assert_eq!(usernames(&result), ["zed"]);
+        assert_eq!(result.next_cursor, None);
+    }
+
+    #[test]
+    fn scan_stops_after_bounded_number_of_entries() {
+        // Only users past the scan bound would match, so a search that
+        // visited every entry would find them; a bounded one stops first
+        // and hands out a cursor instead.
+        for id in 0..10_000 {
+            register(&format!("user{id:05}"), None, id, id >= 5_000);
+        }
+        let result = search_users("user".to_string(), None, 10).unwrap();
+        assert!(result.users.is_empty());
+        let cursor = format!("user{:05}", MAX_SEARCH_SCAN - 1);
+        assert_eq!(result.next_cursor, Some(cursor.clone()));
+
+        let next = search_users("user".to_string(), Some(cursor), 10).unwrap();
+        assert!(next.users.is_empty());
+        assert_eq!(
+            next.next_cursor,
+            Some(format!("user{:05}", 2 * MAX_SEARCH_SCAN - 1))
+        );
+    }
+}
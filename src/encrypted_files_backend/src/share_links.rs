@@ -0,0 +1,640 @@
+//! Share links: a random token that lets anybody holding it read one file,
+//! or all files of a collection, until the link expires, without a grant.
+//! The canister serves the files encrypted as they are; the link carries an
+//! opaque `link_secret`, chosen by the owner's client, from which holders
+//! decrypt them. Only a hash of each token is stored, and tokens are only
+//! ever looked up by that hash, so response times reveal nothing about how
+//! close a guessed token is to a real one.
+//!
+//! Each collection can also have one "link access" link, which its owner
+//! enables, rotates and disables as a setting of the collection. Holders
+//! read through it with update calls only, which count how often the link
+//! is used and turn away callers who present too many invalid tokens; the
+//! query endpoints treat its token as invalid. The limit is kept per
+//! caller, so these endpoints turn away the anonymous principal, which
+//! every unauthenticated client shares; link clients use any identity of
+//! their own, e.g. a freshly generated one.
+
+use crate::collections::owned_collection_names;
+use crate::expiry_index::{register_expiry, unregister_expiry, ExpiryTarget};
+use crate::files::FileMetadata;
+use crate::users::{ensure_active, record_activity};
+use crate::{
+    bytebuf_to_blob, collection_name_to_blob, ApiError, MapId, MapKey, ENCRYPTED_MAPS,
+    FILE_METADATA, SHARE_LINKS, SHARE_LINKS_BY_OWNER,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{ByteBuf, EncryptedMapValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound as RangeBound;
+
+const MAX_SHARE_LINK_TTL_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_LINK_SECRET_LEN: usize = 1_024;
+const MAX_SHARE_LINKS_PER_OWNER: usize = 100;
+const MAX_SHARED_LINK_PAGE: usize = 200;
+/// Smaller than [`MAX_SHARED_LINK_PAGE`] since these pages carry the
+/// encrypted values too.
+const MAX_LINK_ACCESS_PAGE: usize = 100;
+const LINK_TOKEN_LEN: usize = 32;
+const MAX_FAILED_LINK_ATTEMPTS: u32 = 20;
+const FAILED_LINK_ATTEMPT_WINDOW_NS: u64 = 60 * 60 * 1_000_000_000;
+
+type LinkedFileMetadata = (MapKey, FileMetadata);
+
+thread_local! {
+    /// Invalid tokens presented per caller to the update endpoints, with
+    /// the start of the caller's current window. Kept on the heap: losing
+    /// the counts on upgrade only ends the windows early.
+    static FAILED_LINK_ATTEMPTS: RefCell<BTreeMap<Principal, (u64, u32)>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShareLinkRecord {
+    pub owner: Principal,
+    /// Key of the shared collection.
+    pub collection_name: ByteBuf,
+    /// `None` shares the whole collection.
+    pub file_id: Option<ByteBuf>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub link_secret: ByteBuf,
+    /// Set on the link made by enable_link_access, of which a collection
+    /// has at most one.
+    #[serde(default)]
+    pub link_access: bool,
+    /// Uses through the update endpoints; queries cannot count.
+    #[serde(default)]
+    pub use_count: u64,
+    #[serde(default)]
+    pub last_used_at: Option<u64>,
+}
+
+impl Storable for ShareLinkRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ShareLink {
+    /// Shown only once; the canister keeps just its hash.
+    pub token: ByteBuf,
+    /// Identifies the link in list_my_share_links and revoke_share_link.
+    pub link_id: ByteBuf,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ShareLinkInfo {
+    pub link_id: ByteBuf,
+    pub collection_name: ByteBuf,
+    pub file_id: Option<ByteBuf>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub link_access: bool,
+    pub use_count: u64,
+    pub last_used_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SharedLinkPage {
+    pub files: Vec<(ByteBuf, FileMetadata)>,
+    pub link_secret: ByteBuf,
+    pub expires_at: u64,
+    /// Pass as `start_after` to get the next page; `null` on the last page.
+    pub next_cursor: Option<ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LinkedFile {
+    pub file_id: ByteBuf,
+    pub metadata: FileMetadata,
+    pub value: EncryptedMapValue,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LinkedFilesPage {
+    pub files: Vec<LinkedFile>,
+    pub expires_at: u64,
+    /// Pass as `start_after` to get the next page; `null` on the last page.
+    pub next_cursor: Option<ByteBuf>,
+}
+
+/// Creates a link to one file of one of the caller's collections, or to
+/// the whole collection if `file_id` is `null`, valid for `ttl_ns`
+/// nanoseconds.
+#[update]
+async fn create_share_link(
+    collection_name: ByteBuf,
+    file_id: Option<ByteBuf>,
+    ttl_ns: u64,
+    link_secret: ByteBuf,
+) -> Result<ShareLink, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    validate_link_settings(ttl_ns, &link_secret)?;
+    let map_name = collection_name_to_blob(collection_name)?;
+    if !owned_collection_names(caller).contains(&map_name) {
+        return Err("collection not found".to_string().into());
+    }
+    if let Some(file_id) = &file_id {
+        let file_key = (caller, map_name, bytebuf_to_blob(file_id.clone())?);
+        if !FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+            return Err("file not found".to_string().into());
+        }
+    }
+    remove_expired_links(caller);
+    if owned_link_ids(caller).len() >= MAX_SHARE_LINKS_PER_OWNER {
+        return Err(
+            format!("at most {MAX_SHARE_LINKS_PER_OWNER} links can be active at once").into(),
+        );
+    }
+
+    let created_at = ic_cdk::api::time();
+    issue_link(ShareLinkRecord {
+        owner: caller,
+        collection_name: ByteBuf::from(map_name.as_slice().to_vec()),
+        file_id,
+        created_at,
+        expires_at: created_at.saturating_add(ttl_ns),
+        link_secret,
+        link_access: false,
+        use_count: 0,
+        last_used_at: None,
+    })
+    .await
+    .map_err(ApiError::from)
+}
+
+/// Lets anyone holding the returned token read one of the caller's
+/// collections for `ttl_ns` nanoseconds, replacing the collection's
+/// previous link access link, if any. `link_secret` is handed to holders
+/// by get_link_secret, e.g. a wrapped key for client-side decryption.
+#[update]
+async fn enable_link_access(
+    collection_name: ByteBuf,
+    link_secret: ByteBuf,
+    ttl_ns: u64,
+) -> Result<ShareLink, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    validate_link_settings(ttl_ns, &link_secret)?;
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    if !owned_collection_names(caller).contains(&map_id.1) {
+        return Err("collection not found".to_string().into());
+    }
+    remove_expired_links(caller);
+    if link_access_id(map_id).is_none() && owned_link_ids(caller).len() >= MAX_SHARE_LINKS_PER_OWNER
+    {
+        return Err(
+            format!("at most {MAX_SHARE_LINKS_PER_OWNER} links can be active at once").into(),
+        );
+    }
+    let created_at = ic_cdk::api::time();
+    issue_link(ShareLinkRecord {
+        owner: caller,
+        collection_name: ByteBuf::from(map_id.1.as_slice().to_vec()),
+        file_id: None,
+        created_at,
+        expires_at: created_at.saturating_add(ttl_ns),
+        link_secret,
+        link_access: true,
+        use_count: 0,
+        last_used_at: None,
+    })
+    .await
+    .map_err(ApiError::from)
+}
+
+/// Replaces the token of the link access link of one of the caller's
+/// collections, keeping its secret, expiry and usage count. Holders of the
+/// previous token lose access.
+#[update]
+async fn rotate_link_access(collection_name: ByteBuf) -> Result<ShareLink, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_active(caller)?;
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    let record = link_access_id(map_id)
+        .and_then(|link_id| SHARE_LINKS.with_borrow(|links| links.get(&link_id)))
+        .filter(|link| link.expires_at > ic_cdk::api::time())
+        .ok_or_else(|| "link access is not enabled for this collection".to_string())?;
+    issue_link(record).await.map_err(ApiError::from)
+}
+
+/// Turns off link access to one of the caller's collections. Links created
+/// with create_share_link stay valid.
+#[update]
+fn disable_link_access(collection_name: ByteBuf) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    let link_id = link_access_id(map_id)
+        .ok_or_else(|| "link access is not enabled for this collection".to_string())?;
+    remove_link(caller, link_id);
+    Ok(())
+}
+
+/// The files a link shares with their metadata and encrypted values, after
+/// `start_after`. Callable by anyone holding the token, except anonymously;
+/// counts as a use of the link.
+#[update]
+fn get_collection_via_link(
+    token: ByteBuf,
+    start_after: Option<ByteBuf>,
+) -> Result<LinkedFilesPage, String> {
+    let (link_id, link) = checked_link(&token)?;
+    let map_id = (
+        link.owner,
+        collection_name_to_blob(link.collection_name.clone())?,
+    );
+    let (files, next_cursor) = linked_files(&link, start_after, MAX_LINK_ACCESS_PAGE)?;
+    let files = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_ref().unwrap();
+        files
+            .into_iter()
+            .filter_map(|(file_id, metadata)| {
+                // Read on behalf of the owner, who granted access by
+                // creating the link.
+                encrypted_maps
+                    .get_encrypted_value(link.owner, map_id, file_id)
+                    .transpose()
+                    .map(|value| {
+                        value.map(|value| LinkedFile {
+                            file_id: ByteBuf::from(file_id.as_slice().to_vec()),
+                            metadata,
+                            value,
+                        })
+                    })
+            })
+            .collect::<Result<Vec<_>, String>>()
+    })?;
+    record_link_use(link_id);
+    Ok(LinkedFilesPage {
+        files,
+        expires_at: link.expires_at,
+        next_cursor,
+    })
+}
+
+/// The secret the owner attached to a link. Callable by anyone holding the
+/// token, except anonymously; counts as a use of the link.
+#[update]
+fn get_link_secret(token: ByteBuf) -> Result<ByteBuf, String> {
+    let (link_id, link) = checked_link(&token)?;
+    record_link_use(link_id);
+    Ok(link.link_secret)
+}
+
+/// The metadata of the files a link shares, after `start_after`, with the
+/// link secret. Callable by anyone holding the token of a link made with
+/// create_share_link.
+#[query]
+fn get_shared_link_metadata(
+    token: ByteBuf,
+    start_after: Option<ByteBuf>,
+) -> Result<SharedLinkPage, String> {
+    let link = valid_query_link(&token)?;
+    let (files, next_cursor) = linked_files(&link, start_after, MAX_SHARED_LINK_PAGE)?;
+    Ok(SharedLinkPage {
+        files: files
+            .into_iter()
+            .map(|(file_id, metadata)| (ByteBuf::from(file_id.as_slice().to_vec()), metadata))
+            .collect(),
+        link_secret: link.link_secret,
+        expires_at: link.expires_at,
+        next_cursor,
+    })
+}
+
+/// The encrypted content of a file a link shares. `file_id` may be `null`
+/// for links to a single file. Callable by anyone holding the token of a
+/// link made with create_share_link.
+#[query]
+fn get_shared_link_content(
+    token: ByteBuf,
+    file_id: Option<ByteBuf>,
+) -> Result<EncryptedMapValue, String> {
+    let link = valid_query_link(&token)?;
+    let file_id = match (&link.file_id, file_id) {
+        (Some(linked), requested) => {
+            if requested.is_some_and(|requested| &requested != linked) {
+                return Err("file not found".to_string());
+            }
+            linked.clone()
+        }
+        (None, Some(requested)) => requested,
+        (None, None) => return Err("the link shares a collection; pass a file id".to_string()),
+    };
+    let map_name = collection_name_to_blob(link.collection_name)?;
+    let file_key = (link.owner, map_name, bytebuf_to_blob(file_id)?);
+    let available = FILE_METADATA
+        .with_borrow(|file_metadata| file_metadata.get(&file_key))
+        .is_some_and(|metadata| !metadata.is_expired(ic_cdk::api::time()));
+    if !available {
+        return Err("file not found".to_string());
+    }
+    // Read on behalf of the owner, who granted access by creating the link.
+    ENCRYPTED_MAPS
+        .with_borrow(|encrypted_maps| {
+            encrypted_maps.as_ref().unwrap().get_encrypted_value(
+                link.owner,
+                (file_key.0, file_key.1),
+                file_key.2,
+            )
+        })?
+        .ok_or_else(|| "file not found".to_string())
+}
+
+/// The caller's links that have not expired, oldest first.
+#[query]
+fn list_my_share_links() -> Vec<ShareLinkInfo> {
+    let caller = ic_cdk::api::msg_caller();
+    let now = ic_cdk::api::time();
+    let mut links: Vec<ShareLinkInfo> = owned_link_ids(caller)
+        .into_iter()
+        .filter_map(|link_id| {
+            SHARE_LINKS
+                .with_borrow(|links| links.get(&link_id))
+                .filter(|link| link.expires_at > now)
+                .map(|link| ShareLinkInfo {
+                    link_id: ByteBuf::from(link_id.as_slice().to_vec()),
+                    collection_name: link.collection_name,
+                    file_id: link.file_id,
+                    created_at: link.created_at,
+                    expires_at: link.expires_at,
+                    link_access: link.link_access,
+                    use_count: link.use_count,
+                    last_used_at: link.last_used_at,
+                })
+        })
+        .collect();
+    links.sort_by_key(|link| link.created_at);
+    links
+}
+
+#[update]
+fn revoke_share_link(link_id: ByteBuf) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let link_id = bytebuf_to_blob(link_id)?;
+    if !SHARE_LINKS_BY_OWNER.with_borrow(|index| index.contains_key(&(caller, link_id))) {
+        return Err("link not found".to_string());
+    }
+    remove_link(caller, link_id);
+    Ok(())
+}
+
+/// Removes all links of `owner`, e.g. with their account.
+pub fn remove_owned_share_links(owner: Principal) {
+    for link_id in owned_link_ids(owner) {
+        remove_link(owner, link_id);
+    }
+}
+
+/// Removes the links to a collection and to its files.
+pub fn remove_collection_share_links(map_id: MapId) {
+    for link_id in owned_link_ids(map_id.0) {
+        let links_collection = SHARE_LINKS
+            .with_borrow(|links| links.get(&link_id))
+            .is_some_and(|link| link.collection_name.as_ref() == map_id.1.as_slice());
+        if links_collection {
+            remove_link(map_id.0, link_id);
+        }
+    }
+}
+
+/// Up to `limit` files a link shares that have not expired, after
+/// `start_after`, with the cursor of the next page.
+fn linked_files(
+    link: &ShareLinkRecord,
+    start_after: Option<ByteBuf>,
+    limit: usize,
+) -> Result<(Vec<LinkedFileMetadata>, Option<ByteBuf>), String> {
+    let map_name = collection_name_to_blob(link.collection_name.clone())?;
+    let map_id = (link.owner, map_name);
+    let now = ic_cdk::api::time();
+    let mut files: Vec<LinkedFileMetadata> = match &link.file_id {
+        Some(file_id) => {
+            let file_key = (link.owner, map_name, bytebuf_to_blob(file_id.clone())?);
+            FILE_METADATA
+                .with_borrow(|file_metadata| file_metadata.get(&file_key))
+                .map(|metadata| vec![(file_key.2, metadata)])
+                .unwrap_or_default()
+        }
+        None => {
+            let start = match start_after.map(bytebuf_to_blob).transpose()? {
+                Some(file_id) => RangeBound::Excluded((link.owner, map_name, file_id)),
+                None => RangeBound::Included((link.owner, map_name, Blob::default())),
+            };
+            FILE_METADATA.with_borrow(|file_metadata| {
+                file_metadata
+                    .range((start, RangeBound::Unbounded))
+                    .take_while(|((owner, name, _), _)| (*owner, *name) == map_id)
+                    .take(limit + 1)
+                    .map(|((_, _, file_id), metadata)| (file_id, metadata))
+                    .collect()
+            })
+        }
+    };
+    let next_cursor = (files.len() > limit)
+        .then(|| files[limit - 1].0)
+        .map(|file_id| ByteBuf::from(file_id.as_slice().to_vec()));
+    files.truncate(limit);
+    files.retain(|(_, metadata)| !metadata.is_expired(now));
+    Ok((files, next_cursor))
+}
+
+/// valid_link for update calls, which also counts the invalid tokens the
+/// caller presents and turns them away after [`MAX_FAILED_LINK_ATTEMPTS`]
+/// per window. Anonymous callers are rejected, since they would all share
+/// one count that anybody could use up.
+fn checked_link(token: &ByteBuf) -> Result<(Blob<32>, ShareLinkRecord), String> {
+    let caller = ic_cdk::api::msg_caller();
+    if caller == Principal::anonymous() {
+        return Err(
+            "links must be opened with an identity; anonymous calls are not accepted".to_string(),
+        );
+    }
+    let now = ic_cdk::api::time();
+    let failures = FAILED_LINK_ATTEMPTS.with_borrow(|attempts| {
+        attempts
+            .get(&caller)
+            .filter(|(window_start, _)| {
+                window_start.saturating_add(FAILED_LINK_ATTEMPT_WINDOW_NS) > now
+            })
+            .map_or(0, |(_, failures)| *failures)
+    });
+    if failures >= MAX_FAILED_LINK_ATTEMPTS {
+        return Err("too many invalid links; try again later".to_string());
+    }
+    let link = if token.as_ref().len() == LINK_TOKEN_LEN {
+        valid_link(token)
+    } else {
+        Err("invalid or expired link".to_string())
+    };
+    match link {
+        Ok(link) => Ok((link_id(token.as_ref())?, link)),
+        Err(err) => {
+            FAILED_LINK_ATTEMPTS.with_borrow_mut(|attempts| {
+                attempts.retain(|_, (window_start, _)| {
+                    window_start.saturating_add(FAILED_LINK_ATTEMPT_WINDOW_NS) > now
+                });
+                attempts.entry(caller).or_insert((now, 0)).1 += 1;
+            });
+            Err(err)
+        }
+    }
+}
+
+fn record_link_use(link_id: Blob<32>) {
+    SHARE_LINKS.with_borrow_mut(|links| {
+        if let Some(mut link) = links.get(&link_id) {
+            link.use_count += 1;
+            link.last_used_at = Some(ic_cdk::api::time());
+            links.insert(link_id, link);
+        }
+    });
+}
+
+fn validate_link_settings(ttl_ns: u64, link_secret: &ByteBuf) -> Result<(), String> {
+    if ttl_ns == 0 || ttl_ns > MAX_SHARE_LINK_TTL_NS {
+        return Err("links are valid for at most 30 days".to_string());
+    }
+    if link_secret.as_ref().len() > MAX_LINK_SECRET_LEN {
+        return Err(format!(
+            "the link secret must be at most {MAX_LINK_SECRET_LEN} bytes"
+        ));
+    }
+    Ok(())
+}
+
+/// Stores `record` under a fresh random token. A link access link replaces
+/// the collection's previous one, which is looked up only after the await
+/// so that concurrent calls cannot leave two behind.
+async fn issue_link(record: ShareLinkRecord) -> Result<ShareLink, String> {
+    let token = ic_cdk::management_canister::raw_rand()
+        .await
+        .map_err(|e| format!("failed to obtain randomness: {e}"))?;
+    let link_id = link_id(&token)?;
+    let owner = record.owner;
+    if record.link_access {
+        let map_id = (
+            owner,
+            collection_name_to_blob(record.collection_name.clone())?,
+        );
+        if let Some(previous) = link_access_id(map_id) {
+            remove_link(owner, previous);
+        }
+    }
+    let expires_at = record.expires_at;
+    SHARE_LINKS.with_borrow_mut(|links| links.insert(link_id, record));
+    SHARE_LINKS_BY_OWNER.with_borrow_mut(|index| index.insert((owner, link_id), ()));
+    register_expiry(expires_at, ExpiryTarget::share_link(link_id));
+    Ok(ShareLink {
+        token: ByteBuf::from(token),
+        link_id: ByteBuf::from(link_id.as_slice().to_vec()),
+        expires_at,
+    })
+}
+
+/// The link access link of a collection, if enabled.
+fn link_access_id(map_id: MapId) -> Option<Blob<32>> {
+    owned_link_ids(map_id.0).into_iter().find(|link_id| {
+        SHARE_LINKS
+            .with_borrow(|links| links.get(link_id))
+            .is_some_and(|link| {
+                link.link_access && link.collection_name.as_ref() == map_id.1.as_slice()
+            })
+    })
+}
+
+fn valid_link(token: &ByteBuf) -> Result<ShareLinkRecord, String> {
+    let link_id = link_id(token.as_ref())?;
+    SHARE_LINKS
+        .with_borrow(|links| links.get(&link_id))
+        .filter(|link| link.expires_at > ic_cdk::api::time())
+        .ok_or_else(|| "invalid or expired link".to_string())
+}
+
+/// valid_link for queries, which can neither count uses nor limit invalid
+/// attempts. Link access links are reported as invalid, so that queries
+/// reveal nothing about their tokens.
+fn valid_query_link(token: &ByteBuf) -> Result<ShareLinkRecord, String> {
+    let link = valid_link(token)?;
+    if link.link_access {
+        return Err("invalid or expired link".to_string());
+    }
+    Ok(link)
+}
+
+fn link_id(token: &[u8]) -> Result<Blob<32>, String> {
+    Blob::try_from(Sha256::digest(token).as_slice()).map_err(|_| "invalid token".to_string())
+}
+
+fn owned_link_ids(owner: Principal) -> Vec<Blob<32>> {
+    SHARE_LINKS_BY_OWNER.with_borrow(|index| {
+        index
+            .range((owner, Blob::default())..)
+            .take_while(|((link_owner, _), _)| link_owner == &owner)
+            .map(|((_, link_id), _)| link_id)
+            .collect()
+    })
+}
+
+fn remove_expired_links(owner: Principal) {
+    let now = ic_cdk::api::time();
+    for link_id in owned_link_ids(owner) {
+        let expired = SHARE_LINKS
+            .with_borrow(|links| links.get(&link_id))
+            .map_or(true, |link| link.expires_at <= now);
+        if expired {
+            remove_link(owner, link_id);
+        }
+    }
+}
+
+/// Removes a link once it has expired; does nothing if it is gone already.
+pub fn remove_expired_link(link_id: Blob<32>) {
+    let link = SHARE_LINKS.with_borrow(|links| links.get(&link_id));
+    if let Some(link) = link.filter(|link| link.expires_at <= ic_cdk::api::time()) {
+        remove_link(link.owner, link_id);
+    }
+}
+
+/// Registers the expiries of all links in the expiry index.
+pub fn register_share_link_expiries() {
+    let expiries: Vec<(Blob<32>, u64)> = SHARE_LINKS.with_borrow(|links| {
+        links
+            .iter()
+            .map(|(link_id, link)| (link_id, link.expires_at))
+            .collect()
+    });
+    for (link_id, expires_at) in expiries {
+        register_expiry(expires_at, ExpiryTarget::share_link(link_id));
+    }
+}
+
+fn remove_link(owner: Principal, link_id: Blob<32>) {
+    let removed = SHARE_LINKS.with_borrow_mut(|links| links.remove(&link_id));
+    SHARE_LINKS_BY_OWNER.with_borrow_mut(|index| index.remove(&(owner, link_id)));
+    if let Some(link) = removed {
+        unregister_expiry(link.expires_at, &ExpiryTarget::share_link(link_id));
+    }
+}
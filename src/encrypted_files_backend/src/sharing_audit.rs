@@ -0,0 +1,166 @@
+//! Append-only audit log of the sharing grants of each collection: who
+//! changed whose rights from what to what, and when. Unlike the activity
+//! log it survives the deletion of the collection, so that past access can
+//! still be accounted for. Entries are numbered per collection, and only the
+//! newest [`MAX_SHARING_AUDIT_ENTRIES`] are kept.
+
+use crate::{collection_name_to_blob, MapId, SHARING_AUDIT};
+use candid::{CandidType, Principal};
+use ic_cdk::query;
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{AccessRights, ByteBuf};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const MAX_SHARING_AUDIT_ENTRIES: usize = 500;
+const MAX_SHARING_AUDIT_PAGE_LIMIT: u32 = 100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SharingAuditEntry {
+    pub seq: u64,
+    /// Who made the change; the canister itself for expired grants.
+    pub actor: Principal,
+    /// `None` for the entry of revoke_all_access, which lists the revoked
+    /// grants in `revoked_grants` instead.
+    pub grantee: Option<Principal>,
+    /// `None` if the grantee had no access before.
+    pub old_rights: Option<AccessRights>,
+    /// `None` if the grant was revoked.
+    pub new_rights: Option<AccessRights>,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub revoked_grants: Vec<(Principal, AccessRights)>,
+}
+
+impl Storable for SharingAuditEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The sharing changes of one of the caller's collections, newest first,
+/// starting below `before_seq`.
+#[query]
+fn get_sharing_audit_log(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    before_seq: Option<u64>,
+    limit: u32,
+) -> Result<Vec<SharingAuditEntry>, String> {
+    if ic_cdk::api::msg_caller() != collection_owner {
+        return Err("only the owner can view the sharing audit log".to_string());
+    }
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    let before_seq = before_seq.unwrap_or(u64::MAX);
+    let limit = limit.min(MAX_SHARING_AUDIT_PAGE_LIMIT) as usize;
+    let mut entries: Vec<SharingAuditEntry> = SHARING_AUDIT.with_borrow(|audit| {
+        audit
+            .range((map_id, 0)..(map_id, before_seq))
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    entries.reverse();
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+/// Records a change of `grantee`'s rights on a collection, pruning the
+/// oldest entries beyond [`MAX_SHARING_AUDIT_ENTRIES`]. Calls that leave the
+/// rights as they were are not recorded.
+pub fn log_sharing_change(
+    map_id: MapId,
+    actor: Principal,
+    grantee: Principal,
+    old_rights: Option<AccessRights>,
+    new_rights: Option<AccessRights>,
+) {
+    if old_rights == new_rights {
+        return;
+    }
+    append(
+        map_id,
+        SharingAuditEntry {
+            seq: 0,
+            actor,
+            grantee: Some(grantee),
+            old_rights,
+            new_rights,
+            timestamp: ic_cdk::api::time(),
+            revoked_grants: Vec::new(),
+        },
+    );
+}
+
+/// Records the revocation of all grants on a collection as one entry.
+pub fn log_all_access_revoked(
+    map_id: MapId,
+    actor: Principal,
+    revoked_grants: Vec<(Principal, AccessRights)>,
+) {
+    append(
+        map_id,
+        SharingAuditEntry {
+            seq: 0,
+            actor,
+            grantee: None,
+            old_rights: None,
+            new_rights: None,
+            timestamp: ic_cdk::api::time(),
+            revoked_grants,
+        },
+    );
+}
+
+/// Moves the audit log of a collection when it is renamed. The new name
+/// may still have the log of a deleted collection, so the entries are
+/// appended to it and renumbered.
+pub fn move_sharing_audit_log(from: MapId, to: MapId) {
+    for seq in audit_seqs(from) {
+        if let Some(entry) = SHARING_AUDIT.with_borrow_mut(|audit| audit.remove(&(from, seq))) {
+            append(to, entry);
+        }
+    }
+}
+
+/// Removes the audit logs of all collections of `owner`, with their account.
+pub fn remove_owned_sharing_audit_logs(owner: Principal) {
+    SHARING_AUDIT.with_borrow_mut(|audit| {
+        let keys: Vec<(MapId, u64)> = audit
+            .range(((owner, Blob::default()), 0)..)
+            .take_while(|(((map_owner, _), _), _)| map_owner == &owner)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            audit.remove(&key);
+        }
+    });
+}
+
+fn append(map_id: MapId, entry: SharingAuditEntry) {
+    let seqs = audit_seqs(map_id);
+    let seq = seqs.last().map_or(0, |last| last + 1);
+    SHARING_AUDIT.with_borrow_mut(|audit| {
+        audit.insert((map_id, seq), SharingAuditEntry { seq, ..entry });
+        let surplus = (seqs.len() + 1).saturating_sub(MAX_SHARING_AUDIT_ENTRIES);
+        for seq in &seqs[..surplus] {
+            audit.remove(&(map_id, *seq));
+        }
+    });
+}
+
+fn audit_seqs(map_id: MapId) -> Vec<u64> {
+    SHARING_AUDIT.with_borrow(|audit| {
+        audit
+            .range((map_id, 0)..)
+            .take_while(|((key, _), _)| key == &map_id)
+            .map(|((_, seq), _)| seq)
+            .collect()
+    })
+}
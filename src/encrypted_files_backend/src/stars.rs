@@ -0,0 +1,193 @@
+//! Files starred by users across the collections they can access. Stars are
+//! kept per principal, with a reverse index so they can be dropped when the
+//! file goes away.
+
+use crate::file_overrides::is_hidden_from;
+use crate::files::FileMetadata;
+use crate::users::record_activity;
+use crate::versions::FileKey;
+use crate::{
+    bytebuf_to_blob, caller_rights, collection_name_to_blob, MapId, FILE_METADATA, STARS,
+    STARS_BY_FILE,
+};
+use candid::Principal;
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::ByteBuf;
+
+const MAX_STARS_PER_PRINCIPAL: usize = 500;
+
+#[update]
+fn star_file(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_key = (collection_owner, collection_name, bytebuf_to_blob(file_id)?);
+    caller_rights(caller, (collection_owner, collection_name))?;
+    if !FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+        return Err("file not found".to_string());
+    }
+    if STARS.with_borrow(|stars| stars.contains_key(&(caller, file_key))) {
+        return Ok(());
+    }
+    if starred_files(caller).len() >= MAX_STARS_PER_PRINCIPAL {
+        return Err(format!(
+            "at most {MAX_STARS_PER_PRINCIPAL} files can be starred"
+        ));
+    }
+    STARS.with_borrow_mut(|stars| stars.insert((caller, file_key), ic_cdk::api::time()));
+    STARS_BY_FILE.with_borrow_mut(|stars_by_file| stars_by_file.insert((file_key, caller), ()));
+    Ok(())
+}
+
+/// Returns whether the file was starred.
+#[update]
+fn unstar_file(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<bool, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let file_key = (
+        collection_owner,
+        collection_name_to_blob(collection_name)?,
+        bytebuf_to_blob(file_id)?,
+    );
+    Ok(remove_star(caller, file_key))
+}
+
+/// The caller's starred files, oldest star first. Files the caller can no
+/// longer access, files hidden from them and expired files are left out.
+#[query]
+fn list_starred() -> Vec<(Principal, ByteBuf, ByteBuf, FileMetadata)> {
+    let caller = ic_cdk::api::msg_caller();
+    let now = ic_cdk::api::time();
+    starred_files(caller)
+        .into_iter()
+        .filter(|(owner, name, _)| caller_rights(caller, (*owner, *name)).is_ok())
+        .filter(|file_key| !is_hidden_from(*file_key, caller))
+        .filter_map(|file_key| {
+            let metadata = FILE_METADATA
+                .with_borrow(|file_metadata| file_metadata.get(&file_key))
+                .filter(|metadata| !metadata.is_expired(now))?;
+            let (owner, name, file_id) = file_key;
+            Some((
+                owner,
+                ByteBuf::from(name.as_slice().to_vec()),
+                ByteBuf::from(file_id.as_slice().to_vec()),
+                metadata,
+            ))
+        })
+        .collect()
+}
+
+/// Re-keys every user's star on a file, e.g. when its collection is
+/// renamed.
+pub fn move_file_stars(from: FileKey, to: FileKey) {
+    let principals: Vec<Principal> = STARS_BY_FILE.with_borrow(|stars_by_file| {
+        stars_by_file
+            .range((from, Principal::management_canister())..)
+            .take_while(|((key, _), _)| key == &from)
+            .map(|((_, principal), _)| principal)
+            .collect()
+    });
+    for principal in principals {
+        let starred_at = STARS.with_borrow_mut(|stars| stars.remove(&(principal, from)));
+        STARS_BY_FILE.with_borrow_mut(|stars_by_file| {
+            stars_by_file.remove(&(from, principal));
+            stars_by_file.insert((to, principal), ());
+        });
+        if let Some(starred_at) = starred_at {
+            STARS.with_borrow_mut(|stars| stars.insert((principal, to), starred_at));
+        }
+    }
+}
+
+/// Removes every user's star on a file.
+pub fn remove_file_stars(file_key: FileKey) {
+    let principals: Vec<Principal> = STARS_BY_FILE.with_borrow(|stars_by_file| {
+        stars_by_file
+            .range((file_key, Principal::management_canister())..)
+            .take_while(|((key, _), _)| key == &file_key)
+            .map(|((_, principal), _)| principal)
+            .collect()
+    });
+    for principal in principals {
+        remove_star(principal, file_key);
+    }
+}
+
+/// Removes every user's star on files in collections of `owner`.
+pub fn remove_owned_file_stars(owner: Principal) {
+    let stars: Vec<(FileKey, Principal)> = STARS_BY_FILE.with_borrow(|stars_by_file| {
+        stars_by_file
+            .range(
+                (
+                    (owner, Blob::default(), Blob::default()),
+                    Principal::management_canister(),
+                )..,
+            )
+            .take_while(|(((file_owner, _, _), _), _)| file_owner == &owner)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for (file_key, principal) in stars {
+        remove_star(principal, file_key);
+    }
+}
+
+/// Removes the stars `principal` set on files of a collection, e.g. when
+/// they leave it.
+pub fn remove_collection_stars(principal: Principal, map_id: MapId) {
+    let file_keys: Vec<FileKey> = STARS.with_borrow(|stars| {
+        stars
+            .range((principal, (map_id.0, map_id.1, Blob::default()))..)
+            .take_while(|((starred_by, (owner, name, _)), _)| {
+                starred_by == &principal && (*owner, *name) == map_id
+            })
+            .map(|((_, file_key), _)| file_key)
+            .collect()
+    });
+    for file_key in file_keys {
+        remove_star(principal, file_key);
+    }
+}
+
+/// Removes all stars set by `principal`.
+pub fn remove_stars(principal: Principal) {
+    for file_key in starred_files(principal) {
+        remove_star(principal, file_key);
+    }
+}
+
+fn starred_files(principal: Principal) -> Vec<FileKey> {
+    STARS.with_borrow(|stars| {
+        let mut starred: Vec<(u64, FileKey)> = stars
+            .range(
+                (
+                    principal,
+                    (
+                        Principal::management_canister(),
+                        Blob::default(),
+                        Blob::default(),
+                    ),
+                )..,
+            )
+            .take_while(|((starred_by, _), _)| starred_by == &principal)
+            .map(|((_, file_key), starred_at)| (starred_at, file_key))
+            .collect();
+        starred.sort();
+        starred.into_iter().map(|(_, file_key)| file_key).collect()
+    })
+}
+
+fn remove_star(principal: Principal, file_key: FileKey) -> bool {
+    STARS_BY_FILE.with_borrow_mut(|stars_by_file| stars_by_file.remove(&(file_key, principal)));
+    STARS
+        .with_borrow_mut(|stars| stars.remove(&(principal, file_key)))
+        .is_some()
+}
@@ -0,0 +1,155 @@
+use crate::admin::ensure_admin;
+use crate::{COLLECTION_OWNER_COUNT, FILE_METADATA, REGISTRATIONS_PER_HOUR, USERS};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Blob;
+use serde::Deserialize;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const HOURS_PER_WEEK: u64 = 7 * 24;
+const MAX_PRINCIPAL_LEN: usize = 29;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UserStats {
+    pub total_users: u64,
+    pub registered_last_24h: u64,
+    pub registered_last_7d: u64,
+    pub users_owning_collections: u64,
+}
+
+/// Registration counts are kept per hour, so "last 24h" covers the current
+/// hour and the 23 before it.
+#[query]
+fn get_user_stats() -> UserStats {
+    let current_hour = ic_cdk::api::time() / NANOS_PER_HOUR;
+    let registered_since = |hours: u64| {
+        REGISTRATIONS_PER_HOUR.with_borrow(|buckets| {
+            buckets
+                .range(current_hour.saturating_sub(hours - 1)..)
+                .map(|(_, count)| count)
+                .sum()
+        })
+    };
+    UserStats {
+        total_users: USERS.with_borrow(|users| users.len()),
+        registered_last_24h: registered_since(24),
+        registered_last_7d: registered_since(HOURS_PER_WEEK),
+        users_owning_collections: COLLECTION_OWNER_COUNT.with_borrow(|count| *count.get()),
+    }
+}
+
+/// Rebuilds the counters behind get_user_stats from USERS and FILE_METADATA,
+/// in case they drifted. Scans both maps, so it is meant for occasional use.
+#[update]
+fn recount_user_stats() -> Result<UserStats, String> {
+    ensure_admin(ic_cdk::api::msg_caller())?;
+    let first_hour = (ic_cdk::api::time() / NANOS_PER_HOUR).saturating_sub(HOURS_PER_WEEK - 1);
+    let created_at: Vec<u64> = USERS.with_borrow(|users| {
+        users
+            .iter()
+            .map(|(_, user)| user.created_at)
+            .filter(|created_at| created_at / NANOS_PER_HOUR >= first_hour)
+            .collect()
+    });
+    REGISTRATIONS_PER_HOUR.with_borrow_mut(|buckets| {
+        let hours: Vec<u64> = buckets.iter().map(|(hour, _)| hour).collect();
+        for hour in hours {
+            buckets.remove(&hour);
+        }
+        for created_at in created_at {
+            let hour = created_at / NANOS_PER_HOUR;
+            buckets.insert(hour, buckets.get(&hour).unwrap_or(0) + 1);
+        }
+    });
+
+    let mut owners = 0;
+    let mut next_owner = Some(Principal::management_canister());
+    while let Some(owner) = next_owner {
+        next_owner = FILE_METADATA.with_borrow(|metadata| {
+            metadata
+                .range((owner, Blob::default(), Blob::default())..)
+                .next()
+                .map(|((owner, _, _), _)| owner)
+        });
+        if let Some(owner) = next_owner {
+            owners += 1;
+            next_owner = successor(owner);
+        }
+    }
+    COLLECTION_OWNER_COUNT
+        .with_borrow_mut(|count| count.set(owners))
+        .map_err(|_| "failed to store stats".to_string())?;
+    Ok(get_user_stats())
+}
+
+/// Counts a registration at `created_at` and drops buckets older than a week.
+pub fn record_registration(created_at: u64) {
+    let hour = created_at / NANOS_PER_HOUR;
+    REGISTRATIONS_PER_HOUR.with_borrow_mut(|buckets| {
+        buckets.insert(hour, buckets.get(&hour).unwrap_or(0) + 1);
+        let expired: Vec<u64> = buckets
+            .range(..hour.saturating_sub(HOURS_PER_WEEK - 1))
+            .map(|(hour, _)| hour)
+            .collect();
+        for hour in expired {
+            buckets.remove(&hour);
+        }
+    });
+}
+
+/// Un-counts the registration of a deleted user, if it is still bucketed.
+pub fn record_deregistration(created_at: u64) {
+    let hour = created_at / NANOS_PER_HOUR;
+    REGISTRATIONS_PER_HOUR.with_borrow_mut(|buckets| match buckets.get(&hour) {
+        Some(count) if count > 1 => {
+            buckets.insert(hour, count - 1);
+        }
+        Some(_) => {
+            buckets.remove(&hour);
+        }
+        None => {}
+    });
+}
+
+/// Whether `owner` has at least one file, i.e. owns a non-empty collection.
+pub fn owns_files(owner: Principal) -> bool {
+    FILE_METADATA.with_borrow(|metadata| {
+        metadata
+            .range((owner, Blob::default(), Blob::default())..)
+            .next()
+            .is_some_and(|((file_owner, _, _), _)| file_owner == owner)
+    })
+}
+
+/// Adjusts the collection owner count after `owner` went from `had_files`
+/// to `has_files`.
+pub fn record_collection_ownership(had_files: bool, has_files: bool) {
+    if had_files == has_files {
+        return;
+    }
+    COLLECTION_OWNER_COUNT.with_borrow_mut(|count| {
+        let updated = if has_files {
+            *count.get() + 1
+        } else {
+            count.get().saturating_sub(1)
+        };
+        count.set(updated).expect("failed to store stats");
+    });
+}
+
+/// The smallest principal greater than `principal`, used to skip to the
+/// next owner in FILE_METADATA.
+fn successor(principal: Principal) -> Option<Principal> {
+    let mut bytes = principal.as_slice().to_vec();
+    if bytes.len() < MAX_PRINCIPAL_LEN {
+        bytes.push(0);
+        return Some(Principal::from_slice(&bytes));
+    }
+    while let Some(last) = bytes.pop() {
+        if last < u8::MAX {
+            bytes.push(last + 1);
+            return Some(Principal::from_slice(&bytes));
+        }
+    }
+    None
+}
@@ -0,0 +1,82 @@
+//! Small encrypted previews of files, e.g. for image collections. They are
+//! encrypted client-side with the collection's key, so the canister only
+//! stores the bytes.
+
+use crate::collections::ensure_not_archived;
+use crate::file_overrides::ensure_may_modify;
+use crate::users::record_activity;
+use crate::versions::FileKey;
+use crate::{
+    bytebuf_to_blob, collection_name_to_blob, ensure_can_write, ApiError, FILE_METADATA, THUMBNAILS,
+};
+use candid::Principal;
+use ic_cdk::update;
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::ByteBuf;
+
+const MAX_THUMBNAIL_SIZE: usize = 32 * 1024;
+
+/// Sets the thumbnail of a file, replacing any previous one. Requires write
+/// access to the collection, and the file must not be restricted for the
+/// caller.
+#[update]
+fn set_file_thumbnail(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    encrypted_thumb: ByteBuf,
+) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    if encrypted_thumb.as_ref().len() > MAX_THUMBNAIL_SIZE {
+        return Err(format!("thumbnail must be at most {MAX_THUMBNAIL_SIZE} bytes").into());
+    }
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_key = (collection_owner, collection_name, bytebuf_to_blob(file_id)?);
+    ensure_can_write(caller, (collection_owner, collection_name))?;
+    ensure_not_archived((collection_owner, collection_name))?;
+    if !FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+        return Err("file not found".to_string().into());
+    }
+    ensure_may_modify(file_key, caller)?;
+    THUMBNAILS.with_borrow_mut(|thumbnails| {
+        thumbnails.insert(file_key, encrypted_thumb.as_ref().to_vec())
+    });
+    Ok(())
+}
+
+pub fn thumbnail(file_key: FileKey) -> Option<ByteBuf> {
+    THUMBNAILS
+        .with_borrow(|thumbnails| thumbnails.get(&file_key))
+        .map(ByteBuf::from)
+}
+
+pub fn copy_thumbnail(from: FileKey, to: FileKey) {
+    if let Some(thumbnail) = THUMBNAILS.with_borrow(|thumbnails| thumbnails.get(&from)) {
+        THUMBNAILS.with_borrow_mut(|thumbnails| thumbnails.insert(to, thumbnail));
+    }
+}
+
+pub fn move_thumbnail(from: FileKey, to: FileKey) {
+    if let Some(thumbnail) = THUMBNAILS.with_borrow_mut(|thumbnails| thumbnails.remove(&from)) {
+        THUMBNAILS.with_borrow_mut(|thumbnails| thumbnails.insert(to, thumbnail));
+    }
+}
+
+pub fn remove_thumbnail(file_key: FileKey) {
+    THUMBNAILS.with_borrow_mut(|thumbnails| thumbnails.remove(&file_key));
+}
+
+/// Removes the thumbnails of all files in collections of `owner`.
+pub fn remove_owned_thumbnails(owner: Principal) {
+    THUMBNAILS.with_borrow_mut(|thumbnails| {
+        let keys: Vec<FileKey> = thumbnails
+            .range((owner, Blob::default(), Blob::default())..)
+            .take_while(|((key_owner, _, _), _)| key_owner == &owner)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            thumbnails.remove(&key);
+        }
+    });
+}
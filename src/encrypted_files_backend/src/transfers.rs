@@ -0,0 +1,231 @@
+//! Handing a collection over to another user. Collection keys are derived
+//! from the owner and name, so files moved to the new owner stay encrypted
+//! with the previous owner's key. The new owner is given read access to the
+//! old collection to fetch that key, re-uploads the files, and then calls
+//! `complete_collection_reencryption`.
+
+use crate::access_requests::remove_collection_access_requests;
+use crate::collection_activity::move_collection_activity;
+use crate::collection_managers::remove_collection_managers;
+use crate::collection_pins::unpin_collection;
+use crate::collection_stats::{collection_stats, record_upload_time, remove_collection_stats};
+use crate::collections::{
+    collection_metadata, collection_metadata_or_default, finish_rename, owned_collection_names,
+    CollectionMetadata,
+};
+use crate::devices::{grant_linked_access, linked_principals};
+use crate::files::relocate_file;
+use crate::grant_expiry::{move_grant_expiry, set_grant_expiry};
+use crate::groups::remove_collection_group_grants;
+use crate::invitations::cancel_collection_invitations;
+use crate::recent::move_collection_recent_uploads;
+use crate::stats::{owns_files, record_collection_ownership};
+use crate::trash::move_collection_trash;
+use crate::users::{principal_for_username, record_activity};
+use crate::{
+    collection_name_to_blob, MapId, MapKey, Username, COLLECTION_METADATA, COLLECTION_SETTINGS,
+    ENCRYPTED_MAPS, FILE_METADATA,
+};
+use candid::CandidType;
+use ic_cdk::update;
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::types::{AccessRights, ByteBuf};
+use serde::Deserialize;
+
+const MAX_TRANSFER_FILES: usize = 200;
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct TransferReport {
+    /// Files moved by this call, still encrypted with the previous key.
+    pub moved: Vec<ByteBuf>,
+    /// Files left to move; call again until it is 0.
+    pub remaining: u64,
+}
+
+/// Moves one of the caller's collections, with its files, trash and sharing
+/// grants, to the same name under `new_owner_username`, keeping read access
+/// for the caller. Moves at most [`MAX_TRANSFER_FILES`] files per call; call
+/// again until `remaining` is 0. The first call freezes the collection and
+/// revokes its grants, and leaves it archived and empty in the caller's list
+/// until the new owner completes the re-encryption.
+#[update]
+fn transfer_collection_ownership(
+    collection_name: ByteBuf,
+    new_owner_username: Username,
+) -> Result<TransferReport, String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let new_owner = principal_for_username(&new_owner_username)?;
+    if new_owner == caller {
+        return Err("the collection already belongs to this user".to_string());
+    }
+    let from = (caller, collection_name_to_blob(collection_name)?);
+    let to = (new_owner, from.1);
+    if !owned_collection_names(caller).contains(&from.1) {
+        return Err("collection not found".to_string());
+    }
+    match collection_metadata_or_default(from).transferred_to {
+        Some(recipient) if recipient == new_owner => {}
+        Some(_) => return Err("the collection is being transferred to another user".to_string()),
+        None => start_transfer(from, to)?,
+    }
+
+    let had_files = (owns_files(caller), owns_files(new_owner));
+    let file_ids: Vec<MapKey> = FILE_METADATA.with_borrow(|file_metadata| {
+        file_metadata
+            .range((from.0, from.1, Blob::default())..)
+            .take_while(|((owner, name, _), _)| (*owner, *name) == from)
+            .take(MAX_TRANSFER_FILES)
+            .map(|((_, _, file_id), _)| file_id)
+            .collect()
+    });
+    let mut report = TransferReport::default();
+    for file_id in file_ids {
+        if relocate_file(caller, (from.0, from.1, file_id), (to.0, to.1, file_id))? {
+            report
+                .moved
+                .push(ByteBuf::from(file_id.as_slice().to_vec()));
+        }
+    }
+    report.remaining = collection_stats(from).file_count;
+    if report.remaining == 0 {
+        finish_transfer(from, to)?;
+    }
+    record_collection_ownership(had_files.0, owns_files(caller));
+    record_collection_ownership(had_files.1, owns_files(new_owner));
+    Ok(report)
+}
+
+/// Ends the transfer or rename of a collection of the caller once all its
+/// files were re-uploaded with the key of its current owner and name. After
+/// a transfer, removes the caller's access to the previous owner's
+/// collection and its leftover record; after a rename, revokes the grants
+/// kept under the old name and removes the record left there.
+#[update]
+fn complete_collection_reencryption(collection_name: ByteBuf) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (caller, collection_name_to_blob(collection_name)?);
+    let metadata = collection_metadata(map_id).ok_or_else(|| "collection not found".to_string())?;
+    if metadata.pending_reencryption_from.is_none() && metadata.renamed_from.is_none() {
+        return Err("the collection is not waiting for re-encryption".to_string());
+    }
+    if let Some(previous_owner) = metadata.pending_reencryption_from {
+        let previous = (previous_owner, map_id.1);
+        let source =
+            collection_metadata(previous).filter(|source| source.transferred_to == Some(caller));
+        if source.is_some() {
+            if collection_stats(previous).file_count > 0 {
+                return Err("the transfer is still in progress".to_string());
+            }
+            COLLECTION_METADATA
+                .with_borrow_mut(|collection_metadata| collection_metadata.remove(&previous));
+        }
+        ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            encrypted_maps
+                .as_mut()
+                .unwrap()
+                .remove_user(previous_owner, previous, caller)
+        })?;
+    }
+    if let Some(old_name) = &metadata.renamed_from {
+        finish_rename(caller, old_name)?;
+    }
+    let metadata = CollectionMetadata {
+        pending_reencryption_from: None,
+        renamed_from: None,
+        ..metadata
+    };
+    COLLECTION_METADATA
+        .with_borrow_mut(|collection_metadata| collection_metadata.insert(map_id, metadata));
+    Ok(())
+}
+
+/// Moves the grants of `from` to `to` and grants `to` to the new owner's
+/// linked principals; those of the previous owner keep their grants on
+/// `from` only. Lets the previous owner move files into `to` and the new
+/// owner fetch the key of `from`, and records the transfer on both
+/// collections.
+fn start_transfer(from: MapId, to: MapId) -> Result<(), String> {
+    let (previous_owner, new_owner) = (from.0, to.0);
+    if owned_collection_names(new_owner).contains(&to.1) {
+        return Err("the new owner already has a collection with this name".to_string());
+    }
+    let source = collection_metadata_or_default(from);
+    if source.renamed_from.is_some() || source.renamed_to.is_some() {
+        return Err(
+            "a renamed collection must be re-encrypted before it is transferred".to_string(),
+        );
+    }
+    let previous_devices = linked_principals(previous_owner);
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        for (grantee, rights) in
+            encrypted_maps.get_shared_user_access_for_map(previous_owner, from)?
+        {
+            if grantee == previous_owner || previous_devices.contains(&grantee) {
+                continue;
+            }
+            if grantee != new_owner {
+                encrypted_maps.set_user_rights(new_owner, to, grantee, rights)?;
+                move_grant_expiry(from, to, grantee);
+            } else {
+                set_grant_expiry(from, grantee, None);
+            }
+            encrypted_maps.remove_user(previous_owner, from, grantee)?;
+        }
+        encrypted_maps.set_user_rights(new_owner, to, previous_owner, AccessRights::ReadWrite)?;
+        encrypted_maps.set_user_rights(previous_owner, from, new_owner, AccessRights::Read)?;
+        Ok::<(), String>(())
+    })?;
+    grant_linked_access(to);
+    cancel_collection_invitations(from);
+    remove_collection_access_requests(from);
+    remove_collection_managers(from);
+    remove_collection_group_grants(from);
+    let moved = CollectionMetadata {
+        owner: new_owner,
+        is_default: false,
+        archived: false,
+        pending_reencryption_from: Some(previous_owner),
+        ..source.clone()
+    };
+    let left_behind = CollectionMetadata {
+        archived: true,
+        transferred_to: Some(new_owner),
+        ..source
+    };
+    COLLECTION_METADATA.with_borrow_mut(|collection_metadata| {
+        collection_metadata.insert(to, moved);
+        collection_metadata.insert(from, left_behind);
+    });
+    Ok(())
+}
+
+/// Moves what is left of `from` once its files are moved and reduces the
+/// previous owner's access to `to` to reading.
+fn finish_transfer(from: MapId, to: MapId) -> Result<(), String> {
+    let (previous_owner, new_owner) = (from.0, to.0);
+    let last_upload_at = collection_stats(from).last_upload_at;
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        encrypted_maps.remove_map_values(previous_owner, from)?;
+        encrypted_maps.set_user_rights(new_owner, to, previous_owner, AccessRights::Read)?;
+        Ok::<(), String>(())
+    })?;
+    move_collection_trash(from, to);
+    move_collection_recent_uploads(from, to);
+    move_collection_activity(from, to);
+    unpin_collection(from);
+    remove_collection_stats(from);
+    if let Some(last_upload_at) = last_upload_at {
+        record_upload_time(to, last_upload_at);
+    }
+    if let Some(settings) =
+        COLLECTION_SETTINGS.with_borrow_mut(|collection_settings| collection_settings.remove(&from))
+    {
+        COLLECTION_SETTINGS
+            .with_borrow_mut(|collection_settings| collection_settings.insert(to, settings));
+    }
+    Ok(())
+}
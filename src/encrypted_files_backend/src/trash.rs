@@ -0,0 +1,307 @@
+//! Soft deletion. Removed files are kept in a per-collection trash, from
+//! which they can be restored, until they are purged explicitly or by a
+//! timer once they are older than the configured retention.
+
+use crate::admin::ensure_admin;
+use crate::collections::ensure_not_archived;
+use crate::file_overrides::{restore_file_overrides, FileOverride};
+use crate::filenames::ensure_unique_filename;
+use crate::files::{store_metadata, FileMetadata};
+use crate::stats::{owns_files, record_collection_ownership};
+use crate::users::record_activity;
+use crate::versions::FileKey;
+use crate::{
+    bytebuf_to_blob, caller_rights, collection_name_to_blob, ensure_can_write, ApiError, MapId,
+    CONFIG, ENCRYPTED_MAPS, FILE_METADATA, TRASH, TRASH_BY_DELETION_TIME,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{ByteBuf, EncryptedMapValue};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const DEFAULT_TRASH_RETENTION_DAYS: u32 = 30;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrashedFile {
+    pub value: EncryptedMapValue,
+    pub metadata: FileMetadata,
+    pub deleted_by: Principal,
+    /// The file's overrides when it was removed, so that hidden files stay
+    /// hidden in the trash and are restored with them.
+    #[serde(default)]
+    pub overrides: Vec<(Principal, FileOverride)>,
+}
+
+impl TrashedFile {
+    fn file_override(&self, user: Principal) -> Option<FileOverride> {
+        self.overrides
+            .iter()
+            .find(|(grantee, _)| grantee == &user)
+            .map(|(_, file_override)| *file_override)
+    }
+}
+
+impl Storable for TrashedFile {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TrashEntry {
+    pub file_id: ByteBuf,
+    /// Together with `file_id`, identifies the entry for restore_from_trash.
+    pub deleted_at: u64,
+    pub deleted_by: Principal,
+    pub metadata: FileMetadata,
+}
+
+/// The removed files of a collection, oldest first per file id. Requires
+/// read access to the collection. Files hidden from the caller are left
+/// out.
+#[query]
+fn list_trash(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+) -> Result<Vec<TrashEntry>, String> {
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    let caller = ic_cdk::api::msg_caller();
+    caller_rights(caller, map_id)?;
+    Ok(collection_trash(map_id)
+        .into_iter()
+        .filter(|(_, trashed)| trashed.file_override(caller) != Some(FileOverride::Hidden))
+        .map(|((file_key, deleted_at), trashed)| TrashEntry {
+            file_id: ByteBuf::from(file_key.2.as_slice().to_vec()),
+            deleted_at,
+            deleted_by: trashed.deleted_by,
+            metadata: trashed.metadata,
+        })
+        .collect())
+}
+
+/// Puts a removed file back into its collection. Fails if the file id has
+/// been used for another file since, or its filename if the collection
+/// requires unique filenames. Requires write access to the
+/// collection, and the file must not have been restricted for the caller.
+#[update]
+fn restore_from_trash(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    deleted_at: u64,
+) -> Result<FileMetadata, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    let file_id = bytebuf_to_blob(file_id)?;
+    let file_key = (collection_owner, collection_name, file_id);
+    ensure_can_write(caller, map_id)?;
+    ensure_not_archived(map_id)?;
+    let trashed = TRASH
+        .with_borrow(|trash| trash.get(&(file_key, deleted_at)))
+        .ok_or_else(|| "file not found in trash".to_string())?;
+    match trashed.file_override(caller) {
+        None => {}
+        Some(FileOverride::ReadOnly) => {
+            return Err("the file is read-only for you".to_string().into())
+        }
+        Some(FileOverride::Hidden) => return Err("file not found in trash".to_string().into()),
+    }
+    if FILE_METADATA.with_borrow(|file_metadata| file_metadata.contains_key(&file_key)) {
+        return Err(
+            "another file with this id exists; remove it before restoring this one"
+                .to_string()
+                .into(),
+        );
+    }
+    ensure_unique_filename(file_key, &trashed.metadata.filename)?;
+
+    let had_files = owns_files(collection_owner);
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps.as_mut().unwrap().insert_encrypted_value(
+            caller,
+            map_id,
+            file_id,
+            trashed.value,
+        )
+    })?;
+    let time_now = ic_cdk::api::time();
+    let metadata = FileMetadata {
+        // An expiry that has passed is dropped rather than deleting the
+        // restored file right away.
+        expires_at: trashed
+            .metadata
+            .expires_at
+            .filter(|expires_at| *expires_at > time_now),
+        ..trashed.metadata
+    };
+    store_metadata(file_key, metadata.clone());
+    restore_file_overrides(file_key, trashed.overrides);
+    remove_from_trash(file_key, deleted_at);
+    record_collection_ownership(had_files, owns_files(collection_owner));
+    Ok(metadata)
+}
+
+/// Permanently deletes everything in a collection's trash and returns the
+/// number of deleted files. Requires write access to the collection.
+#[update]
+fn purge_trash(collection_owner: Principal, collection_name: ByteBuf) -> Result<u64, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let map_id = (collection_owner, collection_name_to_blob(collection_name)?);
+    ensure_can_write(caller, map_id)?;
+    ensure_not_archived(map_id)?;
+    Ok(remove_collection_trash(map_id))
+}
+
+/// Days after which removed files are purged from the trash.
+#[query]
+fn get_trash_retention_days() -> u32 {
+    trash_retention_days()
+}
+
+#[update]
+fn set_trash_retention_days(days: u32) -> Result<(), String> {
+    ensure_admin(ic_cdk::api::msg_caller())?;
+    if days == 0 {
+        return Err("trash retention must be at least one day".to_string());
+    }
+    CONFIG.with_borrow_mut(|config| {
+        let mut updated = config.get().clone();
+        updated.trash_retention_days = Some(days);
+        config.set(updated).expect("failed to store config");
+    });
+    Ok(())
+}
+
+/// Keeps a removed file in the trash of its collection.
+pub fn move_to_trash(
+    file_key: FileKey,
+    value: EncryptedMapValue,
+    metadata: FileMetadata,
+    deleted_by: Principal,
+    overrides: Vec<(Principal, FileOverride)>,
+) {
+    let deleted_at = ic_cdk::api::time();
+    TRASH.with_borrow_mut(|trash| {
+        trash.insert(
+            (file_key, deleted_at),
+            TrashedFile {
+                value,
+                metadata,
+                deleted_by,
+                overrides,
+            },
+        )
+    });
+    TRASH_BY_DELETION_TIME.with_borrow_mut(|index| index.insert((deleted_at, file_key), ()));
+}
+
+/// Removes the trash of all collections of `owner`.
+pub fn collection_trash_len(map_id: MapId) -> usize {
+    TRASH.with_borrow(|trash| {
+        trash
+            .range(((map_id.0, map_id.1, Blob::default()), 0)..)
+            .take_while(|(((owner, name, _), _), _)| (*owner, *name) == map_id)
+            .count()
+    })
+}
+
+/// Moves the trash of collection `from` to collection `to` of the same
+/// owner, e.g. when the collection is renamed.
+pub fn move_collection_trash(from: MapId, to: MapId) {
+    for ((file_key, deleted_at), trashed) in collection_trash(from) {
+        remove_from_trash(file_key, deleted_at);
+        let new_key = (to.0, to.1, file_key.2);
+        TRASH.with_borrow_mut(|trash| trash.insert((new_key, deleted_at), trashed));
+        TRASH_BY_DELETION_TIME.with_borrow_mut(|index| index.insert((deleted_at, new_key), ()));
+    }
+}
+
+/// Empties the trash of a collection and returns the number of deleted
+/// files.
+pub fn remove_collection_trash(map_id: MapId) -> u64 {
+    let keys: Vec<_> = collection_trash(map_id)
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    for &(file_key, deleted_at) in &keys {
+        remove_from_trash(file_key, deleted_at);
+    }
+    keys.len() as u64
+}
+
+pub fn remove_owned_trash(owner: Principal) {
+    let keys: Vec<_> = TRASH.with_borrow(|trash| {
+        trash
+            .range(((owner, Blob::default(), Blob::default()), 0)..)
+            .take_while(|(((file_owner, _, _), _), _)| file_owner == &owner)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for (file_key, deleted_at) in keys {
+        remove_from_trash(file_key, deleted_at);
+    }
+}
+
+/// Purges expired trash entries periodically. Timers do not survive
+/// upgrades, so this is called from both init and post_upgrade.
+pub fn start_purge_timer() {
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, purge_expired_trash);
+}
+
+fn purge_expired_trash() {
+    let retention = trash_retention_days() as u64 * NANOS_PER_DAY;
+    let cutoff = ic_cdk::api::time().saturating_sub(retention);
+    let expired: Vec<_> = TRASH_BY_DELETION_TIME.with_borrow(|index| {
+        index
+            .range(
+                ..(
+                    cutoff,
+                    (
+                        Principal::management_canister(),
+                        Blob::default(),
+                        Blob::default(),
+                    ),
+                ),
+            )
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for (deleted_at, file_key) in expired {
+        remove_from_trash(file_key, deleted_at);
+    }
+}
+
+fn trash_retention_days() -> u32 {
+    CONFIG
+        .with_borrow(|config| config.get().trash_retention_days)
+        .unwrap_or(DEFAULT_TRASH_RETENTION_DAYS)
+}
+
+fn collection_trash(map_id: MapId) -> Vec<((FileKey, u64), TrashedFile)> {
+    TRASH.with_borrow(|trash| {
+        trash
+            .range(((map_id.0, map_id.1, Blob::default()), 0)..)
+            .take_while(|(((owner, name, _), _), _)| (*owner, *name) == map_id)
+            .collect()
+    })
+}
+
+fn remove_from_trash(file_key: FileKey, deleted_at: u64) {
+    TRASH.with_borrow_mut(|trash| trash.remove(&(file_key, deleted_at)));
+    TRASH_BY_DELETION_TIME.with_borrow_mut(|index| index.remove(&(deleted_at, file_key)));
+}
@@ -0,0 +1,1090 @@
+use crate::access_requests::remove_user_access_requests;
+use crate::admin::{creates_default_collection, ensure_admin};
+use crate::checksums::remove_owned_checksums;
+use crate::collection_activity::remove_owned_collection_activity;
+use crate::collection_managers::{remove_manager, remove_owned_collection_managers};
+use crate::collection_pins::remove_pinned_collections;
+use crate::collection_stats::remove_owned_collection_stats;
+use crate::collection_trash::remove_owned_deleted_collections;
+use crate::collections::{
+    create_default_collection, owned_collection_names, remove_collection_settings,
+    remove_owned_collection_metadata,
+};
+use crate::comments::remove_owned_file_comments;
+use crate::devices::{linked_principals, remove_linked_principals};
+use crate::file_overrides::remove_owned_file_overrides;
+use crate::file_shares::remove_owned_file_shares;
+use crate::file_stats::remove_owned_file_stats;
+use crate::filenames::remove_owned_filenames;
+use crate::grant_expiry::{remove_owned_grant_expiries, set_grant_expiry};
+use crate::groups::remove_user_groups;
+use crate::invitations::remove_user_invitations;
+use crate::notifications::remove_notifications;
+use crate::ordering::remove_owned_file_order;
+use crate::preferences::remove_preferences;
+use crate::read_audit::remove_owned_read_audit_logs;
+use crate::recent::remove_owned_recent_uploads;
+use crate::search::{index_display_name, unindex_display_name};
+use crate::share_links::remove_owned_share_links;
+use crate::sharing_audit::{log_sharing_change, remove_owned_sharing_audit_logs};
+use crate::stars::{remove_owned_file_stars, remove_stars};
+use crate::stats::{record_collection_ownership, record_deregistration, record_registration};
+use crate::thumbnails::remove_owned_thumbnails;
+use crate::trash::remove_owned_trash;
+use crate::versions::remove_owned_versions;
+use crate::{
+    or_trap, ApiError, MapId, Username, AVATARS, ENCRYPTED_MAPS, FILE_METADATA,
+    PRINCIPAL_TO_USERNAME, RESERVED_USERNAMES, USERS,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{storable::Bound, Storable};
+use ic_vetkeys::types::ByteBuf;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ops::Bound as RangeBound;
+use unicode_normalization::UnicodeNormalization;
+
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 32;
+const DEFAULT_RESERVED_USERNAMES: [&str; 6] = [
+    "admin",
+    "administrator",
+    "root",
+    "system",
+    "support",
+    "anonymous",
+];
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+const MAX_BIO_LEN: usize = 500;
+const MAX_CONTACT_LEN: usize = 200;
+const MAX_BATCH_LOOKUP: usize = 100;
+const MAX_LIST_USERS: u64 = 100;
+/// Users list_users inspects per call, discoverable or not.
+const MAX_LIST_SCAN: usize = 1_000;
+/// last_active is only rewritten once this much time has passed, to avoid
+/// re-encoding the user record on every update call.
+const LAST_ACTIVE_RESOLUTION_NS: u64 = 60 * 60 * 1_000_000_000;
+
+thread_local! {
+    /// Legacy USERS keys that could not be normalized because another user
+    /// already holds the normalized form. Recomputed on every upgrade.
+    static USERNAME_COLLISIONS: RefCell<Vec<Username>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A user as registered. `username` keeps the casing chosen at registration,
+/// while USERS is keyed by [`normalize_username`] so that "Alice" and "alice"
+/// cannot both exist.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct User {
+    pub username: Username,
+    pub display_name: Option<String>,
+    /// Free text shown on the profile. Not matched by search_users.
+    #[serde(default)]
+    pub bio: Option<String>,
+    /// How to reach the user outside the app, e.g. an email address.
+    #[serde(default)]
+    pub contact: Option<String>,
+    pub principal: Principal,
+    pub created_at: u64,
+    pub last_modified: u64,
+    /// Whether the user shows up in search_users and list_users.
+    #[serde(default = "default_true")]
+    pub discoverable: bool,
+    /// Time of the user's last update call, accurate to about an hour.
+    #[serde(default)]
+    pub last_active: u64,
+    /// Deactivated users keep their data but cannot upload, share or fetch
+    /// keys, and are hidden from search_users and list_users.
+    #[serde(default = "default_true")]
+    pub active: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl User {
+    pub fn new(username: Username, display_name: Option<String>, principal: Principal) -> Self {
+        let time_now = ic_cdk::api::time();
+        Self {
+            username,
+            display_name,
+            bio: None,
+            contact: None,
+            principal,
+            created_at: time_now,
+            last_modified: time_now,
+            discoverable: true,
+            last_active: time_now,
+            active: true,
+        }
+    }
+
+    pub fn update(
+        self,
+        display_name: Option<String>,
+        bio: Option<String>,
+        contact: Option<String>,
+    ) -> Self {
+        Self {
+            display_name,
+            bio,
+            contact,
+            last_modified: ic_cdk::api::time(),
+            ..self
+        }
+    }
+}
+
+impl Storable for User {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Usernames nobody may register, compared after normalization. Managed by
+/// the admin through reserve_username and unreserve_username.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReservedUsernames(pub Vec<Username>);
+
+impl Default for ReservedUsernames {
+    fn default() -> Self {
+        Self(
+            DEFAULT_RESERVED_USERNAMES
+                .iter()
+                .map(|username| username.to_string())
+                .collect(),
+        )
+    }
+}
+
+impl Storable for ReservedUsernames {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The username rule that was violated, so the frontend can point at it.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum UsernameValidationError {
+    TooShort { min_length: u64 },
+    TooLong { max_length: u64 },
+    InvalidCharacter { character: String },
+    StartsOrEndsWithPunctuation,
+    Reserved,
+}
+
+/// Errors of the endpoints that set a username.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum UsernameError {
+    Invalid(UsernameValidationError),
+    Taken,
+    Other(String),
+}
+
+impl From<String> for UsernameError {
+    fn from(message: String) -> Self {
+        Self::Other(message)
+    }
+}
+
+/// Everything the login flow needs to know about the caller.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WhoAmI {
+    pub principal: Principal,
+    pub is_anonymous: bool,
+    pub username: Option<Username>,
+    pub owned_collection_count: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    /// Pass as `after` to get the next page; `null` on the last page.
+    pub next_cursor: Option<Username>,
+    /// The number of registered users, including those that are not
+    /// discoverable.
+    pub total: u64,
+}
+
+/// What [`delete_my_account`] removed, so the frontend can confirm it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountDeletionSummary {
+    pub username: Username,
+    pub deleted_collections: Vec<ByteBuf>,
+    pub deleted_files: u64,
+    pub revoked_grants: u64,
+    pub left_shared_collections: u64,
+}
+
+#[update]
+fn register_user(username: Username, display_name: Option<String>) -> Result<User, UsernameError> {
+    let caller = ic_cdk::api::msg_caller();
+    if caller == Principal::anonymous() {
+        return Err("anonymous principal cannot register".to_string().into());
+    }
+    validate_text_length("display name", &display_name, MAX_DISPLAY_NAME_LEN)?;
+    if PRINCIPAL_TO_USERNAME.with_borrow(|p2u| p2u.contains_key(&caller)) {
+        return Err("caller is already registered".to_string().into());
+    }
+    let key = normalize_username(&username);
+    validate_username(&key).map_err(UsernameError::Invalid)?;
+    if USERS.with_borrow(|users| users.contains_key(&key)) {
+        return Err(UsernameError::Taken);
+    }
+
+    let user = User::new(username, display_name, caller);
+    USERS.with_borrow_mut(|users| users.insert(key.clone(), user.clone()));
+    PRINCIPAL_TO_USERNAME.with_borrow_mut(|p2u| p2u.insert(caller, key));
+    index_display_name(caller, &user.display_name);
+    record_registration(user.created_at);
+    if creates_default_collection() {
+        create_default_collection(caller);
+    }
+    Ok(user)
+}
+
+/// Replaces the caller's profile fields; `null` clears a field.
+#[update]
+fn update_my_user_profile(
+    display_name: Option<String>,
+    bio: Option<String>,
+    contact: Option<String>,
+) -> Result<User, String> {
+    record_activity(ic_cdk::api::msg_caller());
+    validate_text_length("display name", &display_name, MAX_DISPLAY_NAME_LEN)?;
+    validate_text_length("bio", &bio, MAX_BIO_LEN)?;
+    validate_text_length("contact", &contact, MAX_CONTACT_LEN)?;
+    let (key, user) = caller_user_entry()?;
+    unindex_display_name(user.principal, &user.display_name);
+    let user = user.update(display_name, bio, contact);
+    index_display_name(user.principal, &user.display_name);
+    USERS.with_borrow_mut(|users| users.insert(key, user.clone()));
+    Ok(user)
+}
+
+/// Hides the caller from, or shows them again in, search_users and
+/// list_users. Lookups by exact username keep working either way.
+#[update]
+fn set_discoverability(discoverable: bool) -> Result<User, String> {
+    record_activity(ic_cdk::api::msg_caller());
+    let (key, user) = caller_user_entry()?;
+    let user = User {
+        discoverable,
+        last_modified: ic_cdk::api::time(),
+        ..user
+    };
+    USERS.with_borrow_mut(|users| users.insert(key, user.clone()));
+    Ok(user)
+}
+
+/// Renames the caller. Both maps are updated within the same message, so they
+/// cannot disagree; sharing keeps working because EncryptedMaps access is keyed
+/// by principal rather than by username.
+#[update]
+fn change_username(new_username: Username) -> Result<User, UsernameError> {
+    record_activity(ic_cdk::api::msg_caller());
+    let (old_key, user) = caller_user_entry()?;
+    rename_user(old_key, user, new_username, ic_cdk::api::time())
+}
+
+/// Moves `user`, stored under `old_key`, to `new_username`. All checks come
+/// before the first write, so a rejected rename leaves both maps untouched.
+fn rename_user(
+    old_key: Username,
+    user: User,
+    new_username: Username,
+    now: u64,
+) -> Result<User, UsernameError> {
+    if user.username == new_username {
+        return Ok(user);
+    }
+    let new_key = normalize_username(&new_username);
+    validate_username(&new_key).map_err(UsernameError::Invalid)?;
+    // A change of casing only keeps the key and is always allowed.
+    if new_key != old_key && USERS.with_borrow(|users| users.contains_key(&new_key)) {
+        return Err(UsernameError::Taken);
+    }
+
+    let user = User {
+        username: new_username,
+        last_modified: now,
+        ..user
+    };
+    USERS.with_borrow_mut(|users| {
+        users.remove(&old_key);
+        users.insert(new_key.clone(), user.clone());
+    });
+    repoint_principals(user.principal, &new_key);
+    Ok(user)
+}
+
+/// Deletes the caller's account together with everything it owns: all files
+/// in owned collections, the grants on those collections, and the caller's
+/// own grants on collections shared with them. Owned collections that are
+/// already empty are not enumerable through EncryptedMaps and keep whatever
+/// grants they had. Only the primary principal can delete the account;
+/// linked principals are unlinked along with it.
+#[update]
+fn delete_my_account() -> Result<AccountDeletionSummary, String> {
+    let (key, user) = caller_user_entry()?;
+    let caller = user.principal;
+    if caller != ic_cdk::api::msg_caller() {
+        return Err("the account can only be deleted from its primary principal".to_string());
+    }
+    let mut summary = AccountDeletionSummary {
+        username: user.username.clone(),
+        deleted_collections: Vec::new(),
+        deleted_files: 0,
+        revoked_grants: 0,
+        left_shared_collections: 0,
+    };
+
+    // An Err return does not roll back the writes made before it, so
+    // everything that can fail is read before the first write. The writes
+    // below only fail if EncryptedMaps breaks its own invariants, and then
+    // trap, which rolls back the whole call.
+    let shared_map_ids = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+        encrypted_maps
+            .as_ref()
+            .unwrap()
+            .get_accessible_shared_map_names(caller)
+    });
+    let owned_grantees: Vec<(MapId, Vec<Principal>)> =
+        ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+            let encrypted_maps = encrypted_maps.as_ref().unwrap();
+            owned_collection_names(caller)
+                .into_iter()
+                .map(|map_name| {
+                    let map_id = (caller, map_name);
+                    let grantees = encrypted_maps
+                        .get_shared_user_access_for_map(caller, map_id)?
+                        .into_iter()
+                        .map(|(grantee, _)| grantee)
+                        .filter(|grantee| grantee != &caller)
+                        .collect();
+                    Ok((map_id, grantees))
+                })
+                .collect::<Result<_, String>>()
+        })?;
+
+    ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        let encrypted_maps = encrypted_maps.as_mut().unwrap();
+        // Leaving a shared collection is done on behalf of its owner, which
+        // is the only principal EncryptedMaps lets remove the caller's grant.
+        for map_id in shared_map_ids {
+            let rights = or_trap(
+                "account deletion",
+                encrypted_maps.remove_user(map_id.0, map_id, caller),
+            );
+            log_sharing_change(map_id, caller, caller, rights, None);
+            set_grant_expiry(map_id, caller, None);
+            remove_manager(map_id, caller);
+            summary.left_shared_collections += 1;
+        }
+        for (map_id, grantees) in owned_grantees {
+            for grantee in grantees {
+                or_trap(
+                    "account deletion",
+                    encrypted_maps.remove_user(caller, map_id, grantee),
+                );
+                summary.revoked_grants += 1;
+            }
+            or_trap(
+                "account deletion",
+                encrypted_maps.remove_map_values(caller, map_id),
+            );
+            summary
+                .deleted_collections
+                .push(ByteBuf::from(map_id.1.as_slice().to_vec()));
+        }
+    });
+    or_trap("account deletion", remove_owned_file_shares(caller));
+    remove_owned_grant_expiries(caller);
+    remove_owned_collection_managers(caller);
+    remove_user_groups(caller);
+    remove_owned_share_links(caller);
+    remove_user_invitations(caller);
+    remove_user_access_requests(caller);
+    remove_owned_sharing_audit_logs(caller);
+    remove_owned_read_audit_logs(caller);
+    remove_notifications(caller);
+
+    FILE_METADATA.with_borrow_mut(|file_metadata| {
+        let owned_keys: Vec<_> = file_metadata
+            .range((caller, Blob::default(), Blob::default())..)
+            .take_while(|((owner, _, _), _)| owner == &caller)
+            .map(|(key, _)| key)
+            .collect();
+        for key in &owned_keys {
+            file_metadata.remove(key);
+        }
+        summary.deleted_files = owned_keys.len() as u64;
+    });
+    remove_owned_versions(caller);
+    remove_owned_trash(caller);
+    remove_owned_file_stars(caller);
+    remove_owned_file_comments(caller);
+    remove_owned_file_order(caller);
+    remove_owned_thumbnails(caller);
+    remove_owned_collection_stats(caller);
+    remove_owned_collection_activity(caller);
+    remove_owned_deleted_collections(caller);
+    remove_pinned_collections(caller);
+    remove_owned_collection_metadata(caller);
+    remove_owned_file_stats(caller);
+    remove_owned_file_overrides(caller);
+    remove_owned_filenames(caller);
+    remove_owned_checksums(caller);
+    remove_owned_recent_uploads(caller);
+    for principal in std::iter::once(caller).chain(linked_principals(caller)) {
+        remove_stars(principal);
+    }
+    remove_collection_settings(caller);
+    AVATARS.with_borrow_mut(|avatars| avatars.remove(&caller));
+    remove_linked_principals(caller);
+    remove_preferences(caller);
+    unindex_display_name(caller, &user.display_name);
+    record_deregistration(user.created_at);
+    record_collection_ownership(summary.deleted_files > 0, false);
+    USERS.with_borrow_mut(|users| users.remove(&key));
+    PRINCIPAL_TO_USERNAME.with_borrow_mut(|p2u| p2u.remove(&caller));
+    Ok(summary)
+}
+
+#[query]
+fn get_my_user_profile() -> Option<User> {
+    caller_user().ok()
+}
+
+#[query]
+fn whoami() -> WhoAmI {
+    let caller = ic_cdk::api::msg_caller();
+    let owned_collection_count = owned_collection_names(caller).len() as u64;
+    WhoAmI {
+        principal: caller,
+        is_anonymous: caller == Principal::anonymous(),
+        username: user_for_principal(&caller).map(|user| user.username),
+        owned_collection_count,
+    }
+}
+
+#[query]
+fn get_user_by_username(username: Username) -> Option<User> {
+    USERS.with_borrow(|users| users.get(&normalize_username(&username)))
+}
+
+/// Resolves a principal, e.g. one returned by
+/// get_shared_user_access_for_collection, to its registered username.
+/// Returns `null` for users that search_users does not show either.
+#[query]
+fn get_username_for_principal(principal: Principal) -> Option<Username> {
+    listed_user_for_principal(&principal).map(|user| user.username)
+}
+
+#[query]
+fn get_reserved_usernames() -> Vec<Username> {
+    RESERVED_USERNAMES.with_borrow(|reserved| reserved.get().0.clone())
+}
+
+/// Reserves a username so that nobody can register it or rename to it.
+/// Fails if the name is already taken rather than evicting its owner.
+#[update]
+fn reserve_username(username: Username) -> Result<(), String> {
+    ensure_admin(ic_cdk::api::msg_caller())?;
+    let key = normalize_username(&username);
+    if USERS.with_borrow(|users| users.contains_key(&key)) {
+        return Err(format!("username {username} is in use"));
+    }
+    update_reserved_usernames(|reserved| {
+        if !reserved.contains(&key) {
+            reserved.push(key);
+        }
+    })
+}
+
+#[update]
+fn unreserve_username(username: Username) -> Result<(), String> {
+    ensure_admin(ic_cdk::api::msg_caller())?;
+    let key = normalize_username(&username);
+    update_reserved_usernames(|reserved| reserved.retain(|r| r != &key))
+}
+
+fn update_reserved_usernames(f: impl FnOnce(&mut Vec<Username>)) -> Result<(), String> {
+    RESERVED_USERNAMES.with_borrow_mut(|reserved| {
+        let mut usernames = reserved.get().0.clone();
+        f(&mut usernames);
+        reserved
+            .set(ReservedUsernames(usernames))
+            .map(|_| ())
+            .map_err(|_| "failed to store reserved usernames".to_string())
+    })
+}
+
+/// Disables an account without deleting anything. Admin only.
+#[update]
+fn deactivate_user(username: Username) -> Result<User, String> {
+    set_user_active(&username, false)
+}
+
+#[update]
+fn reactivate_user(username: Username) -> Result<User, String> {
+    set_user_active(&username, true)
+}
+
+fn set_user_active(username: &str, active: bool) -> Result<User, String> {
+    ensure_admin(ic_cdk::api::msg_caller())?;
+    let principal = principal_for_username(username)?;
+    let key = PRINCIPAL_TO_USERNAME
+        .with_borrow(|p2u| p2u.get(&principal))
+        .ok_or_else(|| format!("user {username} not found"))?;
+    USERS.with_borrow_mut(|users| {
+        let user = users
+            .get(&key)
+            .ok_or_else(|| format!("user {username} not found"))?;
+        let user = User {
+            active,
+            last_modified: ic_cdk::api::time(),
+            ..user
+        };
+        users.insert(key, user.clone());
+        Ok(user)
+    })
+}
+
+/// Legacy usernames that the last upgrade could not normalize because they
+/// collide case-insensitively with another user. Those users keep working
+/// through their principal but should pick a new name via change_username.
+#[query]
+fn get_username_collisions() -> Vec<Username> {
+    USERNAME_COLLISIONS.with_borrow(|collisions| collisions.clone())
+}
+
+/// Resolves each principal to its user record, preserving input order.
+/// Users that search_users does not show either resolve to `null`.
+#[query]
+fn get_users_by_principals(principals: Vec<Principal>) -> Result<Vec<Option<User>>, String> {
+    if principals.len() > MAX_BATCH_LOOKUP {
+        return Err(format!(
+            "at most {MAX_BATCH_LOOKUP} principals can be looked up at once"
+        ));
+    }
+    Ok(principals.iter().map(listed_user_for_principal).collect())
+}
+
+/// Pages through discoverable users in username order. Inspects at most
+/// [`MAX_LIST_SCAN`] users per call, so a page can be short, or even empty,
+/// and still have a `next_cursor`.
+#[query]
+fn list_users(after: Option<Username>, limit: u64) -> UserPage {
+    let start = match after {
+        Some(username) => RangeBound::Excluded(normalize_username(&username)),
+        None => RangeBound::Unbounded,
+    };
+    let limit = limit.clamp(1, MAX_LIST_USERS) as usize;
+    USERS.with_borrow(|users| {
+        let mut page = Vec::new();
+        let mut last_key = None;
+        let mut stopped_early = false;
+        for (scanned, (key, user)) in users.range((start, RangeBound::Unbounded)).enumerate() {
+            if page.len() == limit || scanned == MAX_LIST_SCAN {
+                stopped_early = true;
+                break;
+            }
+            if user.discoverable && user.active {
+                page.push(user);
+            }
+            last_key = Some(key);
+        }
+        UserPage {
+            users: page,
+            next_cursor: last_key.filter(|_| stopped_early),
+            total: users.len(),
+        }
+    })
+}
+
+/// Returns the registered user record of the caller.
+pub fn caller_user() -> Result<User, String> {
+    caller_user_entry().map(|(_, user)| user)
+}
+
+/// Like [`caller_user`], but also returns the USERS key the record is stored
+/// under, which is what mutations must use.
+fn caller_user_entry() -> Result<(Username, User), String> {
+    let caller = ic_cdk::api::msg_caller();
+    PRINCIPAL_TO_USERNAME
+        .with_borrow(|p2u| p2u.get(&caller))
+        .and_then(|key| {
+            USERS
+                .with_borrow(|users| users.get(&key))
+                .map(|user| (key, user))
+        })
+        .ok_or_else(|| format!("principal {caller} is not registered"))
+}
+
+pub fn user_for_principal(principal: &Principal) -> Option<User> {
+    PRINCIPAL_TO_USERNAME
+        .with_borrow(|p2u| p2u.get(principal))
+        .and_then(|username| USERS.with_borrow(|users| users.get(&username)))
+}
+
+/// The user of `principal` if they are discoverable and active, i.e. shown
+/// by search_users and list_users.
+fn listed_user_for_principal(principal: &Principal) -> Option<User> {
+    user_for_principal(principal).filter(|user| user.discoverable && user.active)
+}
+
+/// Rejects calls from principals belonging to a deactivated account.
+/// Unregistered principals are not affected.
+pub fn ensure_active(principal: Principal) -> Result<(), ApiError> {
+    match user_for_principal(&principal) {
+        Some(user) if !user.active => Err(ApiError::AccountDeactivated),
+        _ => Ok(()),
+    }
+}
+
+/// Updates the last_active timestamp of the given user, if registered and
+/// not already updated within the last hour. Must only be called from
+/// update methods.
+pub fn record_activity(principal: Principal) {
+    let Some(key) = PRINCIPAL_TO_USERNAME.with_borrow(|p2u| p2u.get(&principal)) else {
+        return;
+    };
+    let time_now = ic_cdk::api::time();
+    USERS.with_borrow_mut(|users| {
+        if let Some(user) = users.get(&key) {
+            if time_now.saturating_sub(user.last_active) >= LAST_ACTIVE_RESOLUTION_NS {
+                let user = User {
+                    last_active: time_now,
+                    ..user
+                };
+                users.insert(key, user);
+            }
+        }
+    });
+}
+
+/// Resolves a registered username to its principal.
+pub fn principal_for_username(username: &str) -> Result<Principal, String> {
+    USERS
+        .with_borrow(|users| users.get(&normalize_username(username)))
+        .map(|user| user.principal)
+        .ok_or_else(|| format!("user {username} not found"))
+}
+
+/// The USERS key for a username: NFC-normalized and lowercased, so that
+/// visually identical names map to the same account.
+pub fn normalize_username(username: &str) -> Username {
+    username.nfc().collect::<String>().to_lowercase()
+}
+
+/// Re-keys USERS entries stored before usernames were normalized. Entries
+/// whose normalized key is already taken are left in place and reported
+/// through get_username_collisions instead of being dropped.
+pub fn migrate_username_keys() {
+    let legacy: Vec<(Username, User)> = USERS.with_borrow(|users| {
+        users
+            .iter()
+            .filter(|(key, _)| *key != normalize_username(key))
+            .collect()
+    });
+    let mut collisions = Vec::new();
+    for (legacy_key, user) in legacy {
+        let key = normalize_username(&legacy_key);
+        if USERS.with_borrow(|users| users.contains_key(&key)) {
+            ic_cdk::println!("username {legacy_key} collides with existing key {key}");
+            collisions.push(legacy_key);
+            continue;
+        }
+        USERS.with_borrow_mut(|users| {
+            users.remove(&legacy_key);
+            users.insert(key.clone(), user.clone());
+        });
+        repoint_principals(user.principal, &key);
+    }
+    USERNAME_COLLISIONS.with_borrow_mut(|stored| *stored = collisions);
+}
+
+/// Points the primary principal and all principals linked to it at `key`.
+fn repoint_principals(primary: Principal, key: &Username) {
+    PRINCIPAL_TO_USERNAME.with_borrow_mut(|p2u| {
+        p2u.insert(primary, key.clone());
+        for principal in linked_principals(primary) {
+            p2u.insert(principal, key.clone());
+        }
+    });
+}
+
+/// Checks a normalized username: 3–32 characters from `[a-z0-9_.-]`, not
+/// starting or ending with punctuation, and not on the reserved list.
+fn validate_username(key: &str) -> Result<(), UsernameValidationError> {
+    let length = key.chars().count();
+    if length < MIN_USERNAME_LEN {
+        return Err(UsernameValidationError::TooShort {
+            min_length: MIN_USERNAME_LEN as u64,
+        });
+    }
+    if length > MAX_USERNAME_LEN {
+        return Err(UsernameValidationError::TooLong {
+            max_length: MAX_USERNAME_LEN as u64,
+        });
+    }
+    if let Some(character) = key
+        .chars()
+        .find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || "_.-".contains(*c)))
+    {
+        return Err(UsernameValidationError::InvalidCharacter {
+            character: character.to_string(),
+        });
+    }
+    if key.starts_with(['_', '.', '-']) || key.ends_with(['_', '.', '-']) {
+        return Err(UsernameValidationError::StartsOrEndsWithPunctuation);
+    }
+    if RESERVED_USERNAMES.with_borrow(|reserved| reserved.get().0.iter().any(|r| r == key)) {
+        return Err(UsernameValidationError::Reserved);
+    }
+    Ok(())
+}
+
+fn validate_text_length(field: &str, value: &Option<String>, max_len: usize) -> Result<(), String> {
+    match value {
+        Some(value) if value.chars().count() > max_len => {
+            Err(format!("{field} must be at most {max_len} characters"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_vetkeys::types::AccessRights;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn register(username: &str, principal: Principal) -> (Username, User) {
+        let key = normalize_username(username);
+        let user = User {
+            username: username.to_string(),
+            display_name: None,
+            bio: None,
+            contact: None,
+            principal,
+            created_at: 0,
+            last_modified: 0,
+            discoverable: true,
+            last_active: 0,
+            active: true,
+        };
+        USERS.with_borrow_mut(|users| users.insert(key.clone(), user.clone()));
+        PRINCIPAL_TO_USERNAME.with_borrow_mut(|p2u| p2u.insert(principal, key.clone()));
+        (key, user)
+    }
+
+    fn key_of(principal: Principal) -> Option<Username> {
+        PRINCIPAL_TO_USERNAME.with_borrow(|p2u| p2u.get(&principal))
+    }
+
+    #[test]
+    fn rename_moves_user_and_principal_together() {
+        let (key, user) = register("alice", principal(1));
+        let renamed = rename_user(key, user, "Alicia".to_string(), 5).unwrap();
+        assert_eq!(renamed.username, "Alicia");
+        assert_eq!(renamed.last_modified, 5);
+        assert!(USERS.with_borrow(|users| !users.contains_key(&"alice".to_string())));
+        assert_eq!(key_of(principal(1)), Some("alicia".to_string()));
+        // Grants are keyed by principal, so the new name resolves to the
+        // principal collections were shared with under the old name.
+        assert_eq!(principal_for_username("alicia"), Ok(principal(1)));
+        assert!(principal_for_username("alice").is_err());
+    }
+
+    #[test]
+    fn rename_keeps_grants_on_shared_collections() {
+        crate::init_encrypted_maps("test_key".to_string());
+        let owner = principal(9);
+        let map_id = (owner, Blob::try_from(b"photos".as_slice()).unwrap());
+        let (key, user) = register("alice", principal(1));
+        ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+            let encrypted_maps = encrypted_maps.as_mut().unwrap();
+            let file_id = Blob::try_from([1].as_slice()).unwrap();
+            encrypted_maps
+                .insert_encrypted_value(owner, map_id, file_id, ByteBuf::from(vec![1]))
+                .unwrap();
+            encrypted_maps
+                .set_user_rights(owner, map_id, principal(1), AccessRights::ReadWrite)
+                .unwrap();
+        });
+
+        rename_user(key, user, "Alicia".to_string(), 5).unwrap();
+        let grantee = principal_for_username("alicia").unwrap();
+        assert_eq!(grantee, principal(1));
+        let rights = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+            encrypted_maps
+                .as_ref()
+                .unwrap()
+                .get_user_rights(owner, map_id, grantee)
+        });
+        assert_eq!(rights, Ok(Some(AccessRights::ReadWrite)));
+        assert_eq!(
+            crate::caller_rights(grantee, map_id),
+            Ok(AccessRights::ReadWrite)
+        );
+    }
+
+    #[test]
+    fn lookups_by_principal_leave_out_unlisted_users() {
+        register("alice", principal(1));
+        let (bob_key, bob) = register("bob", principal(2));
+        let (carol_key, carol) = register("carol", principal(3));
+        USERS.with_borrow_mut(|users| {
+            users.insert(
+                bob_key,
+                User {
+                    discoverable: false,
+                    ..bob
+                },
+            );
+            users.insert(
+                carol_key,
+                User {
+                    active: false,
+                    ..carol
+                },
+            );
+        });
+        let principals = vec![principal(1), principal(2), principal(3), principal(4)];
+        let usernames: Vec<Option<Username>> = get_users_by_principals(principals.clone())
+            .unwrap()
+            .into_iter()
+            .map(|user| user.map(|user| user.username))
+            .collect();
+        assert_eq!(usernames, vec![Some("alice".to_string()), None, None, None]);
+        let usernames: Vec<Option<Username>> = principals
+            .into_iter()
+            .map(get_username_for_principal)
+            .collect();
+        assert_eq!(usernames, vec![Some("alice".to_string()), None, None, None]);
+    }
+
+    #[test]
+    fn list_users_stops_after_the_scan_limit() {
+        for i in 0..MAX_LIST_SCAN + 5 {
+            let (key, user) = register(&format!("user{i:05}"), principal(0));
+            // Only the last few users are discoverable.
+            let discoverable = i >= MAX_LIST_SCAN;
+            USERS.with_borrow_mut(|users| {
+                users.insert(
+                    key,
+                    User {
+                        discoverable,
+                        ..user
+                    },
+                )
+            });
+        }
+
+        let page = list_users(None, 10);
+        assert!(page.users.is_empty());
+        let cursor = format!("user{:05}", MAX_LIST_SCAN - 1);
+        assert_eq!(page.next_cursor, Some(cursor.clone()));
+        assert_eq!(page.total, MAX_LIST_SCAN as u64 + 5);
+
+        let page = list_users(Some(cursor), 3);
+        assert_eq!(page.users.len(), 3);
+        assert_eq!(
+            page.next_cursor,
+            Some(format!("user{:05}", MAX_LIST_SCAN + 2))
+        );
+
+        let page = list_users(page.next_cursor, 3);
+        assert_eq!(page.users.len(), 2);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn rename_to_taken_username_changes_nothing() {
+        let (key, user) = register("alice", principal(1));
+        register("bob", principal(2));
+        let result = rename_user(key, user, "BOB".to_string(), 5);
+        assert!(matches!(result, Err(UsernameError::Taken)));
+        assert_eq!(key_of(principal(1)), Some("alice".to_string()));
+        assert_eq!(key_of(principal(2)), Some("bob".to_string()));
+        assert_eq!(principal_for_username("bob"), Ok(principal(2)));
+        assert_eq!(USERS.with_borrow(|users| users.len()), 2);
+    }
+
+    #[test]
+    fn rename_to_invalid_username_changes_nothing() {
+        let (key, user) = register("alice", principal(1));
+        let result = rename_user(key, user, "al".to_string(), 5);
+        assert!(matches!(
+            result,
+            Err(UsernameError::Invalid(
+                UsernameValidationError::TooShort { .. }
+            ))
+        ));
+        assert_eq!(key_of(principal(1)), Some("alice".to_string()));
+        assert_eq!(principal_for_username("alice"), Ok(principal(1)));
+    }
+
+    #[test]
+    fn change_of_casing_keeps_the_key() {
+        let (key, user) = register("alice", principal(1));
+        let renamed = rename_user(key, user, "Alice".to_string(), 5).unwrap();
+        assert_eq!(renamed.username, "Alice");
+        assert_eq!(key_of(principal(1)), Some("alice".to_string()));
+        let stored = USERS.with_borrow(|users| users.get(&"alice".to_string()));
+        assert_eq!(stored.map(|user| user.username), Some("Alice".to_string()));
+    }
+
+    #[test]
+    fn validate_username_checks_length() {
+        assert_eq!(
+            validate_username("ab"),
+            Err(UsernameValidationError::TooShort { min_length: 3 })
+        );
+        assert_eq!(validate_username("abc"), Ok(()));
+        assert_eq!(validate_username(&"a".repeat(32)), Ok(()));
+        assert_eq!(
+            validate_username(&"a".repeat(33)),
+            Err(UsernameValidationError::TooLong { max_length: 32 })
+        );
+    }
+
+    #[test]
+    fn validate_username_checks_characters() {
+        assert_eq!(validate_username("a_b.c-9"), Ok(()));
+        for (username, character) in [("al ice", " "), ("alice!", "!"), ("Alice", "A")] {
+            assert_eq!(
+                validate_username(username),
+                Err(UsernameValidationError::InvalidCharacter {
+                    character: character.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn validate_username_rejects_leading_and_trailing_punctuation() {
+        for username in ["_alice", ".alice", "-alice", "alice_", "alice.", "alice-"] {
+            assert_eq!(
+                validate_username(username),
+                Err(UsernameValidationError::StartsOrEndsWithPunctuation)
+            );
+        }
+    }
+
+    #[test]
+    fn validate_username_rejects_reserved_names() {
+        assert_eq!(
+            validate_username("admin"),
+            Err(UsernameValidationError::Reserved)
+        );
+        assert_eq!(validate_username("moderator"), Ok(()));
+        update_reserved_usernames(|reserved| reserved.push("moderator".to_string())).unwrap();
+        assert_eq!(
+            validate_username("moderator"),
+            Err(UsernameValidationError::Reserved)
+        );
+    }
+
+    /// User as stored before the bio and contact fields were added.
+    #[derive(Serialize)]
+    struct UserBeforeBio {
+        username: Username,
+        display_name: Option<String>,
+        principal: Principal,
+        created_at: u64,
+        last_modified: u64,
+        discoverable: bool,
+        last_active: u64,
+        active: bool,
+    }
+
+    /// User as first stored, before any field with a default was added.
+    #[derive(Serialize)]
+    struct OriginalUser {
+        username: Username,
+        display_name: Option<String>,
+        principal: Principal,
+        created_at: u64,
+        last_modified: u64,
+    }
+
+    #[test]
+    fn decodes_users_stored_before_bio_and_contact() {
+        let bytes = serde_cbor::to_vec(&UserBeforeBio {
+            username: "alice".to_string(),
+            display_name: Some("Alice".to_string()),
+            principal: principal(1),
+            created_at: 1,
+            last_modified: 2,
+            discoverable: false,
+            last_active: 3,
+            active: false,
+        })
+        .unwrap();
+        let user = User::from_bytes(Cow::Owned(bytes));
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.display_name.as_deref(), Some("Alice"));
+        assert_eq!(user.bio, None);
+        assert_eq!(user.contact, None);
+        assert_eq!(user.principal, principal(1));
+        assert_eq!(
+            (user.created_at, user.last_modified, user.last_active),
+            (1, 2, 3)
+        );
+        assert!(!user.discoverable);
+        assert!(!user.active);
+    }
+
+    #[test]
+    fn decodes_users_stored_before_any_defaulted_field() {
+        let bytes = serde_cbor::to_vec(&OriginalUser {
+            username: "alice".to_string(),
+            display_name: None,
+            principal: principal(1),
+            created_at: 1,
+            last_modified: 2,
+        })
+        .unwrap();
+        let user = User::from_bytes(Cow::Owned(bytes));
+        assert_eq!(user.bio, None);
+        assert_eq!(user.contact, None);
+        assert!(user.discoverable);
+        assert_eq!(user.last_active, 0);
+        assert!(user.active);
+    }
+
+    #[test]
+    fn round_trips_bio_and_contact() {
+        let (_, user) = register("alice", principal(1));
+        let user = User {
+            bio: Some("Hi".to_string()),
+            contact: Some("alice@example.com".to_string()),
+            ..user
+        };
+        let decoded = User::from_bytes(user.to_bytes());
+        assert_eq!(decoded.bio.as_deref(), Some("Hi"));
+        assert_eq!(decoded.contact.as_deref(), Some("alice@example.com"));
+    }
+}
@@ -0,0 +1,188 @@
+//! Previous versions of files. When an upload replaces a file in a
+//! collection whose `keep_versions` setting is non-zero, the replaced
+//! encrypted value and metadata are kept here, numbered per file in
+//! increasing order, and the oldest ones are pruned beyond that count.
+
+use crate::collections::{collection_settings, ensure_not_archived};
+use crate::file_overrides::{ensure_may_modify, is_hidden_from};
+use crate::filenames::ensure_unique_filename;
+use crate::files::{store_metadata, FileMetadata};
+use crate::stats::{owns_files, record_collection_ownership};
+use crate::users::record_activity;
+use crate::{
+    bytebuf_to_blob, caller_rights, collection_name_to_blob, ensure_can_write, ApiError, MapKey,
+    MapName, MapOwner, ENCRYPTED_MAPS, FILE_METADATA, FILE_VERSIONS,
+};
+use candid::Principal;
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::Storable;
+use ic_vetkeys::types::{ByteBuf, EncryptedMapValue};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+pub type FileKey = (MapOwner, MapName, MapKey);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileVersion {
+    pub value: EncryptedMapValue,
+    pub metadata: FileMetadata,
+}
+
+impl Storable for FileVersion {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The kept versions of a file, oldest first. Requires read access to the
+/// collection, and files hidden from the caller are reported as missing.
+#[query]
+fn list_file_versions(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+) -> Result<Vec<(u32, FileMetadata)>, String> {
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let file_key = (collection_owner, collection_name, bytebuf_to_blob(file_id)?);
+    let caller = ic_cdk::api::msg_caller();
+    caller_rights(caller, (collection_owner, collection_name))?;
+    if is_hidden_from(file_key, caller) {
+        return Err("file not found".to_string());
+    }
+    Ok(file_versions(file_key)
+        .into_iter()
+        .map(|(version, file_version)| (version, file_version.metadata))
+        .collect())
+}
+
+/// Makes a kept version the live content of the file again. The content it
+/// replaces is kept as the newest version in turn, so a restore can be
+/// undone, unless the collection no longer keeps versions. Fails if the
+/// collection requires unique filenames and another file has taken the
+/// version's filename since. Requires write access to the collection, and
+/// the file must not be restricted for the caller.
+#[update]
+fn restore_file_version(
+    collection_owner: Principal,
+    collection_name: ByteBuf,
+    file_id: ByteBuf,
+    version: u32,
+) -> Result<FileMetadata, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    record_activity(caller);
+    let collection_name = collection_name_to_blob(collection_name)?;
+    let map_id = (collection_owner, collection_name);
+    let file_id = bytebuf_to_blob(file_id)?;
+    let file_key = (collection_owner, collection_name, file_id);
+    ensure_can_write(caller, map_id)?;
+    ensure_not_archived(map_id)?;
+    ensure_may_modify(file_key, caller)?;
+    let restored = FILE_VERSIONS
+        .with_borrow(|file_versions| file_versions.get(&(file_key, version)))
+        .ok_or_else(|| format!("version {version} not found"))?;
+    ensure_unique_filename(file_key, &restored.metadata.filename)?;
+
+    let had_files = owns_files(collection_owner);
+    let replaced_value = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+        encrypted_maps.as_mut().unwrap().insert_encrypted_value(
+            caller,
+            map_id,
+            file_id,
+            restored.value,
+        )
+    })?;
+    let replaced_metadata = FILE_METADATA.with_borrow(|file_metadata| file_metadata.get(&file_key));
+    let current = replaced_metadata.as_ref().unwrap_or(&restored.metadata);
+    let number_of_modifications = current.number_of_modifications;
+    let revision = current.revision;
+    let time_now = ic_cdk::api::time();
+    let metadata = FileMetadata {
+        last_modification_date: time_now,
+        number_of_modifications: number_of_modifications + 1,
+        last_modified_principal: caller,
+        revision: revision + 1,
+        // An expiry that has passed in the meantime is dropped rather than
+        // deleting the restored file right away.
+        expires_at: restored
+            .metadata
+            .expires_at
+            .filter(|expires_at| *expires_at > time_now),
+        ..restored.metadata
+    };
+    store_metadata(file_key, metadata.clone());
+    FILE_VERSIONS.with_borrow_mut(|file_versions| file_versions.remove(&(file_key, version)));
+    if let Some((value, metadata)) = replaced_value.zip(replaced_metadata) {
+        archive_version(file_key, value, metadata);
+    }
+    record_collection_ownership(had_files, owns_files(collection_owner));
+    Ok(metadata)
+}
+
+/// Keeps a replaced file content as the newest version of the file if its
+/// collection keeps versions, pruning the oldest versions beyond that count.
+pub fn archive_version(file_key: FileKey, value: EncryptedMapValue, metadata: FileMetadata) {
+    let keep_versions = collection_settings((file_key.0, file_key.1)).keep_versions as usize;
+    let mut versions: Vec<u32> = file_versions(file_key)
+        .into_iter()
+        .map(|(version, _)| version)
+        .collect();
+    FILE_VERSIONS.with_borrow_mut(|file_versions| {
+        if keep_versions > 0 {
+            let next_version = versions.last().map_or(0, |last| last + 1);
+            file_versions.insert((file_key, next_version), FileVersion { value, metadata });
+            versions.push(next_version);
+        }
+        let surplus = versions.len().saturating_sub(keep_versions);
+        for version in &versions[..surplus] {
+            file_versions.remove(&(file_key, *version));
+        }
+    });
+}
+
+/// Removes all kept versions of a file.
+/// Re-keys the versions of a file, e.g. when its collection is renamed.
+pub fn move_versions(from: FileKey, to: FileKey) {
+    FILE_VERSIONS.with_borrow_mut(|versions| {
+        for (version, file_version) in file_versions(from) {
+            versions.remove(&(from, version));
+            versions.insert((to, version), file_version);
+        }
+    });
+}
+
+pub fn remove_versions(file_key: FileKey) {
+    for (version, _) in file_versions(file_key) {
+        FILE_VERSIONS.with_borrow_mut(|file_versions| file_versions.remove(&(file_key, version)));
+    }
+}
+
+/// Removes the kept versions of all files in collections of `owner`.
+pub fn remove_owned_versions(owner: Principal) {
+    FILE_VERSIONS.with_borrow_mut(|file_versions| {
+        let keys: Vec<_> = file_versions
+            .range(((owner, Blob::default(), Blob::default()), 0)..)
+            .take_while(|(((file_owner, _, _), _), _)| file_owner == &owner)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            file_versions.remove(&key);
+        }
+    });
+}
+
+fn file_versions(file_key: FileKey) -> Vec<(u32, FileVersion)> {
+    FILE_VERSIONS.with_borrow(|file_versions| {
+        file_versions
+            .range((file_key, 0)..)
+            .take_while(|((key, _), _)| key == &file_key)
+            .map(|((_, version), file_version)| (version, file_version))
+            .collect()
+    })
+}